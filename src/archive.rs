@@ -0,0 +1,265 @@
+//! Moving environment images between machines as archives.
+//!
+//! `yadt export image` saves the environment image built for the current
+//! config, in the OCI archive format where the runtime supports it, next to
+//! a json manifest recording what it was built from: the config hash, the
+//! dev image, the architecture, the resolved config, and the lock file.
+//! `yadt import` loads the archive again, tags it as the image of the local
+//! workspace and remembers it in the state file, so that starting the
+//! environment with the same config reuses it instead of building.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    build::{
+        current_config_hash, environment_tag, image_exists, image_repository, BuildOptions,
+        EnvironmentImage, HostUser, LATEST_TAG,
+    },
+    config::Config,
+    error::Error,
+    lock::{Lockfile, LOCK_FILE_NAME},
+    manage::newest_environment_image,
+    query,
+    run::platform_arch,
+    runtime::{check_status, render_command, stderr_lines, Runtime},
+    state,
+};
+
+/// What an archive's manifest records about the image it holds
+#[derive(Debug, Deserialize, Serialize)]
+struct Manifest {
+    /// Tag of the image when it was exported, which the archive keeps
+    image: String,
+
+    config_hash: String,
+
+    /// The dev image the environment image was built on top of
+    dev_image: String,
+
+    /// Architecture of the image, eg. "arm64"
+    architecture: String,
+
+    /// The workspace the image was exported from
+    workspace: PathBuf,
+
+    /// The resolved config the image was exported with
+    config: serde_json::Value,
+
+    /// The workspace's lock file, if it had one
+    #[serde(default)]
+    lockfile: Option<Lockfile>,
+}
+
+/// Returns the path of the manifest of the archive at `archive`, eg.
+/// "env.tar.json" for "env.tar"
+fn manifest_path(archive: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(".json");
+    PathBuf::from(path)
+}
+
+/// Returns the architecture of `image`, eg. "amd64"
+fn image_architecture(runtime: &Runtime, image: &str) -> Result<String, Error> {
+    Ok(query::query(
+        runtime,
+        &format!("Inspecting {}", image),
+        &["image", "inspect", "--format", "{{.Architecture}}", image],
+    )?
+    .trim()
+    .to_string())
+}
+
+/// Saves the environment image built for the current config of `workspace`
+/// to an archive at `output`, along with its manifest
+pub fn export(
+    runtime: &Runtime,
+    config: &Config,
+    workspace: &Path,
+    output: &Path,
+    force: bool,
+) -> Result<(), Error> {
+    let manifest_output = manifest_path(output);
+    for path in [output, &manifest_output] {
+        if path.exists() && !force {
+            return Err(Error::Conflict(format!(
+                "{} already exists, pass --force to overwrite it",
+                path.display()
+            )));
+        }
+    }
+
+    let not_built = || {
+        Error::Conflict(format!(
+            "No environment was built for {} yet, build one with `yadt image` or `yadt containerfile` first",
+            workspace.display()
+        ))
+    };
+    let dev_image = newest_environment_image(runtime, workspace)?
+        .and_then(|image| image.dev_image)
+        .ok_or_else(not_built)?;
+    let config_hash = current_config_hash(
+        config,
+        workspace,
+        &dev_image,
+        &HostUser::current(),
+        &BuildOptions::from_config(config),
+    )?;
+    let image = environment_tag(workspace, &config_hash);
+    if !image_exists(runtime, &image)? {
+        return Err(Error::Conflict(format!(
+            "{} isn't built for the current config yet, start the environment to build it first",
+            image
+        )));
+    }
+
+    let manifest = Manifest {
+        architecture: image_architecture(runtime, &image)?,
+        image: image.clone(),
+        config_hash,
+        dev_image,
+        workspace: workspace.to_path_buf(),
+        config: serde_json::to_value(config)?,
+        lockfile: Lockfile::read(workspace)?,
+    };
+
+    let mut command = runtime.command();
+    command.args(runtime.backend().save_args(output, &image));
+    if runtime.is_dry_run() {
+        println!("{}", render_command(&command));
+        return Ok(());
+    }
+
+    let step = format!("Saving {} to {}", image, output.display());
+    log::info!("{}", step);
+    let result = runtime.output(&mut command)?;
+    check_status(runtime, &step, result.status, &stderr_lines(&result.stderr))?;
+
+    let text = serde_json::to_string_pretty(&manifest)? + "\n";
+    fs::write(&manifest_output, text).map_err(|source| Error::Path {
+        path: manifest_output.clone(),
+        source,
+    })?;
+
+    log::info!(
+        "Wrote {} and {}",
+        output.display(),
+        manifest_output.display()
+    );
+    Ok(())
+}
+
+/// Loads the environment image saved to the archive at `archive` and tags it
+/// as the image of `workspace`, failing before loading anything if its
+/// architecture isn't the one the environment runs as
+pub fn import(
+    runtime: &Runtime,
+    config: &Config,
+    workspace: &Path,
+    archive: &Path,
+) -> Result<(), Error> {
+    let manifest_input = manifest_path(archive);
+    let text = fs::read_to_string(&manifest_input).map_err(|source| Error::Path {
+        path: manifest_input.clone(),
+        source,
+    })?;
+    let manifest: Manifest = serde_json::from_str(&text).map_err(|error| {
+        Error::Config(format!(
+            "{} is not a manifest written by `yadt export image`: {}",
+            manifest_input.display(),
+            error
+        ))
+    })?;
+
+    // a foreign image would only fail with an exec format error once started
+    let image_arch = platform_arch(&manifest.architecture);
+    let arch = config
+        .platform
+        .as_deref()
+        .map_or(env::consts::ARCH, platform_arch);
+    if image_arch != arch {
+        return Err(Error::Config(match &config.platform {
+            Some(platform) => format!(
+                "{} holds an image for {}, but platform is set to {}",
+                archive.display(),
+                image_arch,
+                platform
+            ),
+            None => format!(
+                "{} holds an image for {}, which can't run on this {} host. Export it again on a {} host, or set platform = \"linux/{}\" in the config to run it emulated",
+                archive.display(),
+                image_arch,
+                arch,
+                arch,
+                manifest.architecture
+            ),
+        }));
+    }
+
+    let current_hash = current_config_hash(
+        config,
+        workspace,
+        &manifest.dev_image,
+        &HostUser::current(),
+        &BuildOptions::from_config(config),
+    )?;
+    if current_hash != manifest.config_hash {
+        log::warn!(
+            "{} was built for another config than the current one of {}, so starting the environment builds it again. The config it was built with is recorded in {}",
+            archive.display(),
+            workspace.display(),
+            manifest_input.display()
+        );
+    }
+
+    let archive_arg = archive.to_string_lossy();
+    runtime.apply(
+        &format!("Loading {}", archive.display()),
+        &["load", "--input", &archive_arg],
+    )?;
+
+    let tag = environment_tag(workspace, &manifest.config_hash);
+    let latest = format!("{}:{}", image_repository(workspace), LATEST_TAG);
+    for name in [&tag, &latest] {
+        runtime.apply(
+            &format!("Tagging {}", name),
+            &["tag", &manifest.image, name],
+        )?;
+    }
+    // the tag of the exporting workspace means nothing here
+    if manifest.image != tag {
+        runtime.apply(
+            &format!("Untagging {}", manifest.image),
+            &["rmi", &manifest.image],
+        )?;
+    }
+    if runtime.is_dry_run() {
+        return Ok(());
+    }
+
+    state::record_build(
+        runtime,
+        workspace,
+        &EnvironmentImage {
+            id: tag.clone(),
+            tag: tag.clone(),
+            config_hash: manifest.config_hash,
+            built: false,
+        },
+    );
+
+    // --frozen builds need the lock, which can otherwise only come from a build
+    if let Some(lockfile) = manifest.lockfile {
+        if Lockfile::read(workspace)?.is_none() {
+            lockfile.write(workspace)?;
+            log::info!("Wrote {}", workspace.join(LOCK_FILE_NAME).display());
+        }
+    }
+
+    log::info!("Imported {} as {}", archive.display(), tag);
+    Ok(())
+}
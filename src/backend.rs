@@ -0,0 +1,916 @@
+//! Translating the images yadt builds and the containers it runs into the
+//! flags of each container runtime.
+//!
+//! Builds and runs are described by a `BuildSpec` or `RunSpec`, and the
+//! `ContainerRuntime` of the detected runtime turns them into arguments.
+//! Most flags are shared, so the trait's defaults follow docker and each
+//! backend only overrides where its runtime differs.
+
+use std::{
+    ffi::{OsStr, OsString},
+    path::Path,
+};
+
+use crate::{
+    build::{host_proxy_variables, HostUser},
+    config::{Gpu, PullPolicy, ResourceLimits},
+    registry::AuthFile,
+};
+
+/// Where a build reads its containerfile from
+#[derive(Clone, Copy, Debug)]
+pub enum Containerfile<'a> {
+    /// A containerfile on the host
+    Path(&'a Path),
+
+    /// A containerfile written to the build's stdin
+    Piped(&'a [u8]),
+}
+
+/// An image to build
+#[derive(Debug)]
+pub struct BuildSpec<'a> {
+    pub containerfile: Containerfile<'a>,
+
+    /// The build context, which is only needed by containerfiles that copy
+    /// files into the image
+    pub context: Option<&'a Path>,
+
+    pub tag: Option<&'a str>,
+    pub build_args: Vec<(&'static str, String)>,
+
+    /// Labels formatted as "KEY=VALUE"
+    pub labels: Vec<OsString>,
+
+    /// Volumes mounted into the build's steps, formatted as
+    /// "HOST:CONTAINER[:OPTIONS]"
+    pub volumes: Vec<OsString>,
+
+    /// When the images the build starts from are pulled, or the runtime's
+    /// default if None
+    pub pull: Option<PullPolicy>,
+
+    /// Credentials for the registries the build pulls from, if any are
+    /// configured
+    pub auth: Option<&'a AuthFile>,
+
+    /// The platform built for, eg. "linux/arm64", or the host's if None
+    pub platform: Option<&'a str>,
+}
+
+/// A container to run an environment in
+#[derive(Debug)]
+pub struct RunSpec<'a> {
+    pub image: &'a str,
+    pub name: &'a str,
+
+    /// Whether to allocate a tty for the container
+    pub tty: bool,
+
+    /// Whether the container runs in the background, with an init process
+    /// reaping what is started in it, instead of attached to yadt's stdio
+    pub detach: bool,
+
+    pub workdir: &'a str,
+
+    /// The user the container runs as, who owns the files it creates in the
+    /// workspace
+    pub user: &'a HostUser,
+
+    /// Whether the user's id is kept in the container's user namespace,
+    /// which podman can't do when it runs as root
+    pub keep_id: bool,
+
+    /// Volumes formatted as "HOST:CONTAINER[:OPTIONS]"
+    pub volumes: Vec<OsString>,
+
+    /// Labels formatted as "KEY=VALUE"
+    pub labels: Vec<OsString>,
+
+    /// The network the container joins, eg. "host"
+    pub network: &'a str,
+
+    /// Whether the host's proxy variables are passed into the container
+    pub proxy: bool,
+
+    /// The platform the image was built for, or the host's if None
+    pub platform: Option<&'a str>,
+
+    /// Ports to publish, formatted as "HOST:CONTAINER/PROTOCOL"
+    pub ports: Vec<String>,
+
+    /// Environment variables formatted as "NAME=VALUE"
+    pub env: Vec<OsString>,
+
+    /// tmpfs mounts formatted as "CONTAINER[:OPTIONS]"
+    pub tmpfs: Vec<String>,
+
+    /// The size of `/dev/shm`, or the runtime's default if None
+    pub shm_size: Option<String>,
+
+    /// GPUs passed into the container, whose host setup was checked already
+    pub gpu: Option<Gpu>,
+
+    /// Host devices passed into the container besides GPUs, eg. "/dev/snd"
+    pub devices: Vec<OsString>,
+
+    /// Limits on the resources the container may use, which were validated
+    /// already
+    pub limits: ResourceLimits,
+
+    /// Capabilities granted to and taken from the container, eg. "SYS_PTRACE"
+    pub cap_add: Vec<String>,
+    pub cap_drop: Vec<String>,
+
+    /// Options passed to `--security-opt`, eg. "seccomp=unconfined"
+    pub security_opt: Vec<String>,
+
+    /// Groups the container user is added to besides its own, eg. the group
+    /// owning a mounted socket
+    pub groups: Vec<String>,
+
+    /// Whether the container runs privileged, with every capability and the
+    /// host's devices, which the user confirmed already
+    pub privileged: bool,
+
+    /// The command the container runs
+    pub command: Vec<OsString>,
+}
+
+/// A command to run in a container that is already running
+#[derive(Debug)]
+pub struct ExecSpec<'a> {
+    /// Name of the running container
+    pub name: &'a str,
+
+    /// Whether to allocate a tty for the command
+    pub tty: bool,
+
+    pub workdir: &'a str,
+    pub command: Vec<OsString>,
+}
+
+/// Parts of the messages of failures that may pass when retried, printed by
+/// the tools running inside builds, such as nix and curl, whatever the
+/// runtime
+const TRANSIENT_FAILURE_MARKERS: &[&str] = &[
+    "could not resolve host",
+    "couldn't resolve host name",
+    "temporary failure in name resolution",
+    "connection timed out",
+    "connection reset by peer",
+    "unable to download",
+    "http error 500",
+    "http error 502",
+    "http error 503",
+    "http error 504",
+];
+
+/// Parts of the messages of failures that fail again when retried, printed by
+/// the tools running inside builds whatever the runtime
+const PERMANENT_FAILURE_MARKERS: &[&str] = &[
+    "http error 404",
+    "does not provide attribute",
+    "undefined variable",
+    "syntax error",
+];
+
+/// Translates build and run specs into the flags of a container runtime
+pub trait ContainerRuntime {
+    /// Whether volumes can be mounted into builds
+    fn can_mount_build_volumes(&self) -> bool {
+        false
+    }
+
+    /// The oldest version yadt is known to work with, eg. "20.10"
+    fn minimum_version(&self) -> &'static str {
+        "20.10"
+    }
+
+    /// The `info --format` template printing the directory images are
+    /// stored in
+    fn storage_root_format(&self) -> &'static str {
+        "{{.DockerRootDir}}"
+    }
+
+    /// Returns the arguments looking `image` up in its registry without
+    /// pulling it, or None if the runtime can't
+    fn resolve_image_args(&self, image: &str) -> Option<Vec<OsString>> {
+        Some(vec!["manifest".into(), "inspect".into(), image.into()])
+    }
+
+    /// The variable the runtime reads the url of a service elsewhere from
+    fn host_variable(&self) -> &'static str {
+        "DOCKER_HOST"
+    }
+
+    /// Returns the global flags connecting to the service at `url`
+    fn host_args(&self, url: &str) -> Vec<OsString> {
+        vec!["--host".into(), url.into()]
+    }
+
+    /// Returns the global flags connecting to the service of the named
+    /// `connection`, or None if the runtime has no named connections
+    fn connection_args(&self, connection: &str) -> Option<Vec<OsString>> {
+        Some(vec!["--context".into(), connection.into()])
+    }
+
+    /// Returns the arguments following `build` for `spec`, except for the
+    /// context, which must be the final argument
+    fn build_args(&self, spec: &BuildSpec) -> Vec<OsString> {
+        let mut args = vec![OsString::from("build"), OsString::from("-f")];
+        args.push(match spec.containerfile {
+            Containerfile::Path(path) => path.into(),
+            Containerfile::Piped(_) => "-".into(),
+        });
+
+        if let Some(tag) = spec.tag {
+            args.extend(["--tag".into(), tag.into()]);
+        }
+        if let Some(platform) = spec.platform {
+            args.extend(["--platform".into(), platform.into()]);
+        }
+        for (key, value) in &spec.build_args {
+            args.extend(["--build-arg".into(), format!("{}={}", key, value).into()]);
+        }
+        for label in &spec.labels {
+            args.extend(["--label".into(), label.clone()]);
+        }
+        for volume in &spec.volumes {
+            args.extend(["--volume".into(), volume.clone()]);
+        }
+        if let Some(pull) = spec.pull {
+            args.extend(self.pull_args(pull));
+        }
+        if let Some(auth) = spec.auth {
+            args.extend(self.auth_args(&auth.path()));
+        }
+
+        args
+    }
+
+    /// Returns the flags of `build` and `pull` reading credentials from the
+    /// auth file at `path`
+    fn auth_args(&self, _path: &Path) -> Vec<OsString> {
+        Vec::new()
+    }
+
+    /// Returns the environment variable pointing `build` and `pull` at the
+    /// auth file at `path`. docker only reads auth files named config.json
+    /// from the directory in `DOCKER_CONFIG`.
+    fn auth_env(&self, path: &Path) -> (&'static str, OsString) {
+        let dir = path.parent().expect("Auth file has no parent");
+        ("DOCKER_CONFIG", dir.into())
+    }
+
+    /// Returns parts of the messages the runtime fails builds and pulls with
+    /// when they may pass if retried, such as a registry that can't be
+    /// reached for the moment, in lowercase
+    fn transient_failure_markers(&self) -> &'static [&'static str] {
+        &[
+            "no such host",
+            "tls handshake timeout",
+            "i/o timeout",
+            "connection refused",
+            "unexpected eof",
+            "toomanyrequests",
+            "500 internal server error",
+            "502 bad gateway",
+            "503 service unavailable",
+            "504 gateway timeout",
+        ]
+    }
+
+    /// Returns parts of the messages the runtime fails builds and pulls with
+    /// when they would fail again, such as an image that doesn't exist, in
+    /// lowercase. These win over `transient_failure_markers`.
+    fn permanent_failure_markers(&self) -> &'static [&'static str] {
+        &[
+            "manifest unknown",
+            "not found",
+            "pull access denied",
+            "dockerfile parse error",
+        ]
+    }
+
+    /// Returns whether the failure whose stderr ended with `stderr_tail` may
+    /// pass if retried
+    fn is_transient_failure(&self, stderr_tail: &[String]) -> bool {
+        let lines: Vec<String> = stderr_tail.iter().map(|line| line.to_lowercase()).collect();
+        let mentions = |markers: &[&str]| {
+            lines
+                .iter()
+                .any(|line| markers.iter().any(|marker| line.contains(marker)))
+        };
+
+        if mentions(PERMANENT_FAILURE_MARKERS) || mentions(self.permanent_failure_markers()) {
+            return false;
+        }
+        mentions(TRANSIENT_FAILURE_MARKERS) || mentions(self.transient_failure_markers())
+    }
+
+    /// Returns the flags of `build` pulling images according to `pull`.
+    /// docker's `--pull` only asks for newer images to be pulled, while
+    /// missing images are always pulled, so "never" can't be honored.
+    fn pull_args(&self, pull: PullPolicy) -> Vec<OsString> {
+        match pull {
+            PullPolicy::Always | PullPolicy::Newer => vec!["--pull".into()],
+            PullPolicy::Missing => Vec::new(),
+            PullPolicy::Never => {
+                log::warn!(
+                    "Missing images are pulled regardless of pull_policy \"never\" with docker"
+                );
+                Vec::new()
+            }
+        }
+    }
+
+    /// Returns the arguments of `run` for `spec`
+    fn run_args(&self, spec: &RunSpec) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec!["run".into(), "--rm".into()];
+        if spec.detach {
+            args.extend(["--detach".into(), "--init".into()]);
+        } else {
+            args.push("--interactive".into());
+            if spec.tty {
+                args.push("--tty".into());
+            }
+        }
+
+        args.extend(["--workdir".into(), spec.workdir.into()]);
+        args.extend(self.user_args(spec.user, spec.keep_id));
+        for tmpfs in &spec.tmpfs {
+            args.extend(["--tmpfs".into(), tmpfs.into()]);
+        }
+        if let Some(shm_size) = &spec.shm_size {
+            args.extend(["--shm-size".into(), shm_size.into()]);
+        }
+        for volume in &spec.volumes {
+            args.extend(["--volume".into(), volume.clone()]);
+        }
+        for variable in &spec.env {
+            args.extend(["--env".into(), variable.clone()]);
+        }
+        args.extend(["--name".into(), spec.name.into()]);
+        if let Some(platform) = spec.platform {
+            args.extend(["--platform".into(), platform.into()]);
+        }
+        for label in &spec.labels {
+            args.extend(["--label".into(), label.clone()]);
+        }
+        if let Some(gpu) = spec.gpu {
+            args.extend(self.gpu_args(gpu));
+        }
+        for device in &spec.devices {
+            args.extend(["--device".into(), device.clone()]);
+        }
+        for group in &spec.groups {
+            args.extend(["--group-add".into(), group.into()]);
+        }
+        if spec.privileged {
+            args.push("--privileged".into());
+        }
+        for capability in &spec.cap_add {
+            args.extend(["--cap-add".into(), capability.into()]);
+        }
+        for capability in &spec.cap_drop {
+            args.extend(["--cap-drop".into(), capability.into()]);
+        }
+        for option in &spec.security_opt {
+            args.extend(["--security-opt".into(), option.into()]);
+        }
+        if let Some(memory) = &spec.limits.memory {
+            args.extend(["--memory".into(), memory.into()]);
+        }
+        if let Some(memory_swap) = &spec.limits.memory_swap {
+            args.extend(["--memory-swap".into(), memory_swap.into()]);
+        }
+        if let Some(cpus) = spec.limits.cpus {
+            args.extend(["--cpus".into(), cpus.to_string().into()]);
+        }
+        if let Some(pids_limit) = spec.limits.pids_limit {
+            args.extend(["--pids-limit".into(), pids_limit.to_string().into()]);
+        }
+        args.extend(["--network".into(), spec.network.into()]);
+        for port in &spec.ports {
+            args.extend(["--publish".into(), port.into()]);
+        }
+        args.extend(self.extra_run_args(spec));
+
+        args.push(spec.image.into());
+        args.extend(spec.command.iter().cloned());
+        args
+    }
+
+    /// Returns the arguments of `exec` for `spec`. The command runs as the
+    /// container's user, with its environment.
+    fn exec_args(&self, spec: &ExecSpec) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec!["exec".into(), "--interactive".into()];
+        if spec.tty {
+            args.push("--tty".into());
+        }
+
+        args.extend(["--workdir".into(), spec.workdir.into()]);
+        args.push(spec.name.into());
+        args.extend(spec.command.iter().cloned());
+        args
+    }
+
+    /// Returns the arguments of `cp` copying `source` to `destination`, one
+    /// of which is prefixed with a container's name. Copies into the
+    /// container keep the host's ownership, which the container user shares.
+    fn cp_args(&self, source: &OsStr, destination: &OsStr) -> Vec<OsString> {
+        vec![
+            "cp".into(),
+            "--archive".into(),
+            source.into(),
+            destination.into(),
+        ]
+    }
+
+    /// Returns the arguments of `save` writing `image` to an archive at
+    /// `output`. docker writes a docker archive, which other runtimes load
+    /// as well.
+    fn save_args(&self, output: &Path, image: &str) -> Vec<OsString> {
+        vec![
+            "save".into(),
+            "--output".into(),
+            output.into(),
+            image.into(),
+        ]
+    }
+
+    /// Returns the mount option giving the container user ownership of a
+    /// fresh named volume. Without one, the runtime is expected to copy the
+    /// owner of the mount point in the image, as docker does.
+    fn owned_volume_option(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Returns the flags passing `gpu` into the container. nvidia GPUs are
+    /// requested with `--gpus`, which the nvidia container toolkit hooks into.
+    fn gpu_args(&self, gpu: Gpu) -> Vec<OsString> {
+        let mut args = Vec::new();
+        if gpu.includes_nvidia() {
+            args.extend(["--gpus".into(), "all".into()]);
+        }
+        if gpu.includes_dri() {
+            args.extend(["--device".into(), "/dev/dri".into()]);
+        }
+        args
+    }
+
+    /// Returns the flags running the container as `user`, keeping their id
+    /// in the container's user namespace if `keep_id` is set and the runtime
+    /// maps ids at all
+    fn user_args(&self, user: &HostUser, _keep_id: bool) -> Vec<OsString> {
+        vec!["--user".into(), format!("{}:{}", user.uid, user.gid).into()]
+    }
+
+    /// Returns flags of `run` for `spec` only this runtime has. By default,
+    /// the host's proxy variables are set in the container one by one.
+    fn extra_run_args(&self, spec: &RunSpec) -> Vec<OsString> {
+        if !spec.proxy || spec.network == "none" {
+            return Vec::new();
+        }
+        host_proxy_variables()
+            .into_iter()
+            .flat_map(|(name, value)| ["--env".into(), format!("{}={}", name, value).into()])
+            .collect()
+    }
+}
+
+/// podman, which can mount volumes into builds and keep the user's id
+#[derive(Clone, Copy, Debug)]
+pub struct Podman;
+
+impl ContainerRuntime for Podman {
+    fn can_mount_build_volumes(&self) -> bool {
+        true
+    }
+
+    fn minimum_version(&self) -> &'static str {
+        "4.0"
+    }
+
+    fn storage_root_format(&self) -> &'static str {
+        "{{.Store.GraphRoot}}"
+    }
+
+    fn host_variable(&self) -> &'static str {
+        "CONTAINER_HOST"
+    }
+
+    fn host_args(&self, url: &str) -> Vec<OsString> {
+        vec!["--url".into(), url.into()]
+    }
+
+    fn connection_args(&self, connection: &str) -> Option<Vec<OsString>> {
+        Some(vec!["--connection".into(), connection.into()])
+    }
+
+    fn pull_args(&self, pull: PullPolicy) -> Vec<OsString> {
+        vec![format!("--pull={}", pull.name()).into()]
+    }
+
+    fn cp_args(&self, source: &OsStr, destination: &OsStr) -> Vec<OsString> {
+        // podman chowns copies to the container's user by default
+        vec!["cp".into(), source.into(), destination.into()]
+    }
+
+    fn save_args(&self, output: &Path, image: &str) -> Vec<OsString> {
+        vec![
+            "save".into(),
+            "--format".into(),
+            "oci-archive".into(),
+            "--output".into(),
+            output.into(),
+            image.into(),
+        ]
+    }
+
+    fn transient_failure_markers(&self) -> &'static [&'static str] {
+        // podman reports registry errors through containers/image
+        &[
+            "no such host",
+            "tls handshake timeout",
+            "i/o timeout",
+            "unexpected eof",
+            "toomanyrequests",
+            "received unexpected http status: 5",
+            "connection refused",
+        ]
+    }
+
+    fn permanent_failure_markers(&self) -> &'static [&'static str] {
+        &[
+            "manifest unknown",
+            "name unknown",
+            "not found",
+            "unknown instruction",
+            "requested access to the resource is denied",
+        ]
+    }
+
+    fn auth_args(&self, path: &Path) -> Vec<OsString> {
+        vec!["--authfile".into(), path.into()]
+    }
+
+    fn auth_env(&self, path: &Path) -> (&'static str, OsString) {
+        ("REGISTRY_AUTH_FILE", path.into())
+    }
+
+    fn owned_volume_option(&self) -> Option<&'static str> {
+        // rootless podman leaves volumes owned by the container's root, which
+        // keep-id maps away from the user. the chown is recursive, so it is
+        // only worth doing once
+        Some("U")
+    }
+
+    fn gpu_args(&self, gpu: Gpu) -> Vec<OsString> {
+        // podman requests nvidia GPUs as a CDI device instead
+        let mut args = Vec::new();
+        if gpu.includes_nvidia() {
+            args.extend(["--device".into(), "nvidia.com/gpu=all".into()]);
+        }
+        if gpu.includes_dri() {
+            args.extend(["--device".into(), "/dev/dri".into()]);
+        }
+        args
+    }
+
+    fn user_args(&self, user: &HostUser, keep_id: bool) -> Vec<OsString> {
+        let mut args = vec!["--user".into(), format!("{}:{}", user.uid, user.gid).into()];
+        // without keep-id, the user's uid inside the container maps to a
+        // subuid on the host
+        if keep_id {
+            args.extend(["--userns".into(), "keep-id".into()]);
+        }
+        args
+    }
+
+    fn extra_run_args(&self, spec: &RunSpec) -> Vec<OsString> {
+        // making the most of the podman dep, though proxies are of no use to
+        // a container without a network
+        if spec.network == "none" {
+            return Vec::new();
+        }
+        // podman passes the proxy in on its own unless told not to
+        vec![format!("--http-proxy={}", spec.proxy).into()]
+    }
+}
+
+/// docker, which has no equivalent of keep-id, so files are only owned
+/// correctly because of `--user`
+#[derive(Clone, Copy, Debug)]
+pub struct Docker;
+
+impl ContainerRuntime for Docker {}
+
+/// nerdctl, the containerd cli, which follows docker's flags
+#[derive(Clone, Copy, Debug)]
+pub struct Nerdctl;
+
+impl ContainerRuntime for Nerdctl {
+    fn minimum_version(&self) -> &'static str {
+        "1.0"
+    }
+
+    fn resolve_image_args(&self, _image: &str) -> Option<Vec<OsString>> {
+        // nerdctl has no manifest subcommand
+        None
+    }
+
+    fn host_variable(&self) -> &'static str {
+        "CONTAINERD_ADDRESS"
+    }
+
+    fn host_args(&self, url: &str) -> Vec<OsString> {
+        vec!["--address".into(), url.into()]
+    }
+
+    fn connection_args(&self, _connection: &str) -> Option<Vec<OsString>> {
+        None
+    }
+
+    fn cp_args(&self, source: &OsStr, destination: &OsStr) -> Vec<OsString> {
+        // nerdctl has no --archive
+        vec!["cp".into(), source.into(), destination.into()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Config, ConfigSources},
+        runtime::{DryRun, RecordingRunner, Runtime},
+    };
+
+    fn user() -> HostUser {
+        HostUser {
+            name: "dev".to_string(),
+            uid: 1000,
+            gid: 100,
+        }
+    }
+
+    /// A spec running `make test` in `image` with nothing else configured
+    fn spec(user: &HostUser) -> RunSpec<'_> {
+        RunSpec {
+            image: "image",
+            name: "yadt-test",
+            tty: false,
+            detach: false,
+            workdir: "/workspace",
+            user,
+            keep_id: true,
+            volumes: Vec::new(),
+            labels: Vec::new(),
+            network: "host",
+            proxy: false,
+            platform: None,
+            ports: Vec::new(),
+            env: Vec::new(),
+            tmpfs: Vec::new(),
+            shm_size: None,
+            gpu: None,
+            devices: Vec::new(),
+            limits: ResourceLimits::default(),
+            cap_add: Vec::new(),
+            cap_drop: Vec::new(),
+            security_opt: Vec::new(),
+            groups: Vec::new(),
+            privileged: false,
+            command: vec!["make".into(), "test".into()],
+        }
+    }
+
+    fn strings(args: Vec<OsString>) -> Vec<String> {
+        args.into_iter()
+            .map(|arg| arg.into_string().expect("The arguments are utf-8"))
+            .collect()
+    }
+
+    /// Returns whether `args` contain `flag` directly followed by `value`
+    fn has_flag(args: &[String], flag: &str, value: &str) -> bool {
+        args.windows(2)
+            .any(|pair| pair[0] == flag && pair[1] == value)
+    }
+
+    #[test]
+    fn podman_keeps_the_user_id_only_when_asked() {
+        let user = user();
+        let mut spec = spec(&user);
+
+        let args = strings(Podman.run_args(&spec));
+        assert!(has_flag(&args, "--user", "1000:100"), "{:?}", args);
+        assert!(has_flag(&args, "--userns", "keep-id"), "{:?}", args);
+
+        spec.keep_id = false;
+        let args = strings(Podman.run_args(&spec));
+        assert!(has_flag(&args, "--user", "1000:100"), "{:?}", args);
+        assert!(!args.contains(&"--userns".to_string()), "{:?}", args);
+    }
+
+    #[test]
+    fn docker_and_nerdctl_run_as_the_user_without_keep_id() {
+        let user = user();
+        let spec = spec(&user);
+        for backend in [&Docker as &dyn ContainerRuntime, &Nerdctl] {
+            let args = strings(backend.run_args(&spec));
+            assert!(has_flag(&args, "--user", "1000:100"), "{:?}", args);
+            assert!(!args.contains(&"--userns".to_string()), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn only_podman_is_told_whether_to_pass_the_proxy() {
+        let user = user();
+        let mut spec = spec(&user);
+
+        assert_eq!(
+            strings(Podman.extra_run_args(&spec)),
+            ["--http-proxy=false"]
+        );
+        spec.proxy = true;
+        assert_eq!(strings(Podman.extra_run_args(&spec)), ["--http-proxy=true"]);
+        for backend in [&Docker as &dyn ContainerRuntime, &Nerdctl] {
+            let args = strings(backend.run_args(&spec));
+            assert!(
+                !args.iter().any(|arg| arg.starts_with("--http-proxy")),
+                "{:?}",
+                args
+            );
+        }
+
+        // a container without a network has no use for a proxy
+        spec.network = "none";
+        for backend in [&Podman as &dyn ContainerRuntime, &Docker, &Nerdctl] {
+            assert!(backend.extra_run_args(&spec).is_empty());
+        }
+    }
+
+    #[test]
+    fn run_args_end_with_the_image_and_command() {
+        let user = user();
+        let spec = spec(&user);
+        for backend in [&Podman as &dyn ContainerRuntime, &Docker, &Nerdctl] {
+            let args = strings(backend.run_args(&spec));
+            assert_eq!(args[..3], ["run", "--rm", "--interactive"]);
+            assert!(!args.contains(&"--tty".to_string()), "{:?}", args);
+            assert_eq!(args[args.len() - 3..], ["image", "make", "test"]);
+            assert!(has_flag(&args, "--name", "yadt-test"), "{:?}", args);
+            assert!(has_flag(&args, "--network", "host"), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn detected_runtime_runs_its_backends_flags() {
+        let user = user();
+        let spec = spec(&user);
+        for (version, backend_flags) in [
+            (
+                "podman version 4.9.3",
+                &["--userns", "--http-proxy=false"][..],
+            ),
+            ("Docker version 24.0.7, build afdd53b", &[]),
+            ("nerdctl version 1.7.6", &[]),
+        ] {
+            let runner = RecordingRunner::printing(version);
+            let runtime = Runtime::detect_with(
+                Box::new(runner.clone()),
+                &Config::default(),
+                &ConfigSources::default(),
+                DryRun::Off,
+            )
+            .expect("The probe succeeds");
+
+            let mut command = runtime.command();
+            command.args(runtime.backend().run_args(&spec));
+            runtime.status(&mut command).expect("The runner records it");
+
+            let commands = runner.commands();
+            let run = commands.last().expect("The run was recorded");
+            // podman is probed first, so it answers for every runtime
+            assert_eq!(run[..2], ["podman", "run"]);
+            for flag in ["--userns", "--http-proxy=false"] {
+                assert_eq!(
+                    run.iter().any(|arg| arg == flag),
+                    backend_flags.contains(&flag),
+                    "{}: {:?}",
+                    version,
+                    run
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn run_args_mount_tmpfs_and_size_shm() {
+        let user = user();
+        let mut spec = spec(&user);
+        for backend in [&Podman as &dyn ContainerRuntime, &Docker, &Nerdctl] {
+            let args = strings(backend.run_args(&spec));
+            assert!(!args.contains(&"--tmpfs".to_string()), "{:?}", args);
+            assert!(!args.contains(&"--shm-size".to_string()), "{:?}", args);
+        }
+
+        spec.tmpfs = vec![
+            "/tmp/scratch:size=2g,mode=1777".to_string(),
+            "/cache".to_string(),
+        ];
+        spec.shm_size = Some("2g".to_string());
+        for backend in [&Podman as &dyn ContainerRuntime, &Docker, &Nerdctl] {
+            let args = strings(backend.run_args(&spec));
+            assert!(
+                has_flag(&args, "--tmpfs", "/tmp/scratch:size=2g,mode=1777"),
+                "{:?}",
+                args
+            );
+            assert!(has_flag(&args, "--tmpfs", "/cache"), "{:?}", args);
+            assert!(has_flag(&args, "--shm-size", "2g"), "{:?}", args);
+        }
+    }
+
+    /// The last line nix prints when a package doesn't exist, whatever runs
+    /// the build
+    const MISSING_PACKAGE: &str = "error: flake 'flake:nixpkgs' does not provide attribute 'packages.x86_64-linux.ripgerp', 'legacyPackages.x86_64-linux.ripgerp' or 'ripgerp'";
+
+    /// Asserts that each stderr line in `cases` is classified as transient
+    /// or not by `backend`
+    fn assert_classified(backend: &dyn ContainerRuntime, cases: &[(&str, &str, bool)]) {
+        for (case, stderr, transient) in cases {
+            let tail = vec!["STEP 1/12: FROM nix".to_string(), stderr.to_string()];
+            assert_eq!(
+                backend.is_transient_failure(&tail),
+                *transient,
+                "{}: {}",
+                case,
+                stderr
+            );
+        }
+    }
+
+    #[test]
+    fn classifies_podman_failures() {
+        assert_classified(
+            &Podman,
+            &[
+                ("dns", "Error: initializing source docker://registry.example.com/app:latest: pinging container registry registry.example.com: Get \"https://registry.example.com/v2/\": dial tcp: lookup registry.example.com on 10.0.0.1:53: no such host", true),
+                ("tls", "Error: initializing source docker://quay.io/app:latest: pinging container registry quay.io: Get \"https://quay.io/v2/\": net/http: TLS handshake timeout", true),
+                ("5xx", "Error: copying system image from manifest list: reading blob sha256:4f4fb700ef54: fetching blob: received unexpected HTTP status: 503 Service Unavailable", true),
+                ("404", "Error: initializing source docker://docker.io/library/nope:latest: reading manifest latest in docker.io/library/nope: manifest unknown", false),
+                ("denied", "Error: initializing source docker://private/app:latest: reading manifest latest in docker.io/private/app: requested access to the resource is denied", false),
+                ("containerfile", "Error: parsing containerfile: unknown instruction: RUNN", false),
+                ("package", MISSING_PACKAGE, false),
+                ("nix dns", "warning: unable to download 'https://cache.nixos.org/nix-cache-info': Couldn't resolve host name (6)", true),
+            ],
+        );
+    }
+
+    #[test]
+    fn classifies_docker_failures() {
+        assert_classified(
+            &Docker,
+            &[
+                ("dns", "ERROR: failed to solve: debian:stable: failed to resolve source metadata for docker.io/library/debian:stable: failed to do request: Head \"https://registry-1.docker.io/v2/library/debian/manifests/stable\": dial tcp: lookup registry-1.docker.io on 127.0.0.11:53: no such host", true),
+                ("tls", "Error response from daemon: Get \"https://registry-1.docker.io/v2/\": net/http: TLS handshake timeout", true),
+                ("5xx", "Error response from daemon: received unexpected HTTP status: 502 Bad Gateway", true),
+                ("rate limit", "ERROR: failed to solve: debian:stable: failed to copy: httpReadSeeker: failed open: unexpected status code https://registry-1.docker.io/v2/library/debian/manifests/sha256:abc: 429 Too Many Requests - Server message: toomanyrequests: You have reached your pull rate limit", true),
+                ("404", "Error response from daemon: manifest for debian:nope not found: manifest unknown: manifest unknown", false),
+                ("dockerfile", "ERROR: failed to solve: dockerfile parse error on line 3: unknown instruction: RUNN", false),
+                ("package", MISSING_PACKAGE, false),
+                ("nix 5xx", "error: unable to download 'https://cache.nixos.org/abc.narinfo': HTTP error 500", true),
+                ("nix 404", "error: unable to download 'https://example.com/src.tar.gz': HTTP error 404", false),
+            ],
+        );
+    }
+
+    #[test]
+    fn classifies_nerdctl_failures() {
+        assert_classified(
+            &Nerdctl,
+            &[
+                ("dns", "time=\"2024-05-01T10:00:00Z\" level=fatal msg=\"failed to resolve reference \\\"docker.io/library/debian:stable\\\": failed to do request: Head \\\"https://registry-1.docker.io/v2/library/debian/manifests/stable\\\": dial tcp: lookup registry-1.docker.io: no such host\"", true),
+                ("tls", "time=\"2024-05-01T10:00:00Z\" level=fatal msg=\"failed to resolve reference \\\"ghcr.io/app:latest\\\": failed to do request: Head \\\"https://ghcr.io/v2/app/manifests/latest\\\": net/http: TLS handshake timeout\"", true),
+                ("5xx", "time=\"2024-05-01T10:00:00Z\" level=fatal msg=\"failed to resolve reference \\\"ghcr.io/app:latest\\\": unexpected status from HEAD request to https://ghcr.io/v2/app/manifests/latest: 503 Service Unavailable\"", true),
+                ("404", "time=\"2024-05-01T10:00:00Z\" level=fatal msg=\"failed to resolve reference \\\"docker.io/library/nope:latest\\\": docker.io/library/nope:latest: not found\"", false),
+                ("package", MISSING_PACKAGE, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn unknown_and_permanent_failures_are_not_retried() {
+        for backend in [&Podman as &dyn ContainerRuntime, &Docker, &Nerdctl] {
+            assert!(!backend.is_transient_failure(&[]));
+            assert!(!backend.is_transient_failure(&["Error: exit status 1".to_string()]));
+            // a missing package stays missing, however flaky the network was
+            assert!(!backend.is_transient_failure(&[
+                "warning: unable to download 'https://cache.nixos.org/nix-cache-info': Couldn't resolve host name (6)".to_string(),
+                MISSING_PACKAGE.to_string(),
+            ]));
+        }
+    }
+}
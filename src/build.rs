@@ -0,0 +1,1359 @@
+//! Building dev images, and the environment images that install the
+//! configured nix packages on top of them with the embedded containerfile.
+
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    env,
+    ffi::{CStr, OsString},
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{ffi::OsStrExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{self, Child, Command, ExitStatus, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::Level;
+
+use crate::{
+    backend::{BuildSpec, Containerfile},
+    config::{canonicalize, expand_home, Config, PullPolicy, HOST_NIX_MOUNT},
+    error::Error,
+    events::{self, Event},
+    lock::{Lockfile, BUILD_MANIFEST_PATH, LOCK_FILE_NAME},
+    progress, query,
+    registry::{auth_hint, image_registry, AuthFile},
+    run::volume_arg,
+    runtime::{check_status, render_command, DryRun, Runtime},
+    signal::{kill_group, Forwarding},
+};
+
+/// Containerfile used to build nix image and copy packages into dev image
+pub static CONTAINERFILE: &[u8] = include_bytes!("Containerfile");
+
+/// Build args a `containerfile_template` must declare, since yadt passes
+/// them to every build and the image is unusable without them
+const REQUIRED_TEMPLATE_ARGS: [&str; 6] = [
+    "NIX_IMAGE",
+    "DEV_IMAGE",
+    "PACKAGES_STRING",
+    "USERNAME",
+    "USER_UID",
+    "USER_GID",
+];
+
+/// Returns the containerfile environment images are built from: the file
+/// `containerfile_template` names, relative to `workspace`, or the embedded
+/// one. A template is checked to declare `REQUIRED_TEMPLATE_ARGS`.
+pub fn environment_containerfile(
+    config: &Config,
+    workspace: &Path,
+) -> Result<Cow<'static, [u8]>, Error> {
+    let Some(template) = &config.containerfile_template else {
+        return Ok(Cow::Borrowed(CONTAINERFILE));
+    };
+
+    let path = workspace.join(expand_home(template));
+    let containerfile = fs::read(&path).map_err(|source| Error::Path {
+        path: path.clone(),
+        source,
+    })?;
+
+    let text = String::from_utf8_lossy(&containerfile);
+    let declared: Vec<&str> = text
+        .lines()
+        .filter_map(|line| {
+            let (instruction, rest) = line.trim_start().split_once(char::is_whitespace)?;
+            instruction.eq_ignore_ascii_case("ARG").then_some(rest)
+        })
+        .filter_map(|rest| rest.trim().split(['=', ' ']).next())
+        .collect();
+    let missing: Vec<&str> = REQUIRED_TEMPLATE_ARGS
+        .into_iter()
+        .filter(|arg| !declared.contains(arg))
+        .collect();
+    if !missing.is_empty() {
+        return Err(Error::Config(format!(
+            "The containerfile_template {} doesn't declare ARG {}, which yadt passes to every build. `yadt containerfile show` prints the embedded containerfile to start from",
+            path.display(),
+            missing.join(", ")
+        )));
+    }
+
+    Ok(Cow::Owned(containerfile))
+}
+
+/// Number of trailing lines of a failed build's stderr included in its error
+const STDERR_TAIL_LINES: usize = 10;
+
+/// Returns a 64 bit FNV-1a hash of `bytes`. Unlike `std::hash`, this is
+/// guaranteed to be stable, so it can name things that outlive this process.
+pub(crate) fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Returns a short stable hash of the canonical path of `workspace`
+pub(crate) fn workspace_hash(workspace: &Path) -> String {
+    format!(
+        "{:08x}",
+        stable_hash(workspace.as_os_str().as_bytes()) as u32
+    )
+}
+
+/// Prefix of the repositories yadt tags its images in
+pub const IMAGE_REPOSITORY_PREFIX: &str = "localhost/yadt/";
+
+/// Tag moved to the environment image `yadt` last built or reused for a
+/// workspace
+pub const LATEST_TAG: &str = "latest";
+
+/// Tag moved to the dev image last built from a workspace's containerfile
+pub const DEV_TAG: &str = "dev";
+
+/// Tag of the snapshot `yadt snapshot` commits a workspace's container to,
+/// which named snapshots append their name to, eg. "snapshot-pip"
+pub const SNAPSHOT_TAG: &str = "snapshot";
+
+/// Returns the repository the images of `workspace` are tagged in, which
+/// names the workspace's directory for readability, along with a short hash
+/// of its path, so that workspaces named alike don't share a repository
+pub fn image_repository(workspace: &Path) -> String {
+    let basename = workspace
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    // repository names only allow separators between alphanumerics
+    let basename: Vec<String> = basename
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(str::to_string)
+        .collect();
+    let hash = workspace_hash(workspace);
+
+    if basename.is_empty() {
+        format!("{}{}", IMAGE_REPOSITORY_PREFIX, hash)
+    } else {
+        format!("{}{}-{}", IMAGE_REPOSITORY_PREFIX, basename.join("-"), hash)
+    }
+}
+
+/// Returns the tag of the environment image for `workspace` built with
+/// `config_hash`
+pub fn environment_tag(workspace: &Path, config_hash: &str) -> String {
+    format!("{}:{}", image_repository(workspace), config_hash)
+}
+
+/// Returns the tag of the snapshot of `workspace` named `name`, or of its
+/// unnamed snapshot
+pub fn snapshot_tag(workspace: &Path, name: Option<&str>) -> Result<String, Error> {
+    let Some(name) = name else {
+        return Ok(format!("{}:{}", image_repository(workspace), SNAPSHOT_TAG));
+    };
+    // the name becomes part of a tag, which allows little else
+    if name.is_empty()
+        || name.len() > 100
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+    {
+        return Err(Error::Config(format!(
+            "Invalid snapshot name \"{}\", expected letters, digits, '_', '.', and '-'",
+            name
+        )));
+    }
+    Ok(format!(
+        "{}:{}-{}",
+        image_repository(workspace),
+        SNAPSHOT_TAG,
+        name
+    ))
+}
+
+/// Returns whether `tag` marks the image a workspace last used or a
+/// snapshot of it, which outlast its older images when pruning
+pub fn is_current_tag(tag: &str) -> bool {
+    tag.strip_prefix(IMAGE_REPOSITORY_PREFIX)
+        .and_then(|tag| tag.rsplit_once(':'))
+        .is_some_and(|(_, tag)| {
+            tag == LATEST_TAG
+                || tag == DEV_TAG
+                || tag == SNAPSHOT_TAG
+                || tag
+                    .strip_prefix(SNAPSHOT_TAG)
+                    .is_some_and(|name| name.starts_with('-'))
+        })
+}
+
+/// Returns whether the image `image` exists locally
+pub(crate) fn image_exists(runtime: &Runtime, image: &str) -> Result<bool, Error> {
+    // docker has no `image exists`, but both support inspect
+    let mut command = runtime.command();
+    command
+        .arg("image")
+        .arg("inspect")
+        .arg(image)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    Ok(runtime.status(&mut command)?.success())
+}
+
+/// Returns whether `image` is the id of an image rather than a name that
+/// can be pulled, as dev images built from a containerfile are
+pub(crate) fn is_image_id(image: &str) -> bool {
+    let id = image.strip_prefix("sha256:").unwrap_or(image);
+    id.len() == 64 && id.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Parts of the messages runtimes fail pulls with
+const PULL_FAILURE_MARKERS: &[&str] = &[
+    "unauthorized",
+    "authentication required",
+    "denied",
+    "manifest unknown",
+    "not found",
+    "no such host",
+    "connection refused",
+    "timeout",
+    "toomanyrequests",
+];
+
+/// Returns the images the build of `spec` starts from that could be pulled
+fn pulled_images(spec: &BuildSpec) -> Vec<String> {
+    let mut images: Vec<String> = spec
+        .build_args
+        .iter()
+        .filter(|(key, _)| key.ends_with("_IMAGE"))
+        .map(|(_, value)| value.clone())
+        .collect();
+
+    if let Containerfile::Path(path) = spec.containerfile {
+        let text = fs::read_to_string(path).unwrap_or_default();
+        images.extend(text.lines().filter_map(|line| {
+            let mut words = line.split_whitespace();
+            if !words.next()?.eq_ignore_ascii_case("FROM") {
+                return None;
+            }
+            words
+                .find(|word| !word.starts_with("--"))
+                .map(str::to_string)
+        }));
+    }
+
+    images.retain(|image| !is_image_id(image) && !image.contains('$'));
+    images
+}
+
+/// Names the image and registry in the error of a build that failed to pull
+/// one of the images it starts from, going by the build's stderr.
+fn name_pull_failure(error: Error, spec: &BuildSpec, stderr_tail: &[String]) -> Error {
+    let Error::Build(message) = error else {
+        return error;
+    };
+
+    let failed_pull = stderr_tail.iter().any(|line| {
+        let line = line.to_lowercase();
+        PULL_FAILURE_MARKERS
+            .iter()
+            .any(|marker| line.contains(marker))
+    });
+    let image = pulled_images(spec)
+        .into_iter()
+        .find(|image| stderr_tail.iter().any(|line| line.contains(image.as_str())));
+
+    let Some(image) = image.filter(|_| failed_pull) else {
+        return Error::Build(message);
+    };
+    let registry = image_registry(&image);
+    let mut message = format!("Pulling {} from {} failed\n{}", image, registry, message);
+    if let Some(hint) = auth_hint(registry, spec.auth, stderr_tail) {
+        message.push('\n');
+        message.push_str(&hint);
+    }
+    Error::Build(message)
+}
+
+/// Pulls `image`, naming its registry if that fails
+fn pull_image(runtime: &Runtime, image: &str, options: &BuildOptions) -> Result<(), Error> {
+    let auth = options.auth;
+    let registry = image_registry(image);
+    let mut command = runtime.command();
+    command.arg("pull");
+    if let Some(auth) = auth {
+        let path = auth.path();
+        command.args(runtime.backend().auth_args(&path));
+        let (name, value) = runtime.backend().auth_env(&path);
+        command.env(name, value);
+    }
+    command.arg(image);
+
+    if runtime.is_dry_run() {
+        println!("{}", render_command(&command));
+        return Ok(());
+    }
+
+    let step = format!("Pulling {} from {}", image, registry);
+    progress::start("pull", step.clone());
+    let (status, stderr_tail) = retry_transient(runtime, &step, options.retries, || {
+        run_logged(
+            runtime,
+            &mut command,
+            &step,
+            None,
+            Level::Debug,
+            options.pull_timeout,
+            "pull_timeout",
+        )
+    })?;
+    check_status(runtime, &step, status, &stderr_tail).map_err(|error| {
+        match (error, auth_hint(registry, auth, &stderr_tail)) {
+            (Error::Runtime(message), Some(hint)) => {
+                Error::Runtime(format!("{}\n{}", message, hint))
+            }
+            (error, _) => error,
+        }
+    })
+}
+
+/// Where to obtain the dev image from
+#[derive(Debug, PartialEq, Eq)]
+pub enum DevImageSource {
+    /// Build the dev image from a containerfile within a build context
+    Containerfile {
+        containerfile: PathBuf,
+        context: Option<PathBuf>,
+    },
+
+    /// Pull or use an existing image
+    Image(String),
+}
+
+/// Proxy variables passed from the host into builds and environments. Tools
+/// disagree on the case they read, so both are passed.
+const PROXY_VARIABLES: [&str; 6] = [
+    "http_proxy",
+    "https_proxy",
+    "no_proxy",
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "NO_PROXY",
+];
+
+/// Returns the proxy variables set on the host, skipping empty ones
+pub(crate) fn host_proxy_variables() -> Vec<(&'static str, String)> {
+    PROXY_VARIABLES
+        .iter()
+        .filter_map(|&name| {
+            let value = env::var(name).ok().filter(|value| !value.is_empty())?;
+            Some((name, value))
+        })
+        .collect()
+}
+
+/// Id or tag of an image, as passed to the container runtime
+pub type ImageId = String;
+
+/// How an environment image is built, as selected on the command line
+#[derive(Debug, Default)]
+pub struct BuildOptions<'a> {
+    /// Build the image even if one exists for the current config
+    pub rebuild: bool,
+
+    /// Build the store paths recorded in the lock file instead of the
+    /// configured packages
+    pub frozen: bool,
+
+    /// Also install the default devShell of the workspace's flake.nix
+    pub flake: bool,
+
+    /// Also install the nix-shell environment of the workspace's shell.nix
+    /// or default.nix
+    pub nix_shell: bool,
+
+    /// When the dev image and nix image are pulled, or the runtime's default
+    /// if None
+    pub pull: Option<PullPolicy>,
+
+    /// Credentials for the registries the images are pulled from, if any are
+    /// configured
+    pub auth: Option<&'a AuthFile>,
+
+    /// Pass the host's proxy variables into the builds
+    pub proxy: bool,
+
+    /// The platform built for, eg. "linux/arm64", or the host's if None
+    pub platform: Option<&'a str>,
+
+    /// How long each build may run, or as long as it needs if None
+    pub build_timeout: Option<Duration>,
+
+    /// How long each pull may run, or as long as it needs if None
+    pub pull_timeout: Option<Duration>,
+
+    /// How many times a build or pull that failed transiently is retried
+    pub retries: u32,
+}
+
+impl<'a> BuildOptions<'a> {
+    /// Returns the options `config` selects that the config hash depends
+    /// on, for computing the hash of an image without building it
+    pub fn from_config(config: &'a Config) -> Self {
+        Self {
+            flake: config.use_flake,
+            nix_shell: config.use_nix_shell,
+            platform: config.platform.as_deref(),
+            ..Default::default()
+        }
+    }
+}
+
+/// An environment image, along with the config hash it was built for
+#[derive(Debug)]
+pub struct EnvironmentImage {
+    pub id: ImageId,
+
+    /// Tag of the image, from `environment_tag`
+    pub tag: String,
+
+    pub config_hash: String,
+
+    /// Whether the image was built rather than reused
+    pub built: bool,
+}
+
+/// The user invoking yadt, which is recreated inside the environment so that
+/// files created in the workspace keep their host ownership
+#[derive(Debug)]
+pub struct HostUser {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl HostUser {
+    /// Looks up the user running this process. The name is taken from `$USER`,
+    /// falling back to the passwd database and then to "dev".
+    pub fn current() -> Self {
+        // SAFETY: getuid and getgid cannot fail
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+        let name = env::var("USER")
+            .ok()
+            .filter(|name| !name.is_empty())
+            .or_else(|| {
+                // SAFETY: getpwuid returns either null or a pointer to a
+                // valid passwd entry, whose name is copied out immediately
+                unsafe {
+                    let passwd = libc::getpwuid(uid);
+                    if passwd.is_null() {
+                        None
+                    } else {
+                        CStr::from_ptr((*passwd).pw_name)
+                            .to_str()
+                            .ok()
+                            .map(str::to_string)
+                    }
+                }
+            })
+            .unwrap_or_else(|| "dev".to_string());
+
+        Self { name, uid, gid }
+    }
+
+    /// Returns the user's home directory in the environment, as created by
+    /// the embedded containerfile. A dev image that already has a user with
+    /// the same id keeps that user's home instead, which `image_home` reads.
+    pub fn container_home(&self) -> String {
+        if self.uid == 0 {
+            "/root".to_string()
+        } else {
+            format!("/home/{}", self.name)
+        }
+    }
+}
+
+/// Where the embedded containerfile records the name and home of the
+/// container user, on a line each
+pub(crate) const USER_FILE_PATH: &str = "/etc/yadt/user";
+
+/// Returns the home of the container user in `image`, as the containerfile
+/// recorded it in `USER_FILE_PATH`
+pub(crate) fn image_home(runtime: &Runtime, image: &str) -> Result<String, Error> {
+    let user = query::query(
+        runtime,
+        "Reading the container user",
+        &["run", "--rm", "--entrypoint", "cat", image, USER_FILE_PATH],
+    )?;
+    user.lines()
+        .nth(1)
+        .map(str::to_string)
+        .filter(|home| home.starts_with('/'))
+        .ok_or_else(|| Error::Runtime(format!("{} of {} is malformed", USER_FILE_PATH, image)))
+}
+
+/// Temporary file passed to `build --iidfile`, which the container runtime
+/// writes the built image's id to. The file is removed when dropped.
+struct IidFile {
+    path: PathBuf,
+}
+
+impl IidFile {
+    /// Picks a path in the temp directory unique to this process and build.
+    fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        Self {
+            path: env::temp_dir().join(format!("yadt-{}-{}.iid", process::id(), count)),
+        }
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the image id, which is written by a successful build.
+    fn read(&self) -> Option<String> {
+        let id = fs::read_to_string(&self.path).ok()?;
+        let id = id.trim();
+        (!id.is_empty()).then(|| id.to_string())
+    }
+}
+
+impl Drop for IidFile {
+    fn drop(&mut self) {
+        // the file may legitimately not exist if the build failed early
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Logs the piped `output` of a child line by line at `level` on a separate
+/// thread, so that neither of the child's outputs can block the other or
+/// waiting for it to exit.
+/// The thread returns the last `STDERR_TAIL_LINES` lines for error messages.
+fn forward_output(output: impl Read + Send + 'static, level: Level) -> JoinHandle<Vec<String>> {
+    thread::spawn(move || {
+        let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+        for line in BufReader::new(output).lines().map_while(Result::ok) {
+            progress::observe(&line);
+            if events::is_enabled() {
+                events::emit(Event::Output { line: line.clone() });
+            } else {
+                log::log!(level, ">>> {}", line);
+            }
+            if tail.len() == STDERR_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        tail.into()
+    })
+}
+
+/// How often a build or pull with a timeout is checked on
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a build or pull that timed out gets to stop before it is killed
+const TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Runs `command`, which performs `step`, in a process group of its own,
+/// writing `stdin` to it and logging its output at `level` with a `>>> `
+/// prefix, or at the debug level while a spinner shows the progress.
+/// Returns its exit status along with the last lines of its stderr.
+/// Interrupts are forwarded to it, and if it runs past `timeout` it is
+/// killed, failing with an error naming `timeout_key`.
+fn run_logged(
+    runtime: &Runtime,
+    command: &mut Command,
+    step: &str,
+    stdin: Option<&[u8]>,
+    level: Level,
+    timeout: Option<Duration>,
+    timeout_key: &'static str,
+) -> Result<(ExitStatus, Vec<String>), Error> {
+    let level = if progress::is_active() {
+        Level::Debug.max(level)
+    } else {
+        level
+    };
+    command
+        .stdin(match stdin {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+    let forwarding = Forwarding::new();
+    let mut child = runtime.spawn(command)?;
+    forwarding.attach(&child);
+
+    let stdin_writer = stdin.map(|contents| {
+        let mut stdin = child.stdin.take().expect("Could not capture stdin.");
+        let contents = contents.to_vec();
+
+        // write stdin from a separate thread so that a child that doesn't
+        // read all of it can't block forwarding its output
+        thread::spawn(move || stdin.write_all(&contents))
+    });
+    let stdout_forwarder = forward_output(
+        child.stdout.take().expect("Could not capture stdout."),
+        level,
+    );
+    let stderr_forwarder = forward_output(
+        child.stderr.take().expect("Could not capture stderr."),
+        level,
+    );
+
+    let status = wait_with_timeout(&mut child, step, timeout, timeout_key);
+    // the writer fails once the child exits without reading everything
+    if let Some(stdin_writer) = stdin_writer {
+        let _ = stdin_writer.join();
+    }
+    let _ = stdout_forwarder.join();
+    let stderr_tail = stderr_forwarder.join().unwrap_or_default();
+    if let Some(signal) = forwarding.interrupted() {
+        return Err(Error::Interrupted {
+            step: step.to_string(),
+            signal,
+        });
+    }
+
+    Ok((status?, stderr_tail))
+}
+
+/// How long the first retry of a build or pull waits, doubling for each
+/// further retry
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Runs `attempt`, which performs `step`, again up to `retries` times while
+/// it fails in a way the runtime considers transient, such as a registry
+/// that can't be reached. The exit status and stderr tail of the last
+/// attempt are returned.
+fn retry_transient(
+    runtime: &Runtime,
+    step: &str,
+    retries: u32,
+    mut attempt: impl FnMut() -> Result<(ExitStatus, Vec<String>), Error>,
+) -> Result<(ExitStatus, Vec<String>), Error> {
+    let mut delay = RETRY_DELAY;
+    for retry in 1..=retries {
+        let (status, stderr_tail) = attempt()?;
+        if status.success() || !runtime.backend().is_transient_failure(&stderr_tail) {
+            return Ok((status, stderr_tail));
+        }
+        log::warn!(
+            "{} failed, retrying in {}s ({} of {})",
+            step,
+            delay.as_secs(),
+            retry,
+            retries
+        );
+        thread::sleep(delay);
+        delay *= 2;
+    }
+    attempt()
+}
+
+/// Waits for `child`, which performs `step`, to exit. If it runs past
+/// `timeout`, it and everything it started is stopped, and killed if it
+/// doesn't stop in time.
+fn wait_with_timeout(
+    child: &mut Child,
+    step: &str,
+    timeout: Option<Duration>,
+    timeout_key: &'static str,
+) -> Result<ExitStatus, Error> {
+    let Some(timeout) = timeout else {
+        return Ok(child.wait()?);
+    };
+
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        thread::sleep(TIMEOUT_POLL_INTERVAL);
+    }
+
+    let elapsed = started.elapsed();
+    log::warn!("{} timed out, stopping it", step);
+    kill_group(child, libc::SIGTERM);
+    let stopping = Instant::now();
+    while child.try_wait()?.is_none() && stopping.elapsed() < TIMEOUT_GRACE_PERIOD {
+        thread::sleep(TIMEOUT_POLL_INTERVAL);
+    }
+    // whatever it started may still hold its output open
+    kill_group(child, libc::SIGKILL);
+    child.wait()?;
+
+    Err(Error::Timeout {
+        step: step.to_string(),
+        key: timeout_key,
+        elapsed,
+    })
+}
+
+/// Builds `spec` with the container runtime, writing a piped containerfile
+/// to the build's stdin, and returns the built image's id.
+/// The build's stdout and stderr are both logged with a `>>> ` prefix so the
+/// user can follow its progress.
+pub(crate) fn build_image(
+    runtime: &Runtime,
+    step: &str,
+    spec: &BuildSpec,
+    options: &BuildOptions,
+) -> Result<ImageId, Error> {
+    if !spec.volumes.is_empty() && !runtime.backend().can_mount_build_volumes() {
+        return Err(Error::Config(format!(
+            "{} can't mount volumes into builds",
+            runtime.describe()
+        )));
+    }
+
+    let piped = match spec.containerfile {
+        Containerfile::Piped(containerfile) => Some(containerfile),
+        Containerfile::Path(_) => None,
+    };
+
+    let mut command = runtime.command();
+    command.args(runtime.backend().build_args(spec));
+    if let Some(auth) = spec.auth {
+        let (name, value) = runtime.backend().auth_env(&auth.path());
+        command.env(name, value);
+    }
+
+    if runtime.is_dry_run() {
+        command.args(spec.context);
+        print!("{}", render_command(&command));
+        match piped {
+            Some(containerfile) if runtime.dry_run == DryRun::PrintContainerfile => {
+                println!(" <<'CONTAINERFILE'");
+                print!("{}", String::from_utf8_lossy(containerfile));
+                println!("CONTAINERFILE");
+            }
+            _ => println!(),
+        }
+        // later commands refer to the image by this placeholder
+        return Ok(format!("<image built by {}>", step.to_lowercase()));
+    }
+
+    let iidfile = IidFile::new();
+
+    command.arg("--iidfile").arg(iidfile.path());
+    // the context must be the final argument
+    command.args(spec.context);
+
+    let (status, stderr_tail) = retry_transient(runtime, step, options.retries, || {
+        run_logged(
+            runtime,
+            &mut command,
+            step,
+            piped,
+            Level::Info,
+            options.build_timeout,
+            "build_timeout",
+        )
+    })?;
+    check_status(runtime, step, status, &stderr_tail)
+        .map_err(|error| name_pull_failure(error.into_build(), spec, &stderr_tail))?;
+
+    iidfile
+        .read()
+        .ok_or_else(|| Error::EmptyBuildOutput(step.to_string()))
+}
+
+/// Label marking images and containers as created by yadt
+pub const YADT_LABEL: &str = "io.github.anglesideangle.yadt";
+
+/// Label recording the canonical workspace path on yadt's images and
+/// containers, used to find a workspace's container even if its name was
+/// overridden
+pub const WORKSPACE_LABEL: &str = "yadt.workspace";
+
+/// Label recording the `config_hash` an image was built with
+pub const CONFIG_HASH_LABEL: &str = "yadt.config-hash";
+
+/// Label recording the GPUs passed into a container, if any
+pub const GPU_LABEL: &str = "yadt.gpu";
+
+/// Label recording the dev image an environment image was built on top of,
+/// so that it can be rebuilt without naming the dev image again
+pub const DEV_IMAGE_LABEL: &str = "yadt.dev-image";
+
+/// Returns the labels of an image or container created by yadt for
+/// `workspace`, including its config hash if known.
+pub(crate) fn yadt_labels(workspace: &Path, config_hash: Option<&str>) -> Vec<OsString> {
+    let mut labels = vec![
+        OsString::from(format!("{}=true", YADT_LABEL)),
+        workspace_label(workspace),
+    ];
+    if let Some(config_hash) = config_hash {
+        labels.push(OsString::from(format!(
+            "{}={}",
+            CONFIG_HASH_LABEL, config_hash
+        )));
+    }
+    labels
+}
+
+/// Returns the build args passed to the embedded containerfile to build
+/// `installables` into the environment image on top of `dev_image`.
+pub fn environment_build_args(
+    config: &Config,
+    dev_image: &str,
+    installables: &[String],
+    user: &HostUser,
+) -> Vec<(&'static str, String)> {
+    let mut build_args = vec![
+        ("NIX_IMAGE", config.nix_image.clone()),
+        ("DEV_IMAGE", dev_image.to_string()),
+        ("PACKAGES_STRING", installables.join(" ")),
+        (
+            "NIXPKGS_REF",
+            config
+                .nixpkgs_ref
+                .as_ref()
+                .map(|_| config.nixpkgs())
+                .unwrap_or_default(),
+        ),
+        ("NIX_SUBSTITUTERS", config.nix_substituters.join(" ")),
+        (
+            "NIX_TRUSTED_PUBLIC_KEYS",
+            config.nix_trusted_public_keys.join(" "),
+        ),
+        ("USERNAME", user.name.clone()),
+        ("USER_UID", user.uid.to_string()),
+        ("USER_GID", user.gid.to_string()),
+    ];
+    // only passed when set, so that the hashes of other configs stay the same
+    if config.nested_containers {
+        build_args.push(("NESTED_CONTAINERS", "1".to_string()));
+    }
+    build_args
+}
+
+/// Build args passed to the embedded containerfile, as "KEY", "VALUE" pairs
+type BuildArgs = Vec<(&'static str, String)>;
+
+/// Returns the build args of the environment image installing `installables`
+/// on top of `dev_image`, along with the workspace's nix environment if
+/// `options` ask for one and the workspace has it.
+fn workspace_build_args(
+    config: &Config,
+    workspace: &Path,
+    dev_image: &str,
+    installables: &[String],
+    user: &HostUser,
+    options: &BuildOptions,
+) -> Result<(BuildArgs, Option<WorkspaceNix>), Error> {
+    let mut build_args = environment_build_args(config, dev_image, installables, user);
+    let workspace_nix = WorkspaceNix::detect(workspace, options.flake, options.nix_shell)?;
+    if let Some(workspace_nix) = workspace_nix {
+        build_args.push(("WORKSPACE_NIX", workspace_nix.build_arg().to_string()));
+        build_args.push(("WORKSPACE_NIX_HASH", workspace_nix.hash(workspace)?));
+    }
+
+    Ok((build_args, workspace_nix))
+}
+
+/// Returns the config hash an environment image built from the configured
+/// packages on top of `dev_image` would have, without building it
+pub fn current_config_hash(
+    config: &Config,
+    workspace: &Path,
+    dev_image: &str,
+    user: &HostUser,
+    options: &BuildOptions,
+) -> Result<String, Error> {
+    let (build_args, _) = workspace_build_args(
+        config,
+        workspace,
+        dev_image,
+        &config.installables(),
+        user,
+        options,
+    )?;
+    let containerfile = environment_containerfile(config, workspace)?;
+    Ok(config_hash(&containerfile, &build_args, options.platform))
+}
+
+/// Returns a stable digest of the build args of an environment image and the
+/// containerfile it is built from, so that identical configs on top of the
+/// same dev image map to identical hashes. Settings that only affect running
+/// the environment are left out. The platform is only part of the digest if
+/// one is given, so that images for different platforms can coexist.
+pub fn config_hash(
+    containerfile: &[u8],
+    build_args: &[(&str, String)],
+    platform: Option<&str>,
+) -> String {
+    let mut input = String::from_utf8_lossy(containerfile).into_owned();
+    if let Some(platform) = platform {
+        input.push_str(&format!("platform={}\n", platform));
+    }
+    for (key, value) in build_args {
+        // package order doesn't change the built image
+        let value = if *key == "PACKAGES_STRING" {
+            let mut packages: Vec<&str> = value.split_whitespace().collect();
+            packages.sort_unstable();
+            packages.join(" ")
+        } else {
+            value.clone()
+        };
+        input.push_str(&format!("{}={}\n", key, value));
+    }
+    format!("{:016x}", stable_hash(input.as_bytes()))
+}
+
+/// Nix files a workspace can describe its development environment with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkspaceNix {
+    /// The default devShell of flake.nix
+    Flake,
+
+    /// The environment `nix-shell` would enter for shell.nix or default.nix
+    Shell(&'static str),
+}
+
+impl WorkspaceNix {
+    /// Decides which nix file in `workspace` to install the environment of,
+    /// given whether a flake or a `nix-shell` environment was requested. A
+    /// flake is preferred over shell.nix, which is preferred over
+    /// default.nix.
+    pub fn detect(workspace: &Path, flake: bool, nix_shell: bool) -> Result<Option<Self>, Error> {
+        let has_flake = workspace.join("flake.nix").is_file();
+
+        if flake {
+            if !has_flake {
+                return Err(Error::Config(format!(
+                    "A flake devShell was requested but {} does not exist",
+                    workspace.join("flake.nix").display()
+                )));
+            }
+            return Ok(Some(WorkspaceNix::Flake));
+        }
+
+        if !nix_shell {
+            return Ok(None);
+        }
+
+        let Some(file) = ["shell.nix", "default.nix"]
+            .into_iter()
+            .find(|file| workspace.join(file).is_file())
+        else {
+            return Err(Error::Config(format!(
+                "A nix-shell environment was requested but {} has no shell.nix or default.nix",
+                workspace.display()
+            )));
+        };
+
+        if has_flake {
+            log::warn!(
+                "{} has both flake.nix and {}, using the flake's devShell",
+                workspace.display(),
+                file
+            );
+            return Ok(Some(WorkspaceNix::Flake));
+        }
+
+        Ok(Some(WorkspaceNix::Shell(file)))
+    }
+
+    /// Returns the value of the `WORKSPACE_NIX` build arg
+    pub fn build_arg(self) -> &'static str {
+        match self {
+            WorkspaceNix::Flake => "flake",
+            WorkspaceNix::Shell(file) => file,
+        }
+    }
+
+    /// Returns a digest of the nix files in `workspace`, so that changing
+    /// them rebuilds the environment. Files they import aren't hashed, so
+    /// changes to those need `--rebuild`.
+    pub fn hash(self, workspace: &Path) -> Result<String, Error> {
+        let files: &[&str] = match self {
+            WorkspaceNix::Flake => &["flake.nix", "flake.lock"],
+            WorkspaceNix::Shell(file) => &[file],
+        };
+
+        let mut input = Vec::new();
+        for file in files {
+            match fs::read(workspace.join(file)) {
+                Ok(contents) => input.extend(contents),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(format!("{:016x}", stable_hash(&input)))
+    }
+}
+
+/// Returns the `--label` value marking a container as belonging to
+/// `workspace`
+pub(crate) fn workspace_label(workspace: &Path) -> OsString {
+    let mut label = OsString::from(WORKSPACE_LABEL);
+    label.push("=");
+    label.push(workspace);
+    label
+}
+
+/// Writes the store paths `installables` resolved to in `image` to the lock
+/// file in `workspace`, unless it is already up to date.
+fn update_lockfile(
+    runtime: &Runtime,
+    workspace: &Path,
+    image: &str,
+    installables: &[String],
+    current: Option<&Lockfile>,
+) -> Result<(), Error> {
+    let manifest = query::query(
+        runtime,
+        "Reading the built packages",
+        &[
+            "run",
+            "--rm",
+            "--entrypoint",
+            "cat",
+            image,
+            BUILD_MANIFEST_PATH,
+        ],
+    )?;
+    let lockfile = Lockfile::from_build_output(installables, &manifest)?;
+
+    if current != Some(&lockfile) {
+        lockfile.write(workspace)?;
+        log::info!("Wrote {}", workspace.join(LOCK_FILE_NAME).display());
+    }
+
+    Ok(())
+}
+
+/// Returns the dev image for `workspace` from `source`, building it first if
+/// it is given as a containerfile.
+pub fn build_dev_image(
+    runtime: &Runtime,
+    workspace: &Path,
+    source: DevImageSource,
+    options: &BuildOptions,
+) -> Result<ImageId, Error> {
+    let (containerfile, context) = match source {
+        DevImageSource::Containerfile {
+            containerfile,
+            context,
+        } => (containerfile, context),
+        DevImageSource::Image(image) => return Ok(image),
+    };
+
+    let containerfile = canonicalize(&containerfile)?;
+    // the runtime picks up a .containerignore or .dockerignore from the
+    // context on its own
+    let context = match context {
+        Some(context) => canonicalize(&context)?,
+        None => containerfile
+            .parent()
+            .expect("Canonical containerfile path has no parent")
+            .to_path_buf(),
+    };
+
+    let tag = format!("{}:{}", image_repository(workspace), DEV_TAG);
+    if !runtime.is_dry_run() {
+        progress::start("build_containerfile", "Building the containerfile");
+    }
+    build_image(
+        runtime,
+        "Building the containerfile",
+        &BuildSpec {
+            containerfile: Containerfile::Path(&containerfile),
+            context: Some(&context),
+            tag: Some(&tag),
+            build_args: if options.proxy {
+                host_proxy_variables()
+            } else {
+                Vec::new()
+            },
+            // the config hash is not known until this image is built
+            labels: yadt_labels(workspace, None),
+            volumes: Vec::new(),
+            pull: options.pull,
+            auth: options.auth,
+            platform: options.platform,
+        },
+        options,
+    )
+}
+
+/// Installs the packages configured for `workspace` into an environment
+/// image on top of `dev_image`, reusing the image already built for the same
+/// config unless `options.rebuild` is set. Unless frozen, the lock file is
+/// updated with the packages the image contains.
+pub fn build_environment_image(
+    config: &Config,
+    runtime: &Runtime,
+    workspace: &Path,
+    dev_image: &str,
+    user: &HostUser,
+    options: &BuildOptions,
+) -> Result<EnvironmentImage, Error> {
+    let installables = config.installables();
+    let lockfile = Lockfile::read(workspace)?;
+    // building the locked store paths keeps the environment from drifting
+    let build_installables = match (&lockfile, options.frozen) {
+        (Some(lockfile), true) => lockfile.frozen_installables(&installables)?,
+        (None, true) => {
+            return Err(Error::Config(format!(
+                "--frozen requires a {} in {}",
+                LOCK_FILE_NAME,
+                workspace.display()
+            )))
+        }
+        (_, false) => installables.clone(),
+    };
+
+    let (build_args, workspace_nix) = workspace_build_args(
+        config,
+        workspace,
+        dev_image,
+        &build_installables,
+        user,
+        options,
+    )?;
+    // the workspace is mounted into the build, which docker can't do
+    if workspace_nix.is_some() && !runtime.backend().can_mount_build_volumes() {
+        return Err(Error::Config(
+            "Installing the workspace's nix environment requires a runtime that can mount volumes into builds, such as podman".to_string(),
+        ));
+    }
+    let containerfile = environment_containerfile(config, workspace)?;
+    let config_hash = config_hash(&containerfile, &build_args, options.platform);
+
+    let tag = environment_tag(workspace, &config_hash);
+
+    let built = options.rebuild || !image_exists(runtime, &tag)?;
+    let id = if !built {
+        log::debug!("Using existing image {}", tag);
+        tag.clone()
+    } else {
+        let mut labels = yadt_labels(workspace, Some(&config_hash));
+        labels.push(format!("{}={}", DEV_IMAGE_LABEL, dev_image).into());
+
+        let pull = match options.pull {
+            // a dev image that was just built can't be pulled, so only the
+            // nix image is
+            Some(PullPolicy::Always | PullPolicy::Newer) if is_image_id(dev_image) => {
+                pull_image(runtime, &config.nix_image, options).map_err(Error::into_build)?;
+                Some(PullPolicy::Missing)
+            }
+            pull => pull,
+        };
+
+        let mut spec = BuildSpec {
+            containerfile: Containerfile::Piped(&containerfile),
+            context: None,
+            tag: Some(&tag),
+            build_args,
+            labels,
+            volumes: Vec::new(),
+            pull,
+            auth: options.auth,
+            platform: options.platform,
+        };
+
+        if workspace_nix.is_some() {
+            spec.volumes
+                .push(volume_arg(workspace, "/workspace", &["ro"]));
+        }
+
+        // the host store only speeds up the build without changing what is
+        // built, so it is left out of the config hash
+        if config.use_host_nix.resolve(runtime)? {
+            spec.volumes
+                .push(volume_arg(Path::new("/nix"), HOST_NIX_MOUNT, &["ro"]));
+            spec.build_args.push(("USE_HOST_NIX", "1".to_string()));
+        }
+        // a proxy only changes how packages are downloaded, so it is left out
+        // of the config hash as well
+        if options.proxy {
+            spec.build_args.extend(host_proxy_variables());
+        }
+
+        if !runtime.is_dry_run() {
+            // the runtime's output shows which phase the build is in
+            let mut phases = vec![(
+                "nix build --json",
+                "install_packages",
+                format!("Installing {} packages", build_installables.len()),
+            )];
+            if workspace_nix.is_some() {
+                phases.push((
+                    "devshell_path",
+                    "install_workspace_nix",
+                    "Installing the workspace's nix environment".to_string(),
+                ));
+            }
+            phases.push((
+                "nix copy --to /tmp/closure",
+                "copy_closure",
+                "Copying the closure".to_string(),
+            ));
+            phases.push((
+                "COPY --from=builder /tmp/closure",
+                "build_dev_layer",
+                "Building the dev layer".to_string(),
+            ));
+            progress::start_with(
+                "build_environment",
+                "Building the environment image",
+                phases,
+            );
+        }
+        let id = build_image(runtime, "Building the environment image", &spec, options)?;
+
+        log::debug!("Built image {}", id);
+        id
+    };
+
+    // the lock is only a record, so failing to update it shouldn't keep the
+    // environment from starting. A reused image was recorded when it was
+    // built, so reading it again would only start a container for nothing
+    if !options.frozen && !runtime.is_dry_run() && (built || lockfile.is_none()) {
+        if let Err(error) =
+            update_lockfile(runtime, workspace, &id, &installables, lockfile.as_ref())
+        {
+            log::warn!("Could not update {}: {}", LOCK_FILE_NAME, error);
+        }
+    }
+
+    // moving latest off the image built before keeps it from outlasting
+    // older images when pruning
+    let latest = format!("{}:{}", image_repository(workspace), LATEST_TAG);
+    runtime.apply(&format!("Tagging {}", latest), &["tag", &id, &latest])?;
+
+    Ok(EnvironmentImage {
+        id,
+        tag,
+        config_hash,
+        built,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A copy of the nix fixture `fixture` in a directory named after `name`
+    /// that is unique to this process, removed when dropped
+    struct Workspace(PathBuf);
+
+    impl Workspace {
+        fn new(name: &str, fixture: Option<&str>) -> Self {
+            let dir = env::temp_dir().join(format!("yadt-test-{}-{}", process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).expect("The temporary directory is writable");
+            if let Some(fixture) = fixture {
+                let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+                    .join("tests/fixtures/nix")
+                    .join(fixture);
+                for entry in fs::read_dir(fixture).expect("The fixture exists") {
+                    let entry = entry.unwrap();
+                    fs::copy(entry.path(), dir.join(entry.file_name())).unwrap();
+                }
+            }
+            Self(dir)
+        }
+    }
+
+    impl Drop for Workspace {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn requested_flake_needs_flake_nix() {
+        let workspace = Workspace::new("nix-no-flake", Some("shell"));
+        let error = WorkspaceNix::detect(&workspace.0, true, false).unwrap_err();
+        assert!(matches!(error, Error::Config(_)));
+        assert!(error.to_string().contains("flake.nix"), "{}", error);
+    }
+
+    #[test]
+    fn shell_nix_is_preferred_over_default_nix() {
+        let workspace = Workspace::new("nix-shell", Some("shell"));
+        fs::write(workspace.0.join("default.nix"), "import ./shell.nix").unwrap();
+        assert_eq!(
+            WorkspaceNix::detect(&workspace.0, false, true).unwrap(),
+            Some(WorkspaceNix::Shell("shell.nix"))
+        );
+
+        fs::remove_file(workspace.0.join("shell.nix")).unwrap();
+        assert_eq!(
+            WorkspaceNix::detect(&workspace.0, false, true).unwrap(),
+            Some(WorkspaceNix::Shell("default.nix"))
+        );
+    }
+
+    #[test]
+    fn flake_is_preferred_over_shell_nix() {
+        let workspace = Workspace::new("nix-flake", Some("flake"));
+        assert_eq!(
+            WorkspaceNix::detect(&workspace.0, false, true).unwrap(),
+            Some(WorkspaceNix::Flake)
+        );
+        assert_eq!(
+            WorkspaceNix::detect(&workspace.0, true, false).unwrap(),
+            Some(WorkspaceNix::Flake)
+        );
+    }
+
+    #[test]
+    fn requested_nix_shell_needs_a_nix_file() {
+        let workspace = Workspace::new("nix-none", None);
+        let error = WorkspaceNix::detect(&workspace.0, false, true).unwrap_err();
+        assert!(matches!(error, Error::Config(_)));
+        assert!(
+            error
+                .to_string()
+                .contains("has no shell.nix or default.nix"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn nix_files_are_ignored_unless_requested() {
+        let workspace = Workspace::new("nix-unrequested", Some("flake"));
+        assert_eq!(
+            WorkspaceNix::detect(&workspace.0, false, false).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn hash_follows_the_nix_files() {
+        let workspace = Workspace::new("nix-hash", Some("flake"));
+        let shell = WorkspaceNix::Shell("shell.nix");
+        let before = shell.hash(&workspace.0).unwrap();
+        assert_eq!(shell.hash(&workspace.0).unwrap(), before);
+
+        fs::write(
+            workspace.0.join("shell.nix"),
+            "{ pkgs ? import <nixpkgs> { } }: pkgs.mkShell { }\n",
+        )
+        .unwrap();
+        assert_ne!(shell.hash(&workspace.0).unwrap(), before);
+
+        // a flake that wasn't locked yet hashes like its flake.nix alone
+        assert!(!workspace.0.join("flake.lock").exists());
+        let flake = WorkspaceNix::Flake.hash(&workspace.0).unwrap();
+        let flake_nix = fs::read(workspace.0.join("flake.nix")).unwrap();
+        assert_eq!(flake, format!("{:016x}", stable_hash(&flake_nix)));
+
+        fs::write(workspace.0.join("flake.lock"), "{}\n").unwrap();
+        assert_ne!(WorkspaceNix::Flake.hash(&workspace.0).unwrap(), flake);
+    }
+}
@@ -0,0 +1,283 @@
+//! Looking up package names in nixpkgs before building.
+//!
+//! A misspelled package otherwise only fails the build minutes in, deep inside
+//! the nix build stage. With `validate_packages` or `yadt check`, bare package
+//! names are evaluated against the configured nixpkgs, with the host's nix if
+//! it has one or in a container of `nix_image` otherwise, and those nixpkgs
+//! lacks are reported along with the closest names it has. Full flake refs are
+//! left to the build. Packages that were found are cached in the data
+//! directory by flake, so that checking them again evaluates nothing.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    process::{self, Command, Output},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    build::host_proxy_variables,
+    config::{data_dir, Config},
+    error::Error,
+    progress,
+    runtime::{check_status, stderr_lines, Runtime},
+};
+
+/// Name of the file in the data directory caching the packages found, by
+/// the flake they were found in
+const CACHE_FILE_NAME: &str = "packages.json";
+
+/// Number of similar names suggested for a package that wasn't found
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Number of trailing lines of nix's stderr included in its error
+const STDERR_TAIL_LINES: usize = 10;
+
+/// The packages found so far, by the flake they were found in
+type Cache = BTreeMap<String, BTreeSet<String>>;
+
+/// What the evaluation reports about a package. `candidates` are the names
+/// of the attribute set a package that wasn't found would be in.
+#[derive(Debug, Deserialize)]
+struct Evaluated {
+    name: String,
+    found: bool,
+    candidates: Vec<String>,
+}
+
+/// Returns the nix expression looking up each of `names`, which may be
+/// attribute paths such as "python3Packages.numpy", in the packages of
+/// `flake` for the current system
+fn lookup_expression(flake: &str, names: &[&str]) -> String {
+    let names: Vec<String> = names.iter().map(|name| format!("\"{}\"", name)).collect();
+    format!(
+        r#"let
+  flake = builtins.getFlake "{flake}";
+  lib = flake.lib;
+  pkgs = flake.legacyPackages.${{builtins.currentSystem}};
+  lookup = name:
+    let
+      path = lib.splitString "." name;
+      parent = lib.attrByPath (lib.init path) null pkgs;
+      found = lib.hasAttrByPath path pkgs;
+    in {{
+      inherit name found;
+      candidates = if !found && builtins.isAttrs parent then builtins.attrNames parent else [ ];
+    }};
+in map lookup [ {names} ]"#,
+        flake = flake,
+        names = names.join(" ")
+    )
+}
+
+/// Returns the bare package names of `config`, which are looked up in its
+/// nixpkgs, skipping full flake refs
+fn bare_packages(config: &Config) -> Vec<String> {
+    let prefix = format!("{}#", config.nixpkgs());
+    config
+        .installables()
+        .iter()
+        .filter_map(|installable| installable.strip_prefix(&prefix))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Returns the number of single character insertions, deletions, and
+/// substitutions turning `a` into `b`
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Returns up to `MAX_SUGGESTIONS` of `candidates` closest to `name`, the
+/// last component of a package's attribute path, closest first. Names
+/// further away than a third of the name's length aren't similar enough.
+fn suggestions<'a>(name: &str, candidates: &'a [String]) -> Vec<&'a str> {
+    let name = name.to_lowercase();
+    let max_distance = (name.chars().count() / 3).max(2);
+    let mut close: Vec<(usize, &str)> = candidates
+        .iter()
+        .map(|candidate| {
+            (
+                edit_distance(&name, &candidate.to_lowercase()),
+                candidate.as_str(),
+            )
+        })
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    close.sort();
+    close
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// Reads the cache, which is empty if it is missing or can't be read
+fn load_cache() -> Cache {
+    data_dir()
+        .and_then(|dir| fs::read_to_string(dir.join(CACHE_FILE_NAME)).ok())
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+/// Writes the cache, which only warns if it fails, since the packages are
+/// only looked up again
+fn save_cache(cache: &Cache) {
+    let Some(dir) = data_dir() else {
+        return;
+    };
+    let path = dir.join(CACHE_FILE_NAME);
+    let temp_path = path.with_extension(format!("json.{}", process::id()));
+
+    let result = fs::create_dir_all(&dir)
+        .and_then(|()| {
+            let text = serde_json::to_string_pretty(cache).map_err(io::Error::from)?;
+            fs::write(&temp_path, text)
+        })
+        .and_then(|()| fs::rename(&temp_path, &path));
+    if let Err(error) = result {
+        let _ = fs::remove_file(&temp_path);
+        log::warn!("Could not update {}: {}", path.display(), error);
+    }
+}
+
+/// Returns whether nix can be run on the host
+fn has_host_nix() -> bool {
+    Command::new("nix")
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Evaluates `expression` with the host's nix if there is one, or in a
+/// container of `nix_image` otherwise, returning its output as json
+fn evaluate(
+    runtime: &Runtime,
+    config: &Config,
+    step: &str,
+    expression: &str,
+) -> Result<String, Error> {
+    let nix_args = [
+        "--extra-experimental-features",
+        "nix-command flakes",
+        "eval",
+        "--impure",
+        "--json",
+        "--expr",
+        expression,
+    ];
+
+    let output: Output = if has_host_nix() {
+        log::debug!("Evaluating with the host's nix");
+        let output = Command::new("nix").args(nix_args).output()?;
+        if !output.status.success() {
+            let stderr_tail = stderr_lines(&output.stderr);
+            let start = stderr_tail.len().saturating_sub(STDERR_TAIL_LINES);
+            let mut message = format!("{} failed: nix exited with {}", step, output.status);
+            for line in &stderr_tail[start..] {
+                message.push_str("\n    ");
+                message.push_str(line);
+            }
+            return Err(Error::Runtime(message));
+        }
+        output
+    } else {
+        let mut command = runtime.command();
+        command.args(["run", "--rm"]);
+        if config.inherit_proxy {
+            for (name, value) in host_proxy_variables() {
+                command.arg("--env").arg(format!("{}={}", name, value));
+            }
+        }
+        command.arg(&config.nix_image).arg("nix").args(nix_args);
+        let output = runtime.output(&mut command)?;
+        let stderr_tail = stderr_lines(&output.stderr);
+        let start = stderr_tail.len().saturating_sub(STDERR_TAIL_LINES);
+        check_status(runtime, step, output.status, &stderr_tail[start..])?;
+        output
+    };
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Looks up the bare package names of `config` in its nixpkgs, failing with
+/// the closest names nixpkgs has for those it lacks. Returns how many were
+/// looked up.
+pub fn check_packages(runtime: &Runtime, config: &Config) -> Result<usize, Error> {
+    let flake = config.nixpkgs();
+    let packages = bare_packages(config);
+    let mut cache = load_cache();
+    let known = cache.get(&flake);
+    let unchecked: Vec<&str> = packages
+        .iter()
+        .map(String::as_str)
+        .filter(|package| !known.is_some_and(|known| known.contains(*package)))
+        .collect();
+    if unchecked.is_empty() {
+        log::debug!(
+            "All {} packages were found in {} before",
+            packages.len(),
+            flake
+        );
+        return Ok(packages.len());
+    }
+
+    let step = format!("Looking up {} packages in {}", unchecked.len(), flake);
+    progress::start("validate_packages", step.clone());
+    let output = evaluate(
+        runtime,
+        config,
+        &step,
+        &lookup_expression(&flake, &unchecked),
+    );
+    progress::finish();
+    let evaluated: Vec<Evaluated> = serde_json::from_str(&output?)?;
+
+    let mut unknown = Vec::new();
+    for package in evaluated {
+        if package.found {
+            cache.entry(flake.clone()).or_default().insert(package.name);
+            continue;
+        }
+        let (parent, name) = match package.name.rsplit_once('.') {
+            Some((parent, name)) => (Some(parent), name),
+            None => (None, package.name.as_str()),
+        };
+        let suggested: Vec<String> = suggestions(name, &package.candidates)
+            .into_iter()
+            .map(|candidate| match parent {
+                Some(parent) => format!("{}.{}", parent, candidate),
+                None => candidate.to_string(),
+            })
+            .collect();
+        unknown.push(match suggested.as_slice() {
+            [] => format!("    {}", package.name),
+            _ => format!(
+                "    {}, did you mean {}?",
+                package.name,
+                suggested.join(" or ")
+            ),
+        });
+    }
+    save_cache(&cache);
+
+    if unknown.is_empty() {
+        return Ok(packages.len());
+    }
+    Err(Error::Config(format!(
+        "{} has no package named\n{}",
+        flake,
+        unknown.join("\n")
+    )))
+}
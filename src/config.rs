@@ -0,0 +1,2571 @@
+//! The config yadt is run with, layered from the global config file, the
+//! workspace's config file, the `--config` override, and `YADT_*`
+//! environment variables.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env, fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use directories_next::{BaseDirs, ProjectDirs};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use toml::Table;
+use toml_edit::DocumentMut;
+
+use crate::{
+    error::Error,
+    manage::parse_duration,
+    presets::builtin_preset,
+    runtime::{DryRun, Runtime, RuntimeKind},
+};
+
+/// File names checked, in order, at the root of the workspace for a
+/// per-workspace config
+static WORKSPACE_CONFIG_NAMES: [&str; 2] = [".yadt.toml", "yadt.toml"];
+
+/// Used by serde to generate a default docker name
+fn default_docker_name() -> String {
+    "podman".to_string()
+}
+
+/// Used by serde to generate the dev image used when a workspace has no
+/// containerfile
+fn default_base_image() -> String {
+    "docker.io/library/debian:stable-slim".to_string()
+}
+
+/// Used by serde to generate a default nix docker image to pull
+fn default_nix_image() -> String {
+    "docker.io/nixos/nix:latest".to_string()
+}
+
+/// Used by serde to generate a default network
+fn default_network() -> String {
+    "host".to_string()
+}
+
+/// Used by serde to generate the default host environment variables passed
+/// into the environment
+fn default_env_passthrough() -> Vec<String> {
+    ["TERM", "COLORTERM", "LANG", "LC_ALL", "EDITOR"]
+        .map(str::to_string)
+        .to_vec()
+}
+
+/// Used by serde to generate the default directories kept writable in a
+/// read-only workspace
+fn default_writable_dirs() -> Vec<String> {
+    ["target", "node_modules"].map(str::to_string).to_vec()
+}
+
+/// Used by serde to default boolean options to true
+fn default_true() -> bool {
+    true
+}
+
+/// Used by serde to generate a default interactive shell
+fn default_shell() -> String {
+    "/bin/bash".to_string()
+}
+
+/// Used by serde to generate how many runs of hooks keep their logs
+fn default_hook_logs() -> u32 {
+    10
+}
+
+/// Used by serde to generate default base packages to install
+fn default_base_packages() -> BTreeSet<String> {
+    // list adapted from
+    // https://github.com/89luca89/distrobox/blob/main/distrobox-init
+    // and
+    // https://github.com/devcontainers/features/blob/main/src/common-utils/main.sh
+    BTreeSet::from_iter(
+        vec![
+            "bash",
+            "bash-completion",
+            "bc",
+            "curl",
+            "diffutils",
+            "findutils",
+            "glibc",
+            "gnupg",
+            "iputils",
+            "inetutils",
+            "keyutils",
+            "less",
+            "lsof",
+            "man",
+            "mlocate",
+            "mtr",
+            "ncurses",
+            "nssmdns",
+            "openssh",
+            "pigz",
+            "pinentry-tty",
+            "procps",
+            "rsync",
+            "shadow",
+            "sudo",
+            "tcpdump",
+            "time",
+            "traceroute",
+            "tree",
+            "tzdata",
+            "unzip",
+            "util-linux",
+            "wget",
+            "zip",
+        ]
+        .iter()
+        .map(|s| s.to_string()),
+    )
+}
+
+/// Stores the values used to configure this application.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Config {
+    /// The name of the docker-compatible cli to use. If not specified,
+    /// "podman", "docker", and "nerdctl" are tried in that order.
+    #[serde(default = "default_docker_name")]
+    pub docker_name: String,
+
+    /// Which flavor of runtime `docker_name` is, which decides the flags
+    /// passed to it. If not specified, it is detected from the output of
+    /// `<docker_name> --version`.
+    #[serde(default)]
+    pub runtime: Option<RuntimeKind>,
+
+    /// A named connection to the runtime's service, passed to podman as
+    /// `--connection` and to docker as `--context`. If not specified,
+    /// `CONTAINER_HOST` or `DOCKER_HOST` pick the service if set, and
+    /// otherwise the local one is used.
+    #[serde(default)]
+    pub connection: Option<String>,
+
+    /// How the workspace volume should be relabeled for SELinux, one of "z"
+    /// (shared), "Z" (private), or "off". If not specified, the workspace is
+    /// relabeled with "z" when SELinux is detected on the host.
+    #[serde(default)]
+    pub selinux_label: Option<SelinuxLabel>,
+
+    /// Where the workspace is mounted in the environment, either an absolute
+    /// path or "host" for the path it has on the host. This defaults to
+    /// "/workspace".
+    #[serde(default)]
+    pub workspace_mount: Option<String>,
+
+    /// Whether the environment's container keeps running in the background
+    /// after its shell exits, so that later invocations open new shells in it
+    /// and what was started in it survives. It is stopped with `yadt stop`.
+    /// This defaults to false.
+    #[serde(default)]
+    pub keep_alive: bool,
+
+    /// Whether the workspaces are mounted read-only, so that the environment
+    /// can't modify them. This defaults to false.
+    #[serde(default)]
+    pub read_only_workspace: bool,
+
+    /// Directories of the working directory, such as build outputs, that stay
+    /// writable in a read-only workspace. Each is covered with a tmpfs, so
+    /// what is written to it is lost when the environment exits, and only
+    /// directories that already exist on the host can be covered.
+    #[serde(default = "default_writable_dirs")]
+    pub writable_dirs: Vec<String>,
+
+    /// Additional volumes to mount into the environment, formatted as
+    /// "HOST:CONTAINER[:OPTIONS]". A leading `~` in the host path expands to
+    /// the home directory and relative host paths are resolved against the
+    /// current directory. This defaults to an empty vector.
+    #[serde(default)]
+    pub volumes: Vec<String>,
+
+    /// tmpfs mounts in the environment, formatted as "CONTAINER[:OPTIONS]"
+    /// where the options are those of `mount -t tmpfs`, eg.
+    /// "/tmp/scratch:size=2g,mode=1777". What is written to them is lost
+    /// when the environment exits. This defaults to an empty vector.
+    #[serde(default)]
+    pub tmpfs: Vec<String>,
+
+    /// The size of the environment's `/dev/shm`, formatted like `memory`.
+    /// If not specified, the runtime's default of 64m applies.
+    #[serde(default)]
+    pub shm_size: Option<String>,
+
+    /// Named volumes that persist caches across containers and rebuilds,
+    /// mapping a cache name to the container path it is mounted at. Each is
+    /// stored in a volume named "yadt-cache-<name>". This defaults to an
+    /// empty map.
+    #[serde(default)]
+    pub cache_volumes: BTreeMap<String, String>,
+
+    /// The network the environment joins, either "host", "bridge", "none",
+    /// or the name of a network created with the runtime. This defaults to
+    /// "host", sharing the host's network.
+    #[serde(default = "default_network")]
+    pub network: String,
+
+    /// Ports to publish from the environment, formatted as
+    /// "HOST:CONTAINER[/PROTOCOL]" where the protocol is "tcp", "udp", or
+    /// "sctp" and defaults to "tcp". Ports are ignored while the environment
+    /// shares the host's network. This defaults to an empty vector.
+    #[serde(default)]
+    pub ports: Vec<String>,
+
+    /// Names of host environment variables passed into the environment,
+    /// which are skipped when unset on the host. This defaults to "TERM",
+    /// "COLORTERM", "LANG", "LC_ALL", and "EDITOR".
+    #[serde(default = "default_env_passthrough")]
+    pub env_passthrough: Vec<String>,
+
+    /// Whether the variables of the workspace's `.env` file, if it has one,
+    /// are set in the environment. This defaults to false.
+    #[serde(default)]
+    pub auto_env_file: bool,
+
+    /// Whether the host's ssh agent, as given by `SSH_AUTH_SOCK`, is
+    /// forwarded into the environment. This defaults to true.
+    #[serde(default = "default_true")]
+    pub ssh_agent: bool,
+
+    /// Whether the host's gpg agent and public keyring are forwarded into
+    /// the environment, eg. for signing commits. This defaults to false.
+    #[serde(default)]
+    pub gpg_agent: bool,
+
+    /// The host's container engine socket mounted into the environment,
+    /// along with a client to use it, one of "podman", "docker", or "none".
+    /// This defaults to "none".
+    #[serde(default)]
+    pub container_socket: ContainerSocket,
+
+    /// Whether podman can run containers inside the environment on its own,
+    /// rather than through the host's socket. This installs podman with its
+    /// rootless helpers and passes in `/dev/fuse`, which the host needs to
+    /// have. This defaults to false.
+    #[serde(default)]
+    pub nested_containers: bool,
+
+    /// Whether the host's `~/.gitconfig` and `~/.config/git` are mounted read
+    /// only into the environment, so that commits carry the host's identity.
+    /// This defaults to true.
+    #[serde(default = "default_true")]
+    pub git_identity: bool,
+
+    /// Whether GUI applications in the environment can connect to the host's
+    /// X server, which requires `DISPLAY` to be set. This defaults to false.
+    #[serde(default)]
+    pub x11: bool,
+
+    /// Whether GUI applications in the environment can connect to the host's
+    /// wayland compositor. Hosts without a wayland session are skipped with a
+    /// warning. This defaults to false.
+    #[serde(default)]
+    pub wayland: bool,
+
+    /// GPUs made available to the environment, one of "nvidia", "amd", or
+    /// "all". "amd" also covers intel GPUs, which share the dri devices. If
+    /// not specified, no GPU is available.
+    #[serde(default)]
+    pub gpu: Option<Gpu>,
+
+    /// Whether applications in the environment can play audio, one of true,
+    /// false, or "alsa". true forwards the host's PipeWire and PulseAudio
+    /// sockets, while "alsa" also passes in the sound devices. Hosts without
+    /// an audio session are skipped with a warning. This defaults to false.
+    #[serde(default)]
+    pub audio: Audio,
+
+    /// Linux capabilities granted to the environment on top of the runtime's
+    /// defaults, eg. "SYS_PTRACE", or "ALL". This defaults to an empty vector.
+    #[serde(default)]
+    pub cap_add: Vec<String>,
+
+    /// Linux capabilities taken from the environment, eg. "NET_RAW". This
+    /// defaults to an empty vector.
+    #[serde(default)]
+    pub cap_drop: Vec<String>,
+
+    /// Security options of the environment, as passed to `--security-opt`,
+    /// eg. "seccomp=unconfined". This defaults to an empty vector.
+    #[serde(default)]
+    pub security_opt: Vec<String>,
+
+    /// The most memory the environment may use, eg. "8g", with a suffix of
+    /// "b", "k", "m", "g", or "t". If not specified, it is unlimited.
+    #[serde(default)]
+    pub memory: Option<String>,
+
+    /// The most memory and swap the environment may use together, formatted
+    /// like `memory` or "-1" for unlimited swap. This requires `memory`. If
+    /// not specified, the runtime allows as much swap as memory.
+    #[serde(default)]
+    pub memory_swap: Option<String>,
+
+    /// How many CPUs the environment may use, which may be fractional, eg.
+    /// 3.5. If not specified, it may use all of them.
+    #[serde(default)]
+    pub cpus: Option<f64>,
+
+    /// The most processes the environment may run at once. If not specified,
+    /// the runtime's default applies.
+    #[serde(default)]
+    pub pids_limit: Option<u32>,
+
+    /// The dev image `yadt` uses without a subcommand, eg.
+    /// "registry.fedoraproject.org/fedora:40". Only one of `image` and
+    /// `containerfile` can be set.
+    #[serde(default)]
+    pub image: Option<String>,
+
+    /// The containerfile `yadt` builds the dev image from without a
+    /// subcommand, relative to the workspace, eg. "./ci/Containerfile"
+    #[serde(default)]
+    pub containerfile: Option<String>,
+
+    /// The build context of `containerfile`, relative to the workspace. This
+    /// defaults to the directory containing the containerfile.
+    #[serde(default)]
+    pub context: Option<String>,
+
+    /// The dev image `yadt` uses without a subcommand when neither `image`
+    /// nor `containerfile` is set and the workspace has no Containerfile or
+    /// Dockerfile. If not specified, the value defaults
+    /// to docker.io/library/debian:stable-slim.
+    #[serde(default = "default_base_image")]
+    pub default_base_image: String,
+
+    /// When images the builds start from are pulled, one of "always",
+    /// "missing", "newer", or "never". If not specified, the runtime's
+    /// default applies, which only pulls missing images.
+    #[serde(default)]
+    pub pull_policy: Option<PullPolicy>,
+
+    /// How long a build may run before yadt kills it, eg. "30m". If not
+    /// specified or "0", builds may take as long as they need.
+    #[serde(default)]
+    pub build_timeout: Option<String>,
+
+    /// How long pulling an image may take before yadt kills the pull, eg.
+    /// "10m". If not specified or "0", pulls may take as long as they need.
+    #[serde(default)]
+    pub pull_timeout: Option<String>,
+
+    /// How many times a build or pull that failed because of the network or
+    /// the registry is retried, waiting twice as long before each retry.
+    /// If not specified, failures aren't retried.
+    #[serde(default)]
+    pub retries: u32,
+
+    /// The platform the environment is built for and runs as, eg.
+    /// "linux/arm64". A platform foreign to the host is emulated with
+    /// qemu-user, which must be registered with binfmt_misc. If not
+    /// specified, the host's platform is used.
+    #[serde(default)]
+    pub platform: Option<String>,
+
+    /// Credentials for the registries images are pulled from, mapping a
+    /// registry's hostname to a `RegistryAuth`. Registries that aren't listed
+    /// are pulled from with the runtime's own login, if any. This defaults
+    /// to an empty map.
+    #[serde(default)]
+    pub registries: BTreeMap<String, RegistryAuth>,
+
+    /// Base image to build all the nix packages from. This must have nix
+    /// cli installed. If not specified, the value defaults to
+    /// docker.io/nixos/nix:latest.
+    #[serde(default = "default_nix_image")]
+    pub nix_image: String,
+
+    /// A containerfile replacing the embedded one environment images are
+    /// built from, relative to the workspace, eg. to add a corporate CA
+    /// certificate. It must declare the build args the embedded one does,
+    /// and `yadt containerfile show` prints that one to start from. If not
+    /// specified, the embedded containerfile is used.
+    #[serde(default)]
+    pub containerfile_template: Option<String>,
+
+    /// Whether the nix build stage substitutes packages from the host's
+    /// `/nix/store` instead of downloading them, one of true, false, or
+    /// "auto". This defaults to "auto", which uses the host store if there is
+    /// one and the runtime is podman, since docker can't mount volumes into
+    /// builds.
+    #[serde(default)]
+    pub use_host_nix: UseHostNix,
+
+    /// Whether the host's proxy variables, such as `http_proxy` and
+    /// `NO_PROXY`, are passed into builds and the environment. This defaults
+    /// to true.
+    #[serde(default = "default_true")]
+    pub inherit_proxy: bool,
+
+    /// The flake that bare package names are taken from, such as
+    /// "github:NixOS/nixpkgs/nixos-24.05" or a nixpkgs commit hash, pinning
+    /// the packages installed. If not specified, the floating "nixpkgs"
+    /// registry entry is used.
+    #[serde(default)]
+    pub nixpkgs_ref: Option<String>,
+
+    /// Whether bare package names are looked up in `nixpkgs_ref` before
+    /// building, so that typos fail at once with suggestions instead of deep
+    /// inside the build. This defaults to false.
+    #[serde(default)]
+    pub validate_packages: bool,
+
+    /// Binary caches the nix build stage substitutes from in addition to
+    /// nix's defaults, eg. "https://example.cachix.org". This defaults to an
+    /// empty vector.
+    #[serde(default)]
+    pub nix_substituters: Vec<String>,
+
+    /// Public keys trusted to sign paths from `nix_substituters` in addition
+    /// to nix's defaults, eg. "example.cachix.org-1:<key>". This defaults to an
+    /// empty vector.
+    #[serde(default)]
+    pub nix_trusted_public_keys: Vec<String>,
+
+    /// The base packages to install into the environment. Bare names such as
+    /// "git" are taken from nixpkgs, while entries containing a '#' are used
+    /// as flake refs as is, eg. "github:helix-editor/helix#helix". This
+    /// defaults to a vector of various nixpkgs that tend to be useful, such as
+    /// git.
+    #[serde(default = "default_base_packages")]
+    pub base_packages: BTreeSet<String>,
+
+    /// Additional packages to install into the environment, given the same
+    /// way as `base_packages`. This defaults to an empty vector and is always
+    /// user specified.
+    #[serde(default)]
+    pub additional_packages: BTreeSet<String>,
+
+    /// Packages left out of `base_packages` and `additional_packages`, eg. to
+    /// drop a few defaults without replacing the whole list. Excluding a
+    /// package that isn't listed does nothing. This defaults to an empty
+    /// vector.
+    #[serde(default)]
+    pub exclude_packages: BTreeSet<String>,
+
+    /// Presets whose packages are installed along with the others, eg.
+    /// "rust" or "python". `yadt presets` lists the available ones. This
+    /// defaults to an empty vector.
+    #[serde(default)]
+    pub presets: BTreeSet<String>,
+
+    /// Presets defined in addition to the built-in ones, mapping a name to
+    /// its packages. One named like a built-in preset replaces it. This
+    /// defaults to an empty map.
+    #[serde(default)]
+    pub custom_presets: BTreeMap<String, BTreeSet<String>>,
+
+    /// Optional groups of packages, mapping a name to its packages, eg.
+    /// "docs" to mdbook and graphviz. Only the groups in `enabled_groups` or
+    /// passed with `--with` are installed, and each combination builds an
+    /// image of its own. This defaults to an empty map.
+    #[serde(default)]
+    pub groups: BTreeMap<String, BTreeSet<String>>,
+
+    /// Groups installed unless `--without` leaves them out. This defaults to
+    /// an empty vector.
+    #[serde(default)]
+    pub enabled_groups: BTreeSet<String>,
+
+    /// Whether to also install the default devShell of the workspace's
+    /// flake.nix, alongside the configured packages. Requires podman, since
+    /// the workspace is mounted into the nix build stage. This defaults to
+    /// false.
+    #[serde(default)]
+    pub use_flake: bool,
+
+    /// Whether to also install the environment `nix-shell` would enter for
+    /// the workspace's shell.nix, or default.nix if there is none. A
+    /// flake.nix is preferred over both. Requires podman. This defaults to
+    /// false.
+    #[serde(default)]
+    pub use_nix_shell: bool,
+
+    /// Whether the container user's home is kept in a named volume per
+    /// workspace, "yadt-home-<hash>", so that shell history and tool state
+    /// survive the container. `dotfiles.path` takes precedence over it, and
+    /// a `dotfiles.repo` is installed into the volume when it is created.
+    /// This defaults to false.
+    #[serde(default)]
+    pub persist_home: bool,
+
+    /// Shell configuration brought into the environment, see `Dotfiles`. If
+    /// not specified, the container user's home is left as the image has it.
+    #[serde(default)]
+    pub dotfiles: Option<Dotfiles>,
+
+    /// The interactive shell started in the environment, either a path such
+    /// as "/bin/bash" or the name of a nix package such as "fish", which is
+    /// then installed as well. This defaults to "/bin/bash".
+    #[serde(default = "default_shell")]
+    pub shell: String,
+
+    /// Commands run with `sh -c` in the working directory each time the
+    /// environment image is built, before the shell starts, eg.
+    /// "cargo fetch". They run in order, and the first to fail aborts the
+    /// environment. This defaults to an empty vector.
+    #[serde(default)]
+    pub on_create: Vec<String>,
+
+    /// How many runs of the `on_create` commands keep their output in the
+    /// data directory per workspace, for `yadt logs --hooks`, with older
+    /// runs removed. 0 keeps none. This defaults to 10.
+    #[serde(default = "default_hook_logs")]
+    pub hook_logs: u32,
+
+    /// Shell snippets sourced every time a shell starts in the environment,
+    /// after the user's own startup files, eg. "source .venv/bin/activate".
+    /// fish sources them as fish, while every other shell sources them as
+    /// POSIX shell. This defaults to an empty vector.
+    #[serde(default)]
+    pub on_enter: Vec<String>,
+}
+
+impl Config {
+    /// Returns every package to install as installables for `nix build`,
+    /// sorted so that the build arg, and with it the build cache, doesn't
+    /// change between runs.
+    pub fn installables(&self) -> Vec<String> {
+        let all_packages: BTreeSet<&str> = self
+            .base_packages
+            .union(&self.additional_packages)
+            .map(String::as_str)
+            .chain(
+                self.presets
+                    .iter()
+                    .filter_map(|name| self.preset_packages(name))
+                    .flatten(),
+            )
+            .chain(
+                self.enabled_groups
+                    .iter()
+                    .filter_map(|name| self.groups.get(name))
+                    .flatten()
+                    .map(String::as_str),
+            )
+            .filter(|package| !self.exclude_packages.contains(*package))
+            .chain(self.shell_package())
+            .chain(self.dotfiles_package())
+            .chain(self.container_socket_package())
+            .chain(
+                self.nested_containers
+                    .then_some(NESTED_CONTAINER_PACKAGES)
+                    .into_iter()
+                    .flatten(),
+            )
+            .collect();
+
+        all_packages
+            .into_iter()
+            .map(|package| installable(package, &self.nixpkgs()))
+            .collect()
+    }
+
+    /// Returns the flake bare package names are taken from. A commit hash is
+    /// taken to be a nixpkgs revision.
+    pub fn nixpkgs(&self) -> String {
+        match &self.nixpkgs_ref {
+            None => "nixpkgs".to_string(),
+            Some(rev) if rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit()) => {
+                format!("github:NixOS/nixpkgs/{}", rev)
+            }
+            Some(flake) => flake.clone(),
+        }
+    }
+
+    /// Returns the shell if it names a package rather than a path
+    pub fn shell_package(&self) -> Option<&str> {
+        (!self.shell.contains('/')).then_some(self.shell.as_str())
+    }
+
+    /// Returns the client of the mounted container socket, if one is
+    /// configured
+    pub fn container_socket_package(&self) -> Option<&str> {
+        match self.container_socket {
+            ContainerSocket::None => None,
+            ContainerSocket::Podman => Some("podman"),
+            ContainerSocket::Docker => Some("docker-client"),
+        }
+    }
+
+    /// Returns the package cloning the dotfiles repository, if one is
+    /// configured
+    pub fn dotfiles_package(&self) -> Option<&str> {
+        self.dotfiles
+            .as_ref()
+            .and_then(|dotfiles| dotfiles.repo.as_ref())
+            .map(|_| "git")
+    }
+
+    /// Returns the packages of the preset `name`, preferring one the config
+    /// defines over the built-in one, or None if there is neither
+    pub fn preset_packages(&self, name: &str) -> Option<Vec<&str>> {
+        match self.custom_presets.get(name) {
+            Some(packages) => Some(packages.iter().map(String::as_str).collect()),
+            None => builtin_preset(name).map(|preset| preset.packages.to_vec()),
+        }
+    }
+
+    /// Checks that every selected preset exists
+    pub fn validate_presets(&self) -> Result<(), Error> {
+        match self
+            .presets
+            .iter()
+            .find(|name| self.preset_packages(name).is_none())
+        {
+            Some(name) => Err(Error::Config(format!(
+                "Unknown preset \"{}\", `yadt presets` lists the available ones",
+                name
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that `name` is one of the config's groups, failing with the
+    /// groups it has otherwise
+    pub fn validate_group(&self, name: &str) -> Result<(), Error> {
+        if self.groups.contains_key(name) {
+            return Ok(());
+        }
+        let available: Vec<&str> = self.groups.keys().map(String::as_str).collect();
+        Err(Error::Config(if available.is_empty() {
+            format!(
+                "Unknown package group \"{}\", the config defines no groups",
+                name
+            )
+        } else {
+            format!(
+                "Unknown package group \"{}\", the config defines {}",
+                name,
+                available.join(", ")
+            )
+        }))
+    }
+
+    /// Checks that every package, including the shell if it names one and
+    /// those of the presets and groups the config defines, is safe to pass
+    /// to the build
+    pub fn validate_packages(&self) -> Result<(), Error> {
+        self.base_packages
+            .iter()
+            .chain(&self.additional_packages)
+            .chain(self.custom_presets.values().flatten())
+            .chain(self.groups.values().flatten())
+            .map(String::as_str)
+            .chain(self.shell_package())
+            .try_for_each(validate_package)
+    }
+}
+
+/// Shell configuration brought into the environment, in one of two modes.
+/// With `path`, a host directory is mounted over the container user's home.
+/// With `repo`, the repository is cloned into `~/.dotfiles` and `install` is
+/// run there the first time the environment starts, into a named volume that
+/// is then mounted at the home of every later environment.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Dotfiles {
+    /// A directory mounted over the container user's home. A leading `~`
+    /// expands to the home directory.
+    #[serde(default)]
+    pub path: Option<String>,
+
+    /// A git repository to clone, eg. "https://github.com/user/dotfiles"
+    #[serde(default)]
+    pub repo: Option<String>,
+
+    /// The command installing the cloned repository, run from its root with
+    /// `sh -c`, eg. "./install.sh"
+    #[serde(default)]
+    pub install: Option<String>,
+}
+
+/// Checks that dotfiles are configured in exactly one mode
+fn validate_dotfiles(config: &Config) -> Result<(), Error> {
+    let Some(dotfiles) = &config.dotfiles else {
+        return Ok(());
+    };
+
+    match (&dotfiles.path, &dotfiles.repo) {
+        (Some(_), Some(_)) => Err(Error::Config(
+            "Set only one of dotfiles.path and dotfiles.repo".to_string(),
+        )),
+        (None, None) => Err(Error::Config(
+            "dotfiles needs either a path or a repo".to_string(),
+        )),
+        (Some(_), None) if dotfiles.install.is_some() => Err(Error::Config(
+            "dotfiles.install can only be used with dotfiles.repo".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Where yadt gets the credentials of a registry from, which is either a
+/// credential helper or an auth file
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RegistryAuth {
+    /// Name of a credential helper, eg. "secretservice" for
+    /// docker-credential-secretservice
+    #[serde(default)]
+    pub credential_helper: Option<String>,
+
+    /// An auth file holding the registry's credentials, as written by
+    /// `podman login --authfile` or docker's config.json. A leading `~`
+    /// expands to the home directory.
+    #[serde(default)]
+    pub auth_file: Option<String>,
+}
+
+/// Checks that each registry gets its credentials from exactly one place
+fn validate_registries(config: &Config) -> Result<(), Error> {
+    for (registry, auth) in &config.registries {
+        match (&auth.credential_helper, &auth.auth_file) {
+            (Some(_), Some(_)) => {
+                return Err(Error::Config(format!(
+                    "Set only one of registries.\"{}\".credential_helper and registries.\"{}\".auth_file",
+                    registry, registry
+                )))
+            }
+            (None, None) => {
+                return Err(Error::Config(format!(
+                    "registries.\"{}\" needs either a credential_helper or an auth_file",
+                    registry
+                )))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Limits on the resources a container may use, as configured or overridden
+/// on the command line
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourceLimits {
+    pub memory: Option<String>,
+    pub memory_swap: Option<String>,
+    pub cpus: Option<f64>,
+    pub pids_limit: Option<u32>,
+}
+
+impl Config {
+    /// Returns how long builds may run, or None if they may take as long as
+    /// they need
+    pub fn build_timeout(&self) -> Result<Option<Duration>, Error> {
+        parse_timeout("build_timeout", self.build_timeout.as_deref())
+    }
+
+    /// Returns how long pulls may run, or None if they may take as long as
+    /// they need
+    pub fn pull_timeout(&self) -> Result<Option<Duration>, Error> {
+        parse_timeout("pull_timeout", self.pull_timeout.as_deref())
+    }
+
+    /// Returns the configured resource limits
+    pub fn resource_limits(&self) -> ResourceLimits {
+        ResourceLimits {
+            memory: self.memory.clone(),
+            memory_swap: self.memory_swap.clone(),
+            cpus: self.cpus,
+            pids_limit: self.pids_limit,
+        }
+    }
+}
+
+/// Parses the timeout `value` of `key`, where "0" disables it
+fn parse_timeout(key: &str, value: Option<&str>) -> Result<Option<Duration>, Error> {
+    match value {
+        Some(value) => {
+            let timeout = parse_duration(value)
+                .map_err(|message| Error::Config(format!("{}: {}", key, message)))?;
+            Ok(Some(timeout).filter(|timeout| !timeout.is_zero()))
+        }
+        None => Ok(None),
+    }
+}
+
+impl ResourceLimits {
+    /// Checks that the limits are well formed, since the runtimes reject
+    /// them with messages that don't name the setting
+    pub fn validate(&self) -> Result<(), Error> {
+        let memory = match &self.memory {
+            Some(memory) => Some(parse_size(memory).ok_or_else(|| invalid_size("memory", memory))?),
+            None => None,
+        };
+
+        if let Some(memory_swap) = &self.memory_swap {
+            let Some(memory) = memory else {
+                return Err(Error::Config(
+                    "memory_swap can only be used with memory".to_string(),
+                ));
+            };
+            if memory_swap != "-1" {
+                let swap = parse_size(memory_swap)
+                    .ok_or_else(|| invalid_size("memory_swap", memory_swap))?;
+                if swap < memory {
+                    return Err(Error::Config(format!(
+                        "memory_swap \"{}\" covers memory and swap together, so it can't be less than memory",
+                        memory_swap
+                    )));
+                }
+            }
+        }
+
+        if let Some(cpus) = self.cpus {
+            if !cpus.is_finite() || cpus <= 0.0 {
+                return Err(Error::Config(format!(
+                    "Invalid cpus {}, expected a positive number such as 3.5",
+                    cpus
+                )));
+            }
+        }
+        if self.pids_limit == Some(0) {
+            return Err(Error::Config(
+                "pids_limit must be at least 1, leave it unset for no limit".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the number of bytes of a size such as "8g" or "512mb", or None if
+/// it is malformed
+fn parse_size(size: &str) -> Option<f64> {
+    let lower = size.trim().to_ascii_lowercase();
+    let number = lower.trim_end_matches('b');
+    let (number, scale) = match number.chars().last()? {
+        'k' => (&number[..number.len() - 1], 1u64 << 10),
+        'm' => (&number[..number.len() - 1], 1 << 20),
+        'g' => (&number[..number.len() - 1], 1 << 30),
+        't' => (&number[..number.len() - 1], 1 << 40),
+        _ => (number, 1),
+    };
+    // rejects signs, exponents, and the like, which parse accepts
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    let number: f64 = number.parse().ok()?;
+    (number > 0.0).then_some(number * scale as f64)
+}
+
+/// Checks that `capability` names a linux capability, with or without its
+/// "CAP_" prefix, or is "ALL"
+pub fn validate_capability(capability: &str) -> Result<(), Error> {
+    let valid = !capability.is_empty()
+        && capability
+            .chars()
+            .all(|c| c.is_ascii_alphabetic() || c == '_');
+    if !valid {
+        return Err(Error::Config(format!(
+            "Invalid capability \"{}\", expected a name such as \"SYS_PTRACE\"",
+            capability
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that the size configured as `key` is well formed
+pub fn validate_size(key: &str, size: &str) -> Result<(), Error> {
+    parse_size(size)
+        .map(drop)
+        .ok_or_else(|| invalid_size(key, size))
+}
+
+/// Checks that a tmpfs mount configured as `key` is formatted as
+/// "CONTAINER[:OPTIONS]" with an absolute path and known options, since the
+/// runtimes only reject it once the container is created
+pub fn validate_tmpfs(key: &str, tmpfs: &str) -> Result<(), Error> {
+    let invalid = |reason: String| {
+        Err(Error::Config(format!(
+            "Invalid {} \"{}\", {}",
+            key, tmpfs, reason
+        )))
+    };
+
+    let (path, options) = tmpfs.split_once(':').unwrap_or((tmpfs, ""));
+    if !path.starts_with('/') {
+        return invalid("the path must be absolute".to_string());
+    }
+    if options.is_empty() {
+        return Ok(());
+    }
+
+    for option in options.split(',') {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+        let valid = match (name, value) {
+            // sizes may also be a percentage of memory
+            ("size", Some(size)) => {
+                parse_size(size).is_some()
+                    || size.strip_suffix('%').is_some_and(|percent| {
+                        percent.parse::<u8>().is_ok_and(|p| p > 0 && p <= 100)
+                    })
+            }
+            ("mode", Some(mode)) => {
+                !mode.is_empty()
+                    && mode.len() <= 4
+                    && mode.chars().all(|c| ('0'..='7').contains(&c))
+            }
+            ("uid" | "gid" | "nr_inodes" | "nr_blocks", Some(number)) => {
+                number.parse::<u64>().is_ok()
+            }
+            (
+                "rw" | "ro" | "exec" | "noexec" | "suid" | "nosuid" | "dev" | "nodev" | "tmpcopyup"
+                | "notmpcopyup",
+                None,
+            ) => true,
+            _ => false,
+        };
+        if !valid {
+            return invalid(format!(
+                "\"{}\" is not a valid tmpfs option, expected eg. size=2g, mode=1777, uid=1000, noexec",
+                option
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the error of a malformed size for `key`
+fn invalid_size(key: &str, size: &str) -> Error {
+    Error::Config(format!(
+        "Invalid {} \"{}\", expected a size such as \"512m\" or \"8g\"",
+        key, size
+    ))
+}
+
+/// Checks that `platform` is formatted as "OS/ARCH[/VARIANT]"
+pub fn validate_platform(platform: &str) -> Result<(), Error> {
+    let parts: Vec<&str> = platform.split('/').collect();
+    if !(2..=3).contains(&parts.len()) || parts.iter().any(|part| part.is_empty()) {
+        return Err(Error::Config(format!(
+            "Invalid platform \"{}\", expected OS/ARCH[/VARIANT] such as \"linux/arm64\"",
+            platform
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that at most one dev image source is configured
+fn validate_dev_image(config: &Config) -> Result<(), Error> {
+    if config.image.is_some() && config.containerfile.is_some() {
+        return Err(Error::Config(
+            "Set only one of image and containerfile".to_string(),
+        ));
+    }
+    if config.context.is_some() && config.containerfile.is_none() {
+        return Err(Error::Config(
+            "context can only be used with containerfile".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that the nixpkgs ref is a flake ref without an attribute, and that
+/// substituters and public keys can be passed to nix as space separated lists
+fn validate_nix_options(config: &Config) -> Result<(), Error> {
+    if let Some(nixpkgs_ref) = &config.nixpkgs_ref {
+        let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_.:/".contains(c);
+        if nixpkgs_ref.is_empty() || !nixpkgs_ref.chars().all(is_safe) {
+            return Err(Error::Config(format!(
+                "Invalid nixpkgs_ref \"{}\": only letters, digits, and -_.:/ are allowed",
+                nixpkgs_ref
+            )));
+        }
+    }
+
+    for (key, values) in [
+        ("nix_substituters", &config.nix_substituters),
+        ("nix_trusted_public_keys", &config.nix_trusted_public_keys),
+    ] {
+        if let Some(value) = values
+            .iter()
+            .find(|value| value.is_empty() || value.contains(char::is_whitespace))
+        {
+            return Err(Error::Config(format!(
+                "Invalid {} entry \"{}\": entries must be nonempty and contain no whitespace",
+                key, value
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns `package` as an installable for `nix build`. Entries containing a
+/// '#' are already flake refs and are left untouched, while bare names are
+/// taken from the `nixpkgs` flake.
+pub fn installable(package: &str, nixpkgs: &str) -> String {
+    if package.contains('#') {
+        package.to_string()
+    } else {
+        format!("{}#{}", nixpkgs, package)
+    }
+}
+
+/// Rejects package names that could do anything but name a package once
+/// `PACKAGES_STRING` is word split by the shell in the build stage. Besides
+/// nixpkgs attribute names, this allows flake refs such as
+/// "github:owner/repo#package".
+pub fn validate_package(package: &str) -> Result<(), Error> {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_.#:/".contains(c);
+
+    if package.is_empty() || !package.chars().all(is_safe) {
+        return Err(Error::Config(format!(
+            "Invalid package \"{}\": only letters, digits, and -_.#:/ are allowed",
+            package
+        )));
+    }
+
+    Ok(())
+}
+
+/// SELinux relabeling options for volumes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+pub enum SelinuxLabel {
+    /// Relabel so that the volume can be shared between containers
+    #[serde(rename = "z")]
+    #[value(name = "z")]
+    Shared,
+
+    /// Relabel so that only this container can use the volume
+    #[serde(rename = "Z")]
+    #[value(name = "Z")]
+    Private,
+
+    /// Don't relabel the volume
+    #[serde(rename = "off")]
+    #[value(name = "off")]
+    Off,
+}
+
+impl SelinuxLabel {
+    /// Resolves the label to use given the value configured, if any. Without
+    /// one, volumes are relabeled as shared only when SELinux is present.
+    pub fn resolve(configured: Option<Self>) -> Self {
+        configured.unwrap_or_else(|| {
+            if Path::new("/sys/fs/selinux/enforce").exists() {
+                SelinuxLabel::Shared
+            } else {
+                SelinuxLabel::Off
+            }
+        })
+    }
+
+    /// Returns the volume option applying this label, if any
+    pub fn volume_option(self) -> Option<&'static str> {
+        match self {
+            SelinuxLabel::Shared => Some("z"),
+            SelinuxLabel::Private => Some("Z"),
+            SelinuxLabel::Off => None,
+        }
+    }
+}
+
+/// When the images builds start from are pulled
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PullPolicy {
+    /// Pull before every build
+    Always,
+
+    /// Only pull images that aren't available locally
+    Missing,
+
+    /// Pull if the registry has a newer image than the local one
+    Newer,
+
+    /// Never pull, failing if an image isn't available locally
+    Never,
+}
+
+impl PullPolicy {
+    /// Returns the name of the policy as configured, eg. "missing"
+    pub fn name(self) -> &'static str {
+        match self {
+            PullPolicy::Always => "always",
+            PullPolicy::Missing => "missing",
+            PullPolicy::Newer => "newer",
+            PullPolicy::Never => "never",
+        }
+    }
+}
+
+/// GPUs that can be passed into the environment
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Gpu {
+    /// nvidia GPUs, through the nvidia container toolkit
+    Nvidia,
+
+    /// AMD and intel GPUs, through the dri devices
+    Amd,
+
+    /// Both of the above
+    All,
+}
+
+impl Gpu {
+    /// Returns the name of the GPU as configured, eg. "nvidia"
+    pub fn name(self) -> &'static str {
+        match self {
+            Gpu::Nvidia => "nvidia",
+            Gpu::Amd => "amd",
+            Gpu::All => "all",
+        }
+    }
+
+    /// Returns whether nvidia GPUs are included
+    pub fn includes_nvidia(self) -> bool {
+        matches!(self, Gpu::Nvidia | Gpu::All)
+    }
+
+    /// Returns whether AMD and intel GPUs are included
+    pub fn includes_dri(self) -> bool {
+        matches!(self, Gpu::Amd | Gpu::All)
+    }
+}
+
+/// The host's nix store, which the nix build stage can substitute from
+pub const HOST_NIX_STORE: &str = "/nix/store";
+
+/// Where the host's /nix is mounted in the nix build stage, matching the
+/// store root configured in the embedded containerfile
+pub const HOST_NIX_MOUNT: &str = "/nix-host/nix";
+
+/// Whether the nix build stage uses the host's nix store
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UseHostNix {
+    /// Use the host store if there is one and the runtime can mount it
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl UseHostNix {
+    /// Decides whether to mount the host store into the nix build stage,
+    /// failing if it is required but can't be used
+    pub fn resolve(self, runtime: &Runtime) -> Result<bool, Error> {
+        let has_store = Path::new(HOST_NIX_STORE).is_dir();
+        let can_mount = runtime.backend().can_mount_build_volumes()
+            && runtime.remote.is_none()
+            && runtime.is_shared(Path::new(HOST_NIX_STORE));
+
+        match self {
+            UseHostNix::Auto => Ok(has_store && can_mount),
+            UseHostNix::Never => Ok(false),
+            UseHostNix::Always if !has_store => Err(Error::Config(format!(
+                "use_host_nix is set but {} does not exist",
+                HOST_NIX_STORE
+            ))),
+            UseHostNix::Always if !can_mount => Err(Error::Config(format!(
+                "use_host_nix is set but {} can't mount volumes into builds",
+                runtime.describe()
+            ))),
+            UseHostNix::Always => Ok(true),
+        }
+    }
+}
+
+impl Serialize for UseHostNix {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            UseHostNix::Auto => serializer.serialize_str("auto"),
+            UseHostNix::Always => serializer.serialize_bool(true),
+            UseHostNix::Never => serializer.serialize_bool(false),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for UseHostNix {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Bool(bool),
+            String(String),
+        }
+
+        match Value::deserialize(deserializer)? {
+            Value::Bool(true) => Ok(UseHostNix::Always),
+            Value::Bool(false) => Ok(UseHostNix::Never),
+            Value::String(value) if value == "auto" => Ok(UseHostNix::Auto),
+            Value::String(value) => Err(de::Error::custom(format!(
+                "invalid use_host_nix \"{}\", expected true, false, or \"auto\"",
+                value
+            ))),
+        }
+    }
+}
+
+/// Packages running rootless containers inside the environment with
+/// `nested_containers`
+const NESTED_CONTAINER_PACKAGES: [&str; 3] = ["podman", "fuse-overlayfs", "slirp4netns"];
+
+/// The host's container engine socket mounted into the environment
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerSocket {
+    #[default]
+    None,
+
+    /// The user's podman socket, which docker clients can use as well
+    Podman,
+
+    /// The docker daemon's socket
+    Docker,
+}
+
+/// How applications in the environment play audio
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Audio {
+    #[default]
+    Off,
+
+    /// Through the host's PipeWire and PulseAudio sockets
+    Session,
+
+    /// Through the host's sockets and sound devices
+    Alsa,
+}
+
+impl Serialize for Audio {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Audio::Off => serializer.serialize_bool(false),
+            Audio::Session => serializer.serialize_bool(true),
+            Audio::Alsa => serializer.serialize_str("alsa"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Audio {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Bool(bool),
+            String(String),
+        }
+
+        match Value::deserialize(deserializer)? {
+            Value::Bool(true) => Ok(Audio::Session),
+            Value::Bool(false) => Ok(Audio::Off),
+            Value::String(value) if value == "alsa" => Ok(Audio::Alsa),
+            Value::String(value) => Err(de::Error::custom(format!(
+                "invalid audio \"{}\", expected true, false, or \"alsa\"",
+                value
+            ))),
+        }
+    }
+}
+
+/// Canonicalizes `path`, naming it in the error if that fails.
+pub fn canonicalize(path: &Path) -> Result<PathBuf, Error> {
+    fs::canonicalize(path).map_err(|source| Error::Path {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Returns the canonical path of `workspace`, which defaults to the current
+/// directory and must be an existing directory.
+pub fn resolve_workspace(workspace: Option<PathBuf>) -> Result<PathBuf, Error> {
+    let workspace = match workspace {
+        Some(workspace) => workspace,
+        None => env::current_dir()?,
+    };
+
+    if !workspace.is_dir() {
+        return Err(Error::Config(format!(
+            "Workspace {} is not an existing directory",
+            workspace.display()
+        )));
+    }
+
+    canonicalize(&workspace)
+}
+
+/// Expands a leading `~` in `path` to the home directory of the current user.
+pub fn expand_home(path: &str) -> PathBuf {
+    let home = || BaseDirs::new().map(|dirs| dirs.home_dir().to_path_buf());
+
+    match path.strip_prefix('~') {
+        Some("") => home().unwrap_or_else(|| PathBuf::from(path)),
+        Some(rest) if rest.starts_with('/') => home()
+            .map(|home| home.join(&rest[1..]))
+            .unwrap_or_else(|| PathBuf::from(path)),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// A layer that contributed values to the resolved config
+#[derive(Debug)]
+pub enum ConfigSource {
+    /// A config file at the given path
+    File(PathBuf),
+
+    /// `YADT_*` environment variables
+    Environment,
+
+    /// Flags such as `--package`
+    Cli,
+}
+
+/// The layers that contributed to the resolved config, along with which one
+/// set each top level key
+#[derive(Debug, Default)]
+pub struct ConfigSources {
+    /// Every contributing layer, from least to most specific
+    layers: Vec<ConfigSource>,
+
+    /// Index into `layers` of the most specific layer setting each key
+    keys: HashMap<String, usize>,
+}
+
+impl ConfigSources {
+    /// Records `source` as the most specific layer so far, setting `keys`
+    pub fn push<'a>(&mut self, source: ConfigSource, keys: impl IntoIterator<Item = &'a String>) {
+        for key in keys {
+            self.keys.insert(key.clone(), self.layers.len());
+        }
+        self.layers.push(source);
+    }
+
+    /// Returns the paths of the config files that contributed, from least to
+    /// most specific
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.layers.iter().filter_map(|layer| match layer {
+            ConfigSource::File(path) => Some(path.as_path()),
+            _ => None,
+        })
+    }
+
+    /// Returns the most specific layer setting `key`, or `None` if it has its
+    /// default value
+    pub fn of(&self, key: &str) -> Option<&ConfigSource> {
+        self.keys.get(key).map(|&index| &self.layers[index])
+    }
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::File(path) => write!(f, "{}", path.display()),
+            ConfigSource::Environment => write!(f, "environment variables"),
+            ConfigSource::Cli => write!(f, "CLI"),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            docker_name: default_docker_name(),
+            runtime: None,
+            connection: None,
+            selinux_label: None,
+            workspace_mount: None,
+            keep_alive: false,
+            read_only_workspace: false,
+            writable_dirs: default_writable_dirs(),
+            volumes: Vec::new(),
+            cache_volumes: BTreeMap::new(),
+            tmpfs: Vec::new(),
+            shm_size: None,
+            network: default_network(),
+            ports: Vec::new(),
+            env_passthrough: default_env_passthrough(),
+            auto_env_file: false,
+            ssh_agent: true,
+            gpg_agent: false,
+            container_socket: ContainerSocket::default(),
+            nested_containers: false,
+            git_identity: true,
+            x11: false,
+            wayland: false,
+            gpu: None,
+            audio: Audio::default(),
+            cap_add: Vec::new(),
+            cap_drop: Vec::new(),
+            security_opt: Vec::new(),
+            memory: None,
+            memory_swap: None,
+            cpus: None,
+            pids_limit: None,
+            image: None,
+            containerfile: None,
+            context: None,
+            default_base_image: default_base_image(),
+            pull_policy: None,
+            build_timeout: None,
+            pull_timeout: None,
+            retries: 0,
+            platform: None,
+            registries: BTreeMap::new(),
+            nix_image: default_nix_image(),
+            containerfile_template: None,
+            use_host_nix: UseHostNix::default(),
+            inherit_proxy: true,
+            nixpkgs_ref: None,
+            validate_packages: false,
+            nix_substituters: Vec::new(),
+            nix_trusted_public_keys: Vec::new(),
+            base_packages: default_base_packages(),
+            additional_packages: Default::default(),
+            exclude_packages: Default::default(),
+            presets: BTreeSet::new(),
+            custom_presets: BTreeMap::new(),
+            groups: BTreeMap::new(),
+            enabled_groups: BTreeSet::new(),
+            use_flake: false,
+            use_nix_shell: false,
+            persist_home: false,
+            dotfiles: None,
+            shell: default_shell(),
+            on_create: Vec::new(),
+            hook_logs: default_hook_logs(),
+            on_enter: Vec::new(),
+        }
+    }
+}
+
+/// Output formats for printing structured data
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    Toml,
+    Json,
+}
+
+/// The resolved config as printed by `yadt config show`
+#[derive(Serialize)]
+struct ResolvedConfig<'a> {
+    /// Every layer the config was loaded from, from least to most specific
+    loaded_from: Vec<String>,
+
+    #[serde(flatten)]
+    config: &'a Config,
+
+    /// Every package that will be installed, as passed to nix
+    packages: Vec<String>,
+}
+
+/// Reads and parses a single config file into a toml table, checking that
+/// it is a valid (partial) config on its own so errors can name the file.
+fn read_config_file(path: &Path) -> Result<Table, Error> {
+    let invalid = |message: &str| Error::Config(format!("{}: {}", path.display(), message));
+
+    let text = fs::read_to_string(path).map_err(|source| Error::Path {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let table: Table = toml::from_str(&text).map_err(|e| invalid(e.message()))?;
+
+    // type errors are only caught once the table is deserialized into a
+    // config, which would otherwise happen after merging
+    toml::Value::Table(table.clone())
+        .try_into::<Config>()
+        .map_err(|e| invalid(e.message()))?;
+
+    Ok(table)
+}
+
+/// Merges `overlay` on top of `base`. Arrays are unioned, tables are merged
+/// key by key, and any other value in `overlay` replaces the one in `base`.
+fn merge_tables(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Array(base_array)), toml::Value::Array(array)) => {
+                for item in array {
+                    if !base_array.contains(&item) {
+                        base_array.push(item);
+                    }
+                }
+            }
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(table)) => {
+                merge_tables(base_table, table);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Returns the path of the global config file, if a config directory can be
+/// determined for this platform.
+///
+/// The `directories_next` crate is used to ensure cross platform
+/// compatibility, although the chances this app works on windows are rather
+/// low.
+pub fn global_config_path() -> Option<PathBuf> {
+    ProjectDirs::from("io.github", "anglesideangle", "yadt")
+        .map(|dirs| dirs.config_dir().join("config.toml"))
+}
+
+/// Returns the directory yadt keeps its own state in, such as the locks
+/// held while environments are built, if one can be determined for this
+/// platform.
+pub fn data_dir() -> Option<PathBuf> {
+    ProjectDirs::from("io.github", "anglesideangle", "yadt")
+        .map(|dirs| dirs.data_dir().to_path_buf())
+}
+
+/// Returns the path of the config file at the root of `workspace`, if one
+/// exists.
+pub fn workspace_config_path(workspace: &Path) -> Option<PathBuf> {
+    WORKSPACE_CONFIG_NAMES
+        .iter()
+        .map(|name| workspace.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Builds a config layer from `YADT_*` environment variables, so that config
+/// can be set where writing a file is awkward (eg. CI). `YADT_DOCKER_NAME`
+/// and `YADT_NIX_IMAGE` replace their config values, while
+/// `YADT_ADDITIONAL_PACKAGES` is a comma separated list of packages.
+/// Variables are read with `lookup`, eg. `env::var`.
+fn env_config_table(
+    lookup: impl Fn(&str) -> Result<String, env::VarError>,
+) -> Result<Table, Error> {
+    // Returns the value of an environment variable, or None if it is unset
+    let var = |name: &str| match lookup(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => {
+            Err(Error::Config(format!("{} is not valid unicode", name)))
+        }
+    };
+
+    let mut table = Table::new();
+
+    for (name, key) in [
+        ("YADT_DOCKER_NAME", "docker_name"),
+        ("YADT_NIX_IMAGE", "nix_image"),
+    ] {
+        if let Some(value) = var(name)? {
+            if value.trim().is_empty() {
+                return Err(Error::Config(format!("{} is set but empty", name)));
+            }
+            table.insert(key.to_string(), toml::Value::String(value));
+        }
+    }
+
+    if let Some(value) = var("YADT_ADDITIONAL_PACKAGES")? {
+        let packages = value
+            .split(',')
+            .map(str::trim)
+            .filter(|package| !package.is_empty())
+            .map(|package| toml::Value::String(package.to_string()))
+            .collect();
+        table.insert(
+            "additional_packages".to_string(),
+            toml::Value::Array(packages),
+        );
+    }
+
+    Ok(table)
+}
+
+impl Config {
+    /// Returns proper config for the application by layering every config file
+    /// that exists, from least to most specific: the global config, the
+    /// workspace's `.yadt.toml` (or `yadt.toml`), then the override file, and
+    /// finally any `YADT_*` environment variables. Values not set by any layer
+    /// fall back to `Config::default()`.
+    ///
+    /// When layering, lists such as `additional_packages` are unioned while
+    /// scalars such as `nix_image` are replaced by the more specific file. A file
+    /// that fails to parse returns an `Error::Config` naming that file, and a
+    /// missing override file is also an error.
+    ///
+    /// Also returns every layer that contributed to the config, from least to
+    /// most specific, and which of them set each key.
+    pub fn load(
+        config_override: Option<PathBuf>,
+        workspace: &Path,
+    ) -> Result<(Config, ConfigSources), Error> {
+        let mut paths: Vec<PathBuf> = global_config_path()
+            .filter(|path| path.is_file())
+            .into_iter()
+            .collect();
+        paths.extend(workspace_config_path(workspace));
+        // bad override config should fail, so it is not checked for existence
+        paths.extend(config_override);
+
+        let mut layers = Vec::new();
+        for path in paths {
+            let table = read_config_file(&path)?;
+            layers.push((ConfigSource::File(path), table));
+        }
+        let env_table = env_config_table(|name| env::var(name))?;
+        if !env_table.is_empty() {
+            layers.push((ConfigSource::Environment, env_table));
+        }
+
+        Self::from_layers(layers)
+    }
+
+    /// Merges `layers`, from least to most specific, into a validated config
+    /// as `load` does
+    fn from_layers(layers: Vec<(ConfigSource, Table)>) -> Result<(Config, ConfigSources), Error> {
+        let mut merged = Table::new();
+        let mut sources = ConfigSources::default();
+        for (source, table) in layers {
+            sources.push(source, table.keys());
+            merge_tables(&mut merged, table);
+        }
+
+        let config: Config = toml::Value::Table(merged)
+            .try_into()
+            .map_err(|e| Error::Config(e.message().to_string()))?;
+        config.validate_packages()?;
+        config.validate_presets()?;
+        for group in &config.enabled_groups {
+            config.validate_group(group)?;
+        }
+        validate_nix_options(&config)?;
+        validate_dotfiles(&config)?;
+        validate_dev_image(&config)?;
+        validate_registries(&config)?;
+        config.resource_limits().validate()?;
+        for capability in config.cap_add.iter().chain(&config.cap_drop) {
+            validate_capability(capability)?;
+        }
+        for tmpfs in &config.tmpfs {
+            validate_tmpfs("tmpfs", tmpfs)?;
+        }
+        if let Some(shm_size) = &config.shm_size {
+            validate_size("shm_size", shm_size)?;
+        }
+        if let Some(platform) = &config.platform {
+            validate_platform(platform)?;
+        }
+        config.build_timeout()?;
+        config.pull_timeout()?;
+
+        Ok((config, sources))
+    }
+}
+
+/// Adds packages passed with `--package` to `additional_packages`, recording
+/// the CLI as the most specific layer.
+pub fn add_cli_packages(
+    config: &mut Config,
+    sources: &mut ConfigSources,
+    packages: Vec<String>,
+) -> Result<(), Error> {
+    if packages.is_empty() {
+        return Ok(());
+    }
+
+    config.additional_packages.extend(packages);
+    sources.push(ConfigSource::Cli, &["additional_packages".to_string()]);
+    config.validate_packages()
+}
+
+/// Adds presets passed with `--preset` to `presets`, recording the CLI as the
+/// most specific layer.
+pub fn add_cli_presets(
+    config: &mut Config,
+    sources: &mut ConfigSources,
+    presets: Vec<String>,
+) -> Result<(), Error> {
+    if presets.is_empty() {
+        return Ok(());
+    }
+
+    config.presets.extend(presets);
+    sources.push(ConfigSource::Cli, &["presets".to_string()]);
+    config.validate_presets()
+}
+
+/// Enables the groups passed with `--with` and disables those passed with
+/// `--without`, recording the CLI as the most specific layer.
+pub fn toggle_cli_groups(
+    config: &mut Config,
+    sources: &mut ConfigSources,
+    with: Vec<String>,
+    without: Vec<String>,
+) -> Result<(), Error> {
+    if with.is_empty() && without.is_empty() {
+        return Ok(());
+    }
+
+    for group in with.iter().chain(&without) {
+        config.validate_group(group)?;
+    }
+    config.enabled_groups.extend(with);
+    for group in &without {
+        config.enabled_groups.remove(group);
+    }
+    sources.push(ConfigSource::Cli, &["enabled_groups".to_string()]);
+    Ok(())
+}
+
+/// Renders `Config::default()` as a commented toml file, meant as a starting
+/// point for users writing their own config.
+pub fn config_template() -> String {
+    // Renders a list of strings as a multiline toml array
+    fn array<'a>(items: impl IntoIterator<Item = &'a String>) -> String {
+        let mut items: Vec<&String> = items.into_iter().collect();
+        items.sort();
+        if items.is_empty() {
+            return "[]".to_string();
+        }
+        let lines: String = items
+            .into_iter()
+            .map(|item| format!("    {},\n", toml::Value::String(item.clone())))
+            .collect();
+        format!("[\n{}]", lines)
+    }
+
+    let config = Config::default();
+
+    format!(
+        r#"# yadt config file
+#
+# Every key is optional. Lists are merged with those set by less specific
+# config files (global < workspace .yadt.toml < --config), while other values
+# replace them.
+
+# The name of the docker-compatible cli to use. When not set, podman, docker,
+# and nerdctl are tried in that order.
+# docker_name = {docker_name}
+
+# Which flavor of runtime docker_name is, "podman", "docker", or "nerdctl".
+# This is detected from `docker_name --version` when not set.
+# runtime = "podman"
+
+# A named connection of `podman system connection` or docker context to run
+# containers with. A service on another host can't mount the workspace.
+# connection = "devbox"
+
+# How the workspace volume is relabeled for SELinux, "z", "Z", or "off". This
+# defaults to "z" when SELinux is detected on the host.
+# selinux_label = "z"
+
+# Where the workspace is mounted, an absolute path or "host" for the path it has
+# on the host, eg. for tools caching absolute paths.
+# workspace_mount = "/workspace"
+
+# Whether the container keeps running after the shell exits, so that background
+# processes survive and later invocations open new shells in it. Stop it with
+# `yadt stop`.
+# keep_alive = false
+
+# Whether the workspace is mounted read-only, eg. for reviewing code.
+# read_only_workspace = false
+
+# Directories of the working directory that stay writable in a read-only
+# workspace, on a tmpfs. Only directories that exist on the host are covered.
+# writable_dirs = ["target", "node_modules"]
+
+# Additional volumes to mount, formatted as "HOST:CONTAINER[:OPTIONS]".
+# volumes = ["~/.cargo/registry:/home/dev/.cargo/registry:rw"]
+
+# The network the environment joins, "host", "bridge", "none", or the name of a
+# network. Named networks are created by `--create-network` if missing.
+# network = "host"
+
+# Ports to publish, formatted as "HOST:CONTAINER[/PROTOCOL]". These are ignored
+# while the environment uses the host's network.
+# ports = ["8080:80"]
+
+# Host environment variables passed into the environment when they are set.
+# env_passthrough = ["TERM", "COLORTERM", "LANG", "LC_ALL", "EDITOR"]
+
+# Whether to set the variables of the workspace's .env file in the environment.
+# auto_env_file = false
+
+# Whether to forward the host's ssh agent, as given by SSH_AUTH_SOCK.
+# ssh_agent = true
+
+# Whether to forward the host's gpg agent and public keyring, eg. for signing
+# commits.
+# gpg_agent = false
+
+# The host's container socket to mount, along with a client using it, "podman",
+# "docker", or "none". The docker socket gives the environment root on the host.
+# container_socket = "none"
+
+# Whether podman can run its own rootless containers inside the environment.
+# This needs /dev/fuse and unprivileged user namespaces on the host.
+# nested_containers = false
+
+# Whether to mount the host's ~/.gitconfig and ~/.config/git read only.
+# git_identity = true
+
+# Whether GUI applications can connect to the host's X server.
+# x11 = false
+
+# Whether GUI applications can connect to the host's wayland compositor. This
+# can be combined with x11 for applications only supporting X.
+# wayland = false
+
+# GPUs available to the environment, "nvidia", "amd" (also intel), or "all".
+# nvidia GPUs need the nvidia container toolkit on the host.
+# gpu = "nvidia"
+
+# Whether applications can play audio through the host's PipeWire or
+# PulseAudio, true, false, or "alsa" to also pass in the sound devices.
+# audio = false
+
+# Capabilities granted to or taken from the environment, and its security
+# options. `--debug-tools` grants what gdb, strace, and perf need.
+# cap_add = ["SYS_PTRACE"]
+# cap_drop = []
+# security_opt = ["seccomp=unconfined"]
+
+# Limits on the resources the environment, its on_create hooks, and one-off
+# commands may use. memory_swap covers memory and swap together, or -1 for
+# unlimited swap.
+# memory = "8g"
+# memory_swap = "12g"
+# cpus = 3.5
+# pids_limit = 4096
+
+# tmpfs mounts for fast scratch space, with the options of `mount -t tmpfs`, and
+# the size of /dev/shm, which defaults to 64m.
+# tmpfs = ["/tmp/scratch:size=2g,mode=1777"]
+# shm_size = "2g"
+
+# Named volumes persisting caches across rebuilds, mapping a name to the path
+# they are mounted at. Remove them with `yadt prune --caches`.
+# [cache_volumes]
+# cargo = "/home/dev/.cargo"
+
+# Base image to build all the nix packages from. This must have the nix cli
+# installed.
+nix_image = {nix_image}
+
+# A containerfile replacing the embedded one environment images are built
+# from, relative to the workspace. It must declare the same ARGs, and
+# `yadt containerfile show` prints the embedded one to start from.
+# containerfile_template = "./ci/yadt.Containerfile"
+
+# The dev image `yadt` without a subcommand uses, or the containerfile it builds
+# it from, relative to the workspace. `yadt image` and `yadt containerfile`
+# override these.
+# image = "registry.fedoraproject.org/fedora:40"
+# containerfile = "./ci/Containerfile"
+# context = "."
+
+# The dev image `yadt` without a subcommand uses when neither of the above is
+# set and the workspace has no Containerfile or Dockerfile.
+# default_base_image = "docker.io/library/debian:stable-slim"
+
+# When the dev image and nix_image are pulled, "always", "missing", "newer", or
+# "never". `yadt update` pulls them regardless.
+# pull_policy = "missing"
+
+# How long builds and pulls may run before yadt kills them. "0" waits as long
+# as they take.
+# build_timeout = "30m"
+# pull_timeout = "10m"
+
+# How many times builds and pulls that failed because of the network or the
+# registry are retried.
+# retries = 0
+
+# The platform the environment is built for and runs as. Foreign platforms are
+# emulated with qemu-user.
+# platform = "linux/arm64"
+
+# Whether the nix build stage substitutes packages from the host's /nix/store
+# instead of downloading them, true, false, or "auto". "auto" uses the host
+# store when there is one and the runtime is podman.
+# use_host_nix = "auto"
+
+# Whether the host's http_proxy, https_proxy, and no_proxy variables, lowercase
+# or uppercase, are passed into builds and the environment.
+# inherit_proxy = true
+
+# The flake bare package names are taken from, eg. a nixpkgs branch or commit
+# hash, pinning the installed packages. This defaults to the nixpkgs registry
+# entry.
+# nixpkgs_ref = "github:NixOS/nixpkgs/nixos-24.05"
+
+# Whether bare package names are looked up in the flake above before building,
+# suggesting the closest names for those it lacks. `yadt check` does the same
+# on demand.
+# validate_packages = false
+
+# Binary caches to substitute from and the keys they sign with, in addition to
+# nix's defaults.
+# nix_substituters = ["https://example.cachix.org"]
+# nix_trusted_public_keys = ["example.cachix.org-1:..."]
+
+# The base packages to install into the environment. Setting this replaces
+# the default list below.
+base_packages = {base_packages}
+
+# Additional packages to install into the environment, eg. "ripgrep". Bare
+# names come from nixpkgs, while entries containing a '#' are used as flake
+# refs, eg. "github:helix-editor/helix#helix".
+additional_packages = {additional_packages}
+
+# Packages to leave out of the lists above, eg. to drop a few of the defaults.
+exclude_packages = {exclude_packages}
+
+# Presets whose packages are installed as well, eg. "rust" for cargo, rustc,
+# clippy, and rust-analyzer. `yadt presets` lists the available ones.
+# presets = ["rust", "python"]
+
+# Presets of your own, or replacements of built-in ones of the same name.
+# custom_presets.mycompany = ["awscli2", "terraform"]
+
+# Optional groups of packages, only installed when enabled below or passed with
+# `--with`. `--without` leaves out an enabled one.
+# groups.docs = ["mdbook", "graphviz"]
+# enabled_groups = ["docs"]
+
+# Whether to also install the default devShell of the workspace's flake.nix.
+# This requires podman.
+# use_flake = false
+
+# Whether to also install the nix-shell environment of the workspace's
+# shell.nix or default.nix. This requires podman.
+# use_nix_shell = false
+
+# The interactive shell, either a path or the name of a package to install.
+shell = {shell}
+
+# Commands run in the working directory whenever the environment image is
+# built, before the shell starts. Pass --rebuild to run them again.
+# on_create = ["cargo fetch"]
+
+# How many runs of the on_create commands keep their output for
+# `yadt logs --hooks`.
+# hook_logs = 10
+
+# Shell snippets sourced whenever a shell starts, after its own startup files.
+# fish sources them as fish, other shells as POSIX shell.
+# on_enter = ["source .venv/bin/activate"]
+
+# Whether to keep the home directory in a volume per workspace, so that shell
+# history and tool state survive the container. `yadt rm --keep-home` keeps it.
+# persist_home = false
+
+# Credentials for private registries, from either a credential helper or an
+# auth file written by `podman login --authfile`.
+# [registries."registry.example.com"]
+# credential_helper = "secretservice"
+# auth_file = "~/.config/containers/auth.json"
+
+# Shell configuration to bring into the environment, either a directory mounted
+# over the home directory, or a git repository cloned into ~/.dotfiles with an
+# install command run there once. Changing the repo or install command installs
+# them afresh, unless persist_home is set, which installs them into the
+# persistent home once. A path takes precedence over persist_home.
+# [dotfiles]
+# path = "~/dotfiles"
+# repo = "https://github.com/user/dotfiles"
+# install = "./install.sh"
+"#,
+        docker_name = toml::Value::String(config.docker_name),
+        nix_image = toml::Value::String(config.nix_image),
+        base_packages = array(&config.base_packages),
+        additional_packages = array(&config.additional_packages),
+        exclude_packages = array(&config.exclude_packages),
+        shell = toml::Value::String(config.shell),
+    )
+}
+
+/// Writes the starter config to `path`, or to stdout if `stdout` is set. An
+/// existing file is only replaced if `force` is set.
+pub fn init_config(path: Option<PathBuf>, force: bool, stdout: bool) -> Result<(), Error> {
+    let template = config_template();
+
+    if stdout {
+        return Ok(io::stdout().write_all(template.as_bytes())?);
+    }
+
+    let path = path.or_else(global_config_path).ok_or_else(|| {
+        Error::Config("Could not determine the config directory, pass --config instead".to_string())
+    })?;
+
+    if path.exists() && !force {
+        return Err(Error::Conflict(format!(
+            "{} already exists, pass --force to overwrite it",
+            path.display()
+        )));
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, template)?;
+
+    log::info!("Wrote config to {}", path.display());
+    Ok(())
+}
+
+/// Returns the config file `yadt add` and `yadt remove` edit: the override
+/// if given, otherwise the workspace's config file if it has one, otherwise
+/// the global one.
+fn package_config_path(
+    config_override: Option<PathBuf>,
+    workspace: &Path,
+) -> Result<PathBuf, Error> {
+    config_override
+        .or_else(|| workspace_config_path(workspace))
+        .or_else(global_config_path)
+        .ok_or_else(|| {
+            Error::Config(
+                "Could not determine the config directory, pass --config instead".to_string(),
+            )
+        })
+}
+
+/// Applies `edit` to the config file at `path`, creating it if it doesn't
+/// exist. Comments and formatting are preserved, and the edited file must
+/// still be a valid config.
+fn edit_config_file(
+    path: &Path,
+    edit: impl FnOnce(&mut DocumentMut) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(source) => {
+            return Err(Error::Path {
+                path: path.to_path_buf(),
+                source,
+            })
+        }
+    };
+
+    let mut document: DocumentMut = text.parse().map_err(|e: toml_edit::TomlError| {
+        Error::Config(format!("{}: {}", path.display(), e.message()))
+    })?;
+    edit(&mut document)?;
+
+    let text = document.to_string();
+    toml::from_str::<Config>(&text)
+        .map_err(|e| Error::Config(format!("{}: {}", path.display(), e.message())))?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, text)?;
+    Ok(())
+}
+
+/// Returns the array at `key` in `document`, adding an empty one if it is
+/// missing
+fn string_array<'a>(
+    document: &'a mut DocumentMut,
+    path: &Path,
+    key: &str,
+) -> Result<&'a mut toml_edit::Array, Error> {
+    document
+        .entry(key)
+        .or_insert_with(|| toml_edit::value(toml_edit::Array::new()))
+        .as_array_mut()
+        .ok_or_else(|| Error::Config(format!("{}: {} is not an array", path.display(), key)))
+}
+
+/// Adds `packages` to `additional_packages` in the config file for
+/// `workspace`, skipping any that are already listed.
+pub fn add_packages(
+    config_override: Option<PathBuf>,
+    workspace: &Path,
+    packages: Vec<String>,
+) -> Result<(), Error> {
+    for package in &packages {
+        validate_package(package)?;
+    }
+
+    let path = package_config_path(config_override, workspace)?;
+    edit_config_file(&path, |document| {
+        let additional = string_array(document, &path, "additional_packages")?;
+        for package in &packages {
+            if !additional
+                .iter()
+                .any(|value| value.as_str() == Some(package))
+            {
+                additional.push(package.as_str());
+            }
+        }
+        Ok(())
+    })?;
+
+    log::info!("Added {} to {}", packages.join(", "), path.display());
+    Ok(())
+}
+
+/// Removes `packages` from `additional_packages` in the config file for
+/// `workspace`. Base packages are only removed if `base` is set, by adding
+/// them to `exclude_packages`.
+pub fn remove_packages(
+    config_override: Option<PathBuf>,
+    workspace: &Path,
+    packages: Vec<String>,
+    base: bool,
+) -> Result<(), Error> {
+    let (config, _) = Config::load(config_override.clone(), workspace)?;
+    let path = package_config_path(config_override, workspace)?;
+
+    edit_config_file(&path, |document| {
+        for package in &packages {
+            let additional = string_array(document, &path, "additional_packages")?;
+            let index = additional
+                .iter()
+                .position(|value| value.as_str() == Some(package));
+            if let Some(index) = index {
+                let removed = additional.remove(index);
+                // keep the spacing before the removed entry, eg. no space
+                // after an opening bracket
+                if let Some(next) = additional.get_mut(index) {
+                    let prefix = removed.decor().prefix().cloned().unwrap_or_default();
+                    next.decor_mut().set_prefix(prefix);
+                }
+            } else if config.base_packages.contains(package) {
+                if !base {
+                    return Err(Error::Config(format!(
+                        "{} is a base package, pass --base to exclude it instead",
+                        package
+                    )));
+                }
+                let exclude = string_array(document, &path, "exclude_packages")?;
+                if !exclude.iter().any(|value| value.as_str() == Some(package)) {
+                    exclude.push(package.as_str());
+                }
+            } else {
+                return Err(Error::Config(format!(
+                    "{} is not in additional_packages of {}",
+                    package,
+                    path.display()
+                )));
+            }
+        }
+        Ok(())
+    })?;
+
+    log::info!("Removed {} from {}", packages.join(", "), path.display());
+    Ok(())
+}
+
+/// Prints the config resolved for `workspace` in the given format, including
+/// any `packages` passed on the command line. Unless a layer sets
+/// `docker_name`, it is the runtime that would be detected.
+pub fn show_config(
+    config_override: Option<PathBuf>,
+    workspace: &Path,
+    format: Format,
+    packages: Vec<String>,
+) -> Result<(), Error> {
+    let (mut config, mut sources) = Config::load(config_override, workspace)?;
+    add_cli_packages(&mut config, &mut sources, packages)?;
+    if sources.of("docker_name").is_none() {
+        match Runtime::detect(&config, &sources, DryRun::Print) {
+            Ok(runtime) => config.docker_name = runtime.name,
+            Err(error) => log::debug!("Showing the default docker_name, {}", error),
+        }
+    }
+
+    let mut loaded_from: Vec<String> = sources.layers.iter().map(ToString::to_string).collect();
+    if loaded_from.is_empty() {
+        loaded_from.push("built-in defaults".to_string());
+    }
+
+    let resolved = ResolvedConfig {
+        loaded_from,
+        config: &config,
+        packages: config.installables(),
+    };
+
+    let text = match format {
+        Format::Toml => {
+            toml::to_string_pretty(&resolved).map_err(|e| Error::Config(e.to_string()))?
+        }
+        Format::Json => serde_json::to_string_pretty(&resolved)? + "\n",
+    };
+
+    Ok(io::stdout().write_all(text.as_bytes())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `text` as a config file layer
+    fn file_layer(text: &str) -> (ConfigSource, Table) {
+        let table = text.parse().expect("The test config is valid toml");
+        (ConfigSource::File(PathBuf::from("yadt.toml")), table)
+    }
+
+    /// Builds the environment layer from `vars`, as if they were all that is
+    /// set
+    fn env_layer(vars: &[(&str, &str)]) -> Result<Table, Error> {
+        env_config_table(|name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+                .ok_or(env::VarError::NotPresent)
+        })
+    }
+
+    /// Writes `contents` to a file named `name` unique to this process in
+    /// the temporary directory, returning its path
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("yadt-test-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).expect("The temporary directory is writable");
+        path
+    }
+
+    fn strings(items: &[&str]) -> BTreeSet<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn env_overrides_win_over_files() {
+        let file = file_layer(
+            r#"
+            docker_name = "docker"
+            nix_image = "docker.io/nixos/nix:2.20"
+            additional_packages = ["ripgrep"]
+            "#,
+        );
+        let env = env_layer(&[
+            ("YADT_DOCKER_NAME", "nerdctl"),
+            ("YADT_ADDITIONAL_PACKAGES", "fd, jq,"),
+        ])
+        .unwrap();
+        let (config, sources) =
+            Config::from_layers(vec![file, (ConfigSource::Environment, env)]).unwrap();
+
+        assert_eq!(config.docker_name, "nerdctl");
+        assert_eq!(config.nix_image, "docker.io/nixos/nix:2.20");
+        assert_eq!(
+            config.additional_packages,
+            strings(&["fd", "jq", "ripgrep"])
+        );
+        assert!(matches!(
+            sources.of("docker_name"),
+            Some(ConfigSource::Environment)
+        ));
+        assert!(matches!(
+            sources.of("nix_image"),
+            Some(ConfigSource::File(_))
+        ));
+    }
+
+    #[test]
+    fn unset_env_overrides_leave_files_alone() {
+        let env = env_layer(&[]).unwrap();
+        assert!(env.is_empty());
+
+        let (config, sources) =
+            Config::from_layers(vec![file_layer(r#"docker_name = "docker""#)]).unwrap();
+        assert_eq!(config.docker_name, "docker");
+        assert!(sources.of("nix_image").is_none());
+        assert_eq!(config.nix_image, Config::default().nix_image);
+    }
+
+    #[test]
+    fn empty_env_override_is_an_error() {
+        let error = env_layer(&[("YADT_DOCKER_NAME", " ")]).unwrap_err();
+        assert_eq!(error.to_string(), "YADT_DOCKER_NAME is set but empty");
+    }
+
+    #[test]
+    fn cli_packages_win_over_env_overrides() {
+        let env = env_layer(&[("YADT_ADDITIONAL_PACKAGES", "fd")]).unwrap();
+        let (mut config, mut sources) =
+            Config::from_layers(vec![(ConfigSource::Environment, env)]).unwrap();
+        add_cli_packages(&mut config, &mut sources, vec!["jq".to_string()]).unwrap();
+
+        assert_eq!(config.additional_packages, strings(&["fd", "jq"]));
+        assert!(matches!(
+            sources.of("additional_packages"),
+            Some(ConfigSource::Cli)
+        ));
+    }
+
+    #[test]
+    fn later_files_replace_scalars_and_union_lists() {
+        let (config, _) = Config::from_layers(vec![
+            file_layer(
+                r#"
+                docker_name = "docker"
+                additional_packages = ["ripgrep"]
+                "#,
+            ),
+            file_layer(
+                r#"
+                docker_name = "podman"
+                additional_packages = ["fd", "ripgrep"]
+                "#,
+            ),
+        ])
+        .unwrap();
+
+        assert_eq!(config.docker_name, "podman");
+        assert_eq!(config.additional_packages, strings(&["fd", "ripgrep"]));
+    }
+
+    #[test]
+    fn invalid_config_file_names_the_file() {
+        let path = temp_file("invalid.toml", "docker_name = 3\n");
+        let error = read_config_file(&path).unwrap_err();
+        let _ = fs::remove_file(&path);
+
+        assert!(matches!(error, Error::Config(_)));
+        assert_eq!(error.code(), 2);
+        let message = error.to_string();
+        assert!(
+            message.starts_with(&format!("{}: ", path.display())),
+            "{}",
+            message
+        );
+        assert!(message.contains("invalid type"), "{}", message);
+    }
+
+    #[test]
+    fn unparsable_config_file_names_the_file() {
+        let path = temp_file("unparsable.toml", "docker_name = \"podman\n");
+        let error = read_config_file(&path).unwrap_err();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(error.code(), 2);
+        assert!(error
+            .to_string()
+            .starts_with(&format!("{}: ", path.display())));
+    }
+
+    #[test]
+    fn missing_override_file_is_a_path_error() {
+        let path = env::temp_dir().join(format!("yadt-test-{}-missing.toml", std::process::id()));
+        let error = Config::load(Some(path.clone()), &env::temp_dir()).unwrap_err();
+
+        assert!(matches!(&error, Error::Path { path: missing, .. } if *missing == path));
+        assert_eq!(error.code(), 2);
+        assert!(error
+            .to_string()
+            .starts_with(&format!("{}: ", path.display())));
+    }
+
+    #[test]
+    fn rejects_package_names_the_shell_would_interpret() {
+        for package in [
+            "",
+            "foo bar",
+            "$(rm -rf ~)",
+            "${HOME}",
+            "`id`",
+            "hello;ls",
+            "hello|sh",
+            "hello&&id",
+            "hello>out",
+            "\"hello\"",
+            "'hello'",
+            "hello\nid",
+            "hello\\",
+        ] {
+            let error = validate_package(package).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!(
+                    "Invalid package \"{}\": only letters, digits, and -_.#:/ are allowed",
+                    package
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_package_names_and_flake_refs() {
+        for package in [
+            "ripgrep",
+            "rust-analyzer",
+            "gcc_13",
+            "python3Packages.numpy",
+            "nixpkgs#hello",
+            "nixpkgs/nixos-24.05#python312",
+            "github:helix-editor/helix#helix",
+        ] {
+            assert!(validate_package(package).is_ok(), "{}", package);
+        }
+    }
+
+    #[test]
+    fn loading_rejects_unsafe_packages_in_every_list() {
+        for text in [
+            r#"additional_packages = ["ripgrep", "$(id)"]"#,
+            r#"base_packages = ["$(id)"]"#,
+            r#"groups.docs = ["$(id)"]"#,
+            r#"custom_presets.web = ["$(id)"]"#,
+            r#"shell = "$(id)""#,
+        ] {
+            let error = Config::from_layers(vec![file_layer(text)]).unwrap_err();
+            assert!(
+                error.to_string().starts_with("Invalid package \"$(id)\""),
+                "{}: {}",
+                text,
+                error
+            );
+        }
+    }
+
+    /// Returns a config installing only `packages`
+    fn with_packages(packages: &[&str]) -> Config {
+        Config {
+            base_packages: BTreeSet::new(),
+            additional_packages: strings(packages),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn only_bare_names_are_prefixed_with_nixpkgs() {
+        let config = with_packages(&[
+            "ripgrep",
+            "github:helix-editor/helix#helix",
+            "nixpkgs/nixos-24.05#python312",
+            "python3Packages.numpy",
+        ]);
+
+        assert_eq!(
+            config.installables(),
+            [
+                "github:helix-editor/helix#helix",
+                "nixpkgs/nixos-24.05#python312",
+                "nixpkgs#python3Packages.numpy",
+                "nixpkgs#ripgrep",
+            ]
+        );
+    }
+
+    #[test]
+    fn bare_names_use_the_pinned_nixpkgs() {
+        assert_eq!(installable("hello", "nixpkgs"), "nixpkgs#hello");
+        assert_eq!(
+            installable("hello", "github:NixOS/nixpkgs/nixos-24.05"),
+            "github:NixOS/nixpkgs/nixos-24.05#hello"
+        );
+        assert_eq!(
+            installable("github:owner/repo#tool", "github:NixOS/nixpkgs/nixos-24.05"),
+            "github:owner/repo#tool"
+        );
+
+        let config = Config {
+            nixpkgs_ref: Some("0123456789abcdef0123456789abcdef01234567".to_string()),
+            ..with_packages(&["hello", "nixpkgs#jq"])
+        };
+        assert_eq!(
+            config.installables(),
+            [
+                "github:NixOS/nixpkgs/0123456789abcdef0123456789abcdef01234567#hello",
+                "nixpkgs#jq",
+            ]
+        );
+    }
+
+    #[test]
+    fn installables_dont_depend_on_insertion_order() {
+        let packages = ["ripgrep", "fd", "nixpkgs#jq", "bat", "python3Packages.pip"];
+        let mut reversed = packages;
+        reversed.reverse();
+
+        let config = with_packages(&packages);
+        let installables = config.installables();
+        assert_eq!(installables, config.installables());
+        assert_eq!(installables, with_packages(&reversed).installables());
+
+        // packages split across layers end up in the same order either way
+        let layers = |first: &str, second: &str| {
+            Config::from_layers(vec![file_layer(first), file_layer(second)])
+                .unwrap()
+                .0
+                .installables()
+        };
+        assert_eq!(
+            layers(
+                r#"additional_packages = ["ripgrep", "fd"]"#,
+                r#"additional_packages = ["bat"]"#
+            ),
+            layers(
+                r#"additional_packages = ["bat"]"#,
+                r#"additional_packages = ["fd", "ripgrep"]"#
+            ),
+        );
+    }
+
+    #[test]
+    fn tmpfs_options_are_checked() {
+        for tmpfs in [
+            "/tmp/scratch",
+            "/tmp/scratch:size=2g,mode=1777",
+            "/cache:size=50%,uid=1000,gid=100,noexec,nosuid",
+        ] {
+            assert!(validate_tmpfs("tmpfs", tmpfs).is_ok(), "{}", tmpfs);
+        }
+        for (tmpfs, reason) in [
+            ("tmp/scratch", "the path must be absolute"),
+            (
+                "/tmp/scratch:size=huge",
+                "\"size=huge\" is not a valid tmpfs option",
+            ),
+            (
+                "/tmp/scratch:mode=999",
+                "\"mode=999\" is not a valid tmpfs option",
+            ),
+            (
+                "/tmp/scratch:size=0%",
+                "\"size=0%\" is not a valid tmpfs option",
+            ),
+            (
+                "/tmp/scratch:exec=1",
+                "\"exec=1\" is not a valid tmpfs option",
+            ),
+        ] {
+            let message = validate_tmpfs("tmpfs", tmpfs).unwrap_err().to_string();
+            assert!(
+                message.starts_with(&format!("Invalid tmpfs \"{}\", {}", tmpfs, reason)),
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_scratch_space_names_the_config_key() {
+        let error = Config::from_layers(vec![file_layer(r#"tmpfs = ["/ok", "scratch"]"#)])
+            .expect_err("The tmpfs path is relative");
+        assert!(matches!(error, Error::Config(_)));
+        assert_eq!(
+            error.to_string(),
+            "Invalid tmpfs \"scratch\", the path must be absolute"
+        );
+
+        let error = Config::from_layers(vec![file_layer(r#"shm_size = "-1g""#)])
+            .expect_err("The size is negative");
+        assert_eq!(
+            error.to_string(),
+            "Invalid shm_size \"-1g\", expected a size such as \"512m\" or \"8g\""
+        );
+
+        let (config, _) = Config::from_layers(vec![file_layer(
+            r#"
+            tmpfs = ["/tmp/scratch:size=2g,mode=1777"]
+            shm_size = "2g"
+            "#,
+        )])
+        .unwrap();
+        assert_eq!(config.tmpfs, ["/tmp/scratch:size=2g,mode=1777"]);
+        assert_eq!(config.shm_size.as_deref(), Some("2g"));
+    }
+}
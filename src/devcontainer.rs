@@ -0,0 +1,724 @@
+//! Importing environments described by a `devcontainer.json`.
+//!
+//! The file is JSON with comments and trailing commas, which are stripped
+//! before it is deserialized. The fields yadt has an equivalent for are
+//! mapped onto the arguments of `yadt image` or `yadt containerfile`, while
+//! the rest, such as features and customizations, are reported and ignored.
+//! `${localWorkspaceFolder}`, `${localWorkspaceFolderBasename}`,
+//! `${containerWorkspaceFolder}`, and `${localEnv:NAME}` are substituted in
+//! the values that are mapped.
+//!
+//! The other way around, a minimal devcontainer.json is rendered from the
+//! resolved config, referring to the environment image yadt built by its tag.
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    build::{
+        current_config_hash, environment_tag, image_exists, BuildOptions, DevImageSource, HostUser,
+    },
+    config::Config,
+    error::Error,
+    manage::newest_environment_image,
+    run::{
+        cache_volume_specs, workspace_mount_path, EnvironmentArgs, VolumeSpec,
+        DEFAULT_WORKSPACE_MOUNT,
+    },
+    runtime::Runtime,
+};
+
+/// Where a devcontainer.json is looked for in a workspace, in order
+pub static DEVCONTAINER_PATHS: [&str; 2] =
+    [".devcontainer/devcontainer.json", ".devcontainer.json"];
+
+/// Fields that don't change the environment, so ignoring them isn't worth a
+/// warning
+static SILENT_FIELDS: [&str; 2] = ["$schema", "name"];
+
+/// The fields of a devcontainer.json yadt understands
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DevContainer {
+    image: Option<String>,
+    build: Option<Build>,
+
+    /// The dockerfile, before it moved into `build`
+    docker_file: Option<String>,
+
+    /// The build context, before it moved into `build`
+    context: Option<String>,
+
+    workspace_folder: Option<String>,
+
+    #[serde(default)]
+    mounts: Vec<DevMount>,
+
+    #[serde(default)]
+    container_env: BTreeMap<String, String>,
+
+    /// Variables set for processes started in the container, where null
+    /// unsets a variable
+    #[serde(default)]
+    remote_env: BTreeMap<String, Option<String>>,
+
+    post_create_command: Option<LifecycleCommand>,
+
+    #[serde(default)]
+    forward_ports: Vec<ForwardPort>,
+
+    /// Every other field, which is ignored with a warning
+    #[serde(flatten)]
+    unsupported: BTreeMap<String, serde_json::Value>,
+}
+
+/// The `build` section of a devcontainer.json
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Build {
+    dockerfile: Option<String>,
+    context: Option<String>,
+
+    /// Every other field, which is ignored with a warning
+    #[serde(flatten)]
+    unsupported: BTreeMap<String, serde_json::Value>,
+}
+
+/// A mount, either as a `--mount` style string or as an object
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DevMount {
+    Text(String),
+    Object {
+        source: Option<String>,
+        target: String,
+        #[serde(rename = "type", default)]
+        kind: Option<String>,
+    },
+}
+
+/// A lifecycle command, which is a shell command, an argument vector run
+/// without a shell, or named commands that may run in parallel
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LifecycleCommand {
+    Shell(String),
+    Args(Vec<String>),
+    Named(BTreeMap<String, LifecycleCommand>),
+}
+
+/// A forwarded port, either a number or "HOST:PORT" naming a service
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ForwardPort {
+    Number(u16),
+    Text(String),
+}
+
+/// What a devcontainer.json maps to, as arguments of `run_environment`
+pub struct Imported {
+    pub source: DevImageSource,
+    pub workspace_mount: Option<String>,
+
+    /// Bind mounts formatted as "HOST:CONTAINER[:OPTIONS]"
+    pub volumes: Vec<String>,
+
+    /// Named volumes, mapped to the path they are mounted at
+    pub cache_volumes: BTreeMap<String, String>,
+
+    /// Environment variables formatted as "NAME=VALUE"
+    pub env: Vec<String>,
+
+    /// Ports formatted as "HOST:CONTAINER"
+    pub ports: Vec<String>,
+
+    pub on_create: Vec<String>,
+
+    /// What was ignored, which was warned about already, eg. "features" or
+    /// "build.args"
+    pub ignored: Vec<String>,
+}
+
+impl Imported {
+    /// Puts what was imported before what `environment` sets on the command
+    /// line, which can override it, returning where the dev image comes from
+    pub fn apply(self, environment: &mut EnvironmentArgs) -> DevImageSource {
+        environment.mount_path = environment.mount_path.take().or(self.workspace_mount);
+        environment.volumes.splice(0..0, self.volumes);
+        environment.env.splice(0..0, self.env);
+        environment.ports.splice(0..0, self.ports);
+        environment.on_create.extend(self.on_create);
+        environment.cache_volumes.extend(self.cache_volumes);
+        self.source
+    }
+}
+
+/// Returns the path of the devcontainer.json of `workspace`, if it has one
+pub fn find(workspace: &Path) -> Option<PathBuf> {
+    DEVCONTAINER_PATHS
+        .iter()
+        .map(|path| workspace.join(path))
+        .find(|path| path.is_file())
+}
+
+/// Reads the devcontainer.json at `path` and maps it onto yadt for
+/// `workspace`, warning about every field that is ignored
+pub fn import(path: &Path, workspace: &Path) -> Result<Imported, Error> {
+    let text = fs::read_to_string(path).map_err(|source| Error::Path {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let invalid = |reason: String| Error::Config(format!("{}: {}", path.display(), reason));
+
+    let json = strip_jsonc(&text).map_err(invalid)?;
+    let devcontainer: DevContainer =
+        serde_json::from_str(&json).map_err(|error| invalid(error.to_string()))?;
+
+    // paths in the file are relative to the directory containing it
+    let dir = path
+        .parent()
+        .expect("A devcontainer.json path has a parent")
+        .to_path_buf();
+    let container_workspace = devcontainer
+        .workspace_folder
+        .clone()
+        .unwrap_or_else(|| DEFAULT_WORKSPACE_MOUNT.to_string());
+    let expand = |value: &str| substitute(value, workspace, &container_workspace);
+
+    let mut ignored: Vec<String> = devcontainer
+        .unsupported
+        .keys()
+        .filter(|field| !SILENT_FIELDS.contains(&field.as_str()))
+        .cloned()
+        .collect();
+
+    let (dockerfile, context) = match devcontainer.build {
+        Some(build) => {
+            ignored.extend(
+                build
+                    .unsupported
+                    .keys()
+                    .map(|field| format!("build.{}", field)),
+            );
+            (build.dockerfile, build.context)
+        }
+        None => (devcontainer.docker_file, devcontainer.context),
+    };
+    let source = match (devcontainer.image, dockerfile) {
+        (_, Some(dockerfile)) => DevImageSource::Containerfile {
+            containerfile: dir.join(expand(&dockerfile)),
+            context: Some(dir.join(expand(context.as_deref().unwrap_or(".")))),
+        },
+        (Some(image), None) => DevImageSource::Image(expand(&image)),
+        (None, None) => {
+            return Err(invalid(
+                "expected an image or a build.dockerfile".to_string(),
+            ))
+        }
+    };
+
+    let mut volumes = Vec::new();
+    let mut cache_volumes = BTreeMap::new();
+    for mount in &devcontainer.mounts {
+        let (source, target, kind, read_only) = match mount {
+            DevMount::Text(text) => {
+                let options = parse_mount(&expand(text));
+                let read_only = options.contains_key("readonly") || options.contains_key("ro");
+                let option =
+                    |names: &[&str]| names.iter().find_map(|name| options.get(*name).cloned());
+                let Some(target) = option(&["target", "destination", "dst"]) else {
+                    return Err(invalid(format!("mount \"{}\" has no target", text)));
+                };
+                (
+                    option(&["source", "src"]),
+                    target,
+                    option(&["type"]),
+                    read_only,
+                )
+            }
+            DevMount::Object {
+                source,
+                target,
+                kind,
+            } => (
+                source.as_deref().map(expand),
+                expand(target),
+                kind.clone(),
+                false,
+            ),
+        };
+
+        match (kind.as_deref().unwrap_or("bind"), source) {
+            ("bind", Some(source)) => {
+                let options = if read_only { "ro" } else { "rw" };
+                volumes.push(format!("{}:{}:{}", source, target, options));
+            }
+            ("volume", Some(name)) => {
+                cache_volumes.insert(name, target);
+            }
+            (kind, _) => ignored.push(format!("mounts ({} mount at {})", kind, target)),
+        }
+    }
+
+    // remoteEnv is applied after containerEnv, so it wins
+    let mut variables: BTreeMap<String, Option<String>> = devcontainer
+        .container_env
+        .into_iter()
+        .map(|(name, value)| (name, Some(value)))
+        .collect();
+    variables.extend(devcontainer.remote_env);
+    let env = variables
+        .into_iter()
+        .filter_map(|(name, value)| Some(format!("{}={}", name, expand(&value?))))
+        .collect();
+
+    let mut ports = Vec::new();
+    for port in &devcontainer.forward_ports {
+        match port {
+            ForwardPort::Number(port) => ports.push(format!("{}:{}", port, port)),
+            ForwardPort::Text(port) => ignored.push(format!("forwardPorts ({})", port)),
+        }
+    }
+
+    let mut on_create = Vec::new();
+    if let Some(command) = &devcontainer.post_create_command {
+        push_commands(command, &expand, &mut on_create);
+    }
+
+    if !ignored.is_empty() {
+        log::warn!(
+            "Ignoring what yadt doesn't support in {}: {}",
+            path.display(),
+            ignored.join(", ")
+        );
+    }
+
+    Ok(Imported {
+        source,
+        workspace_mount: devcontainer.workspace_folder,
+        volumes,
+        cache_volumes,
+        env,
+        ports,
+        on_create,
+        ignored,
+    })
+}
+
+/// Appends the shell commands `command` consists of to `commands`. Named
+/// commands are run one after another rather than in parallel.
+fn push_commands(
+    command: &LifecycleCommand,
+    expand: &dyn Fn(&str) -> String,
+    commands: &mut Vec<String>,
+) {
+    match command {
+        LifecycleCommand::Shell(command) => commands.push(expand(command)),
+        LifecycleCommand::Args(args) => commands.push(
+            args.iter()
+                .map(|arg| format!("'{}'", expand(arg).replace('\'', r"'\''")))
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+        LifecycleCommand::Named(named) => {
+            for command in named.values() {
+                push_commands(command, expand, commands);
+            }
+        }
+    }
+}
+
+/// Parses a `--mount` style "KEY=VALUE,..." string, where flags such as
+/// "readonly" have no value
+fn parse_mount(text: &str) -> BTreeMap<String, String> {
+    text.split(',')
+        .map(|option| match option.split_once('=') {
+            Some((key, value)) => (key.trim().to_string(), value.trim().to_string()),
+            None => (option.trim().to_string(), String::new()),
+        })
+        .collect()
+}
+
+/// Substitutes the devcontainer variables in `value`. Unknown variables are
+/// left as they are.
+fn substitute(value: &str, workspace: &Path, container_workspace: &str) -> String {
+    let mut result = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        let variable = &rest[start + 2..start + end];
+        match variable {
+            "localWorkspaceFolder" => result.push_str(&workspace.to_string_lossy()),
+            "localWorkspaceFolderBasename" => result.push_str(
+                &workspace
+                    .file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or_default(),
+            ),
+            "containerWorkspaceFolder" => result.push_str(container_workspace),
+            _ => match variable
+                .strip_prefix("localEnv:")
+                .or_else(|| variable.strip_prefix("env:"))
+            {
+                // a default may follow the name, eg. ${localEnv:HOME:/root}
+                Some(variable) => {
+                    let (name, default) = variable.split_once(':').unwrap_or((variable, ""));
+                    result.push_str(&env::var(name).unwrap_or_else(|_| default.to_string()));
+                }
+                None => result.push_str(&rest[start..start + end + 1]),
+            },
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Strips the comments and trailing commas JSON with comments allows,
+/// failing on an unterminated block comment
+fn strip_jsonc(text: &str) -> Result<String, String> {
+    let mut json = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut in_string = false;
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            json.push(c);
+            match c {
+                '\\' => json.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                json.push(c);
+            }
+            ('/', Some('/')) => while chars.next_if(|&c| c != '\n').is_some() {},
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if previous == '*' => break,
+                        Some(c) => previous = c,
+                        None => return Err("unterminated block comment".to_string()),
+                    }
+                }
+                // keep tokens on either side of the comment apart
+                json.push(' ');
+            }
+            (']' | '}', _) => {
+                // a trailing comma is the last thing before the bracket
+                let trimmed = json.trim_end().len();
+                if json[..trimmed].ends_with(',') {
+                    json.truncate(trimmed - 1);
+                }
+                json.push(c);
+            }
+            _ => json.push(c),
+        }
+    }
+
+    Ok(json)
+}
+
+/// The devcontainer.json `export` writes
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportedDevContainer {
+    name: String,
+    image: String,
+    workspace_mount: String,
+    workspace_folder: String,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    mounts: Vec<String>,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    container_env: BTreeMap<String, String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_create_command: Option<String>,
+}
+
+/// Writes a devcontainer.json for `workspace` into its `.devcontainer`
+/// directory, or prints it with `stdout`, refusing to overwrite an existing
+/// file unless `force` is set. The image is the environment image for the
+/// current config on top of the dev image the workspace was last built on,
+/// under the tag yadt gives it, so an environment must have been built.
+pub fn export(
+    runtime: &Runtime,
+    config: &Config,
+    workspace: &Path,
+    force: bool,
+    stdout: bool,
+) -> Result<(), Error> {
+    let path = workspace.join(DEVCONTAINER_PATHS[0]);
+    if path.exists() && !force && !stdout {
+        return Err(Error::Conflict(format!(
+            "{} already exists, pass --force to overwrite it",
+            path.display()
+        )));
+    }
+
+    let dev_image = newest_environment_image(runtime, workspace)?
+        .and_then(|image| image.dev_image)
+        .ok_or_else(|| {
+            Error::Conflict(format!(
+                "No environment was built for {} yet, build one with `yadt image` or `yadt containerfile` first",
+                workspace.display()
+            ))
+        })?;
+    let options = BuildOptions::from_config(config);
+    let hash = current_config_hash(
+        config,
+        workspace,
+        &dev_image,
+        &HostUser::current(),
+        &options,
+    )?;
+    let image = environment_tag(workspace, &hash);
+    if !image_exists(runtime, &image)? {
+        log::warn!(
+            "{} isn't built for the current config yet, start the environment to build it",
+            image
+        );
+    }
+
+    // the workspace is mounted by the devcontainer's own workspaceMount
+    let workspace_folder = workspace_mount_path(config.workspace_mount.as_deref(), workspace)?;
+    let mut mounts: Vec<String> = config
+        .volumes
+        .iter()
+        .map(|entry| {
+            let volume = VolumeSpec::parse(entry)?;
+            let mut mount = format!(
+                "source={},target={},type=bind",
+                volume.host.display(),
+                volume.container
+            );
+            if volume.options.iter().any(|option| option == "ro") {
+                mount.push_str(",readonly");
+            }
+            Ok(mount)
+        })
+        .collect::<Result<_, Error>>()?;
+    mounts.extend(
+        cache_volume_specs(config)?
+            .into_iter()
+            .map(|(name, container)| format!("source={},target={},type=volume", name, container)),
+    );
+
+    // values are taken from the host when the container starts, as yadt does
+    let container_env = config
+        .env_passthrough
+        .iter()
+        .map(|name| (name.clone(), format!("${{localEnv:{}}}", name)))
+        .collect();
+
+    let devcontainer = ExportedDevContainer {
+        name: workspace
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        image,
+        workspace_mount: format!(
+            "source=${{localWorkspaceFolder}},target={},type=bind",
+            workspace_folder
+        ),
+        workspace_folder,
+        mounts,
+        container_env,
+        post_create_command: (!config.on_create.is_empty()).then(|| config.on_create.join(" && ")),
+    };
+    let text = serde_json::to_string_pretty(&devcontainer)? + "\n";
+
+    if stdout {
+        print!("{}", text);
+        return Ok(());
+    }
+
+    let dir = path
+        .parent()
+        .expect("The devcontainer.json path has a parent");
+    fs::create_dir_all(dir).map_err(|source| Error::Path {
+        path: dir.to_path_buf(),
+        source,
+    })?;
+    fs::write(&path, text).map_err(|source| Error::Path {
+        path: path.clone(),
+        source,
+    })?;
+
+    log::info!("Wrote {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    /// Returns the path of the devcontainer.json fixture `name`, in a
+    /// workspace of its own
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/devcontainer")
+            .join(name)
+            .join(DEVCONTAINER_PATHS[0])
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn imports_an_image_with_comments_and_trailing_commas() {
+        let workspace = Path::new("/home/dev/api-server");
+        let imported = import(&fixture("image"), workspace).unwrap();
+
+        assert_eq!(
+            imported.source,
+            DevImageSource::Image("mcr.microsoft.com/devcontainers/rust:1-bookworm".to_string())
+        );
+        assert_eq!(imported.workspace_mount.as_deref(), Some("/src"));
+        assert_eq!(imported.volumes, ["/home/dev/api-server/data:/data:ro"]);
+        assert_eq!(
+            imported.cache_volumes,
+            BTreeMap::from([("cargo-cache".to_string(), "/usr/local/cargo".to_string())])
+        );
+        // remoteEnv wins over containerEnv, and null unsets
+        assert_eq!(imported.env, ["RUST_LOG=trace", "SRC=/src/crates"]);
+        assert_eq!(imported.ports, ["8080:8080"]);
+        assert_eq!(
+            imported.on_create,
+            [
+                "cargo fetch",
+                r"'git' 'config' 'core.hooksPath' 'it'\''s/hooks'",
+            ]
+        );
+    }
+
+    #[test]
+    fn warns_about_every_ignored_field() {
+        let imported = import(&fixture("image"), Path::new("/home/dev/api-server")).unwrap();
+        // $schema and name don't change the environment
+        assert_eq!(
+            imported.ignored,
+            [
+                "customizations",
+                "features",
+                "mounts (tmpfs mount at /tmp/scratch)",
+                "forwardPorts (db:5432)",
+            ]
+        );
+
+        let imported = import(&fixture("build"), Path::new("/home/dev/web")).unwrap();
+        assert_eq!(imported.ignored, ["build.args"]);
+    }
+
+    #[test]
+    fn imports_a_dockerfile_relative_to_the_file() {
+        let file = fixture("build");
+        let dir = file.parent().unwrap();
+        let imported = import(&file, Path::new("/home/dev/web")).unwrap();
+
+        assert_eq!(
+            imported.source,
+            DevImageSource::Containerfile {
+                containerfile: dir.join("Containerfile"),
+                context: Some(dir.join(".")),
+            }
+        );
+        assert_eq!(imported.workspace_mount, None);
+        assert!(imported.volumes.is_empty());
+        assert!(imported.env.is_empty());
+        assert_eq!(imported.on_create, ["make setup web"]);
+    }
+
+    #[test]
+    fn command_line_wins_over_the_import() {
+        let imported = import(&fixture("image"), Path::new("/home/dev/api-server")).unwrap();
+        let mut environment = EnvironmentArgs {
+            mount_path: Some("/work".to_string()),
+            volumes: strings(&["/host/cache:/cache"]),
+            env: strings(&["RUST_LOG=info"]),
+            ports: strings(&["3000:3000"]),
+            ..Default::default()
+        };
+
+        let source = imported.apply(&mut environment);
+        assert_eq!(
+            source,
+            DevImageSource::Image("mcr.microsoft.com/devcontainers/rust:1-bookworm".to_string())
+        );
+        assert_eq!(environment.mount_path.as_deref(), Some("/work"));
+        assert_eq!(
+            environment.volumes,
+            ["/home/dev/api-server/data:/data:ro", "/host/cache:/cache"]
+        );
+        // later variables win, so the command line's come last
+        assert_eq!(
+            environment.env,
+            ["RUST_LOG=trace", "SRC=/src/crates", "RUST_LOG=info"]
+        );
+        assert_eq!(environment.ports, ["8080:8080", "3000:3000"]);
+        assert_eq!(environment.on_create.len(), 2);
+        assert_eq!(
+            environment
+                .cache_volumes
+                .get("cargo-cache")
+                .map(String::as_str),
+            Some("/usr/local/cargo")
+        );
+    }
+
+    #[test]
+    fn malformed_files_fail_with_their_path() {
+        let dir = env::temp_dir().join(format!("yadt-test-{}-devcontainer", process::id()));
+        fs::create_dir_all(&dir).expect("The temporary directory is writable");
+        let failure = |name: &str, text: &str| {
+            let path = dir.join(name);
+            fs::write(&path, text).expect("The temporary directory is writable");
+            let error = import(&path, Path::new("/home/dev/web")).err().unwrap();
+            assert!(matches!(error, Error::Config(_)));
+            (path, error.to_string())
+        };
+
+        let (path, message) = failure("unterminated.json", r#"{ "image": "debian" /* oops }"#);
+        assert_eq!(
+            message,
+            format!("{}: unterminated block comment", path.display())
+        );
+        let (path, message) = failure("no-image.json", r#"{ "name": "web", }"#);
+        assert_eq!(
+            message,
+            format!(
+                "{}: expected an image or a build.dockerfile",
+                path.display()
+            )
+        );
+        let (path, message) = failure("invalid.json", r#"{ "image": 3 }"#);
+        assert!(
+            message.starts_with(&format!("{}: invalid type", path.display())),
+            "{}",
+            message
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
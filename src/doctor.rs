@@ -0,0 +1,465 @@
+//! Diagnosing why environments can't be built or started on this host.
+//!
+//! `yadt doctor` checks what yadt relies on outside of itself: that the
+//! config parses, that the runtime can be run and is new enough, that
+//! rootless podman can map the user's id, how SELinux is set up, that the
+//! nix image can be found, that the runtime's storage has room, and on macOS
+//! that the podman machine runs. Each check passes, warns, or fails with a
+//! hint on what to do about it, and yadt exits with the worst of them.
+
+use std::{
+    ffi::CString,
+    fs,
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    build::{image_exists, HostUser},
+    config::{Config, ConfigSources, SelinuxLabel},
+    query::{self, format_size},
+    registry::{auth_hint, image_registry},
+    runtime::{stderr_lines, DryRun, Runtime, RuntimeKind},
+};
+
+/// Free space in the runtime's storage below which builds likely fail
+const FAIL_FREE_BYTES: u64 = 2 << 30;
+
+/// Free space in the runtime's storage below which a few builds fill it up
+const WARN_FREE_BYTES: u64 = 10 << 30;
+
+/// How a check turned out, ordered from best to worst
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Outcome {
+    /// Returns how the outcome is printed
+    fn label(self) -> &'static str {
+        match self {
+            Outcome::Pass => "pass",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "fail",
+        }
+    }
+
+    /// Returns the code yadt exits with if this is the worst outcome
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Outcome::Pass => 0,
+            Outcome::Warn => 1,
+            Outcome::Fail => 2,
+        }
+    }
+}
+
+/// The result of one check
+struct Check {
+    name: &'static str,
+    outcome: Outcome,
+    message: String,
+
+    /// What to do about a warning or failure
+    hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome: Outcome::Pass,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, message: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome: Outcome::Warn,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn fail(name: &'static str, message: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome: Outcome::Fail,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn print(&self) {
+        println!("{}  {}: {}", self.outcome.label(), self.name, self.message);
+        if let Some(hint) = &self.hint {
+            println!("      {}", hint);
+        }
+    }
+}
+
+/// Runs every check that applies to this host for the config of `workspace`,
+/// printing each as it completes, and returns the worst outcome. Checks that
+/// need the runtime are skipped if it can't be run.
+pub fn doctor(config_override: Option<PathBuf>, workspace: &Path) -> Outcome {
+    let mut worst = Outcome::Pass;
+    let mut report = |check: Check| {
+        check.print();
+        worst = worst.max(check.outcome);
+    };
+
+    let (config, sources) = match Config::load(config_override, workspace) {
+        Ok((config, sources)) => {
+            let files: Vec<_> = sources
+                .files()
+                .map(|path| path.display().to_string())
+                .collect();
+            report(Check::pass(
+                "config",
+                if files.is_empty() {
+                    "no config files, using the defaults".to_string()
+                } else {
+                    format!("loaded {}", files.join(", "))
+                },
+            ));
+            (config, sources)
+        }
+        Err(error) => {
+            report(Check::fail(
+                "config",
+                error.to_string(),
+                "Fix the file named above, the remaining checks use the default config",
+            ));
+            (Config::default(), ConfigSources::default())
+        }
+    };
+
+    let runtime = match Runtime::detect(&config, &sources, DryRun::Off) {
+        Ok(runtime) => runtime,
+        Err(error) => {
+            // the error ends with a hint of its own where it has one
+            let error = error.to_string();
+            let (message, hint) = error.split_once('\n').unwrap_or((
+                &error,
+                "Install podman or docker, or set docker_name to the path of a docker-compatible cli",
+            ));
+            report(Check::fail("runtime", message, hint));
+            return worst;
+        }
+    };
+    report(check_version(&runtime));
+
+    if cfg!(target_os = "macos") && runtime.kind == RuntimeKind::Podman {
+        report(check_machine(&runtime, &config));
+    }
+    if let Some(check) = check_subids(&runtime) {
+        report(check);
+    }
+    if cfg!(target_os = "linux") {
+        report(check_selinux(&config));
+    }
+    report(check_nix_image(&runtime, &config));
+    report(check_storage(&runtime));
+
+    worst
+}
+
+/// Returns whether the version `version` is at least `minimum`, comparing
+/// their numeric components, eg. "4.9.3-dev" is at least "4.0"
+fn version_at_least(version: &str, minimum: &str) -> bool {
+    let components = |version: &str| -> Vec<u64> {
+        version
+            .split('.')
+            .map(|component| {
+                let digits: String = component.chars().take_while(char::is_ascii_digit).collect();
+                digits.parse().unwrap_or(0)
+            })
+            .collect()
+    };
+    components(version) >= components(minimum)
+}
+
+/// Checks that the runtime is at least the version yadt needs
+fn check_version(runtime: &Runtime) -> Check {
+    let minimum = runtime.backend().minimum_version();
+    match &runtime.version {
+        None => Check::warn(
+            "runtime",
+            format!("{} doesn't report its version", runtime.name),
+            format!(
+                "yadt needs {} {} or newer, check that it is",
+                runtime.name, minimum
+            ),
+        ),
+        Some(version) if !version_at_least(version, minimum) => Check::fail(
+            "runtime",
+            format!("{} is older than {}", runtime.describe(), minimum),
+            format!("Upgrade {} to {} or newer", runtime.name, minimum),
+        ),
+        Some(_) => Check::pass("runtime", runtime.describe()),
+    }
+}
+
+/// Checks that the podman machine containers run in on macOS exists, runs,
+/// and can keep the user's id
+fn check_machine(runtime: &Runtime, config: &Config) -> Check {
+    let Some(machine) = &runtime.machine else {
+        if runtime.remote.is_some() || config.connection.is_some() {
+            return Check::pass("machine", "podman connects to a configured service");
+        }
+        return Check::fail(
+            "machine",
+            "podman has no machine to run containers in",
+            "Create one with `podman machine init`, then start it with `podman machine start`",
+        );
+    };
+
+    if !machine.running {
+        Check::fail(
+            "machine",
+            format!("podman machine {} isn't running", machine.name),
+            format!(
+                "Start it with `podman machine start {}`, or pass --start-machine",
+                machine.name
+            ),
+        )
+    } else if machine.rootful {
+        Check::warn(
+            "machine",
+            format!(
+                "podman machine {} runs rootful, so the workspace's files are owned by root in containers",
+                machine.name
+            ),
+            format!(
+                "Switch it with `podman machine stop {0} && podman machine set --rootful=false {0}`",
+                machine.name
+            ),
+        )
+    } else {
+        Check::pass(
+            "machine",
+            format!("podman machine {} is running", machine.name),
+        )
+    }
+}
+
+/// Returns whether `file`, eg. "/etc/subuid", has an entry for `user`, by
+/// name or by id, or None if it can't be read
+fn has_subid_entry(file: &Path, user: &HostUser) -> Option<bool> {
+    let text = fs::read_to_string(file).ok()?;
+    let uid = user.uid.to_string();
+    Some(text.lines().any(|line| {
+        line.split(':')
+            .next()
+            .is_some_and(|owner| owner == user.name || owner == uid)
+    }))
+}
+
+/// Checks that rootless podman can map the user's id into containers, for
+/// which the user needs subordinate ids. Returns None where podman doesn't
+/// map ids on this host.
+fn check_subids(runtime: &Runtime) -> Option<Check> {
+    let user = HostUser::current();
+    if !cfg!(target_os = "linux")
+        || runtime.kind != RuntimeKind::Podman
+        || runtime.remote.is_some()
+        || user.uid == 0
+    {
+        return None;
+    }
+
+    let missing: Vec<&str> = ["/etc/subuid", "/etc/subgid"]
+        .into_iter()
+        .filter(|file| has_subid_entry(Path::new(file), &user) != Some(true))
+        .collect();
+    Some(if missing.is_empty() {
+        Check::pass(
+            "rootless",
+            format!("{} has subordinate uids and gids", user.name),
+        )
+    } else {
+        Check::fail(
+            "rootless",
+            format!("{} has no entry for {}", missing.join(" and "), user.name),
+            format!(
+                "Add them with `sudo usermod --add-subuids 100000-165535 --add-subgids 100000-165535 {}`, then run `podman system migrate`",
+                user.name
+            ),
+        )
+    })
+}
+
+/// Checks how SELinux is set up, and that volumes are relabeled if it
+/// enforces its policy
+fn check_selinux(config: &Config) -> Check {
+    let mode = match fs::read_to_string("/sys/fs/selinux/enforce") {
+        Err(_) => return Check::pass("selinux", "disabled"),
+        Ok(enforce) if enforce.trim() == "1" => "enforcing",
+        Ok(_) => "permissive",
+    };
+
+    let label = SelinuxLabel::resolve(config.selinux_label);
+    match label.volume_option() {
+        None if mode == "enforcing" => Check::warn(
+            "selinux",
+            "enforcing, but selinux_label is \"off\", so containers are denied access to the workspace",
+            "Set selinux_label = \"z\" in the config, or leave it unset",
+        ),
+        None => Check::pass("selinux", format!("{}, volumes aren't relabeled", mode)),
+        Some(option) => Check::pass(
+            "selinux",
+            format!("{}, volumes are relabeled with :{}", mode, option),
+        ),
+    }
+}
+
+/// Checks that the nix image builds start from is present or can be pulled
+fn check_nix_image(runtime: &Runtime, config: &Config) -> Check {
+    let image = &config.nix_image;
+    match image_exists(runtime, image) {
+        Ok(true) => return Check::pass("nix image", format!("{} is present", image)),
+        Ok(false) => {}
+        Err(error) => {
+            return Check::warn(
+                "nix image",
+                format!("{} couldn't be looked up: {}", image, error),
+                format!(
+                    "Check that `{} image inspect {}` works",
+                    runtime.name, image
+                ),
+            )
+        }
+    }
+
+    let Some(args) = runtime.backend().resolve_image_args(image) else {
+        return Check::warn(
+            "nix image",
+            format!(
+                "{} isn't pulled yet, and {} can't look it up without pulling it",
+                image, runtime.name
+            ),
+            format!("Pull it with `{} pull {}`", runtime.name, image),
+        );
+    };
+    let mut command = runtime.command();
+    command.args(args);
+    let output = match runtime.output(&mut command) {
+        Ok(output) => output,
+        Err(error) => {
+            return Check::warn(
+                "nix image",
+                format!("{} couldn't be looked up: {}", image, error),
+                format!("Pull it with `{} pull {}`", runtime.name, image),
+            )
+        }
+    };
+    if output.status.success() {
+        return Check::pass(
+            "nix image",
+            format!("{} is available from {}", image, image_registry(image)),
+        );
+    }
+
+    let stderr_tail = stderr_lines(&output.stderr);
+    let reason = stderr_tail.last().map_or("", String::as_str);
+    Check::fail(
+        "nix image",
+        format!(
+            "{} can't be resolved from {}: {}",
+            image,
+            image_registry(image),
+            reason
+        ),
+        auth_hint(image_registry(image), None, &stderr_tail).unwrap_or_else(|| {
+            "Check the network connection, and that nix_image names an image that exists"
+                .to_string()
+        }),
+    )
+}
+
+/// Returns the space available to unprivileged users on the filesystem of
+/// `path`
+fn free_space(path: &Path) -> Option<u64> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: path is nul terminated, and stat is only read once statvfs
+    // filled it in
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    // the types of the fields differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Checks that the directory the runtime stores images in has room for
+/// builds. Storage in a podman machine or on another host isn't checked.
+fn check_storage(runtime: &Runtime) -> Check {
+    if let Some(remote) = &runtime.remote {
+        return Check::pass(
+            "storage",
+            format!("images are stored on {}, which isn't checked", remote),
+        );
+    }
+    if let Some(machine) = &runtime.machine {
+        return Check::pass(
+            "storage",
+            format!(
+                "images are stored in podman machine {}, which isn't checked",
+                machine.name
+            ),
+        );
+    }
+
+    let root = match query::query(
+        runtime,
+        "Looking up the storage directory",
+        &["info", "--format", runtime.backend().storage_root_format()],
+    ) {
+        Ok(root) if !root.trim().is_empty() => PathBuf::from(root.trim()),
+        Ok(_) => {
+            return Check::warn(
+                "storage",
+                format!("{} doesn't report a storage directory", runtime.name),
+                format!("Check that `{} info` works", runtime.name),
+            )
+        }
+        Err(error) => {
+            return Check::warn(
+                "storage",
+                format!("the storage directory couldn't be looked up: {}", error),
+                format!("Check that `{} info` works", runtime.name),
+            )
+        }
+    };
+
+    // the storage of a rootful runtime may only be searchable by root, but
+    // its parents are on the same filesystem unless it is mounted separately
+    let Some(free) = root.ancestors().find_map(free_space) else {
+        return Check::warn(
+            "storage",
+            format!("the free space in {} couldn't be read", root.display()),
+            format!("Check it with `df -h {}`", root.display()),
+        );
+    };
+    let message = format!("{} free in {}", format_size(free), root.display());
+    let hint = format!(
+        "Free up space with `yadt prune` and `{} system prune`",
+        runtime.name
+    );
+    if free < FAIL_FREE_BYTES {
+        Check::fail("storage", message, hint)
+    } else if free < WARN_FREE_BYTES {
+        Check::warn("storage", message, hint)
+    } else {
+        Check::pass("storage", message)
+    }
+}
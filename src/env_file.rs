@@ -0,0 +1,213 @@
+//! Parsing `.env` files into the environment variables they set.
+//!
+//! docker and podman disagree on how `--env-file` treats quotes, so files
+//! are parsed here and their variables passed to the runtime one by one.
+//! Each line is either blank, a `#` comment, or `KEY=VALUE`, optionally
+//! preceded by `export`. Values may be double quoted, with `\n`, `\t`, `\"`,
+//! and `\\` escapes, or single quoted, which are taken literally. Unquoted
+//! values are trimmed and end at a `#` preceded by whitespace.
+
+use std::{fs, path::Path};
+
+use crate::error::Error;
+
+/// Name of the env file loaded from the workspace with `auto_env_file`
+pub const WORKSPACE_ENV_FILE: &str = ".env";
+
+/// Reads the env file at `path`, returning its variables in the order they
+/// are set. Malformed lines fail with the file and line number.
+pub fn read(path: &Path) -> Result<Vec<(String, String)>, Error> {
+    let text = fs::read_to_string(path).map_err(|source| Error::Path {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    parse(&text)
+        .map_err(|(line, reason)| Error::Config(format!("{}:{}: {}", path.display(), line, reason)))
+}
+
+/// Parses the contents of an env file, failing with the 1-based number of
+/// the first malformed line and what is wrong with it.
+pub fn parse(text: &str) -> Result<Vec<(String, String)>, (usize, String)> {
+    let mut variables = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line
+            .strip_prefix("export")
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+            .map_or(line, str::trim_start);
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err((index + 1, "expected KEY=VALUE".to_string()));
+        };
+
+        let key = key.trim_end();
+        let valid_key = key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+        if !valid_key {
+            return Err((index + 1, format!("invalid variable name \"{}\"", key)));
+        }
+
+        let value = parse_value(value.trim_start()).map_err(|reason| (index + 1, reason))?;
+        variables.push((key.to_string(), value));
+    }
+
+    Ok(variables)
+}
+
+/// Parses the value of a line, which may be quoted and followed by a comment
+fn parse_value(value: &str) -> Result<String, String> {
+    let (parsed, rest) = match value.chars().next() {
+        Some('"') => {
+            let mut parsed = String::new();
+            let mut chars = value[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 2,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => parsed.push('\n'),
+                        Some((_, 't')) => parsed.push('\t'),
+                        Some((_, c @ ('"' | '\\' | '$'))) => parsed.push(c),
+                        Some((_, c)) => {
+                            parsed.push('\\');
+                            parsed.push(c);
+                        }
+                        None => return Err("unterminated double quote".to_string()),
+                    },
+                    Some((_, c)) => parsed.push(c),
+                    None => return Err("unterminated double quote".to_string()),
+                }
+            };
+            (parsed, &value[end..])
+        }
+        Some('\'') => {
+            let Some(end) = value[1..].find('\'') else {
+                return Err("unterminated single quote".to_string());
+            };
+            (value[1..end + 1].to_string(), &value[end + 2..])
+        }
+        _ => {
+            // a '#' only starts a comment after whitespace, so that it can
+            // appear in values such as urls
+            let end = value
+                .char_indices()
+                .find(|&(i, c)| c == '#' && value[..i].ends_with(char::is_whitespace))
+                .map_or(value.len(), |(i, _)| i);
+            return Ok(value[..end].trim_end().to_string());
+        }
+    };
+
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("unexpected \"{}\" after quoted value", rest));
+    }
+
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn variables(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let text =
+            "# database\n\n   \nDB_HOST=localhost\n  # indented comment\nexport DB_PORT=5432\n";
+        assert_eq!(
+            parse(text).unwrap(),
+            variables(&[("DB_HOST", "localhost"), ("DB_PORT", "5432")])
+        );
+    }
+
+    #[test]
+    fn unquoted_values_are_trimmed_and_end_at_comments() {
+        let text = "A = spaced out  \nB=value # comment\nURL=http://host/#anchor\nEMPTY=\n";
+        assert_eq!(
+            parse(text).unwrap(),
+            variables(&[
+                ("A", "spaced out"),
+                ("B", "value"),
+                ("URL", "http://host/#anchor"),
+                ("EMPTY", ""),
+            ])
+        );
+    }
+
+    #[test]
+    fn values_may_contain_equals_signs() {
+        let text = "TOKEN=abc==\nQUERY=\"a=1&b=2\"\n";
+        assert_eq!(
+            parse(text).unwrap(),
+            variables(&[("TOKEN", "abc=="), ("QUERY", "a=1&b=2")])
+        );
+    }
+
+    #[test]
+    fn double_quotes_unescape_and_single_quotes_dont() {
+        let text = concat!(
+            r#"GREETING="hello\n\t\"world\" \\ \$HOME" # trailing comment"#,
+            "\n",
+            r#"RAW='hello\n "world" # not a comment'"#,
+            "\n",
+            r#"UNKNOWN="\q""#,
+            "\n",
+        );
+        assert_eq!(
+            parse(text).unwrap(),
+            variables(&[
+                ("GREETING", "hello\n\t\"world\" \\ $HOME"),
+                ("RAW", r#"hello\n "world" # not a comment"#),
+                ("UNKNOWN", r"\q"),
+            ])
+        );
+    }
+
+    #[test]
+    fn malformed_lines_fail_with_their_number() {
+        for (text, line, reason) in [
+            ("A=1\nnot a variable\n", 2, "expected KEY=VALUE"),
+            ("\n\n1ST=one\n", 3, "invalid variable name \"1ST\""),
+            ("MY-VAR=1\n", 1, "invalid variable name \"MY-VAR\""),
+            ("A=\"open\n", 1, "unterminated double quote"),
+            ("A=ok\nB='open\n", 2, "unterminated single quote"),
+            (
+                "A=\"quoted\" extra\n",
+                1,
+                "unexpected \"extra\" after quoted value",
+            ),
+        ] {
+            assert_eq!(parse(text), Err((line, reason.to_string())), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn read_errors_name_the_file_and_line() {
+        let path = env::temp_dir().join(format!("yadt-test-{}-malformed.env", process::id()));
+        fs::write(&path, "# ok\nA=1\nB\n").expect("The temporary directory is writable");
+        let error = read(&path).unwrap_err();
+        let _ = fs::remove_file(&path);
+
+        assert!(matches!(error, Error::Config(_)));
+        assert_eq!(
+            error.to_string(),
+            format!("{}:3: expected KEY=VALUE", path.display())
+        );
+
+        let error = read(&path).unwrap_err();
+        assert!(matches!(error, Error::Path { .. }));
+    }
+}
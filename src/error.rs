@@ -0,0 +1,132 @@
+//! The errors yadt can fail with.
+
+use std::{io, path::PathBuf, process::ExitCode, time::Duration};
+
+use thiserror::Error;
+
+/// Errors yadt can fail with, grouped by what the user should look at to fix
+/// them. Each group exits with its own code so scripts can tell them apart.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// A config file couldn't be parsed, or a config value or argument is
+    /// invalid
+    #[error("{0}")]
+    Config(String),
+
+    /// A path given in the config or on the command line couldn't be used
+    #[error("{}: {source}", path.display())]
+    Path {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The container runtime's cli couldn't be executed
+    #[error(
+        "Could not run container runtime `{name}` ({origin}): {source}\n\
+         Install podman, or set `docker_name` in the config to a docker-compatible cli"
+    )]
+    MissingRuntime {
+        name: String,
+
+        /// Where the runtime's name was configured
+        origin: String,
+
+        #[source]
+        source: io::Error,
+    },
+
+    /// Building an image failed
+    #[error("{0}")]
+    Build(String),
+
+    /// A build succeeded without reporting the id of the image it built
+    #[error("{0} succeeded without writing an image id")]
+    EmptyBuildOutput(String),
+
+    /// A container runtime command other than a build failed
+    #[error("{0}")]
+    Runtime(String),
+
+    /// The requested action conflicts with the current state, eg. a container
+    /// that is already running
+    #[error("{0}")]
+    Conflict(String),
+
+    /// An `on_create` command failed, which yadt exits with the exit code of
+    #[error("on_create command `{command}` exited with code {code}, pass --rebuild to run it again once fixed")]
+    Hook { command: String, code: u8 },
+
+    /// The shell or command yadt waited for instead of replacing itself with
+    /// exited unsuccessfully, which yadt exits with the exit code of
+    #[error("The environment exited with code {0}")]
+    Exited(u8),
+
+    /// A build or pull ran past the timeout configured with `key`
+    #[error("{step} timed out after {}s, raise {key} in the config or set it to \"0\" to wait as long as it takes", elapsed.as_secs())]
+    Timeout {
+        step: String,
+        key: &'static str,
+        elapsed: Duration,
+    },
+
+    /// A build was interrupted by `signal`, which yadt exits with as 128 plus
+    /// its number, like shells do
+    #[error("{step} was interrupted")]
+    Interrupted { step: String, signal: i32 },
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl Error {
+    /// Reclassifies a failed runtime command as a failed build
+    pub fn into_build(self) -> Self {
+        match self {
+            Error::Runtime(message) => Error::Build(message),
+            error => error,
+        }
+    }
+
+    /// Returns the code yadt exits with for this error
+    pub fn code(&self) -> u8 {
+        match self {
+            Error::Io(_) => 1,
+            Error::Config(_) | Error::Path { .. } => 2,
+            Error::Build(_) | Error::EmptyBuildOutput(_) => 3,
+            Error::MissingRuntime { .. } | Error::Runtime(_) | Error::Timeout { .. } => 4,
+            Error::Conflict(_) => 5,
+            Error::Hook { code, .. } | Error::Exited(code) => *code,
+            Error::Interrupted { signal, .. } => 128 + *signal as u8,
+        }
+    }
+
+    /// Returns the process exit code for this error
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(self.code())
+    }
+
+    /// Returns the name of this error's variant, as reported in json events
+    pub fn category(&self) -> &'static str {
+        match self {
+            Error::Config(_) => "config",
+            Error::Path { .. } => "path",
+            Error::MissingRuntime { .. } => "missing_runtime",
+            Error::Build(_) => "build",
+            Error::EmptyBuildOutput(_) => "empty_build_output",
+            Error::Runtime(_) => "runtime",
+            Error::Conflict(_) => "conflict",
+            Error::Hook { .. } => "hook",
+            Error::Exited(_) => "exited",
+            Error::Timeout { .. } => "timeout",
+            Error::Interrupted { .. } => "interrupted",
+            Error::Io(_) => "io",
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Io(error.into())
+    }
+}
@@ -0,0 +1,235 @@
+//! The events `--output json` writes to stdout instead of yadt's messages.
+//!
+//! Each event is a json object on a line of its own, tagged with its kind in
+//! `event` and with `EVENTS_VERSION` in `version`, which changes whenever an
+//! event changes incompatibly. yadt's messages become `log` events, the
+//! output of builds `output` events, and a failure the final `error` event.
+//! Once the environment's shell or command takes over, the `exec` event is
+//! the last one, and whatever follows on stdout is the environment's own.
+
+use std::{
+    io::{self, Write},
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Version of the events, written with every event
+pub const EVENTS_VERSION: u32 = 1;
+
+/// Whether events are written to stdout
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a phase started or finished
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PhaseStatus {
+    Started,
+    Finished,
+}
+
+/// Something yadt reports while it runs
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A phase of building or starting the environment, such as "pull" or
+    /// "install_packages", along with how it is described to people
+    Phase {
+        name: String,
+        description: String,
+        status: PhaseStatus,
+    },
+
+    /// A message yadt would otherwise print to stderr
+    Log { level: String, message: String },
+
+    /// A line of output of a build or pull
+    Output { line: String },
+
+    /// The environment image the environment runs, and whether it was built
+    /// rather than reused
+    Image {
+        id: String,
+        tag: String,
+        built: bool,
+    },
+
+    /// The container the environment runs in
+    Container { name: String },
+
+    /// The environment's shell or command takes over, after which nothing
+    /// else is written
+    Exec,
+
+    /// What yadt failed with, where `category` names the variant of `Error`
+    /// and `exit_code` is what yadt exits with
+    Error {
+        category: String,
+        message: String,
+        exit_code: u8,
+    },
+}
+
+impl Event {
+    /// Returns the event reporting `error`
+    pub fn error(error: &Error) -> Self {
+        Event::Error {
+            category: error.category().to_string(),
+            message: error.to_string(),
+            exit_code: error.code(),
+        }
+    }
+}
+
+/// An event as written, along with the version of the events
+#[derive(Serialize)]
+struct Envelope<'a> {
+    version: u32,
+
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Writes events to stdout from now on
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns whether events are written to stdout
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns `event` as the line it is written as, without the newline
+pub fn to_line(event: &Event) -> String {
+    serde_json::to_string(&Envelope {
+        version: EVENTS_VERSION,
+        event,
+    })
+    .expect("Events serialize to json")
+}
+
+/// Writes `event` to stdout if events are enabled, flushing it at once so
+/// that nothing is left to write once the environment takes over
+pub fn emit(event: Event) {
+    if !is_enabled() {
+        return;
+    }
+    let mut stdout = io::stdout().lock();
+    let _ = writeln!(stdout, "{}", to_line(&event)).and_then(|()| stdout.flush());
+}
+
+/// Points the stdout of `command`, whose output is passed through, at
+/// stderr while events are written to stdout, so that it can't be mistaken
+/// for them
+pub fn keep_off_stdout(command: &mut Command) {
+    if is_enabled() {
+        command.stdout(io::stderr());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_written_with_their_kind_and_version() {
+        let cases = [
+            (
+                Event::Phase {
+                    name: "pull".to_string(),
+                    description: "Pulling debian:stable".to_string(),
+                    status: PhaseStatus::Started,
+                },
+                r#"{"version":1,"event":"phase","name":"pull","description":"Pulling debian:stable","status":"started"}"#,
+            ),
+            (
+                Event::Log {
+                    level: "warn".to_string(),
+                    message: "Rebuilding".to_string(),
+                },
+                r#"{"version":1,"event":"log","level":"warn","message":"Rebuilding"}"#,
+            ),
+            (
+                Event::Output {
+                    line: "STEP 1/4".to_string(),
+                },
+                r#"{"version":1,"event":"output","line":"STEP 1/4"}"#,
+            ),
+            (
+                Event::Image {
+                    id: "sha256:abc".to_string(),
+                    tag: "yadt-ws:abc".to_string(),
+                    built: true,
+                },
+                r#"{"version":1,"event":"image","id":"sha256:abc","tag":"yadt-ws:abc","built":true}"#,
+            ),
+            (
+                Event::Container {
+                    name: "yadt-ws".to_string(),
+                },
+                r#"{"version":1,"event":"container","name":"yadt-ws"}"#,
+            ),
+            (Event::Exec, r#"{"version":1,"event":"exec"}"#),
+            (
+                Event::Error {
+                    category: "build".to_string(),
+                    message: "Building failed".to_string(),
+                    exit_code: 3,
+                },
+                r#"{"version":1,"event":"error","category":"build","message":"Building failed","exit_code":3}"#,
+            ),
+        ];
+        for (event, line) in cases {
+            assert_eq!(to_line(&event), line);
+        }
+    }
+
+    #[test]
+    fn errors_are_reported_with_their_category_and_exit_code() {
+        assert_eq!(
+            Event::error(&Error::EmptyBuildOutput("Building".to_string())),
+            Event::Error {
+                category: "empty_build_output".to_string(),
+                message: "Building succeeded without writing an image id".to_string(),
+                exit_code: 3,
+            }
+        );
+        assert_eq!(
+            Event::error(&Error::Interrupted {
+                step: "Building".to_string(),
+                signal: 2,
+            }),
+            Event::Error {
+                category: "interrupted".to_string(),
+                message: "Building was interrupted".to_string(),
+                exit_code: 130,
+            }
+        );
+    }
+
+    #[test]
+    fn multiline_messages_stay_on_one_line() {
+        let error = Error::MissingRuntime {
+            name: "podman".to_string(),
+            origin: "the default".to_string(),
+            source: io::Error::from(io::ErrorKind::NotFound),
+        };
+        let events = [
+            Event::error(&error),
+            Event::Log {
+                level: "info".to_string(),
+                message: "first\nsecond\r\n".to_string(),
+            },
+        ];
+        for event in events {
+            let line = to_line(&event);
+            assert!(!line.contains(['\n', '\r']), "{}", line);
+            let parsed: Event = serde_json::from_str(&line).unwrap();
+            assert_eq!(parsed, event);
+        }
+    }
+}
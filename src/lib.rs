@@ -0,0 +1,54 @@
+//! yadt builds development environments from a dev image and a set of nix
+//! packages, and runs them as containers with the workspace mounted.
+//!
+//! yadt only builds for unix hosts, Linux and macOS, since it locks, signals,
+//! and replaces itself with the environment's shell through their APIs.
+//!
+//! The `yadt` binary only parses the command line and dispatches to the
+//! modules here:
+//!
+//! - `config` loads and edits the layered config files
+//! - `check` looks up the configured packages in nixpkgs before building
+//! - `presets` defines the clusters of packages languages tend to want
+//! - `build` builds dev images and the environment images installing the
+//!   configured packages on top of them
+//! - `run` starts and enters the containers running environments
+//! - `manage` lists and removes what yadt created
+//! - `runtime` drives the container runtime, through a `Runner` that can be
+//!   substituted
+//! - `backend` translates builds and runs into each runtime's flags
+//! - `registry` authenticates builds and pulls to the configured registries
+//! - `signal` forwards interrupts to running builds
+//! - `progress` shows the phases of builds on a terminal
+//! - `events` reports what yadt does as json for other programs
+//! - `state` remembers environments between runs
+//! - `doctor` diagnoses what keeps environments from running on a host
+//! - `logs` shows what environments and their hooks printed
+//! - `env_file` parses the `.env` files passed into environments
+//! - `devcontainer` imports environments described by a `devcontainer.json`
+//! - `archive` moves environment images between machines
+
+#[cfg(not(unix))]
+compile_error!("yadt only builds for unix hosts");
+
+pub mod archive;
+pub mod backend;
+pub mod build;
+pub mod check;
+pub mod config;
+pub mod devcontainer;
+pub mod doctor;
+pub mod env_file;
+pub mod error;
+pub mod events;
+pub mod lock;
+pub mod logs;
+pub mod manage;
+pub mod presets;
+pub mod progress;
+pub mod query;
+pub mod registry;
+pub mod run;
+pub mod runtime;
+pub mod signal;
+pub mod state;
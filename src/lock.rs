@@ -0,0 +1,207 @@
+//! The `yadt.lock` file, recording the nix store paths each package of an
+//! environment resolved to.
+//!
+//! The embedded containerfile saves the output of `nix build --json` in the
+//! environment image, which is read back after a build to write the lock.
+//! With `--frozen`, the locked store paths are built instead of the
+//! configured packages, so the environment can't drift from the lock.
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Name of the lock file in the workspace
+pub const LOCK_FILE_NAME: &str = "yadt.lock";
+
+/// Where the embedded containerfile saves the output of `nix build --json`
+pub const BUILD_MANIFEST_PATH: &str = "/etc/yadt/packages.json";
+
+/// Where the embedded containerfile saves the installables it built, on a
+/// single line in the order of `BUILD_MANIFEST_PATH`
+pub const BUILD_INSTALLABLES_PATH: &str = "/etc/yadt/installables";
+
+/// Written at the top of every lock file
+static LOCK_FILE_HEADER: &str = "# Generated by yadt, do not edit by hand.\n\n";
+
+/// The contents of a lock file
+#[derive(Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Lockfile {
+    #[serde(rename = "package", default)]
+    pub packages: Vec<LockedPackage>,
+}
+
+/// A package and the store paths it resolved to
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LockedPackage {
+    /// The installable as passed to `nix build`, eg. "nixpkgs#git"
+    pub installable: String,
+
+    /// The package name, parsed from its store path
+    pub name: String,
+
+    pub version: Option<String>,
+
+    /// Store paths of every output that was built
+    pub store_paths: Vec<String>,
+}
+
+/// An entry of `nix build --json` output
+#[derive(Deserialize)]
+struct BuildResult {
+    outputs: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+    /// Creates a lock from the output of `nix build --json`, which lists the
+    /// results in the order of `installables`.
+    pub fn from_build_output(installables: &[String], json: &str) -> Result<Self, Error> {
+        let results: Vec<BuildResult> = serde_json::from_str(json)?;
+
+        if results.len() != installables.len() {
+            return Err(Error::Build(format!(
+                "Expected {} packages in the build output, found {}",
+                installables.len(),
+                results.len()
+            )));
+        }
+
+        let packages = installables
+            .iter()
+            .zip(results)
+            .map(|(installable, result)| {
+                // other outputs append their name to the version, eg. "-man"
+                let (name, version) = result
+                    .outputs
+                    .get("out")
+                    .or(result.outputs.values().next())
+                    .map(|path| parse_store_path(path))
+                    .unwrap_or_default();
+                let store_paths = result.outputs.into_values().collect();
+
+                LockedPackage {
+                    installable: installable.clone(),
+                    name,
+                    version,
+                    store_paths,
+                }
+            })
+            .collect();
+
+        Ok(Self { packages })
+    }
+
+    /// Reads the lock file in `workspace`, if there is one
+    pub fn read(workspace: &Path) -> Result<Option<Self>, Error> {
+        let path = workspace.join(LOCK_FILE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let text = fs::read_to_string(&path).map_err(|source| Error::Path {
+            path: path.clone(),
+            source,
+        })?;
+        toml::from_str(&text)
+            .map(Some)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e.message())))
+    }
+
+    /// Writes the lock file into `workspace`
+    pub fn write(&self, workspace: &Path) -> Result<(), Error> {
+        let text = toml::to_string_pretty(self).map_err(|e| Error::Config(e.to_string()))?;
+        fs::write(
+            workspace.join(LOCK_FILE_NAME),
+            LOCK_FILE_HEADER.to_string() + &text,
+        )?;
+        Ok(())
+    }
+
+    /// Returns the locked store paths to build in place of `installables`,
+    /// failing if the configured packages no longer match the lock.
+    pub fn frozen_installables(&self, installables: &[String]) -> Result<Vec<String>, Error> {
+        let locked: Vec<&String> = self.packages.iter().map(|p| &p.installable).collect();
+
+        let added: Vec<&str> = installables
+            .iter()
+            .filter(|installable| !locked.contains(installable))
+            .map(String::as_str)
+            .collect();
+        let removed: Vec<&str> = locked
+            .iter()
+            .filter(|installable| !installables.contains(installable))
+            .map(|installable| installable.as_str())
+            .collect();
+
+        if !added.is_empty() || !removed.is_empty() {
+            let mut message = format!("{} is out of date with the config", LOCK_FILE_NAME);
+            if !added.is_empty() {
+                message.push_str(&format!("\n    not locked: {}", added.join(" ")));
+            }
+            if !removed.is_empty() {
+                message.push_str(&format!(
+                    "\n    no longer configured: {}",
+                    removed.join(" ")
+                ));
+            }
+            message.push_str("\nRun without --frozen to update it");
+            return Err(Error::Config(message));
+        }
+
+        let store_paths: Vec<String> = self
+            .packages
+            .iter()
+            .flat_map(|package| package.store_paths.iter().cloned())
+            .collect();
+
+        if let Some(path) = store_paths.iter().find(|path| !is_store_path(path)) {
+            return Err(Error::Config(format!(
+                "{}: invalid store path \"{}\"",
+                LOCK_FILE_NAME, path
+            )));
+        }
+
+        Ok(store_paths)
+    }
+}
+
+/// Returns whether `path` looks like a nix store path, which is also safe to
+/// pass to the build shell
+fn is_store_path(path: &str) -> bool {
+    path.strip_prefix("/nix/store/").is_some_and(|name| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.+".contains(c))
+    })
+}
+
+/// Splits a store path such as "/nix/store/<hash>-git-2.44.0" into the
+/// package name and version. The version starts at the first dash followed
+/// by a digit, as in nix's own `parseDrvName`.
+fn parse_store_path(path: &str) -> (String, Option<String>) {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let drv_name = file_name
+        .split_once('-')
+        .map_or(file_name, |(_hash, name)| name);
+
+    let version_start = drv_name
+        .char_indices()
+        .find(|&(i, c)| {
+            c == '-'
+                && drv_name[i + 1..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_ascii_digit())
+        })
+        .map(|(i, _)| i);
+
+    match version_start {
+        Some(i) => (
+            drv_name[..i].to_string(),
+            Some(drv_name[i + 1..].to_string()),
+        ),
+        None => (drv_name.to_string(), None),
+    }
+}
@@ -0,0 +1,219 @@
+//! Showing what environments running in the background printed.
+//!
+//! `yadt logs` passes the output of a workspace's container through from the
+//! runtime. The output of `on_create` commands is also written to a log file
+//! per run in the data directory, since their containers are removed once
+//! they exit, and only the newest `hook_logs` runs are kept.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{self, Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    build::workspace_hash,
+    config::data_dir,
+    error::Error,
+    events,
+    run::running_workspace_container,
+    runtime::{check_status, render_command, Runtime},
+    state::State,
+};
+
+/// Directory of the data directory the hook logs of each workspace are kept
+/// in, under the workspace's hash
+const HOOK_LOGS_DIR: &str = "logs";
+
+/// Returns the directory the hook logs of `workspace` are kept in
+fn hook_log_dir(workspace: &Path) -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join(HOOK_LOGS_DIR).join(workspace_hash(workspace)))
+}
+
+/// Returns the hook logs in `dir`, oldest first. Their names start with the
+/// time they were created, so they sort by it.
+fn hook_logs(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut logs: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "log"))
+        .collect();
+    logs.sort();
+    Ok(logs)
+}
+
+/// The log file the output of one run's `on_create` commands is written to
+#[derive(Clone)]
+pub struct HookLog {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl HookLog {
+    /// Creates the log file of a run of `workspace`'s hooks, removing all
+    /// but the newest `keep` runs. Returns None if `keep` is 0, and only
+    /// warns if the file can't be created, since the hooks run regardless.
+    pub fn create(workspace: &Path, keep: u32) -> Option<Self> {
+        if keep == 0 {
+            return None;
+        }
+        let dir = hook_log_dir(workspace)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        let path = dir.join(format!("{:012}-{}.log", now, process::id()));
+
+        let file = match fs::create_dir_all(&dir).and_then(|()| File::create(&path)) {
+            Ok(file) => file,
+            Err(error) => {
+                log::warn!("Not logging hooks to {}: {}", path.display(), error);
+                return None;
+            }
+        };
+
+        if let Ok(logs) = hook_logs(&dir) {
+            let stale = logs.len().saturating_sub(keep as usize);
+            for log in &logs[..stale] {
+                let _ = fs::remove_file(log);
+            }
+        }
+
+        Some(Self {
+            path,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Runs `command`, the container running the hook `hook`, passing its
+    /// output through while also writing it to the log
+    pub fn run(
+        &self,
+        runtime: &Runtime,
+        command: &mut Command,
+        hook: &str,
+    ) -> io::Result<ExitStatus> {
+        self.write(format!("$ {}\n", hook).as_bytes());
+
+        let mut child = runtime.spawn(command.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let copies = [
+            // events own stdout, so the hook's output can't go there
+            self.copy(stdout, |bytes| {
+                if events::is_enabled() {
+                    io::stderr().write_all(bytes)
+                } else {
+                    io::stdout().write_all(bytes)
+                }
+            }),
+            self.copy(stderr, |bytes| io::stderr().write_all(bytes)),
+        ];
+        let status = child.wait();
+        for copy in copies {
+            let _ = copy.join();
+        }
+
+        if let Ok(status) = &status {
+            self.write(format!("[{}]\n\n", status).as_bytes());
+        }
+        status
+    }
+
+    /// Appends `bytes` to the log, which only warns if it fails
+    fn write(&self, bytes: &[u8]) {
+        let mut file = self.file.lock().unwrap_or_else(|error| error.into_inner());
+        if let Err(error) = file.write_all(bytes) {
+            log::warn!("Could not write to {}: {}", self.path.display(), error);
+        }
+    }
+
+    /// Copies `output` to the log and to `pass_through` as it arrives
+    fn copy(
+        &self,
+        mut output: impl Read + Send + 'static,
+        pass_through: impl Fn(&[u8]) -> io::Result<()> + Send + 'static,
+    ) -> thread::JoinHandle<()> {
+        let log = self.clone();
+        thread::spawn(move || {
+            let mut buffer = [0; 8192];
+            while let Ok(read @ 1..) = output.read(&mut buffer) {
+                let _ = pass_through(&buffer[..read]);
+                log.write(&buffer[..read]);
+            }
+        })
+    }
+}
+
+/// Prints the output of the container `workspace`'s environment runs in, or
+/// last ran in while it still exists, following it with `follow`. `since`
+/// is passed to the runtime, eg. "10m".
+pub fn show_logs(
+    runtime: &Runtime,
+    workspace: &Path,
+    follow: bool,
+    since: Option<&str>,
+) -> Result<(), Error> {
+    let name = match running_workspace_container(runtime, workspace)? {
+        Some(name) => name,
+        None => State::load(runtime)?
+            .get(workspace)
+            .and_then(|entry| entry.container.clone())
+            .ok_or_else(|| {
+                Error::Conflict(format!(
+                    "No environment ran for {} yet, start one with `yadt image` or `yadt containerfile` first",
+                    workspace.display()
+                ))
+            })?,
+    };
+
+    let mut command = runtime.command();
+    command.arg("logs");
+    if follow {
+        command.arg("--follow");
+    }
+    if let Some(since) = since {
+        command.arg("--since").arg(since);
+    }
+    command.arg(&name);
+
+    if runtime.is_dry_run() {
+        println!("{}", render_command(&command));
+        return Ok(());
+    }
+
+    let status = runtime.status(&mut command)?;
+    check_status(
+        runtime,
+        &format!("Showing the logs of container {}", name),
+        status,
+        &[],
+    )
+}
+
+/// Prints the log of the last run of `workspace`'s `on_create` commands
+pub fn show_hook_log(workspace: &Path) -> Result<(), Error> {
+    let no_logs = || {
+        Error::Conflict(format!(
+            "No on_create commands were logged for {} yet",
+            workspace.display()
+        ))
+    };
+    let dir = hook_log_dir(workspace).ok_or_else(no_logs)?;
+    let logs = match hook_logs(&dir) {
+        Ok(logs) => logs,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(source) => return Err(Error::Path { path: dir, source }),
+    };
+    let path = logs.last().ok_or_else(no_logs)?;
+
+    let text = fs::read(path).map_err(|source| Error::Path {
+        path: path.clone(),
+        source,
+    })?;
+    log::info!("on_create output logged in {}", path.display());
+    io::stdout().write_all(&text)?;
+    Ok(())
+}
@@ -1,151 +1,161 @@
-use core::str;
 use std::{
-    collections::HashSet,
-    ffi::OsString,
-    fs,
-    io::{self, BufRead, BufReader, Write},
-    os::unix::process::CommandExt,
-    path::PathBuf,
-    process::{Command, Stdio},
-    thread,
+    env,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
 };
 
-use clap::{Parser, Subcommand};
-use directories_next::ProjectDirs;
-use serde::Deserialize;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use env_logger::{Target, WriteStyle};
+use log::{Level, LevelFilter};
+use yadt::{
+    archive,
+    build::{environment_containerfile, DevImageSource},
+    check::check_packages,
+    config::{
+        add_packages, init_config, remove_packages, resolve_workspace, show_config, Config, Format,
+    },
+    devcontainer,
+    doctor::{doctor, Outcome},
+    error::Error,
+    events::{self, Event},
+    logs::{show_hook_log, show_logs},
+    manage::{
+        discard_snapshot, list_environments, list_packages, list_recent, parse_duration, prune,
+        prune_caches, remove_environment, show_status, snapshot,
+    },
+    presets::list_presets,
+    progress,
+    run::{
+        copy_files, enter_environment, rebuild_environment, run_detected, run_devcontainer,
+        run_environment, stop_environment, update_environment, EnvironmentArgs, GroupArgs,
+        StopTarget,
+    },
+    runtime::{DryRun, Runtime},
+    state::recall_workspace,
+};
 
-/// Containerfile used to build nix image and copy packages into dev image
-static CONTAINERFILE: &'static [u8] = include_bytes!("Containerfile");
+/// Development environments built from any dev image with nix packages added,
+/// run as containers with the workspace mounted
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// What yadt should do. Without one, the environment of the current
+    /// directory is built from its Containerfile or Dockerfile, or from
+    /// `default_base_image` if it has neither
+    #[command(subcommand)]
+    mode: Option<Mode>,
 
-/// Used by serde to generate a default docker name
-fn default_docker_name() -> String {
-    "podman".to_string()
-}
+    /// Override default config directory
+    #[arg(short, long, value_name = "FILE", global = true)]
+    config: Option<PathBuf>,
 
-/// Used by serde to generate a default nix docker image to pull
-fn default_nix_image() -> String {
-    "docker.io/nixos/nix:latest".to_string()
+    /// Print the container runtime commands that would change anything
+    /// instead of running them
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Also print the embedded containerfile piped to builds
+    #[arg(long, global = true, requires = "dry_run")]
+    show_containerfile: bool,
+
+    /// Print more details, such as image ids, or with -vv every container
+    /// runtime command that is run. Must come before the subcommand, since
+    /// -v of `yadt image` is --volume
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only print errors
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Pass the output of builds through instead of showing their progress,
+    /// as is done when stdout or stderr isn't a terminal
+    #[arg(long, global = true)]
+    plain: bool,
+
+    /// Package groups to toggle when no subcommand is given. Subcommands
+    /// building environments take --with and --without themselves
+    #[command(flatten)]
+    groups: GroupArgs,
+
+    /// How to report what yadt does. With json, yadt writes one event per
+    /// line to stdout instead of its messages, the last being "exec" once
+    /// the environment takes over the terminal
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputMode::Human,
+        conflicts_with = "dry_run"
+    )]
+    output: OutputMode,
 }
 
-/// Used by serde to generate default base packages to install
-fn default_base_packages() -> HashSet<String> {
-    // list adapted from
-    // https://github.com/89luca89/distrobox/blob/main/distrobox-init
-    // and
-    // https://github.com/devcontainers/features/blob/main/src/common-utils/main.sh
-    HashSet::from_iter(
-        vec![
-            "bash",
-            "bash-completion",
-            "bc",
-            "curl",
-            "diffutils",
-            "findutils",
-            "glibc",
-            "gnupg",
-            "iputils",
-            "inetutils",
-            "keyutils",
-            "less",
-            "lsof",
-            "man",
-            "mlocate",
-            "mtr",
-            "ncurses",
-            "nssmdns",
-            "openssh",
-            "pigz",
-            "pinentry-tty",
-            "procps",
-            "rsync",
-            "shadow",
-            "sudo",
-            "tcpdump",
-            "time",
-            "traceroute",
-            "tree",
-            "tzdata",
-            "unzip",
-            "util-linux",
-            "wget",
-            "zip",
-        ]
-        .iter()
-        .map(|s| s.to_string()),
-    )
-}
+/// How yadt reports what it does
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputMode {
+    /// Messages and progress for people, on stderr
+    Human,
 
-/// Stores the values used to configure this application.
-#[derive(Debug, Deserialize)]
-struct Config {
-    /// The name of the docker-compatible cli to use. This should be "podman"
-    /// if podman is installed.
-    #[serde(default = "default_docker_name")]
-    docker_name: String,
-
-    /// Base image to build all the nix packages from. This must have nix
-    /// cli installed. If not specified, the value defaults to
-    /// docker.io/nixos/nix:latest.
-    #[serde(default = "default_nix_image")]
-    nix_image: String,
-
-    /// The base packages to install into the environment. This defaults to a
-    /// vector of various nixpkgs that tend to be useful, such as git.
-    #[serde(default = "default_base_packages")]
-    base_packages: HashSet<String>,
-
-    /// Additional packages to install into the environment. This defaults to
-    //// an empty vector and is always user specified.
-    additional_packages: HashSet<String>,
+    /// Newline-delimited json events for other programs, on stdout
+    Json,
 }
 
-impl Config {
-    fn all_packages(&self) -> String {
-        let all_packages = self.base_packages.union(&self.additional_packages);
+impl Cli {
+    /// Returns the most verbose level of messages to print
+    fn log_level(&self) -> LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => LevelFilter::Error,
+            (false, 0) => LevelFilter::Info,
+            (false, 1) => LevelFilter::Debug,
+            (false, _) => LevelFilter::Trace,
+        }
+    }
 
-        all_packages
-            .into_iter()
-            .map(|s| format!("nixpkgs#{}", s))
-            .reduce(|a, b| format!("{} {}", a, b))
-            .unwrap_or_default()
+    /// Returns whether the progress of builds is shown by a spinner, which
+    /// only replaces their output when nothing else should be printed
+    fn show_progress(&self) -> bool {
+        !self.plain
+            && !self.dry_run
+            && self.output == OutputMode::Human
+            && self.log_level() == LevelFilter::Info
+            && io::stdout().is_terminal()
+            && io::stderr().is_terminal()
     }
-}
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            docker_name: default_docker_name(),
-            nix_image: default_nix_image(),
-            base_packages: default_base_packages(),
-            additional_packages: Default::default(),
+    /// Returns the dry run mode selected by the flags
+    fn dry_run(&self) -> DryRun {
+        match (self.dry_run, self.show_containerfile) {
+            (false, _) => DryRun::Off,
+            (true, false) => DryRun::Print,
+            (true, true) => DryRun::PrintContainerfile,
         }
     }
 }
 
-/// TODO
-#[derive(Parser)]
-#[command(version, about)]
-struct Cli {
-    /// Whether to build a containerfile or use an image
-    #[command(subcommand)]
-    mode: Mode,
-
-    /// Directory to map into the container
-    workspace: PathBuf,
-
-    /// Override default config directory
-    #[arg(short, long, value_name = "FILE")]
-    config: Option<PathBuf>,
-}
-
-/// Where to obtain the dev image from
+/// Top level commands, most of which obtain a dev image and enter it
 #[derive(Subcommand)]
 enum Mode {
     /// Build and set up a containerfile
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
     Containerfile {
-        /// Specify the containerfile to build from
-        #[arg(value_name = "CONTAINERFILE")]
-        containerfile: PathBuf,
+        /// Specify the containerfile to build from. One named like a
+        /// subcommand, such as "show", must be given as eg. "./show"
+        #[arg(value_name = "CONTAINERFILE", required = true)]
+        containerfile: Option<PathBuf>,
+
+        /// Build context directory, defaults to the directory containing the
+        /// containerfile
+        #[arg(long, value_name = "DIR")]
+        context: Option<PathBuf>,
+
+        #[command(flatten)]
+        environment: EnvironmentArgs,
+
+        #[command(subcommand)]
+        action: Option<ContainerfileAction>,
     },
 
     /// Pull or use an existing image
@@ -153,168 +163,759 @@ enum Mode {
         /// Specify the image to use or pull
         #[arg(value_name = "IMAGE NAME")]
         image: String,
+
+        #[command(flatten)]
+        environment: EnvironmentArgs,
+    },
+
+    /// Build and set up the environment described by a devcontainer.json
+    Devcontainer {
+        /// The devcontainer.json to read, defaults to the workspace's
+        /// .devcontainer/devcontainer.json or .devcontainer.json
+        #[arg(long, value_name = "PATH")]
+        file: Option<PathBuf>,
+
+        #[command(flatten)]
+        environment: EnvironmentArgs,
+    },
+
+    /// Render the environment of a workspace for other tools
+    Export {
+        #[command(subcommand)]
+        format: ExportFormat,
+    },
+
+    /// Load an environment image saved by `yadt export image`, so that the
+    /// environment of a workspace starts without building it
+    Import {
+        /// The archive to load, next to its manifest
+        archive: PathBuf,
+
+        /// Workspace to import the environment for, defaults to the current
+        /// directory
+        #[arg(long)]
+        workspace: Option<PathBuf>,
+    },
+
+    /// Manage the yadt config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    /// Open another shell in the environment already running for a workspace
+    Enter {
+        /// Workspace whose environment to enter, defaults to the current
+        /// directory. Anything but a directory picks out a workspace that
+        /// ran before by part of its name, eg. "api" for ~/src/api-server
+        workspace: Option<String>,
+
+        /// Enter the environment that ran most recently
+        #[arg(long, conflicts_with = "workspace")]
+        last: bool,
+
+        /// Wait for the shell to exit instead of replacing yadt with it
+        #[arg(long)]
+        no_exec: bool,
+    },
+
+    /// Copy files between the host and the environment of a workspace
+    Cp {
+        /// What to copy, prefixed with env: if it is in the environment, eg.
+        /// env:target/release/app for a path in the workspace mount
+        source: String,
+
+        /// Where to copy it, prefixed with env: if it is in the environment
+        destination: String,
+
+        /// Workspace whose environment to copy from or to, defaults to the
+        /// current directory
+        #[arg(long)]
+        workspace: Option<PathBuf>,
+    },
+
+    /// Pull the dev image and nix image of a workspace again, then rebuild
+    /// and enter its environment
+    Update {
+        /// Workspace whose environment to update, defaults to the current
+        /// directory
+        workspace: Option<PathBuf>,
+    },
+
+    /// Commit the environment running for a workspace to a snapshot, keeping
+    /// what was installed into it by hand
+    Snapshot {
+        /// Workspace whose environment to snapshot, defaults to the current
+        /// directory
+        workspace: Option<PathBuf>,
+
+        /// Name of the snapshot, which is tagged as snapshot-NAME
+        #[arg(long, value_name = "NAME")]
+        tag: Option<String>,
+
+        /// Start the environment from the snapshot from now on instead of
+        /// building it from the config
+        #[arg(long = "use")]
+        use_snapshot: bool,
+
+        /// Start the environment from its config again instead of a snapshot
+        #[arg(long, conflicts_with_all = ["tag", "use_snapshot"])]
+        discard: bool,
+    },
+
+    /// Show the output of the environment running for a workspace in the
+    /// background, or of its on_create commands
+    Logs {
+        /// Workspace whose logs to show, defaults to the current directory
+        workspace: Option<PathBuf>,
+
+        /// Keep printing output as it arrives
+        #[arg(short, long, conflicts_with = "hooks")]
+        follow: bool,
+
+        /// Only show output since then, eg. "10m" or a timestamp
+        #[arg(long, conflicts_with = "hooks")]
+        since: Option<String>,
+
+        /// Show the output of the last run of the on_create commands instead,
+        /// even once their container is gone
+        #[arg(long)]
+        hooks: bool,
+    },
+
+    /// Stop the environment running for a workspace, such as one kept alive
+    Stop {
+        /// Workspace whose environment to stop, defaults to the current
+        /// directory
+        workspace: Option<PathBuf>,
+
+        /// Stop every environment yadt started instead
+        #[arg(long, conflicts_with = "workspace")]
+        all: bool,
+
+        /// Seconds to wait for the container to exit before killing it
+        #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+        time: u32,
+
+        /// Kill the container if stopping it fails
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Look up the configured packages in nixpkgs, suggesting the closest
+    /// names for those it lacks
+    Check {
+        /// Workspace whose config to check, defaults to the current directory
+        workspace: Option<PathBuf>,
     },
+
+    /// Check that this host can build and run environments, exiting with 1
+    /// if any check warns and with 2 if any fails
+    Doctor {
+        /// Workspace whose config to check, defaults to the current directory
+        workspace: Option<PathBuf>,
+    },
+
+    /// Show whether the environment of a workspace is built, up to date, and
+    /// running
+    Status {
+        /// Workspace whose environment to show, defaults to the current
+        /// directory
+        workspace: Option<PathBuf>,
+
+        /// Print the status as json instead of a summary
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List the images and containers created by yadt
+    List {
+        /// Print the images and containers as json instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List the environments that ran most recently
+    Recent {
+        /// How many environments to list
+        #[arg(short = 'n', long, default_value_t = 10)]
+        limit: usize,
+
+        /// Print the environments as json instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Remove the container, images, cache volumes, and persistent home of a
+    /// workspace
+    Rm {
+        /// Workspace whose environment to remove, defaults to the current
+        /// directory
+        workspace: Option<PathBuf>,
+
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Keep the workspace's persistent home volume
+        #[arg(long)]
+        keep_home: bool,
+    },
+
+    /// List the packages installed in the environment built for a workspace
+    Packages {
+        /// Workspace whose packages to list, defaults to the current directory
+        workspace: Option<PathBuf>,
+
+        /// Print the packages as json instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List the presets available to `presets` and --preset, marking the
+    /// ones the config selects with *
+    Presets {
+        /// Workspace whose config may define presets, defaults to the current
+        /// directory
+        workspace: Option<PathBuf>,
+
+        /// Print the presets as json instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Add packages to additional_packages in the workspace's config file, or
+    /// the global one if the workspace has none
+    Add {
+        /// Packages to add, either nixpkgs names or flake refs
+        #[arg(required = true, value_name = "PACKAGE")]
+        packages: Vec<String>,
+
+        #[command(flatten)]
+        edit: PackageEditArgs,
+    },
+
+    /// Remove packages from additional_packages in the workspace's config
+    /// file, or the global one if the workspace has none
+    Remove {
+        /// Packages to remove
+        #[arg(required = true, value_name = "PACKAGE")]
+        packages: Vec<String>,
+
+        /// Allow removing base packages, which adds them to exclude_packages
+        #[arg(long)]
+        base: bool,
+
+        #[command(flatten)]
+        edit: PackageEditArgs,
+    },
+
+    /// Remove resources created by yadt
+    Prune {
+        /// Also remove the image most recently built for each workspace
+        #[arg(long)]
+        all: bool,
+
+        /// Only remove images and containers created longer ago than this,
+        /// eg. "7d" or "12h"
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        older_than: Option<Duration>,
+
+        /// Also remove the named cache volumes and installed dotfiles
+        #[arg(long)]
+        caches: bool,
+    },
+
+    /// Print a completion script for a shell, eg. for
+    /// `yadt completions fish | source`
+    Completions { shell: Shell },
+
+    /// Print the packages in the config, one per line, for completion
+    #[command(name = "__complete-packages", hide = true)]
+    CompletePackages,
 }
 
-/// Returns proper config for the application based on the following logic:
-/// If the override file exists, parse it. Otherwise, if a config in the
-/// default location exists, parse it. If neither exist, use the default
-/// config values. Any failed parse returns an `std::io::Error`.
-///
-/// The `directories_next` crate is used to ensure cross platform
-/// compatibility, although the chances this app works on windows are rather
-/// low.
-fn parse_config(config_override: Option<PathBuf>) -> Result<Config, io::Error> {
-    // Returns the default config text if it exists or can be read,
-    // otherwise returns None
-    fn default_config_text() -> Option<String> {
-        let project_dirs = ProjectDirs::from("io.github", "anglesideangle", "yadt");
-        let path = project_dirs.map(|dirs| dirs.config_dir().to_path_buf());
-        path.map(|p| fs::read_to_string(p).ok()).flatten()
-    }
+/// Arguments shared by the modes that edit the package set
+#[derive(Args)]
+struct PackageEditArgs {
+    /// Workspace whose config file to edit, defaults to the current directory
+    #[arg(short, long)]
+    workspace: Option<PathBuf>,
+
+    /// Afterwards, rebuild the workspace's environment on top of the dev image
+    /// it was last built on and enter it
+    #[arg(long)]
+    rebuild: bool,
+}
+
+/// Subcommands of `yadt export`
+#[derive(Subcommand)]
+enum ExportFormat {
+    /// Write a .devcontainer/devcontainer.json using the environment image
+    /// yadt built
+    Devcontainer {
+        /// Workspace whose environment to export, defaults to the current
+        /// directory
+        workspace: Option<PathBuf>,
+
+        /// Overwrite the devcontainer.json if it already exists
+        #[arg(long)]
+        force: bool,
+
+        /// Print the devcontainer.json to stdout instead of writing a file
+        #[arg(long, conflicts_with = "force")]
+        stdout: bool,
+    },
 
-    // bad override config should fail
-    let override_text = config_override.map(fs::read_to_string).transpose()?;
+    /// Save the environment image built for the current config to an
+    /// archive, which `yadt import` loads on another machine. A manifest of
+    /// what it was built from is written next to it, eg. env.tar.json.
+    Image {
+        /// Workspace whose environment to export, defaults to the current
+        /// directory
+        workspace: Option<PathBuf>,
 
-    if let Some(config_text) = override_text.or_else(default_config_text) {
-        toml::from_str(&config_text)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.message()))
-    } else {
-        Ok(Default::default())
-    }
+        /// Where to write the archive
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Overwrite the archive and its manifest if they already exist
+        #[arg(long)]
+        force: bool,
+    },
 }
 
-fn main() -> Result<(), io::Error> {
+/// Subcommands of `yadt containerfile`
+#[derive(Subcommand)]
+enum ContainerfileAction {
+    /// Print the containerfile environment images are built from, which is
+    /// containerfile_template if one is configured or else the embedded one,
+    /// eg. as a starting point for a template
+    Show {
+        /// Workspace whose config to use, defaults to the current directory
+        workspace: Option<PathBuf>,
+    },
+}
+
+/// Subcommands of `yadt config`
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Write a commented starter config populated with the default values
+    Init {
+        /// Overwrite the config file if it already exists
+        #[arg(long)]
+        force: bool,
+
+        /// Print the starter config to stdout instead of writing a file
+        #[arg(long, conflicts_with = "force")]
+        stdout: bool,
+    },
+
+    /// Print the fully resolved config and where it was loaded from
+    Show {
+        /// Workspace whose config file should be included, defaults to the
+        /// current directory
+        workspace: Option<PathBuf>,
+
+        /// Format to print the resolved config in
+        #[arg(long, value_enum, default_value_t = Format::Toml)]
+        format: Format,
+
+        /// Additional package to include, as passed to `yadt image`
+        #[arg(short, long = "package", value_name = "PACKAGE")]
+        packages: Vec<String>,
+    },
+}
+
+fn main() -> ExitCode {
     // clap is actually magic
     let cli = Cli::parse();
-
-    let config = parse_config(cli.config)?;
-
-    let dev_image = match cli.mode {
-        Mode::Containerfile { containerfile } => {
-            let mut cmd = Command::new(&config.docker_name)
-                .arg("build")
-                .arg("-f")
-                .arg(
-                    fs::canonicalize(containerfile)
-                        .expect("Could not canonicalize containerfile path"),
-                )
-                .stdout(Stdio::piped())
-                .spawn()?;
-
-            BufReader::new(
-                cmd.stdout
-                    .take()
-                    .expect("Could not capture build process stdout."),
-            )
-            .lines()
-            .last()
-            .expect("Build command did not write to stdout.")
-            .expect("Could not read last line of stdout")
+    if cli.output == OutputMode::Json {
+        events::enable();
+    } else if cli.show_progress() {
+        progress::enable();
+    }
+    init_logger(cli.log_level());
+
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        // the shell reported its own failure already
+        Err(error @ Error::Exited(_)) => error.exit_code(),
+        Err(error) => {
+            progress::clear();
+            if events::is_enabled() {
+                events::emit(Event::error(&error));
+            } else {
+                eprintln!("yadt: {}", error);
+            }
+            error.exit_code()
         }
-        Mode::Image { image } => image,
-    };
+    }
+}
 
-    // TODO
-    // if desired nix packages exist on the home system, copy them in to save
-    // bandwidth
-    // or just mount the entire host /nix into the nix image
-    // or get rid of the base image and just copy stuff from the host system
-
-    let mut workspace_arg = OsString::from("WORKSPACE=");
-    workspace_arg.push(fs::canonicalize(&cli.workspace)?);
-
-    // TODO automatic caching is completely broken, needs manual impl
-    let mut build_process = Command::new(&config.docker_name)
-        .arg("build")
-        .arg("-f")
-        .arg("-")
-        .arg("--build-arg")
-        .arg(format!("NIX_IMAGE={}", config.nix_image))
-        .arg("--build-arg")
-        .arg(format!("DEV_IMAGE={}", dev_image))
-        .arg("--build-arg")
-        .arg(format!("PACKAGES_STRING={}", config.all_packages()))
-        // .arg("--build-arg")
-        // .arg(format!("USERNAME={}", username))
-        // .arg("--build-arg")
-        // .arg(format!("UID={}", uid))
-        // .arg("--build-arg")
-        // .arg(format!("GID={}", gid))
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
-
-    let mut stdin = build_process
-        .stdin
-        .take()
-        .expect("Could not capture build process stdin.");
-
-    let stdout = build_process
-        .stdout
-        .take()
-        .expect("Could not capture build process stdout.");
-
-    // write containerfile from this app's binary to the build process stdin
-    // this seems to need to be in a separate thread because
-    thread::spawn(move || stdin.write_all(CONTAINERFILE));
-
-    // build_process.wait_with_output()?;
-    // build_process.wait()?;
-
-    let reader = BufReader::new(stdout);
-
-    // this is a hack to forward command's progress to stdout while keeping it
-    // an iterator. ideally the side effects from this shouldn't matter
-    let container_id = reader
-        .lines()
-        .map(|line| {
-            if let Ok(l) = &line {
-                println!(">>> {}", l);
+/// Prints yadt's messages to stderr up to `level`. Progress is printed as is,
+/// while other levels are prefixed with their name, colored only if stderr
+/// is a terminal and `NO_COLOR` is unset. With `--output json`, messages
+/// are written to stdout as `log` events instead.
+fn init_logger(level: LevelFilter) {
+    let mut builder = env_logger::Builder::new();
+    if events::is_enabled() {
+        builder
+            .target(Target::Stdout)
+            .filter_module("yadt", level)
+            .format(|buf, record| {
+                let event = Event::Log {
+                    level: record.level().as_str().to_lowercase(),
+                    message: record.args().to_string(),
+                };
+                writeln!(buf, "{}", events::to_line(&event))
+            })
+            .init();
+        return;
+    }
+    if progress::is_active() {
+        // the spinner is hidden while messages are written around it
+        builder
+            .target(Target::Pipe(Box::new(progress::Writer)))
+            .write_style(if env::var_os("NO_COLOR").is_some() {
+                WriteStyle::Never
+            } else {
+                WriteStyle::Always
+            });
+    }
+    builder
+        .filter_module("yadt", level)
+        .format(|buf, record| {
+            if record.level() == Level::Info {
+                return writeln!(buf, "{}", record.args());
             }
-            line
+            let style = buf.default_level_style(record.level());
+            let name = match record.level() {
+                Level::Warn => "warning".to_string(),
+                level => level.as_str().to_lowercase(),
+            };
+            writeln!(buf, "yadt: {style}{}{style:#}: {}", name, record.args())
         })
-        .last()
-        .expect("Build command did not write to stdout.")
-        .expect("Could not read last line of stdout");
-
-    println!("hash: {:?}", container_id);
-
-    // build_process.wait()?;
-
-    // let mut workspace_volume_arg = OsString::from("WORKSPACE=");
-    let mut workspace_vol_arg = fs::canonicalize(&cli.workspace)?;
-    workspace_vol_arg.push(":/workspace:rw");
-
-    // TODO go back to old $HOME impl bc .bash_history gets added right now
-    Command::new(&config.docker_name)
-        .arg("run")
-        .arg("--rm")
-        .arg("--tty")
-        .arg("--interactive")
-        .arg("--volume")
-        .arg(workspace_vol_arg)
-        .arg("--workdir")
-        .arg("/workspace")
-        .arg("--userns")
-        .arg("keep-id") // TODO this creates a hard dependency on podman
-        .arg("--name")
-        .arg("yadt-test-run")
-        .arg("--http-proxy") // making the most of the podman dep
-        .arg("--network")
-        .arg("host")
-        .arg("--env-merge")
-        .arg("PATH=${PATH}:/yadt-bin")
-        .arg(container_id)
-        .arg("/bin/bash")
-        .exec();
-    // .output()?;
-
-    // println!("hmm");
-
-    // .arg("--user")
-    // .arg(format!("{}:{}", uid))
-    // .arg("userns")
-
-    Ok(())
+        .init();
+}
+
+/// Runs the subcommand selected by `cli`
+fn run(cli: Cli) -> Result<(), Error> {
+    let dry_run = cli.dry_run();
+
+    let Some(mode) = cli.mode else {
+        return run_detected(cli.config, cli.groups, dry_run);
+    };
+    if !cli.groups.with.is_empty() || !cli.groups.without.is_empty() {
+        return Err(Error::Config(
+            "--with and --without go after the subcommand".to_string(),
+        ));
+    }
+
+    match mode {
+        Mode::Containerfile {
+            action: Some(ContainerfileAction::Show { workspace }),
+            ..
+        } => {
+            let workspace = resolve_workspace(workspace)?;
+            let (config, _) = Config::load(cli.config, &workspace)?;
+            let containerfile = environment_containerfile(&config, &workspace)?;
+            Ok(io::stdout().write_all(&containerfile)?)
+        }
+        Mode::Containerfile {
+            containerfile,
+            context,
+            environment,
+            action: None,
+        } => run_environment(
+            DevImageSource::Containerfile {
+                containerfile: containerfile.expect("The containerfile is required"),
+                context,
+            },
+            environment,
+            cli.config,
+            dry_run,
+        ),
+        Mode::Image { image, environment } => run_environment(
+            DevImageSource::Image(image),
+            environment,
+            cli.config,
+            dry_run,
+        ),
+        Mode::Devcontainer { file, environment } => {
+            run_devcontainer(file, environment, cli.config, dry_run)
+        }
+        Mode::Export {
+            format:
+                ExportFormat::Devcontainer {
+                    workspace,
+                    force,
+                    stdout,
+                },
+        } => {
+            let workspace = resolve_workspace(workspace)?;
+            let (config, sources) = Config::load(cli.config, &workspace)?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            devcontainer::export(&runtime, &config, &workspace, force, stdout)
+        }
+        Mode::Export {
+            format:
+                ExportFormat::Image {
+                    workspace,
+                    output,
+                    force,
+                },
+        } => {
+            let workspace = resolve_workspace(workspace)?;
+            let (config, sources) = Config::load(cli.config, &workspace)?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            archive::export(&runtime, &config, &workspace, &output, force)
+        }
+        Mode::Import { archive, workspace } => {
+            let workspace = resolve_workspace(workspace)?;
+            let (config, sources) = Config::load(cli.config, &workspace)?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            archive::import(&runtime, &config, &workspace, &archive)
+        }
+        Mode::Config {
+            action: ConfigAction::Init { force, stdout },
+        } => init_config(cli.config, force, stdout),
+        Mode::Config {
+            action:
+                ConfigAction::Show {
+                    workspace,
+                    format,
+                    packages,
+                },
+        } => show_config(cli.config, &resolve_workspace(workspace)?, format, packages),
+        Mode::Enter {
+            workspace,
+            last,
+            no_exec,
+        } => {
+            let workspace = match workspace {
+                Some(workspace) if Path::new(&workspace).is_dir() => {
+                    resolve_workspace(Some(workspace.into()))?
+                }
+                None if !last => resolve_workspace(None)?,
+                query => {
+                    // the runtime is needed in case the state is rebuilt
+                    let (config, sources) = Config::load(cli.config.clone(), Path::new("."))?;
+                    let runtime = Runtime::detect(&config, &sources, dry_run)?;
+                    recall_workspace(&runtime, query.as_deref())?
+                }
+            };
+            let (config, sources) = Config::load(cli.config, &workspace)?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            enter_environment(&runtime, &config, &workspace, no_exec)
+        }
+        Mode::Update { workspace } => {
+            update_environment(cli.config, resolve_workspace(workspace)?, dry_run)
+        }
+        Mode::Stop {
+            workspace,
+            all,
+            time,
+            force,
+        } => {
+            let target = if all {
+                StopTarget::All
+            } else {
+                StopTarget::Workspace(resolve_workspace(workspace)?)
+            };
+            let config_dir = match &target {
+                StopTarget::Workspace(workspace) => workspace.as_path(),
+                StopTarget::All => Path::new("."),
+            };
+            let (config, sources) = Config::load(cli.config, config_dir)?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            stop_environment(&runtime, &target, time, force)
+        }
+        Mode::Cp {
+            source,
+            destination,
+            workspace,
+        } => {
+            let workspace = resolve_workspace(workspace)?;
+            let (config, sources) = Config::load(cli.config, &workspace)?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            copy_files(&runtime, &config, &workspace, &source, &destination)
+        }
+        Mode::Logs {
+            workspace,
+            follow,
+            since,
+            hooks,
+        } => {
+            let workspace = resolve_workspace(workspace)?;
+            if hooks {
+                return show_hook_log(&workspace);
+            }
+            let (config, sources) = Config::load(cli.config, &workspace)?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            show_logs(&runtime, &workspace, follow, since.as_deref())
+        }
+        Mode::Snapshot {
+            workspace,
+            tag,
+            use_snapshot,
+            discard,
+        } => {
+            let workspace = resolve_workspace(workspace)?;
+            let (config, sources) = Config::load(cli.config, &workspace)?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            if discard {
+                discard_snapshot(&runtime, &workspace)
+            } else {
+                snapshot(&runtime, &workspace, tag.as_deref(), use_snapshot)
+            }
+        }
+        Mode::Check { workspace } => {
+            let workspace = resolve_workspace(workspace)?;
+            let (config, sources) = Config::load(cli.config, &workspace)?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            let found = check_packages(&runtime, &config)?;
+            log::info!("Found all {} packages in {}", found, config.nixpkgs());
+            Ok(())
+        }
+        Mode::Doctor { workspace } => {
+            let workspace = resolve_workspace(workspace)?;
+            match doctor(cli.config, &workspace) {
+                Outcome::Pass => Ok(()),
+                // the checks printed what went wrong already
+                outcome => Err(Error::Exited(outcome.exit_code())),
+            }
+        }
+        Mode::Status { workspace, json } => {
+            let workspace = resolve_workspace(workspace)?;
+            let (config, sources) = Config::load(cli.config, &workspace)?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            show_status(&runtime, &config, &sources, &workspace, json)
+        }
+        Mode::List { json } => {
+            let (config, sources) = Config::load(cli.config, Path::new("."))?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            list_environments(&runtime, json)
+        }
+        Mode::Recent { limit, json } => {
+            let (config, sources) = Config::load(cli.config, Path::new("."))?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            list_recent(&runtime, limit, json)
+        }
+        Mode::Rm {
+            workspace,
+            yes,
+            keep_home,
+        } => {
+            let workspace = resolve_workspace(workspace)?;
+            let (config, sources) = Config::load(cli.config, &workspace)?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            remove_environment(&runtime, &config, &workspace, yes, keep_home)
+        }
+        Mode::Packages { workspace, json } => {
+            let workspace = resolve_workspace(workspace)?;
+            let (config, sources) = Config::load(cli.config, &workspace)?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+            list_packages(&runtime, &config, &workspace, json)
+        }
+        Mode::Presets { workspace, json } => {
+            let workspace = resolve_workspace(workspace)?;
+            let (config, _) = Config::load(cli.config, &workspace)?;
+            list_presets(&config, json)
+        }
+        Mode::Add { packages, edit } => {
+            let workspace = resolve_workspace(edit.workspace)?;
+            add_packages(cli.config.clone(), &workspace, packages)?;
+            if edit.rebuild {
+                rebuild_environment(cli.config, workspace, dry_run)?;
+            }
+            Ok(())
+        }
+        Mode::Remove {
+            packages,
+            base,
+            edit,
+        } => {
+            let workspace = resolve_workspace(edit.workspace)?;
+            remove_packages(cli.config.clone(), &workspace, packages, base)?;
+            if edit.rebuild {
+                rebuild_environment(cli.config, workspace, dry_run)?;
+            }
+            Ok(())
+        }
+        Mode::Prune {
+            all,
+            older_than,
+            caches,
+        } => {
+            let (config, sources) = Config::load(cli.config, Path::new("."))?;
+            let runtime = Runtime::detect(&config, &sources, dry_run)?;
+
+            prune(&runtime, all, older_than)?;
+            if caches {
+                prune_caches(&runtime)?;
+            }
+            Ok(())
+        }
+        Mode::Completions { shell } => print_completions(shell),
+        Mode::CompletePackages => {
+            let workspace = resolve_workspace(None)?;
+            let (config, _) = Config::load(cli.config, &workspace)?;
+            for package in config.base_packages.union(&config.additional_packages) {
+                println!("{}", package);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Completes `--package` and the packages of `yadt add` and `yadt remove`
+/// with the packages in the config, wrapping the generated `_yadt`
+static BASH_PACKAGE_COMPLETION: &str = r#"
+_yadt_packages() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}"
+    if [[ "$prev" == "-p" || "$prev" == "--package" ]] \
+        || [[ "${COMP_WORDS[1]}" =~ ^(add|remove)$ && COMP_CWORD -gt 1 && "$cur" != -* ]]; then
+        COMPREPLY=($(compgen -W "$(yadt __complete-packages 2>/dev/null)" -- "$cur"))
+        return 0
+    fi
+    _yadt "$@"
+}
+complete -F _yadt_packages -o bashdefault -o default yadt
+"#;
+
+/// Like `BASH_PACKAGE_COMPLETION`, for zsh
+static ZSH_PACKAGE_COMPLETION: &str = r#"
+_yadt_packages() {
+    if [[ "${words[CURRENT-1]}" == (-p|--package) ]] \
+        || [[ "${words[2]}" == (add|remove) && CURRENT -gt 2 && "${words[CURRENT]}" != -* ]]; then
+        local -a packages
+        packages=(${(f)"$(yadt __complete-packages 2>/dev/null)"})
+        compadd -a packages
+        return
+    fi
+    _yadt "$@"
+}
+compdef _yadt_packages yadt
+"#;
+
+/// Like `BASH_PACKAGE_COMPLETION`, for fish, whose completions add up
+static FISH_PACKAGE_COMPLETION: &str = r#"
+complete -c yadt -s p -l package -x -a '(yadt __complete-packages 2>/dev/null)'
+complete -c yadt -n '__fish_seen_subcommand_from add remove' -x -a '(yadt __complete-packages 2>/dev/null)'
+"#;
+
+/// Writes the completion script for `shell` to stdout, completing packages
+/// from the config where the shell allows it
+fn print_completions(shell: Shell) -> Result<(), Error> {
+    let mut stdout = io::stdout();
+    clap_complete::generate(shell, &mut Cli::command(), "yadt", &mut stdout);
+
+    let package_completion = match shell {
+        Shell::Bash => BASH_PACKAGE_COMPLETION,
+        Shell::Zsh => ZSH_PACKAGE_COMPLETION,
+        Shell::Fish => FISH_PACKAGE_COMPLETION,
+        _ => "",
+    };
+    Ok(stdout.write_all(package_completion.as_bytes())?)
 }
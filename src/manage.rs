@@ -0,0 +1,944 @@
+//! Listing and removing the images, containers, and volumes yadt created.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{
+    build::{
+        current_config_hash, environment_tag, image_exists, is_current_tag, snapshot_tag,
+        BuildOptions, HostUser,
+    },
+    config::{Config, ConfigSources},
+    error::Error,
+    lock::{LockedPackage, Lockfile, BUILD_INSTALLABLES_PATH, BUILD_MANIFEST_PATH},
+    query,
+    run::{
+        cache_volume_specs, container_config_hash, home_volume, running_workspace_container,
+        volume_exists, CACHE_VOLUME_PREFIX, DOTFILES_VOLUME_PREFIX,
+    },
+    runtime::{check_status, stderr_lines, Runtime},
+    state::{self, State},
+};
+
+/// Removes every cache volume created by yadt, including the volumes dotfiles
+/// were installed into, printing each that was removed, or only printing them
+/// in dry runs.
+pub fn prune_caches(runtime: &Runtime) -> Result<(), Error> {
+    let dry_run = runtime.is_dry_run();
+
+    let mut command = runtime.command();
+
+    command
+        .arg("volume")
+        .arg("ls")
+        .arg("--format")
+        .arg("{{.Name}}");
+
+    let output = runtime.output(&mut command)?;
+    check_status(
+        runtime,
+        "Listing volumes",
+        output.status,
+        &stderr_lines(&output.stderr),
+    )?;
+
+    let names: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|name| {
+            name.starts_with(CACHE_VOLUME_PREFIX) || name.starts_with(DOTFILES_VOLUME_PREFIX)
+        })
+        .map(str::to_string)
+        .collect();
+
+    for name in names {
+        if dry_run {
+            log::info!("Would remove volume {}", name);
+            continue;
+        }
+
+        query::query(
+            runtime,
+            &format!("Removing volume {}", name),
+            &["volume", "rm", &name],
+        )?;
+        log::info!("Removed volume {}", name);
+    }
+
+    Ok(())
+}
+
+/// Removes stopped containers and stale images created by yadt. The newest
+/// image of each workspace is kept unless `all` is set, as are images used by
+/// a remaining container. With `older_than`, only things created longer ago
+/// are removed. In dry runs, candidates are only printed.
+pub fn prune(runtime: &Runtime, all: bool, older_than: Option<Duration>) -> Result<(), Error> {
+    let dry_run = runtime.is_dry_run();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64);
+    // things with an unknown creation time are only old enough if there is
+    // no cutoff at all
+    let old_enough = |created: Option<i64>| match (older_than, created) {
+        (None, _) => true,
+        (Some(older_than), Some(created)) => now - created >= older_than.as_secs() as i64,
+        (Some(_), None) => false,
+    };
+
+    let containers = query::list_containers(runtime)?;
+    let images = query::list_images(runtime)?;
+
+    let (removed_containers, kept_containers): (Vec<_>, Vec<_>) = containers
+        .into_iter()
+        .partition(|container| container.state != "running" && old_enough(container.created));
+
+    // the images of each workspace tagged latest and dev are the ones it
+    // last used, which for images tagged before those tags is the newest
+    let mut newest: HashMap<&str, &query::ImageInfo> = HashMap::new();
+    for image in &images {
+        if let Some(workspace) = &image.workspace {
+            let entry = newest.entry(workspace).or_insert(image);
+            if image.created > entry.created {
+                *entry = image;
+            }
+        }
+    }
+
+    let removed_images: Vec<&query::ImageInfo> = images
+        .iter()
+        .filter(|image| {
+            all || !(newest.values().any(|newest| newest.id == image.id)
+                || image.tags.iter().any(|tag| is_current_tag(tag)))
+        })
+        .filter(|image| {
+            !kept_containers
+                .iter()
+                .any(|container| container.image == image.id)
+        })
+        .filter(|image| old_enough(image.created))
+        .collect();
+
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+
+    for container in &removed_containers {
+        if !dry_run {
+            query::query(
+                runtime,
+                &format!("Removing container {}", container.name),
+                &["rm", &container.id],
+            )?;
+        }
+        log::info!("{} container {}", verb, container.name);
+    }
+
+    let mut reclaimed = 0;
+    for image in &removed_images {
+        if !dry_run {
+            // removing by id fails on docker while the image has several
+            // tags, while removing every tag removes the image as well
+            let mut args = vec!["image", "rm"];
+            if image.tags.is_empty() {
+                args.push(&image.id);
+            } else {
+                args.extend(image.tags.iter().map(String::as_str));
+            }
+            query::query(runtime, &format!("Removing image {}", image.id), &args)?;
+        }
+
+        let name = image
+            .tags
+            .first()
+            .cloned()
+            .unwrap_or_else(|| image.id.chars().take(12).collect());
+        log::info!(
+            "{} image {} ({})",
+            verb,
+            name,
+            query::format_size(image.size)
+        );
+        reclaimed += image.size;
+    }
+
+    log::info!(
+        "{} {} containers and {} images, reclaiming {}",
+        verb,
+        removed_containers.len(),
+        removed_images.len(),
+        query::format_size(reclaimed)
+    );
+
+    Ok(())
+}
+
+/// Asks the user a yes or no question on stdin, defaulting to no.
+pub(crate) fn confirm(question: &str) -> Result<bool, Error> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Removes the container, images, and cache volumes belonging to `workspace`,
+/// as well as its persistent home unless `keep_home` is set, asking for
+/// confirmation unless `yes` is set. Resources that are already gone are
+/// skipped, so this can safely be repeated.
+pub fn remove_environment(
+    runtime: &Runtime,
+    config: &Config,
+    workspace: &Path,
+    yes: bool,
+    keep_home: bool,
+) -> Result<(), Error> {
+    let workspace_str = workspace.to_string_lossy();
+    let belongs = |label: &Option<String>| label.as_deref() == Some(workspace_str.as_ref());
+
+    let containers: Vec<query::ContainerInfo> = query::list_containers(runtime)?
+        .into_iter()
+        .filter(|container| belongs(&container.workspace))
+        .collect();
+    let images: Vec<query::ImageInfo> = query::list_images(runtime)?
+        .into_iter()
+        .filter(|image| belongs(&image.workspace))
+        .collect();
+    let mut volumes = Vec::new();
+    for (name, _) in cache_volume_specs(config)? {
+        if volume_exists(runtime, &name)? {
+            volumes.push(name);
+        }
+    }
+    let home = home_volume(workspace);
+    if !keep_home && volume_exists(runtime, &home)? {
+        volumes.push(home);
+    }
+
+    let image_name = |image: &query::ImageInfo| {
+        image
+            .tags
+            .first()
+            .cloned()
+            .unwrap_or_else(|| image.id.chars().take(12).collect())
+    };
+
+    if containers.is_empty() && images.is_empty() && volumes.is_empty() {
+        log::info!("Nothing to remove for {}", workspace.display());
+        return Ok(());
+    }
+
+    if !yes && !runtime.is_dry_run() {
+        println!("This will remove:");
+        for container in &containers {
+            println!("    container {}", container.name);
+        }
+        for image in &images {
+            println!("    image {}", image_name(image));
+        }
+        for volume in &volumes {
+            println!("    volume {}", volume);
+        }
+        if !confirm("Continue?")? {
+            log::info!("Nothing was removed");
+            return Ok(());
+        }
+    }
+
+    let removed = |what: String| {
+        if !runtime.is_dry_run() {
+            log::info!("Removed {}", what);
+        }
+    };
+
+    for container in &containers {
+        // --force stops the container first if it is running
+        runtime.apply(
+            &format!("Removing container {}", container.name),
+            &["rm", "--force", &container.id],
+        )?;
+        removed(format!("container {}", container.name));
+    }
+    for image in &images {
+        runtime.apply(
+            &format!("Removing image {}", image_name(image)),
+            &["image", "rm", "--force", &image.id],
+        )?;
+        removed(format!("image {}", image_name(image)));
+    }
+    for volume in &volumes {
+        runtime.apply(
+            &format!("Removing volume {}", volume),
+            &["volume", "rm", volume],
+        )?;
+        removed(format!("volume {}", volume));
+    }
+    if !runtime.is_dry_run() {
+        state::forget(runtime, workspace);
+    }
+
+    Ok(())
+}
+
+/// Commits the container running for `workspace` to its snapshot named
+/// `name`, or to its unnamed snapshot, keeping what was installed into it by
+/// hand. With `use_snapshot`, the environment starts from the snapshot from
+/// then on instead of from the image built for its config.
+pub fn snapshot(
+    runtime: &Runtime,
+    workspace: &Path,
+    name: Option<&str>,
+    use_snapshot: bool,
+) -> Result<(), Error> {
+    let tag = snapshot_tag(workspace, name)?;
+    let container = running_workspace_container(runtime, workspace)?.ok_or_else(|| {
+        Error::Conflict(format!(
+            "No environment is running for {}, start it and install what the snapshot should keep first",
+            workspace.display()
+        ))
+    })?;
+
+    runtime.apply(
+        &format!("Committing container {}", container),
+        &["commit", &container, &tag],
+    )?;
+    if runtime.is_dry_run() {
+        return Ok(());
+    }
+    log::info!("Saved container {} as {}", container, tag);
+
+    if use_snapshot {
+        state::use_snapshot(runtime, workspace, Some(tag.clone()))?;
+        log::warn!(
+            "The environment of {} now starts from {}, which is no longer reproducible from its config. `yadt snapshot --discard` goes back to building it from the config",
+            workspace.display(),
+            tag
+        );
+    }
+
+    Ok(())
+}
+
+/// Makes the environment of `workspace` start from the image built for its
+/// config again, instead of from a snapshot. The snapshot itself is kept
+/// until it is pruned with `yadt prune --all`.
+pub fn discard_snapshot(runtime: &Runtime, workspace: &Path) -> Result<(), Error> {
+    let Some(tag) = State::load(runtime)?
+        .get(workspace)
+        .and_then(|entry| entry.snapshot.clone())
+    else {
+        log::info!(
+            "The environment of {} already starts from its config",
+            workspace.display()
+        );
+        return Ok(());
+    };
+
+    if !runtime.is_dry_run() {
+        state::use_snapshot(runtime, workspace, None)?;
+    }
+    log::info!(
+        "The environment of {} starts from its config again instead of {}, restart it with --replace to leave the snapshot",
+        workspace.display(),
+        tag
+    );
+    Ok(())
+}
+
+/// Parses a duration such as "30s", "15m", "12h", "7d", or "2w". A bare
+/// number is taken as seconds.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let digits_end = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(digits_end);
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration \"{}\", expected eg. \"7d\"", text))?;
+    let multiplier = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "Invalid duration unit \"{}\", expected one of s, m, h, d, or w",
+                unit
+            ))
+        }
+    };
+
+    Ok(Duration::from_secs(number * multiplier))
+}
+
+/// Prints the images and containers created by yadt, as a table or as json.
+pub fn list_environments(runtime: &Runtime, json: bool) -> Result<(), Error> {
+    let images = query::list_images(runtime)?;
+    let containers = query::list_containers(runtime)?;
+    let state = State::load(runtime)?;
+    let last_run = |workspace: &Option<String>| {
+        workspace
+            .as_deref()
+            .and_then(|workspace| state.get(Path::new(workspace)))
+            .and_then(|entry| entry.last_run)
+            .map(query::format_timestamp)
+            .unwrap_or_else(|| "-".to_string())
+    };
+
+    if json {
+        #[derive(Serialize)]
+        struct Listing<'a> {
+            images: &'a [query::ImageInfo],
+            containers: &'a [query::ContainerInfo],
+        }
+
+        let text = serde_json::to_string_pretty(&Listing {
+            images: &images,
+            containers: &containers,
+        })?;
+        println!("{}", text);
+        return Ok(());
+    }
+
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let mut rows = vec![[
+        "WORKSPACE".to_string(),
+        "IMAGE".to_string(),
+        "SIZE".to_string(),
+        "CONTAINER".to_string(),
+        "GPU".to_string(),
+        "CREATED".to_string(),
+        "LAST RUN".to_string(),
+    ]];
+
+    for image in &images {
+        let image_containers: Vec<&query::ContainerInfo> = containers
+            .iter()
+            .filter(|container| container.image == image.id)
+            .collect();
+        let states: Vec<&str> = image_containers
+            .iter()
+            .map(|container| container.state.as_str())
+            .collect();
+        let mut gpus: Vec<&str> = image_containers
+            .iter()
+            .filter_map(|container| container.gpu.as_deref())
+            .collect();
+        gpus.dedup();
+
+        rows.push([
+            or_dash(image.workspace.clone()),
+            image
+                .tags
+                .first()
+                .cloned()
+                .unwrap_or_else(|| image.id.chars().take(12).collect()),
+            query::format_size(image.size),
+            if states.is_empty() {
+                "-".to_string()
+            } else {
+                states.join(", ")
+            },
+            if gpus.is_empty() {
+                "-".to_string()
+            } else {
+                gpus.join(", ")
+            },
+            or_dash(image.created.map(query::format_timestamp)),
+            last_run(&image.workspace),
+        ]);
+    }
+
+    // containers whose image is no longer listed, eg. because it was removed
+    for container in &containers {
+        if !images.iter().any(|image| image.id == container.image) {
+            rows.push([
+                or_dash(container.workspace.clone()),
+                container.image.chars().take(12).collect(),
+                "-".to_string(),
+                container.state.clone(),
+                or_dash(container.gpu.clone()),
+                or_dash(container.created.map(query::format_timestamp)),
+                last_run(&container.workspace),
+            ]);
+        }
+    }
+
+    print_table(&rows);
+    Ok(())
+}
+
+/// Prints `rows` as a table with aligned columns, the first row being the
+/// header
+pub(crate) fn print_table<const N: usize>(rows: &[[String; N]]) {
+    let mut widths = [0; N];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// Returns the environment image most recently built for `workspace`
+pub fn newest_environment_image(
+    runtime: &Runtime,
+    workspace: &Path,
+) -> Result<Option<query::ImageInfo>, Error> {
+    let workspace_str = workspace.to_string_lossy();
+    Ok(query::list_images(runtime)?
+        .into_iter()
+        .filter(|image| image.workspace.as_deref() == Some(workspace_str.as_ref()))
+        // dev images built from a containerfile have no config hash
+        .filter(|image| image.config_hash.is_some())
+        .max_by_key(|image| image.created))
+}
+
+/// Returns the installables an environment image was built with, along with
+/// the packages they resolved to
+fn installed_packages(
+    runtime: &Runtime,
+    image: &str,
+) -> Result<(Vec<String>, Vec<LockedPackage>), Error> {
+    let manifest = query::query(
+        runtime,
+        "Reading the installed packages",
+        &[
+            "run",
+            "--rm",
+            "--entrypoint",
+            "cat",
+            image,
+            BUILD_INSTALLABLES_PATH,
+            BUILD_MANIFEST_PATH,
+        ],
+    )?;
+    // the installables are a single line, followed by the manifest
+    let (installables, manifest) = manifest.split_once('\n').unwrap_or((&manifest, ""));
+    let installables: Vec<String> = installables
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    let packages = Lockfile::from_build_output(&installables, manifest)?.packages;
+    Ok((installables, packages))
+}
+
+/// Prints the packages installed in the environment image most recently
+/// built for `workspace`, or the packages the config would install if none
+/// was built yet.
+pub fn list_packages(
+    runtime: &Runtime,
+    config: &Config,
+    workspace: &Path,
+    json: bool,
+) -> Result<(), Error> {
+    let image = newest_environment_image(runtime, workspace)?;
+
+    let packages = match &image {
+        Some(image) => installed_packages(runtime, &image.id)?.1,
+        None => config
+            .installables()
+            .into_iter()
+            .map(|installable| LockedPackage {
+                name: installable
+                    .split_once('#')
+                    .map_or(installable.as_str(), |(_, name)| name)
+                    .to_string(),
+                installable,
+                version: None,
+                store_paths: Vec::new(),
+            })
+            .collect(),
+    };
+
+    // the flake a package came from, eg. "nixpkgs"
+    let source = |package: &LockedPackage| {
+        package
+            .installable
+            .split_once('#')
+            .map_or(String::new(), |(flake, _)| flake.to_string())
+    };
+
+    if json {
+        #[derive(Serialize)]
+        struct Package<'a> {
+            name: &'a str,
+            version: Option<&'a str>,
+            source: String,
+            store_paths: &'a [String],
+        }
+
+        #[derive(Serialize)]
+        struct Listing<'a> {
+            /// `None` if no environment image was built yet
+            image: Option<&'a str>,
+            packages: Vec<Package<'a>>,
+        }
+
+        let text = serde_json::to_string_pretty(&Listing {
+            image: image.as_ref().map(|image| image.id.as_str()),
+            packages: packages
+                .iter()
+                .map(|package| Package {
+                    name: &package.name,
+                    version: package.version.as_deref(),
+                    source: source(package),
+                    store_paths: &package.store_paths,
+                })
+                .collect(),
+        })?;
+        println!("{}", text);
+        return Ok(());
+    }
+
+    if image.is_none() {
+        log::info!(
+            "No environment was built for {} yet, these packages would be installed:",
+            workspace.display()
+        );
+    }
+
+    let mut rows = vec![[
+        "NAME".to_string(),
+        "VERSION".to_string(),
+        "SOURCE".to_string(),
+    ]];
+    for package in &packages {
+        rows.push([
+            package.name.clone(),
+            package.version.clone().unwrap_or_else(|| "-".to_string()),
+            source(package),
+        ]);
+    }
+
+    print_table(&rows);
+    Ok(())
+}
+
+/// The state of a workspace's environment, as printed by `yadt status`
+#[derive(Debug, Serialize)]
+struct Status {
+    workspace: String,
+
+    /// The environment image most recently built for the workspace
+    image: Option<String>,
+
+    /// Size of `image` in bytes
+    image_size: Option<u64>,
+
+    /// Creation time of `image` in seconds since the unix epoch
+    image_created: Option<i64>,
+
+    /// Whether an image was built for the current config on top of the dev
+    /// image `image` was built on
+    image_current: bool,
+
+    /// Whether a config file was modified after `image` was built
+    config_modified: bool,
+
+    /// Name of the container running for the workspace
+    container: Option<String>,
+
+    /// Whether `container` runs an image built for the current config
+    container_current: Option<bool>,
+
+    /// When the environment last ran in seconds since the unix epoch
+    last_run: Option<i64>,
+
+    /// The snapshot the environment starts from instead of its config
+    snapshot: Option<String>,
+
+    /// Packages in the config that `image` lacks
+    added_packages: Vec<String>,
+
+    /// Packages in `image` that are no longer in the config
+    removed_packages: Vec<String>,
+}
+
+/// Prints whether an environment was built for the current config of
+/// `workspace`, whether one is running, and how the configured packages
+/// differ from the built ones, as a summary or as json. The dev image isn't
+/// known without a source, so the current config is compared against the
+/// dev image the newest environment image was built on.
+pub fn show_status(
+    runtime: &Runtime,
+    config: &Config,
+    sources: &ConfigSources,
+    workspace: &Path,
+    json: bool,
+) -> Result<(), Error> {
+    let image = newest_environment_image(runtime, workspace)?;
+    let container = running_workspace_container(runtime, workspace)?;
+    let remembered = State::load(runtime)?.get(workspace).cloned();
+
+    let mut current_hash = None;
+    let mut added_packages = Vec::new();
+    let mut removed_packages = Vec::new();
+    if let Some(image) = &image {
+        if let Some(dev_image) = &image.dev_image {
+            let options = BuildOptions::from_config(config);
+            current_hash = Some(current_config_hash(
+                config,
+                workspace,
+                dev_image,
+                &HostUser::current(),
+                &options,
+            )?);
+        }
+
+        let configured = config.installables();
+        let (installed, _) = installed_packages(runtime, &image.id)?;
+        added_packages = configured
+            .iter()
+            .filter(|package| !installed.contains(package))
+            .cloned()
+            .collect();
+        removed_packages = installed
+            .into_iter()
+            .filter(|package| !configured.contains(package))
+            .collect();
+    }
+
+    let image_current = match &current_hash {
+        Some(hash) => image_exists(runtime, &environment_tag(workspace, hash))?,
+        None => false,
+    };
+    let container_current = match (&container, &current_hash) {
+        (Some(name), Some(hash)) => {
+            Some(container_config_hash(runtime, name)?.as_ref() == Some(hash))
+        }
+        _ => None,
+    };
+
+    // files whose modification time can't be read are left out
+    let config_modified = image
+        .as_ref()
+        .and_then(|image| image.created)
+        .is_some_and(|created| {
+            sources.files().any(|path| {
+                fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .is_some_and(|modified| modified.as_secs() as i64 > created)
+            })
+        });
+
+    let status = Status {
+        workspace: workspace.to_string_lossy().into_owned(),
+        image: image.as_ref().map(|image| {
+            image
+                .tags
+                .first()
+                .cloned()
+                .unwrap_or_else(|| image.id.clone())
+        }),
+        image_size: image.as_ref().map(|image| image.size),
+        image_created: image.as_ref().and_then(|image| image.created),
+        image_current,
+        config_modified,
+        container,
+        container_current,
+        last_run: remembered.as_ref().and_then(|entry| entry.last_run),
+        snapshot: remembered.and_then(|entry| entry.snapshot),
+        added_packages,
+        removed_packages,
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+
+    if let Some(snapshot) = &status.snapshot {
+        log::warn!(
+            "The environment starts from the snapshot {}, so it is no longer reproducible from the config. `yadt snapshot --discard` goes back to building it from the config",
+            snapshot
+        );
+    }
+    let image_state = match &status.image {
+        None => "none built yet".to_string(),
+        Some(name) => {
+            let freshness = if status.image_current {
+                "up to date"
+            } else {
+                "stale, the config changed since it was built"
+            };
+            format!(
+                "{} ({}, {})",
+                name,
+                query::format_size(status.image_size.unwrap_or_default()),
+                freshness
+            )
+        }
+    };
+    let container_state = match (&status.container, status.container_current) {
+        (None, _) => "not running".to_string(),
+        (Some(name), Some(false)) => {
+            format!("{} running a stale image, restart it to rebuild", name)
+        }
+        (Some(name), _) => format!("{} running", name),
+    };
+    let mut package_changes: Vec<String> = status
+        .added_packages
+        .iter()
+        .map(|package| format!("+{}", package))
+        .chain(
+            status
+                .removed_packages
+                .iter()
+                .map(|package| format!("-{}", package)),
+        )
+        .collect();
+    if package_changes.is_empty() && status.image.is_some() {
+        package_changes.push("as configured".to_string());
+    }
+
+    let mut rows = vec![
+        ["Workspace:".to_string(), status.workspace.clone()],
+        ["Image:".to_string(), image_state],
+    ];
+    if let Some(created) = status.image_created {
+        rows.push(["Built:".to_string(), query::format_timestamp(created)]);
+    }
+    if status.config_modified {
+        rows.push([
+            "Config:".to_string(),
+            "modified since the image was built".to_string(),
+        ]);
+    }
+    if let Some(snapshot) = &status.snapshot {
+        rows.push([
+            "Snapshot:".to_string(),
+            format!("{}, not reproducible from the config", snapshot),
+        ]);
+    }
+    rows.push(["Container:".to_string(), container_state]);
+    if let Some(last_run) = status.last_run {
+        rows.push(["Last run:".to_string(), query::format_timestamp(last_run)]);
+    }
+    if !package_changes.is_empty() {
+        rows.push(["Packages:".to_string(), package_changes.join(" ")]);
+    }
+
+    print_table(&rows);
+    Ok(())
+}
+
+/// An environment yadt ran, as printed by `yadt recent`
+#[derive(Debug, Serialize)]
+struct Recent {
+    workspace: String,
+
+    /// When the environment last ran in seconds since the unix epoch
+    last_run: i64,
+
+    /// Name of the container the environment last ran in
+    container: Option<String>,
+
+    /// Whether `container` is still running
+    running: bool,
+
+    /// The environment image last built for the workspace
+    image: Option<String>,
+
+    /// Why the environment can't be started as it was, if it can't
+    stale: Option<&'static str>,
+}
+
+/// Prints the environments that ran most recently, newest first, at most
+/// `limit` of them, as a table or as json.
+pub fn list_recent(runtime: &Runtime, limit: usize, json: bool) -> Result<(), Error> {
+    let state = State::load(runtime)?;
+    let images = query::list_images(runtime)?;
+    let containers = query::list_containers(runtime)?;
+
+    let mut entries: Vec<_> = state
+        .workspaces
+        .into_values()
+        .filter_map(|entry| Some((entry.last_run?, entry)))
+        .collect();
+    entries.sort_by_key(|(last_run, _)| std::cmp::Reverse(*last_run));
+    entries.truncate(limit);
+
+    let recent: Vec<Recent> = entries
+        .into_iter()
+        .map(|(last_run, entry)| {
+            let running = entry.container.as_ref().is_some_and(|name| {
+                containers
+                    .iter()
+                    .any(|container| &container.name == name && container.state == "running")
+            });
+            let image_exists = images.iter().any(|image| {
+                entry.image_id.as_ref() == Some(&image.id)
+                    || entry
+                        .image_tag
+                        .as_ref()
+                        .is_some_and(|tag| image.tags.contains(tag))
+            });
+            let stale = if !entry.workspace.is_dir() {
+                Some("workspace is gone")
+            } else if !image_exists {
+                Some("image was removed")
+            } else {
+                None
+            };
+            Recent {
+                workspace: entry.workspace.to_string_lossy().into_owned(),
+                last_run,
+                container: entry.container,
+                running,
+                image: entry.image_tag.or(entry.image_id),
+                stale,
+            }
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&recent)?);
+        return Ok(());
+    }
+
+    let mut rows = vec![[
+        "WORKSPACE".to_string(),
+        "LAST RUN".to_string(),
+        "CONTAINER".to_string(),
+        "IMAGE".to_string(),
+    ]];
+    for entry in recent {
+        let container = match (entry.container, entry.running) {
+            (Some(name), true) => format!("{} (running)", name),
+            (Some(name), false) => name,
+            (None, _) => "-".to_string(),
+        };
+        let image = match entry.stale {
+            Some(reason) => reason.to_string(),
+            None => entry.image.unwrap_or_else(|| "-".to_string()),
+        };
+        rows.push([
+            entry.workspace,
+            query::format_timestamp(entry.last_run),
+            container,
+            image,
+        ]);
+    }
+
+    print_table(&rows);
+    Ok(())
+}
@@ -0,0 +1,129 @@
+//! The clusters of packages environments for a language tend to want.
+//!
+//! Presets are selected with `presets` or `--preset`, and their packages are
+//! installed along with the others. Besides the presets built into yadt,
+//! configs can define their own in `custom_presets`, where one named like a
+//! built-in preset replaces it.
+
+use serde::Serialize;
+
+use crate::{config::Config, error::Error, manage::print_table};
+
+/// A preset built into yadt
+pub struct Preset {
+    pub name: &'static str,
+    pub packages: &'static [&'static str],
+}
+
+/// The presets built into yadt, by name
+pub const BUILTIN_PRESETS: &[Preset] = &[
+    Preset {
+        name: "cpp",
+        packages: &["clang-tools", "cmake", "gcc", "gdb", "gnumake"],
+    },
+    Preset {
+        name: "go",
+        packages: &["delve", "go", "gopls"],
+    },
+    Preset {
+        name: "haskell",
+        packages: &["cabal-install", "ghc", "haskell-language-server"],
+    },
+    Preset {
+        name: "java",
+        packages: &["gradle", "jdk", "maven"],
+    },
+    Preset {
+        name: "node",
+        packages: &["nodejs"],
+    },
+    Preset {
+        name: "python",
+        packages: &["python3", "python3Packages.pip"],
+    },
+    Preset {
+        name: "rust",
+        packages: &["cargo", "clippy", "rust-analyzer", "rustc", "rustfmt"],
+    },
+    Preset {
+        name: "zig",
+        packages: &["zig", "zls"],
+    },
+];
+
+/// Returns the preset built into yadt named `name`
+pub fn builtin_preset(name: &str) -> Option<&'static Preset> {
+    BUILTIN_PRESETS.iter().find(|preset| preset.name == name)
+}
+
+/// A preset as listed by `yadt presets`
+#[derive(Debug, Serialize)]
+struct ListedPreset<'a> {
+    name: &'a str,
+
+    /// Where the preset is defined, "built-in" or "config"
+    source: &'static str,
+
+    /// Whether the preset replaces the built-in one of the same name
+    overrides: bool,
+
+    /// Whether the config selects the preset
+    selected: bool,
+
+    packages: Vec<&'a str>,
+}
+
+/// Prints the presets available with `config`, the built-in ones along with
+/// those it defines, as a table or as json
+pub fn list_presets(config: &Config, json: bool) -> Result<(), Error> {
+    let mut presets: Vec<ListedPreset> = BUILTIN_PRESETS
+        .iter()
+        .filter(|preset| !config.custom_presets.contains_key(preset.name))
+        .map(|preset| ListedPreset {
+            name: preset.name,
+            source: "built-in",
+            overrides: false,
+            selected: config.presets.contains(preset.name),
+            packages: preset.packages.to_vec(),
+        })
+        .collect();
+    presets.extend(
+        config
+            .custom_presets
+            .iter()
+            .map(|(name, packages)| ListedPreset {
+                name,
+                source: "config",
+                overrides: builtin_preset(name).is_some(),
+                selected: config.presets.contains(name),
+                packages: packages.iter().map(String::as_str).collect(),
+            }),
+    );
+    presets.sort_by_key(|preset| preset.name);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&presets)?);
+        return Ok(());
+    }
+
+    let mut rows = vec![[
+        "NAME".to_string(),
+        "SOURCE".to_string(),
+        "PACKAGES".to_string(),
+    ]];
+    for preset in &presets {
+        let name = if preset.selected {
+            format!("{} *", preset.name)
+        } else {
+            preset.name.to_string()
+        };
+        let source = if preset.overrides {
+            "config, replacing built-in"
+        } else {
+            preset.source
+        };
+        rows.push([name, source.to_string(), preset.packages.join(" ")]);
+    }
+    print_table(&rows);
+    Ok(())
+}
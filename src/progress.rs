@@ -0,0 +1,210 @@
+//! The progress shown while environments are built and started.
+//!
+//! On a terminal, the current phase, such as pulling the nix image or
+//! installing the packages, is shown by a spinner with the time it has
+//! taken, which is replaced by a line with its duration once the phase ends.
+//! Builds advance through their phases as the instructions of the embedded
+//! containerfile appear in the runtime's output, which is then only logged
+//! at the debug level. Without a terminal, with `--plain`, or with `-v`,
+//! phases are logged as they start and the output is passed through. With
+//! `--output json`, phases are reported as events instead, by the name
+//! identifying them.
+
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::events::{self, Event, PhaseStatus};
+
+/// Whether phases are shown by a spinner
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The current phase, if any
+static CURRENT: Mutex<Option<Phase>> = Mutex::new(None);
+
+/// How often the spinner is redrawn
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A phase that starts once `marker` appears in the output, identified by
+/// `id` in events and described by the last field
+pub type NextPhase = (&'static str, &'static str, String);
+
+/// A phase shown by the spinner or reported as events
+struct Phase {
+    id: &'static str,
+    name: String,
+    started: Instant,
+
+    /// The spinner, unless the phase is only reported as events
+    bar: Option<ProgressBar>,
+
+    /// The phases that follow, each started once its marker appears in the
+    /// output, skipping those in between
+    next: Vec<NextPhase>,
+}
+
+impl Phase {
+    fn new(id: &'static str, name: String, next: Vec<NextPhase>) -> Self {
+        let bar = is_active().then(|| {
+            let bar = ProgressBar::new_spinner()
+                .with_style(
+                    ProgressStyle::with_template("{spinner} {msg} ({elapsed})")
+                        .expect("The spinner template is valid"),
+                )
+                .with_message(name.clone());
+            bar.enable_steady_tick(TICK_INTERVAL);
+            bar
+        });
+        let phase = Self {
+            id,
+            name,
+            started: Instant::now(),
+            bar,
+            next,
+        };
+        phase.report(PhaseStatus::Started);
+        phase
+    }
+
+    fn report(&self, status: PhaseStatus) {
+        events::emit(Event::Phase {
+            name: self.id.to_string(),
+            description: self.name.clone(),
+            status,
+        });
+    }
+
+    /// Replaces the spinner with a line naming the phase and its duration
+    fn end(self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+            eprintln!("{} ({})", self.name, format_elapsed(self.started.elapsed()));
+        }
+        self.report(PhaseStatus::Finished);
+    }
+}
+
+/// Returns the current phase, which stays usable even if a thread holding it
+/// panicked
+fn current() -> MutexGuard<'static, Option<Phase>> {
+    CURRENT.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// Shows phases by a spinner from now on, which stderr must be a terminal for
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns whether phases are shown by a spinner
+pub fn is_active() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns whether phases are shown by a spinner or reported as events,
+/// rather than only logged
+fn is_tracked() -> bool {
+    is_active() || events::is_enabled()
+}
+
+/// Starts the phase `id` described by `name`, ending the current one
+pub fn start(id: &'static str, name: impl Into<String>) {
+    start_with(id, name, Vec::new());
+}
+
+/// Starts the phase `id` described by `name`, ending the current one. The
+/// phases in `next` start once their markers appear in the output passed to
+/// `observe`.
+pub fn start_with(id: &'static str, name: impl Into<String>, next: Vec<NextPhase>) {
+    let name = name.into();
+    if !is_tracked() {
+        log::info!("{}", name);
+        return;
+    }
+
+    finish();
+    let phase = Phase::new(id, name, next);
+    *current() = Some(phase);
+}
+
+/// Advances through the phases of the current one as their markers appear
+/// in `line` of the runtime's output. podman's "STEP 3/9: ..." lines are
+/// shown as the phase's progress.
+pub fn observe(line: &str) {
+    if !is_tracked() {
+        return;
+    }
+
+    let mut current = current();
+    let Some(phase) = current.as_mut() else {
+        return;
+    };
+    let Some(index) = phase
+        .next
+        .iter()
+        .position(|(marker, _, _)| line.contains(marker))
+    else {
+        if let (Some(bar), Some((step, _))) = (
+            &phase.bar,
+            line.strip_prefix("STEP ")
+                .and_then(|line| line.split_once(':')),
+        ) {
+            bar.set_message(format!("{}, step {}", phase.name, step));
+        }
+        return;
+    };
+
+    let mut next = phase.next.split_off(index);
+    let (_, id, name) = next.remove(0);
+    drop(current);
+    start_with(id, name, next);
+}
+
+/// Ends the current phase, if any
+pub fn finish() {
+    let ended = current().take();
+    if let Some(phase) = ended {
+        phase.end();
+    }
+}
+
+/// Removes the spinner of the current phase, which failed, if any
+pub fn clear() {
+    let cleared = current().take();
+    if let Some(bar) = cleared.and_then(|phase| phase.bar) {
+        bar.finish_and_clear();
+    }
+}
+
+/// Returns `elapsed` rounded to seconds, eg. "42s" or "3m 12s"
+pub fn format_elapsed(elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs();
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, seconds) => format!("{}s", seconds),
+        (0, minutes, seconds) => format!("{}m {}s", minutes, seconds),
+        (hours, minutes, _) => format!("{}h {}m", hours, minutes),
+    }
+}
+
+/// Writes yadt's messages to stderr, hiding the spinner meanwhile so that
+/// the two don't garble each other
+pub struct Writer;
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match current().as_ref().and_then(|phase| phase.bar.as_ref()) {
+            Some(bar) => bar.suspend(|| io::stderr().write(buf)),
+            None => io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
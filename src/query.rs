@@ -0,0 +1,496 @@
+//! Queries the container runtime for the images and containers yadt created.
+//!
+//! `ps` and `images` print differently shaped JSON on podman and docker, so
+//! they are only used to list ids, which are then passed to `inspect`. Its
+//! output follows the same docker schema on both runtimes, apart from a few
+//! details handled during deserialization.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    build::{CONFIG_HASH_LABEL, DEV_IMAGE_LABEL, GPU_LABEL, WORKSPACE_LABEL, YADT_LABEL},
+    error::Error,
+    runtime::{check_status, stderr_lines, Runtime},
+};
+
+/// An image created by yadt
+#[derive(Debug, Serialize)]
+pub struct ImageInfo {
+    pub id: String,
+    pub tags: Vec<String>,
+
+    /// Size in bytes
+    pub size: u64,
+
+    /// Creation time in seconds since the unix epoch
+    pub created: Option<i64>,
+
+    pub workspace: Option<String>,
+    pub config_hash: Option<String>,
+
+    /// The dev image an environment image was built on top of
+    pub dev_image: Option<String>,
+}
+
+/// A container created by yadt
+#[derive(Debug, Serialize)]
+pub struct ContainerInfo {
+    pub id: String,
+    pub name: String,
+
+    /// Id of the image the container was created from
+    pub image: String,
+
+    /// Runtime specific state, eg. "running" or "exited"
+    pub state: String,
+
+    /// Creation time in seconds since the unix epoch
+    pub created: Option<i64>,
+
+    pub workspace: Option<String>,
+
+    /// The GPUs passed into the container, eg. "nvidia"
+    pub gpu: Option<String>,
+}
+
+/// `Config` section of `inspect` output
+#[derive(Deserialize)]
+struct InspectConfig {
+    #[serde(rename = "Labels", default)]
+    labels: Option<HashMap<String, String>>,
+}
+
+/// An entry of `image inspect` output
+#[derive(Deserialize)]
+struct ImageInspect {
+    #[serde(rename = "Id")]
+    id: String,
+
+    #[serde(rename = "RepoTags", default)]
+    repo_tags: Option<Vec<String>>,
+
+    #[serde(rename = "Size", default)]
+    size: u64,
+
+    #[serde(rename = "Created", default)]
+    created: Option<String>,
+
+    #[serde(rename = "Config", default)]
+    config: Option<InspectConfig>,
+}
+
+/// `State` section of `container inspect` output
+#[derive(Deserialize)]
+struct ContainerState {
+    #[serde(rename = "Status")]
+    status: String,
+}
+
+/// An entry of `container inspect` output
+#[derive(Deserialize)]
+struct ContainerInspect {
+    #[serde(rename = "Id")]
+    id: String,
+
+    /// docker prefixes container names with a '/', podman doesn't
+    #[serde(rename = "Name")]
+    name: String,
+
+    #[serde(rename = "Image")]
+    image: String,
+
+    #[serde(rename = "Created", default)]
+    created: Option<String>,
+
+    #[serde(rename = "State")]
+    state: ContainerState,
+
+    #[serde(rename = "Config", default)]
+    config: Option<InspectConfig>,
+}
+
+/// Strips the "sha256:" prefix docker puts on ids, which podman leaves out
+fn strip_digest_prefix(id: String) -> String {
+    match id.strip_prefix("sha256:") {
+        Some(id) => id.to_string(),
+        None => id,
+    }
+}
+
+/// Returns the value of `label` from optional inspect config
+fn label(config: &Option<InspectConfig>, label: &str) -> Option<String> {
+    config
+        .as_ref()
+        .and_then(|config| config.labels.as_ref())
+        .and_then(|labels| labels.get(label))
+        .cloned()
+}
+
+/// Parses `image inspect` output from either runtime
+pub fn parse_image_inspect(json: &str) -> Result<Vec<ImageInfo>, Error> {
+    let images: Vec<ImageInspect> = serde_json::from_str(json)?;
+
+    Ok(images
+        .into_iter()
+        .map(|image| ImageInfo {
+            tags: image.repo_tags.unwrap_or_default(),
+            size: image.size,
+            created: image.created.as_deref().and_then(parse_timestamp),
+            workspace: label(&image.config, WORKSPACE_LABEL),
+            config_hash: label(&image.config, CONFIG_HASH_LABEL),
+            dev_image: label(&image.config, DEV_IMAGE_LABEL),
+            id: strip_digest_prefix(image.id),
+        })
+        .collect())
+}
+
+/// Parses `container inspect` output from either runtime
+pub fn parse_container_inspect(json: &str) -> Result<Vec<ContainerInfo>, Error> {
+    let containers: Vec<ContainerInspect> = serde_json::from_str(json)?;
+
+    Ok(containers
+        .into_iter()
+        .map(|container| ContainerInfo {
+            name: container.name.trim_start_matches('/').to_string(),
+            image: strip_digest_prefix(container.image),
+            state: container.state.status,
+            created: container.created.as_deref().and_then(parse_timestamp),
+            workspace: label(&container.config, WORKSPACE_LABEL),
+            gpu: label(&container.config, GPU_LABEL),
+            id: container.id,
+        })
+        .collect())
+}
+
+/// Parses an RFC 3339 timestamp, as printed by `inspect`, into seconds since
+/// the unix epoch. Fractional seconds are ignored.
+pub fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let (date, time) = timestamp.split_once(['T', ' '])?;
+
+    let mut date_parts = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (
+        date_parts.next()?.ok()?,
+        date_parts.next()?.ok()?,
+        date_parts.next()?.ok()?,
+    );
+
+    // the offset starts at the first 'Z', '+', or '-' after the seconds
+    let offset_start = time.find(['Z', 'z', '+', '-']).unwrap_or(time.len());
+    let (clock, offset) = time.split_at(offset_start);
+
+    let mut clock_parts = clock.splitn(3, ':');
+    let hours: i64 = clock_parts.next()?.parse().ok()?;
+    let minutes: i64 = clock_parts.next()?.parse().ok()?;
+    let seconds: i64 = clock_parts.next()?.split('.').next()?.parse().ok()?;
+
+    let offset_seconds = match offset.trim() {
+        "" | "Z" | "z" => 0,
+        offset => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            // podman may append a zone name after the offset
+            let digits = offset[1..].split_whitespace().next()?.replace(':', "");
+            let hours: i64 = digits.get(..2)?.parse().ok()?;
+            let minutes: i64 = digits.get(2..4).unwrap_or("00").parse().ok()?;
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+
+    Some(
+        days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds
+            - offset_seconds,
+    )
+}
+
+/// Returns the number of days between the unix epoch and the given date in
+/// the proleptic gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // from http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Formats seconds since the unix epoch as a UTC "YYYY-MM-DD HH:MM" string.
+pub fn format_timestamp(timestamp: i64) -> String {
+    // inverse of days_from_civil, from the same source
+    let days = timestamp.div_euclid(86400);
+    let seconds_of_day = timestamp.rem_euclid(86400);
+
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60
+    )
+}
+
+/// Formats a size in bytes with a binary unit suffix, eg. "1.5 GiB".
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Runs `args` with the runtime and returns its stdout, failing with the
+/// runtime's stderr if it exits unsuccessfully.
+pub fn query(runtime: &Runtime, step: &str, args: &[&str]) -> Result<String, Error> {
+    let output = runtime.output(runtime.command().args(args))?;
+    check_status(runtime, step, output.status, &stderr_lines(&output.stderr))?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Returns the ids printed one per line by a listing command
+fn ids(output: &str) -> Vec<&str> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .collect()
+}
+
+/// Lists every image carrying the yadt label
+pub fn list_images(runtime: &Runtime) -> Result<Vec<ImageInfo>, Error> {
+    let filter = format!("label={}", YADT_LABEL);
+    let listing = query(
+        runtime,
+        "Listing images",
+        &["images", "--filter", &filter, "--format", "{{.ID}}"],
+    )?;
+
+    let mut ids = ids(&listing);
+    // an image with several tags is listed once per tag
+    ids.sort_unstable();
+    ids.dedup();
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut args = vec!["image", "inspect"];
+    args.extend(ids);
+    parse_image_inspect(&query(runtime, "Inspecting images", &args)?)
+}
+
+/// Lists every container, running or not, carrying the yadt label
+pub fn list_containers(runtime: &Runtime) -> Result<Vec<ContainerInfo>, Error> {
+    let filter = format!("label={}", YADT_LABEL);
+    let listing = query(
+        runtime,
+        "Listing containers",
+        &["ps", "--all", "--filter", &filter, "--format", "{{.ID}}"],
+    )?;
+
+    let ids = ids(&listing);
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut args = vec!["container", "inspect"];
+    args.extend(ids);
+    parse_container_inspect(&query(runtime, "Inspecting containers", &args)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `docker image inspect` of an environment image, trimmed to the fields
+    /// yadt reads and a few it doesn't
+    const DOCKER_IMAGE_INSPECT: &str = r#"[
+        {
+            "Id": "sha256:5b0d8a9f2c1e",
+            "RepoTags": ["localhost/yadt/api-1a2b3c4d:latest", "localhost/yadt/api-1a2b3c4d:0f1e2d3c"],
+            "RepoDigests": [],
+            "Created": "2024-05-01T12:34:56.123456789Z",
+            "Size": 1610612736,
+            "Architecture": "amd64",
+            "Config": {
+                "Env": ["PATH=/usr/bin:/yadt-bin"],
+                "Labels": {
+                    "io.github.anglesideangle.yadt": "true",
+                    "yadt.workspace": "/home/user/src/api",
+                    "yadt.config-hash": "0f1e2d3c",
+                    "yadt.dev-image": "docker.io/library/debian:stable-slim"
+                }
+            }
+        }
+    ]"#;
+
+    /// `podman image inspect` of a dev image, which has no yadt labels but
+    /// the one marking it, and no tags
+    const PODMAN_IMAGE_INSPECT: &str = r#"[
+        {
+            "Id": "9c4e7a1b3d2f",
+            "Digest": "sha256:7a1b",
+            "RepoTags": null,
+            "Created": "2024-05-01T14:34:56.987654321+02:00",
+            "Size": 524288,
+            "Config": {
+                "Labels": {
+                    "io.github.anglesideangle.yadt": "true"
+                }
+            },
+            "Version": "",
+            "User": ""
+        }
+    ]"#;
+
+    /// `docker container inspect` of a running environment
+    const DOCKER_CONTAINER_INSPECT: &str = r#"[
+        {
+            "Id": "e3f1c2a4b5d6",
+            "Created": "2024-05-02T08:00:00.5Z",
+            "Path": "sleep",
+            "State": {"Status": "running", "Running": true, "Pid": 4242},
+            "Image": "sha256:5b0d8a9f2c1e",
+            "Name": "/yadt-api-1a2b3c4d",
+            "Config": {
+                "Labels": {
+                    "io.github.anglesideangle.yadt": "true",
+                    "yadt.workspace": "/home/user/src/api",
+                    "yadt.gpu": "nvidia"
+                }
+            }
+        }
+    ]"#;
+
+    /// `podman container inspect` of an exited environment, whose labels
+    /// podman prints as null when a container has none
+    const PODMAN_CONTAINER_INSPECT: &str = r#"[
+        {
+            "Id": "a1b2c3d4e5f6",
+            "Created": "2024-05-02T10:00:00.123456789+02:00 CEST",
+            "State": {"OciVersion": "1.1.0", "Status": "exited", "Running": false},
+            "Image": "9c4e7a1b3d2f",
+            "ImageName": "localhost/yadt/api-1a2b3c4d:latest",
+            "Name": "yadt-api-1a2b3c4d-cp-42",
+            "Config": {"Labels": null}
+        }
+    ]"#;
+
+    #[test]
+    fn parses_docker_image_inspect() {
+        let images = parse_image_inspect(DOCKER_IMAGE_INSPECT).unwrap();
+        let [image] = images.as_slice() else {
+            panic!("Expected one image, got {:?}", images);
+        };
+
+        assert_eq!(image.id, "5b0d8a9f2c1e");
+        assert_eq!(image.tags.len(), 2);
+        assert_eq!(image.size, 1610612736);
+        assert_eq!(image.created, parse_timestamp("2024-05-01T12:34:56Z"));
+        assert_eq!(image.workspace.as_deref(), Some("/home/user/src/api"));
+        assert_eq!(image.config_hash.as_deref(), Some("0f1e2d3c"));
+        assert_eq!(
+            image.dev_image.as_deref(),
+            Some("docker.io/library/debian:stable-slim")
+        );
+    }
+
+    #[test]
+    fn parses_podman_image_inspect() {
+        let images = parse_image_inspect(PODMAN_IMAGE_INSPECT).unwrap();
+        let [image] = images.as_slice() else {
+            panic!("Expected one image, got {:?}", images);
+        };
+
+        assert_eq!(image.id, "9c4e7a1b3d2f");
+        assert!(image.tags.is_empty());
+        // the same instant as docker's sample, in another offset
+        assert_eq!(image.created, parse_timestamp("2024-05-01T12:34:56Z"));
+        assert_eq!(image.workspace, None);
+        assert_eq!(image.config_hash, None);
+    }
+
+    #[test]
+    fn parses_docker_container_inspect() {
+        let containers = parse_container_inspect(DOCKER_CONTAINER_INSPECT).unwrap();
+        let [container] = containers.as_slice() else {
+            panic!("Expected one container, got {:?}", containers);
+        };
+
+        assert_eq!(container.name, "yadt-api-1a2b3c4d");
+        assert_eq!(container.image, "5b0d8a9f2c1e");
+        assert_eq!(container.state, "running");
+        assert_eq!(container.created, parse_timestamp("2024-05-02T08:00:00Z"));
+        assert_eq!(container.workspace.as_deref(), Some("/home/user/src/api"));
+        assert_eq!(container.gpu.as_deref(), Some("nvidia"));
+    }
+
+    #[test]
+    fn parses_podman_container_inspect() {
+        let containers = parse_container_inspect(PODMAN_CONTAINER_INSPECT).unwrap();
+        let [container] = containers.as_slice() else {
+            panic!("Expected one container, got {:?}", containers);
+        };
+
+        assert_eq!(container.name, "yadt-api-1a2b3c4d-cp-42");
+        assert_eq!(container.image, "9c4e7a1b3d2f");
+        assert_eq!(container.state, "exited");
+        assert_eq!(container.created, parse_timestamp("2024-05-02T08:00:00Z"));
+        assert_eq!(container.workspace, None);
+    }
+
+    #[test]
+    fn rejects_malformed_inspect() {
+        assert!(parse_image_inspect(r#"{"Id": "not a list"}"#).is_err());
+        assert!(parse_container_inspect(r#"[{"Id": "a1b2"}]"#).is_err());
+    }
+
+    #[test]
+    fn parses_timestamps_with_offsets() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_timestamp("2024-05-01T12:34:56Z"), Some(1714566896));
+        assert_eq!(
+            parse_timestamp("2024-05-01 05:34:56.5 -0700 MST"),
+            Some(1714566896)
+        );
+        assert_eq!(
+            parse_timestamp("2024-05-01T18:04:56+05:30"),
+            Some(1714566896)
+        );
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn formats_timestamps_and_sizes() {
+        assert_eq!(format_timestamp(1714566896), "2024-05-01 12:34");
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(1610612736), "1.5 GiB");
+    }
+}
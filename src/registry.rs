@@ -0,0 +1,237 @@
+//! Authenticating to the registries images are pulled from.
+//!
+//! Each registry in the `registries` config section gets its credentials
+//! either from a credential helper or from an auth file written by
+//! `podman login` or `docker login`. Both runtimes read the same json format,
+//! so the configured registries are merged into one auth file for the
+//! duration of the command, which builds and pulls are pointed at. The
+//! credentials copied into it are never logged.
+
+use std::{
+    env,
+    fs::{self, DirBuilder, OpenOptions},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use serde_json::{Map, Value};
+
+use crate::{
+    config::{expand_home, Config},
+    error::Error,
+    runtime::Runtime,
+};
+
+/// Name of the merged auth file, which docker only finds under this name in
+/// the directory `DOCKER_CONFIG` points to
+const AUTH_FILE_NAME: &str = "config.json";
+
+/// The key docker records docker.io's credentials under
+const DOCKER_HUB_KEY: &str = "https://index.docker.io/v1/";
+
+/// Returns the registry `image` is pulled from, eg. "docker.io" for
+/// "debian:stable-slim"
+pub(crate) fn image_registry(image: &str) -> &str {
+    match image.split_once('/') {
+        // like the runtimes, the first component only names a registry if
+        // it looks like a host
+        Some((host, _)) if host.contains(['.', ':']) || host == "localhost" => host,
+        _ => "docker.io",
+    }
+}
+
+/// Returns the registry an auth file key refers to. Keys may be urls or
+/// include a repository, eg. "https://quay.io/v1/" or "quay.io/org".
+fn key_registry(key: &str) -> &str {
+    let key = key
+        .strip_prefix("https://")
+        .or_else(|| key.strip_prefix("http://"))
+        .unwrap_or(key);
+    match key.split('/').next().unwrap_or(key) {
+        "index.docker.io" | "registry-1.docker.io" => "docker.io",
+        registry => registry,
+    }
+}
+
+/// The auth file merging the credentials of every configured registry,
+/// which is removed when dropped
+#[derive(Debug)]
+pub struct AuthFile {
+    dir: PathBuf,
+    registries: Vec<String>,
+    written: bool,
+}
+
+impl AuthFile {
+    /// Merges the credentials of the registries configured in `config`, or
+    /// returns None if there are none. In a dry run, nothing is written.
+    pub fn new(config: &Config, runtime: &Runtime) -> Result<Option<Self>, Error> {
+        if config.registries.is_empty() {
+            return Ok(None);
+        }
+
+        let mut auths = Map::new();
+        let mut helpers = Map::new();
+        for (registry, auth) in &config.registries {
+            // docker looks its own hub up by url, podman by name
+            let keys: &[&str] = match registry.as_str() {
+                "docker.io" => &["docker.io", DOCKER_HUB_KEY],
+                registry => &[registry],
+            };
+
+            if let Some(helper) = &auth.credential_helper {
+                for key in keys {
+                    helpers.insert(key.to_string(), Value::String(helper.clone()));
+                }
+            }
+            if let Some(path) = &auth.auth_file {
+                let path = expand_home(path);
+                match read_credentials(&path, registry)? {
+                    Some((key, entry)) => {
+                        let map = if key == "credHelpers" {
+                            &mut helpers
+                        } else {
+                            &mut auths
+                        };
+                        for key in keys {
+                            map.insert(key.to_string(), entry.clone());
+                        }
+                    }
+                    None => log::warn!(
+                        "{} has no credentials for {}, log in with `{} login --authfile {} {}`",
+                        path.display(),
+                        registry,
+                        runtime.name,
+                        path.display(),
+                        registry
+                    ),
+                }
+            }
+        }
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let mut auth_file = Self {
+            dir: env::temp_dir().join(format!("yadt-auth-{}-{}", process::id(), count)),
+            registries: config.registries.keys().cloned().collect(),
+            written: false,
+        };
+        if runtime.is_dry_run() {
+            return Ok(Some(auth_file));
+        }
+
+        let mut contents = Map::new();
+        contents.insert("auths".to_string(), Value::Object(auths));
+        contents.insert("credHelpers".to_string(), Value::Object(helpers));
+
+        // only the user may read the credentials
+        DirBuilder::new()
+            .mode(0o700)
+            .create(&auth_file.dir)
+            .map_err(|source| Error::Path {
+                path: auth_file.dir.clone(),
+                source,
+            })?;
+        auth_file.written = true;
+        let path = auth_file.path();
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut file| file.write_all(Value::Object(contents).to_string().as_bytes()))
+            .map_err(|source| Error::Path { path, source })?;
+
+        Ok(Some(auth_file))
+    }
+
+    /// Returns the path of the merged auth file
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(AUTH_FILE_NAME)
+    }
+
+    /// Returns whether credentials are configured for `registry`
+    pub fn covers(&self, registry: &str) -> bool {
+        self.registries
+            .iter()
+            .any(|configured| configured == registry)
+    }
+}
+
+impl Drop for AuthFile {
+    fn drop(&mut self) {
+        if self.written {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// Returns the entry of the auth file at `path` holding the credentials of
+/// `registry`, along with whether it names a helper ("credHelpers") or holds
+/// them itself ("auths").
+fn read_credentials(path: &Path, registry: &str) -> Result<Option<(&'static str, Value)>, Error> {
+    let text = fs::read_to_string(path).map_err(|source| Error::Path {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    // the error of serde_json would quote the file, and with it credentials
+    let file: Value = serde_json::from_str(&text)
+        .map_err(|_| Error::Config(format!("{} is not a valid auth file", path.display())))?;
+
+    for section in ["auths", "credHelpers"] {
+        let entry = file
+            .get(section)
+            .and_then(Value::as_object)
+            .and_then(|entries| {
+                entries
+                    .iter()
+                    .find(|(key, _)| key_registry(key) == registry)
+            });
+        if let Some((_, entry)) = entry {
+            return Ok(Some((section, entry.clone())));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Parts of the messages runtimes fail pulls with when a registry refuses
+/// them
+const REFUSED_MARKERS: &[&str] = &[
+    "401",
+    "403",
+    "unauthorized",
+    "authentication required",
+    "denied",
+];
+
+/// Returns a hint on authenticating to `registry` if the stderr of a failed
+/// pull shows that the registry refused it
+pub(crate) fn auth_hint(
+    registry: &str,
+    auth: Option<&AuthFile>,
+    stderr_tail: &[String],
+) -> Option<String> {
+    let refused = stderr_tail.iter().any(|line| {
+        let line = line.to_lowercase();
+        REFUSED_MARKERS.iter().any(|marker| line.contains(marker))
+    });
+    if !refused {
+        return None;
+    }
+
+    Some(if auth.is_some_and(|auth| auth.covers(registry)) {
+        format!(
+            "{} refused the credentials configured in registries.\"{}\"",
+            registry, registry
+        )
+    } else {
+        format!(
+            "{} requires authentication, set registries.\"{}\".credential_helper or registries.\"{}\".auth_file in the config",
+            registry, registry, registry
+        )
+    })
+}
@@ -0,0 +1,3360 @@
+//! Starting and entering the containers environments run in.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, DirBuilder, OpenOptions},
+    io::{self, IsTerminal, Write},
+    net::{Ipv4Addr, TcpListener, UdpSocket},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use directories_next::BaseDirs;
+
+use crate::{
+    backend::{ExecSpec, RunSpec},
+    build::{
+        build_dev_image, build_environment_image, image_exists, image_home, image_repository,
+        is_image_id, stable_hash, workspace_hash, workspace_label, yadt_labels, BuildOptions,
+        DevImageSource, EnvironmentImage, HostUser, CONFIG_HASH_LABEL, GPU_LABEL, LATEST_TAG,
+        YADT_LABEL,
+    },
+    check::check_packages,
+    config::{
+        add_cli_packages, add_cli_presets, canonicalize, data_dir, expand_home, resolve_workspace,
+        toggle_cli_groups, validate_capability, validate_platform, validate_size, validate_tmpfs,
+        Audio, Config, ContainerSocket, Gpu, PullPolicy, ResourceLimits, SelinuxLabel,
+    },
+    devcontainer,
+    env_file::{self, WORKSPACE_ENV_FILE},
+    error::Error,
+    events::{self, Event},
+    logs::HookLog,
+    manage::{confirm, newest_environment_image},
+    progress::{self, format_elapsed},
+    query::{self, query},
+    registry::AuthFile,
+    runtime::{check_status, render_command, stderr_lines, DryRun, Runtime, RuntimeKind},
+    state::{self, State},
+};
+
+/// Script run with `sh -c` to start the configured shell, passed as `$0`, as a
+/// login shell, falling back to bash if it can't be found. Whether the
+/// workspace is read-only is announced first, since saves failing would
+/// otherwise be confusing. With `on_enter` snippets, each shell is pointed at
+/// the startup files generated by `on_enter_dir` in its own way.
+static SHELL_LAUNCHER: &str = r#"if [ -n "$YADT_READ_ONLY_WORKSPACE" ]; then
+    echo "yadt: the workspace is read-only, changes to it will fail" >&2
+fi
+shell="$0"
+if ! command -v "$shell" >/dev/null 2>&1; then
+    echo "yadt: shell $shell not found, falling back to bash" >&2
+    shell=bash
+fi
+if [ -n "$YADT_ON_ENTER" ]; then
+    case "${shell##*/}" in
+        bash) exec "$shell" --rcfile "$YADT_ON_ENTER/bashrc" -i ;;
+        zsh) export ZDOTDIR="$YADT_ON_ENTER/zsh"; exec "$shell" -l ;;
+        fish) exec "$shell" -l -C "source $YADT_ON_ENTER/on-enter.sh" ;;
+        *) export ENV="$YADT_ON_ENTER/on-enter.sh"; exec "$shell" -l ;;
+    esac
+fi
+exec "$shell" -l"#;
+
+/// Where the workspace is mounted unless `workspace_mount` says otherwise
+pub const DEFAULT_WORKSPACE_MOUNT: &str = "/workspace";
+
+/// Container paths the workspace can't be mounted at or under, since the
+/// environment needs what the image has there
+static RESERVED_MOUNT_TREES: [&str; 6] =
+    ["/nix", "/proc", "/sys", "/dev", "/yadt-bin", "/run/yadt"];
+
+/// Container paths the workspace can't be mounted at, though it can be
+/// mounted under them
+static RESERVED_MOUNT_PATHS: [&str; 12] = [
+    "/", "/bin", "/etc", "/home", "/lib", "/lib64", "/root", "/run", "/sbin", "/tmp", "/usr",
+    "/var",
+];
+
+/// Resolves where `workspace` is mounted in the container from the
+/// configured `mount`, where "host" stands for the workspace's own path.
+pub fn workspace_mount_path(mount: Option<&str>, workspace: &Path) -> Result<String, Error> {
+    let path = match mount {
+        None => return Ok(DEFAULT_WORKSPACE_MOUNT.to_string()),
+        Some("host") => workspace.to_string_lossy().into_owned(),
+        Some(path) => match path.trim_end_matches('/') {
+            "" if path.starts_with('/') => "/".to_string(),
+            trimmed => trimmed.to_string(),
+        },
+    };
+    let invalid = |reason: &str| {
+        Error::Config(format!(
+            "Invalid workspace mount \"{}\": {}",
+            mount.unwrap_or_default(),
+            reason
+        ))
+    };
+
+    if !path.starts_with('/') {
+        return Err(invalid("path must be absolute"));
+    }
+    if path.split('/').any(|part| part == "." || part == "..") {
+        return Err(invalid("path must not contain . or .."));
+    }
+    let is_under = |tree: &str| path == tree || path.starts_with(&format!("{}/", tree));
+    if let Some(tree) = RESERVED_MOUNT_TREES.into_iter().find(|tree| is_under(tree)) {
+        return Err(invalid(&format!("{} is needed by the environment", tree)));
+    }
+    if RESERVED_MOUNT_PATHS.contains(&path.as_str()) {
+        return Err(invalid("it would hide a directory the environment needs"));
+    }
+
+    Ok(path)
+}
+
+/// A workspace directory mounted into the container
+#[derive(Debug)]
+pub struct Mount {
+    /// Canonical path of the directory on the host
+    pub host: PathBuf,
+    pub container: String,
+}
+
+impl Mount {
+    /// Parses an `--extra-workspace` entry formatted as "HOST:CONTAINER",
+    /// where the container path is validated like `workspace_mount` and may
+    /// also be "host".
+    pub fn parse_extra(entry: &str) -> Result<Self, Error> {
+        let Some((host, container)) = entry.rsplit_once(':') else {
+            return Err(Error::Config(format!(
+                "Invalid extra workspace \"{}\": expected HOST:CONTAINER",
+                entry
+            )));
+        };
+
+        let host = canonicalize(&expand_home(host))?;
+        let container = workspace_mount_path(Some(container), &host)?;
+        Ok(Self { host, container })
+    }
+
+    /// Returns the host path of `path` if it is the mount's container path or
+    /// lies under it
+    fn host_path(&self, path: &str) -> Option<PathBuf> {
+        let rest = path.strip_prefix(&self.container)?;
+        if rest.is_empty() {
+            Some(self.host.clone())
+        } else {
+            // the root mount was rejected, so the container path never ends
+            // with a '/'
+            rest.strip_prefix('/').map(|rest| self.host.join(rest))
+        }
+    }
+}
+
+/// Returns a tmpfs for each of `dirs` under the working directory that exists
+/// on the host at `host_workdir`, keeping it writable in a read-only
+/// workspace. Mount points can't be created in a read-only mount, so missing
+/// directories are skipped. The tmpfs is owned by `user` and allows
+/// executables, which build outputs often are.
+fn writable_dir_tmpfs(
+    dirs: &[String],
+    workdir: &str,
+    host_workdir: &Path,
+    user: &HostUser,
+) -> Result<Vec<String>, Error> {
+    let mut tmpfs = Vec::new();
+    for dir in dirs {
+        let dir = dir.trim_end_matches('/');
+        if dir.is_empty()
+            || dir.starts_with('/')
+            || dir.split('/').any(|part| part == "." || part == "..")
+        {
+            return Err(Error::Config(format!(
+                "Invalid writable dir \"{}\": expected a path relative to the working directory",
+                dir
+            )));
+        }
+
+        if !host_workdir.join(dir).is_dir() {
+            log::debug!("Not covering {} with a tmpfs, it doesn't exist", dir);
+            continue;
+        }
+        tmpfs.push(format!(
+            "{}/{}:exec,mode=0755,uid={},gid={}",
+            workdir, dir, user.uid, user.gid
+        ));
+    }
+
+    Ok(tmpfs)
+}
+
+/// Checks that no two workspaces or volumes are mounted at the same path
+fn check_duplicate_mounts(mounts: &[Mount], volumes: &[VolumeSpec]) -> Result<(), Error> {
+    let mut paths: Vec<&str> = Vec::new();
+    for path in mounts
+        .iter()
+        .map(|mount| mount.container.as_str())
+        .chain(volumes.iter().map(|volume| volume.container.as_str()))
+    {
+        if paths.contains(&path) {
+            return Err(Error::Config(format!(
+                "More than one workspace or volume is mounted at {}",
+                path
+            )));
+        }
+        paths.push(path);
+    }
+
+    Ok(())
+}
+
+/// Resolves the initial working directory from `--workdir`, which may be
+/// relative to the workspace, the first of `mounts`. It must lie within one
+/// of the mounts and exist on the host. Without one, the workspace is the
+/// working directory.
+fn resolve_workdir(workdir: Option<&str>, mounts: &[Mount]) -> Result<String, Error> {
+    let workspace = &mounts[0];
+    let Some(workdir) = workdir else {
+        return Ok(workspace.container.clone());
+    };
+
+    let invalid =
+        |reason: &str| Error::Config(format!("Invalid workdir \"{}\": {}", workdir, reason));
+    let path = if workdir.starts_with('/') {
+        workdir.trim_end_matches('/').to_string()
+    } else {
+        format!("{}/{}", workspace.container, workdir.trim_end_matches('/'))
+    };
+    if path.split('/').any(|part| part == "." || part == "..") {
+        return Err(invalid("path must not contain . or .."));
+    }
+
+    let Some(host) = mounts.iter().find_map(|mount| mount.host_path(&path)) else {
+        return Err(invalid("it isn't inside a mounted workspace"));
+    };
+    if !host.is_dir() {
+        return Err(invalid(&format!("{} is not a directory", host.display())));
+    }
+
+    Ok(path)
+}
+
+/// Formats the value of a `--volume` argument mounting `host` at `container`
+/// with the given mount options.
+pub(crate) fn volume_arg(host: &Path, container: &str, options: &[&str]) -> OsString {
+    let mut arg = host.as_os_str().to_os_string();
+    arg.push(":");
+    arg.push(container);
+    if !options.is_empty() {
+        arg.push(":");
+        arg.push(options.join(","));
+    }
+    arg
+}
+
+/// A parsed "HOST:CONTAINER[:OPTIONS]" volume, as given in the config or with
+/// `--volume`
+#[derive(Debug)]
+pub struct VolumeSpec {
+    /// Canonical path of the host side of the volume
+    pub host: PathBuf,
+    pub container: String,
+    pub options: Vec<String>,
+}
+
+impl VolumeSpec {
+    /// Parses and validates a volume entry, resolving its host path. Errors
+    /// name the offending entry so that they can be reported before anything
+    /// is built.
+    pub fn parse(entry: &str) -> Result<Self, Error> {
+        let invalid =
+            |reason: &str| Error::Config(format!("Invalid volume \"{}\": {}", entry, reason));
+
+        let mut parts = entry.splitn(3, ':');
+        let host = parts.next().filter(|host| !host.is_empty());
+        let container = parts.next().filter(|container| !container.is_empty());
+        let options = parts.next();
+
+        let (Some(host), Some(container)) = (host, container) else {
+            return Err(invalid("expected HOST:CONTAINER[:OPTIONS]"));
+        };
+        if !container.starts_with('/') {
+            return Err(invalid("container path must be absolute"));
+        }
+
+        let host = fs::canonicalize(expand_home(host))
+            .map_err(|e| invalid(&format!("host path {}: {}", host, e)))?;
+
+        Ok(Self {
+            host,
+            container: container.to_string(),
+            options: options
+                .into_iter()
+                .flat_map(|options| options.split(','))
+                .filter(|option| !option.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+
+    /// Formats the spec as a `--volume` argument, adding `selinux_label`
+    /// unless a relabeling option was given explicitly.
+    pub fn to_arg(&self, selinux_label: SelinuxLabel) -> OsString {
+        let mut options: Vec<&str> = self.options.iter().map(String::as_str).collect();
+        if !options
+            .iter()
+            .any(|option| *option == "z" || *option == "Z")
+        {
+            options.extend(selinux_label.volume_option());
+        }
+        volume_arg(&self.host, &self.container, &options)
+    }
+}
+
+/// A parsed "HOST:CONTAINER[/PROTOCOL]" port, as given in the config or with
+/// `--publish`
+#[derive(Debug, PartialEq, Eq)]
+pub struct PortSpec {
+    host: u16,
+    container: u16,
+
+    /// One of "tcp", "udp", or "sctp"
+    protocol: String,
+}
+
+impl PortSpec {
+    /// Parses and validates a port entry. The protocol defaults to tcp.
+    pub fn parse(entry: &str) -> Result<Self, Error> {
+        let invalid =
+            |reason: &str| Error::Config(format!("Invalid port \"{}\": {}", entry, reason));
+
+        let (ports, protocol) = entry.split_once('/').unwrap_or((entry, "tcp"));
+        if !matches!(protocol, "tcp" | "udp" | "sctp") {
+            return Err(invalid("protocol must be tcp, udp, or sctp"));
+        }
+        let Some((host, container)) = ports.split_once(':') else {
+            return Err(invalid("expected HOST:CONTAINER[/PROTOCOL]"));
+        };
+
+        let port = |port: &str| {
+            port.parse::<u16>()
+                .ok()
+                .filter(|port| *port != 0)
+                .ok_or_else(|| invalid(&format!("{} is not a port between 1 and 65535", port)))
+        };
+
+        Ok(Self {
+            host: port(host)?,
+            container: port(container)?,
+            protocol: protocol.to_string(),
+        })
+    }
+
+    /// Formats the spec as a `--publish` argument
+    pub fn to_arg(&self) -> String {
+        format!("{}:{}/{}", self.host, self.container, self.protocol)
+    }
+
+    /// Returns whether another process is already listening on the host
+    /// port. Only tcp and udp ports are checked.
+    fn in_use(&self) -> bool {
+        let address = (Ipv4Addr::UNSPECIFIED, self.host);
+        let bound = match self.protocol.as_str() {
+            "tcp" => TcpListener::bind(address).map(drop),
+            "udp" => UdpSocket::bind(address).map(drop),
+            _ => return false,
+        };
+        matches!(bound, Err(error) if error.kind() == io::ErrorKind::AddrInUse)
+    }
+}
+
+/// Parses port entries, failing if a host port is published twice or is
+/// already taken on the host, which the runtime would only report once the
+/// container starts.
+pub fn port_specs<'a>(
+    entries: impl IntoIterator<Item = &'a String>,
+) -> Result<Vec<PortSpec>, Error> {
+    let mut ports: Vec<PortSpec> = Vec::new();
+    for entry in entries {
+        let port = PortSpec::parse(entry)?;
+        if let Some(other) = ports
+            .iter()
+            .find(|other| other.host == port.host && other.protocol == port.protocol)
+        {
+            return Err(Error::Config(format!(
+                "Host port {}/{} is published twice, by \"{}\" and \"{}\"",
+                port.host,
+                port.protocol,
+                other.to_arg(),
+                entry
+            )));
+        }
+        if port.in_use() {
+            return Err(Error::Conflict(format!(
+                "Host port {}/{} is already in use",
+                port.host, port.protocol
+            )));
+        }
+        ports.push(port);
+    }
+
+    Ok(ports)
+}
+
+/// Returns the environment variables to set in the container, formatted as
+/// "NAME=VALUE". `passthrough` names and entries without a value take the
+/// host's value, and are skipped when it is unset, while "NAME=VALUE" entries
+/// override earlier ones.
+pub fn environment_variables(
+    passthrough: &[String],
+    entries: &[String],
+) -> Result<Vec<OsString>, Error> {
+    let mut variables: Vec<(&str, OsString)> = Vec::new();
+    for entry in passthrough.iter().chain(entries) {
+        let (name, value) = match entry.split_once('=') {
+            Some((name, value)) => (name, Some(OsString::from(value))),
+            None => (entry.as_str(), env::var_os(entry)),
+        };
+
+        let valid_name = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+        if !valid_name {
+            return Err(Error::Config(format!(
+                "Invalid environment variable \"{}\"",
+                entry
+            )));
+        }
+
+        let Some(value) = value else {
+            continue;
+        };
+        match variables.iter_mut().find(|(other, _)| *other == name) {
+            Some((_, existing)) => *existing = value,
+            None => variables.push((name, value)),
+        }
+    }
+
+    // each variable is its own argument, so values need no quoting
+    Ok(variables
+        .into_iter()
+        .map(|(name, value)| {
+            let mut variable = OsString::from(name);
+            variable.push("=");
+            variable.push(value);
+            variable
+        })
+        .collect())
+}
+
+/// A file the host keeps using that is mounted into the container, such as
+/// an agent's socket
+#[derive(Debug)]
+struct HostFile {
+    host: PathBuf,
+    container: String,
+    read_only: bool,
+}
+
+impl HostFile {
+    /// Formats the `--volume` argument mounting the file. Host files are owned
+    /// by the host user, who the container runs as through `--user` and
+    /// podman's keep-id, so only SELinux can keep the container from using
+    /// them. Relabeling is always shared, since a private label would cut the
+    /// host itself off from the file.
+    fn to_arg(&self, selinux_label: SelinuxLabel) -> OsString {
+        let mut options = Vec::new();
+        if self.read_only {
+            options.push("ro");
+        }
+        if selinux_label != SelinuxLabel::Off {
+            options.push("z");
+        }
+        volume_arg(&self.host, &self.container, &options)
+    }
+}
+
+/// Where the host's ssh agent socket is mounted in the container
+pub const SSH_AGENT_SOCKET: &str = "/run/yadt/ssh-agent.sock";
+
+/// Returns the host's ssh agent socket, or None if `SSH_AUTH_SOCK` is unset.
+/// The socket must exist on the host, as runtimes refuse to mount missing
+/// paths, eg. a stale `/run/user/<uid>` socket of an earlier session.
+fn ssh_agent_socket() -> Option<HostFile> {
+    let socket = env::var_os("SSH_AUTH_SOCK").filter(|socket| !socket.is_empty())?;
+    match fs::canonicalize(&socket) {
+        Ok(socket) => Some(HostFile {
+            host: socket,
+            container: SSH_AGENT_SOCKET.to_string(),
+            read_only: false,
+        }),
+        Err(error) => {
+            log::warn!(
+                "Not forwarding the ssh agent, SSH_AUTH_SOCK {}: {}",
+                Path::new(&socket).display(),
+                error
+            );
+            None
+        }
+    }
+}
+
+/// Returns the host's git config files in `host_home`, mounted read only into
+/// the container user's `container_home`, along with the `GIT_CONFIG_GLOBAL`
+/// naming the mounted `.gitconfig`. The variable keeps git reading it even
+/// when the container user's home is elsewhere. Missing files are skipped.
+fn git_identity_files(host_home: &Path, container_home: &str) -> (Vec<HostFile>, Option<String>) {
+    // dotfile managers often symlink the config, and the link's target
+    // doesn't exist in the container
+    let mount = |path: &str| {
+        fs::canonicalize(host_home.join(path))
+            .ok()
+            .map(|host| HostFile {
+                host,
+                container: format!("{}/{}", container_home, path),
+                read_only: true,
+            })
+    };
+
+    let gitconfig = mount(".gitconfig");
+    let config_global = gitconfig
+        .as_ref()
+        .map(|file| format!("GIT_CONFIG_GLOBAL={}", file.container));
+    let files = gitconfig.into_iter().chain(mount(".config/git")).collect();
+
+    (files, config_global)
+}
+
+/// The host's directory of X server sockets, mounted at the same path
+static X11_SOCKET_DIR: &str = "/tmp/.X11-unix";
+
+/// Where the generated xauth cookie is mounted in the container
+pub const XAUTHORITY: &str = "/run/yadt/Xauthority";
+
+/// Returns the X server named by `DISPLAY` along with the directory of its
+/// sockets, failing if it isn't set
+fn x11_display() -> Result<(String, HostFile), Error> {
+    let display = env::var("DISPLAY")
+        .ok()
+        .filter(|display| !display.is_empty())
+        .ok_or_else(|| {
+            Error::Config(
+                "x11 is enabled but DISPLAY isn't set, is an X server running?".to_string(),
+            )
+        })?;
+
+    let sockets = HostFile {
+        host: PathBuf::from(X11_SOCKET_DIR),
+        container: X11_SOCKET_DIR.to_string(),
+        read_only: false,
+    };
+    Ok((display, sockets))
+}
+
+/// Returns the xauth cookie of `display` for `container`, in a private
+/// directory of its own, or None with a warning if there is none.
+/// `~/.Xauthority` only holds cookies for the host's hostname, so the cookie
+/// of the display is copied into a file of its own, with its family rewritten
+/// to match any host. In dry runs nothing is written.
+fn xauth_cookie(
+    display: &str,
+    container: &str,
+    dry_run: bool,
+) -> Result<Option<(HostFile, PrivateDir)>, Error> {
+    // the cookie grants access to the display, so only the user may read it
+    let dir = PrivateDir::create("xauth", container, dry_run)?;
+    let cookie = dir.path.join("Xauthority");
+    if !dry_run {
+        // servers allowing local connections with xhost need no cookie
+        if let Err(error) = write_xauth_cookie(display, &cookie) {
+            log::warn!("Not forwarding an xauth cookie, {}", error);
+            return Ok(None);
+        }
+    }
+
+    let file = HostFile {
+        host: cookie,
+        container: XAUTHORITY.to_string(),
+        read_only: true,
+    };
+    Ok(Some((file, dir)))
+}
+
+/// Writes the xauth cookie of `display` into the new file `path` with
+/// `xauth nmerge`, after replacing the family of each entry with "ffff", for
+/// any host
+fn write_xauth_cookie(display: &str, path: &Path) -> Result<(), String> {
+    // xauth runs on the host, not through the container runtime
+    let output = process::Command::new("xauth")
+        .args(["nlist", display])
+        .output()
+        .map_err(|error| format!("xauth: {}", error))?;
+    let cookies: String = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.len() > 4)
+        .map(|line| format!("ffff{}\n", &line[4..]))
+        .collect();
+    if !output.status.success() || cookies.is_empty() {
+        return Err(format!("xauth has no cookie for {}", display));
+    }
+
+    let mut merge = process::Command::new("xauth")
+        .arg("-f")
+        .arg(path)
+        .args(["nmerge", "-"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|error| format!("xauth: {}", error))?;
+    merge
+        .stdin
+        .take()
+        .expect("stdin of xauth is piped")
+        .write_all(cookies.as_bytes())
+        .map_err(|error| format!("xauth: {}", error))?;
+    match merge.wait() {
+        Ok(status) if status.success() => {}
+        Ok(_) => return Err("`xauth nmerge` failed".to_string()),
+        Err(error) => return Err(format!("xauth: {}", error)),
+    }
+
+    // xauth creates the file according to the umask
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .map_err(|error| format!("{}: {}", path.display(), error))
+}
+
+/// Where the startup files sourcing `on_enter` snippets are mounted
+pub const ON_ENTER_DIR: &str = "/run/yadt/on-enter";
+
+/// Startup file bash is started with through `--rcfile`, which replaces the
+/// files of a login shell, so they are sourced as bash would before the
+/// snippets
+static ON_ENTER_BASHRC: &str = r#"[ -f /etc/profile ] && . /etc/profile
+if [ -f ~/.bash_profile ]; then
+    . ~/.bash_profile
+elif [ -f ~/.bash_login ]; then
+    . ~/.bash_login
+elif [ -f ~/.profile ]; then
+    . ~/.profile
+fi
+. "$YADT_ON_ENTER/on-enter.sh"
+"#;
+
+/// zsh startup files read from `ZDOTDIR`, each sourcing its counterpart in
+/// the home directory. `.zshrc` then hands the remaining files back to the
+/// home directory and sources the snippets.
+static ON_ENTER_ZSH_FILES: [(&str, &str); 3] = [
+    (".zshenv", "[ -f ~/.zshenv ] && . ~/.zshenv\n"),
+    (".zprofile", "[ -f ~/.zprofile ] && . ~/.zprofile\n"),
+    (
+        ".zshrc",
+        "ZDOTDIR=$HOME\n[ -f ~/.zshrc ] && . ~/.zshrc\n. \"$YADT_ON_ENTER/on-enter.sh\"\n",
+    ),
+];
+
+/// A directory only the user may access, for files generated for a
+/// container and mounted into it. It is removed when dropped, so that it
+/// doesn't outlive a run that fails or is interrupted, unless it is kept for
+/// a container that outlives yadt.
+#[derive(Debug)]
+struct PrivateDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl PrivateDir {
+    /// Creates the directory of `kind` for `container` in the host's
+    /// `XDG_RUNTIME_DIR`, or its temporary directory without one
+    fn create(kind: &str, container: &str, dry_run: bool) -> Result<Self, Error> {
+        let base = env::var_os("XDG_RUNTIME_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_dir())
+            .unwrap_or_else(env::temp_dir);
+        Self::create_in(&base, kind, container, dry_run)
+    }
+
+    /// Creates the directory of `kind` for `container` in `base`, replacing
+    /// the one its previous container with that name left behind. In dry
+    /// runs nothing is written, and nothing is removed once dropped.
+    fn create_in(base: &Path, kind: &str, container: &str, dry_run: bool) -> Result<Self, Error> {
+        // names given with --name are only checked by the runtime
+        let container: String = container
+            .chars()
+            .map(|c| match c {
+                c if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') => c,
+                _ => '_',
+            })
+            .collect();
+        let path = base.join(format!("yadt-{}-{}", container, kind));
+        if dry_run {
+            return Ok(Self { path, keep: true });
+        }
+
+        let failed = |source| Error::Path {
+            path: path.clone(),
+            source,
+        };
+        match fs::remove_dir_all(&path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(failed(error)),
+            _ => {}
+        }
+        DirBuilder::new()
+            .mode(0o700)
+            .create(&path)
+            .map_err(failed)?;
+        Ok(Self { path, keep: false })
+    }
+
+    /// Leaves the directory in place for the container mounting it
+    fn keep(mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+/// Writes `contents` into the new file `path`, failing if it already exists
+fn write_new(path: PathBuf, contents: &str) -> Result<(), Error> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|source| Error::Path { path, source })
+}
+
+/// Writes the `on_enter` snippets and the startup files of each shell
+/// sourcing them into the private directory of `container`, returning it to
+/// be mounted at `ON_ENTER_DIR`. In dry runs nothing is written.
+fn on_enter_dir(
+    snippets: &[String],
+    container: &str,
+    dry_run: bool,
+) -> Result<(HostFile, PrivateDir), Error> {
+    let private_dir = PrivateDir::create("on-enter", container, dry_run)?;
+    let dir = &private_dir.path;
+    let file = HostFile {
+        host: dir.clone(),
+        container: ON_ENTER_DIR.to_string(),
+        read_only: true,
+    };
+    if dry_run {
+        return Ok((file, private_dir));
+    }
+
+    let zsh_dir = dir.join("zsh");
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&zsh_dir)
+        .map_err(|source| Error::Path {
+            path: zsh_dir.clone(),
+            source,
+        })?;
+    write_new(dir.join("on-enter.sh"), &(snippets.join("\n") + "\n"))?;
+    write_new(dir.join("bashrc"), ON_ENTER_BASHRC)?;
+    for (name, contents) in ON_ENTER_ZSH_FILES {
+        write_new(zsh_dir.join(name), contents)?;
+    }
+
+    Ok((file, private_dir))
+}
+
+/// Returns the host's wayland socket mounted into the container user's
+/// `XDG_RUNTIME_DIR`, and the name it is mounted as, or None with a warning if
+/// the host has no wayland session.
+fn wayland_socket(user: &HostUser) -> Option<(HostFile, String)> {
+    let var = |name| env::var_os(name).filter(|value| !value.is_empty());
+    let (Some(display), Some(runtime_dir)) = (var("WAYLAND_DISPLAY"), var("XDG_RUNTIME_DIR"))
+    else {
+        log::warn!("Not forwarding wayland, WAYLAND_DISPLAY or XDG_RUNTIME_DIR isn't set");
+        return None;
+    };
+
+    // WAYLAND_DISPLAY may also be an absolute path to the socket
+    let socket = Path::new(&runtime_dir).join(&display);
+    if !socket.exists() {
+        log::warn!(
+            "Not forwarding wayland, its socket {} doesn't exist",
+            socket.display()
+        );
+        return None;
+    }
+
+    let name = socket.file_name()?.to_string_lossy().into_owned();
+    Some((
+        HostFile {
+            host: socket,
+            container: format!("{}/{}", container_runtime_dir(user), name),
+            read_only: false,
+        },
+        name,
+    ))
+}
+
+/// Sockets of the host's audio servers in its `XDG_RUNTIME_DIR`, along with
+/// the variable pointing clients at each, which are `PIPEWIRE_REMOTE` for
+/// native PipeWire clients and `PULSE_SERVER` for PulseAudio clients,
+/// including those of pipewire-pulse
+static AUDIO_SOCKETS: [(&str, &str, &str); 2] = [
+    ("pipewire-0", "PIPEWIRE_REMOTE", ""),
+    ("pulse/native", "PULSE_SERVER", "unix:"),
+];
+
+/// Returns the host's audio sockets mounted into the container user's
+/// `XDG_RUNTIME_DIR`, along with the variables pointing clients at them, or
+/// nothing with a warning if the host has no audio session.
+fn audio_sockets(user: &HostUser) -> (Vec<HostFile>, Vec<String>) {
+    let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) else {
+        log::warn!("Not forwarding audio, XDG_RUNTIME_DIR isn't set");
+        return (Vec::new(), Vec::new());
+    };
+
+    let mut files = Vec::new();
+    let mut variables = Vec::new();
+    for (socket, variable, scheme) in AUDIO_SOCKETS {
+        let host = Path::new(&runtime_dir).join(socket);
+        if !host.exists() {
+            continue;
+        }
+        let container = format!("{}/{}", container_runtime_dir(user), socket);
+        variables.push(format!("{}={}{}", variable, scheme, container));
+        files.push(HostFile {
+            host,
+            container,
+            read_only: false,
+        });
+    }
+
+    if files.is_empty() {
+        log::warn!(
+            "Not forwarding audio, neither PipeWire nor PulseAudio is running in {}",
+            Path::new(&runtime_dir).display()
+        );
+    }
+    (files, variables)
+}
+
+/// Returns the container user's `XDG_RUNTIME_DIR`
+fn container_runtime_dir(user: &HostUser) -> String {
+    format!("/run/user/{}", user.uid)
+}
+
+/// Returns the tmpfs mounts and `/dev/shm` size configured for the
+/// environment, with the `tmpfs` of the command line mounted after the
+/// config's and its `shm_size` winning over the config's. The config's were
+/// validated when it was loaded.
+fn scratch_space(
+    config: &Config,
+    tmpfs: &[String],
+    shm_size: Option<String>,
+) -> Result<(Vec<String>, Option<String>), Error> {
+    for entry in tmpfs {
+        validate_tmpfs("--tmpfs", entry)?;
+    }
+    if let Some(shm_size) = &shm_size {
+        validate_size("--shm-size", shm_size)?;
+    }
+
+    let tmpfs = config.tmpfs.iter().chain(tmpfs).cloned().collect();
+    Ok((tmpfs, shm_size.or(config.shm_size.clone())))
+}
+
+/// Returns the tmpfs holding the container user's `XDG_RUNTIME_DIR`. Mount
+/// points are created owned by root, while clients such as wayland's insist
+/// on a runtime dir only the user can access. The user keeps its host uid in
+/// the container on either runtime, so the uid can be given as is.
+fn runtime_dir_tmpfs(user: &HostUser) -> String {
+    format!(
+        "{}:mode=0700,uid={},gid={}",
+        container_runtime_dir(user),
+        user.uid,
+        user.gid
+    )
+}
+
+/// Directories runtimes look up CDI specs in, one of which podman needs to
+/// have a spec for nvidia GPUs
+static CDI_SPEC_DIRS: [&str; 2] = ["/etc/cdi", "/var/run/cdi"];
+
+/// Returns whether `program` is an executable on `PATH`
+fn on_path(program: &str) -> bool {
+    env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
+
+/// Checks that the host is set up to pass `gpu` into containers run with
+/// `runtime`, which would otherwise start without the GPU or fail with a
+/// cryptic error.
+fn check_gpu(runtime: &Runtime, gpu: Gpu) -> Result<(), Error> {
+    if gpu.includes_dri() && !Path::new("/dev/dri").exists() {
+        return Err(Error::Config(format!(
+            "gpu = \"{}\" needs /dev/dri, which the host doesn't have. Is a GPU driver loaded?",
+            gpu.name()
+        )));
+    }
+
+    if !gpu.includes_nvidia() {
+        return Ok(());
+    }
+    if !Path::new("/dev/nvidiactl").exists() {
+        return Err(Error::Config(format!(
+            "gpu = \"{}\" needs /dev/nvidiactl, which the host doesn't have. Is the nvidia driver loaded?",
+            gpu.name()
+        )));
+    }
+
+    if runtime.kind == RuntimeKind::Podman {
+        let has_spec = CDI_SPEC_DIRS.iter().any(|dir| {
+            fs::read_dir(dir).is_ok_and(|mut entries| {
+                entries.any(|entry| {
+                    entry.is_ok_and(|entry| {
+                        entry.file_name().to_string_lossy().starts_with("nvidia")
+                    })
+                })
+            })
+        });
+        if !has_spec {
+            return Err(Error::Config(
+                "podman needs a CDI spec for nvidia GPUs, generate one with `sudo nvidia-ctk cdi generate --output=/etc/cdi/nvidia.yaml`".to_string(),
+            ));
+        }
+    } else if !on_path("nvidia-ctk") && !on_path("nvidia-container-runtime") {
+        return Err(Error::Config(format!(
+            "{} needs the nvidia container toolkit for nvidia GPUs, install it and run `sudo nvidia-ctk runtime configure`",
+            runtime.name
+        )));
+    }
+
+    Ok(())
+}
+
+/// Packages `--debug-tools` installs
+const DEBUG_TOOLS_PACKAGES: [&str; 3] = ["gdb", "strace", "perf"];
+
+/// Capabilities `--debug-tools` grants, to attach to processes and read
+/// performance counters
+const DEBUG_TOOLS_CAPABILITIES: [&str; 2] = ["SYS_PTRACE", "PERFMON"];
+
+/// The default seccomp profile blocks `perf_event_open`, and `ptrace` on
+/// older kernels
+const DEBUG_TOOLS_SECURITY_OPT: &str = "seccomp=unconfined";
+
+/// Capabilities that give the environment control over the host
+const DANGEROUS_CAPABILITIES: [&str; 5] =
+    ["ALL", "SYS_ADMIN", "SYS_MODULE", "SYS_RAWIO", "NET_ADMIN"];
+
+/// Warns in one line about settings that weaken the environment's isolation
+/// from the host, which are easy to leave on once debugging is done.
+fn warn_weakened_isolation(config: &Config) {
+    let mut weakened: Vec<&str> = config
+        .cap_add
+        .iter()
+        .map(|capability| capability.as_str())
+        .filter(|capability| {
+            let name = capability.to_ascii_uppercase();
+            DANGEROUS_CAPABILITIES.contains(&name.trim_start_matches("CAP_"))
+        })
+        .collect();
+    weakened.extend(
+        config
+            .security_opt
+            .iter()
+            .map(String::as_str)
+            .filter(|option| option.ends_with("=unconfined") || *option == "label=disable"),
+    );
+
+    if !weakened.is_empty() {
+        log::warn!(
+            "The environment runs with {}, which weakens its isolation from the host",
+            weakened.join(", ")
+        );
+    }
+}
+
+/// Warns that the environment is about to run privileged and asks the user
+/// to confirm it, unless `yes` is set. Capabilities and security options
+/// would be overridden by `--privileged`, so they may not be configured.
+fn confirm_privileged(config: &Config, yes: bool, dry_run: DryRun) -> Result<(), Error> {
+    if !config.cap_add.is_empty() || !config.cap_drop.is_empty() || !config.security_opt.is_empty()
+    {
+        return Err(Error::Config(
+            "--privileged can't be combined with cap_add, cap_drop, or security_opt".to_string(),
+        ));
+    }
+
+    log::warn!(
+        "--privileged gives the environment every capability and the host's devices, so anything it runs can take over the host"
+    );
+    if yes || dry_run != DryRun::Off {
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() {
+        return Err(Error::Config(
+            "Pass --yes to run privileged when stdin is not a terminal".to_string(),
+        ));
+    }
+    if !confirm("Run the environment privileged?")? {
+        return Err(Error::Conflict(
+            "Not running the environment privileged".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Starts the podman machine of `runtime` if it isn't running, asking the
+/// user first unless `start` is set
+fn start_machine(runtime: &Runtime, start: bool) -> Result<(), Error> {
+    let Some(machine) = runtime.machine.as_ref().filter(|machine| !machine.running) else {
+        return Ok(());
+    };
+
+    if !start && !runtime.is_dry_run() {
+        let not_running = || {
+            Error::Runtime(format!(
+                "podman machine {} isn't running, start it with `podman machine start {}` or pass --start-machine",
+                machine.name, machine.name
+            ))
+        };
+        if !io::stdin().is_terminal() {
+            return Err(not_running());
+        }
+        if !confirm(&format!(
+            "podman machine {} isn't running, start it?",
+            machine.name
+        ))? {
+            return Err(not_running());
+        }
+    }
+    runtime.apply(
+        &format!("Starting podman machine {}", machine.name),
+        &["machine", "start", &machine.name],
+    )
+}
+
+/// Returns the line summing up the environment image a run uses: its tag,
+/// its size, and how long building it took, or that it was reused
+fn build_summary(runtime: &Runtime, image: &EnvironmentImage, elapsed: Duration) -> String {
+    let mut details = Vec::new();
+    // the summary is only informative, so a failed inspect leaves the size out
+    let size = query(
+        runtime,
+        &format!("Inspecting {}", image.tag),
+        &["image", "inspect", "--format", "{{.Size}}", &image.tag],
+    );
+    if let Some(size) = size.ok().and_then(|size| size.trim().parse().ok()) {
+        details.push(query::format_size(size));
+    }
+    details.push(if image.built {
+        format!("built in {}", format_elapsed(elapsed))
+    } else {
+        "cached".to_string()
+    });
+
+    format!("Environment image {} ({})", image.tag, details.join(", "))
+}
+
+/// Returns the snapshot `yadt snapshot --use` set the environment of
+/// `workspace` to start from, if its image still exists
+fn snapshot_image(runtime: &Runtime, workspace: &Path) -> Result<Option<EnvironmentImage>, Error> {
+    let Some(tag) = State::load(runtime)?
+        .get(workspace)
+        .and_then(|entry| entry.snapshot.clone())
+    else {
+        return Ok(None);
+    };
+    let Some(image) = query::list_images(runtime)?
+        .into_iter()
+        .find(|image| image.tags.contains(&tag))
+    else {
+        log::warn!(
+            "The snapshot {} the environment was set to start from is gone, building it from the config instead",
+            tag
+        );
+        return Ok(None);
+    };
+
+    log::warn!(
+        "Starting from the snapshot {}, which is no longer reproducible from the config. `yadt snapshot --discard` goes back to building it from the config",
+        tag
+    );
+    Ok(Some(EnvironmentImage {
+        id: image.id,
+        tag,
+        // the snapshot keeps the labels of the container it was committed from
+        config_hash: image.config_hash.unwrap_or_default(),
+        built: false,
+    }))
+}
+
+/// Returns the architecture of `platform` in qemu's naming, eg. "aarch64"
+/// for "linux/arm64"
+pub(crate) fn platform_arch(platform: &str) -> &str {
+    match platform.split('/').nth(1).unwrap_or(platform) {
+        "amd64" => "x86_64",
+        "arm64" => "aarch64",
+        "386" => "i386",
+        "ppc64le" => "ppc64le",
+        arch => arch,
+    }
+}
+
+/// Checks that the host can run containers of `platform`, emulating it with
+/// qemu-user if its architecture is foreign, which would otherwise fail
+/// with an exec format error in the middle of the build.
+fn check_platform(platform: &str) -> Result<(), Error> {
+    let arch = platform_arch(platform);
+    if arch == env::consts::ARCH {
+        return Ok(());
+    }
+
+    let handler = Path::new("/proc/sys/fs/binfmt_misc").join(format!("qemu-{}", arch));
+    let enabled =
+        fs::read_to_string(&handler).is_ok_and(|handler| handler.lines().next() == Some("enabled"));
+    if !enabled {
+        return Err(Error::Config(format!(
+            "Running {} containers on {} needs qemu-{} registered with binfmt_misc. Install qemu-user-static, eg. `sudo apt install qemu-user-static binfmt-support` or `sudo dnf install qemu-user-static`, or register it with `sudo podman run --rm --privileged docker.io/tonistiigi/binfmt --install {}`",
+            platform,
+            env::consts::ARCH,
+            arch,
+            platform.split('/').nth(1).unwrap_or(arch)
+        )));
+    }
+
+    Ok(())
+}
+
+/// The environment's `GNUPGHOME`, where the host's gpg agent socket and
+/// public keyring are mounted
+pub const GNUPG_HOME: &str = "/run/yadt/gnupg";
+
+/// Files of the host's gnupg home mounted read only into `GNUPG_HOME`, so
+/// that gpg in the container knows the host's keys. They are skipped when
+/// missing on the host.
+static GNUPG_HOME_FILES: [&str; 2] = ["pubring.kbx", "trustdb.gpg"];
+
+/// Returns the host's gpg agent socket and keyring files, or None with a
+/// warning if gpg can't be located on the host. The agent is launched first,
+/// since it otherwise only starts along with gpg and its socket may not exist
+/// yet.
+fn gpg_agent_files() -> Option<Vec<HostFile>> {
+    let not_forwarding = |reason: &dyn std::fmt::Display| {
+        log::warn!("Not forwarding the gpg agent, {}", reason);
+        None
+    };
+
+    // gpgconf runs on the host, not through the container runtime
+    let _ = process::Command::new("gpgconf")
+        .args(["--launch", "gpg-agent"])
+        .stderr(Stdio::null())
+        .status();
+    let output = match process::Command::new("gpgconf").arg("--list-dirs").output() {
+        Ok(output) if output.status.success() => output,
+        Ok(_) => return not_forwarding(&"`gpgconf --list-dirs` failed"),
+        Err(error) => return not_forwarding(&format!("gpgconf: {}", error)),
+    };
+
+    // each line is "NAME:VALUE", with colons in values percent escaped
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let dir = |name: &str| {
+        stdout
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .map(|value| PathBuf::from(percent_decode(value)))
+    };
+    let (Some(socket), Some(home)) = (dir("agent-socket"), dir("homedir")) else {
+        return not_forwarding(&"gpgconf didn't list the agent socket and homedir");
+    };
+    if !socket.exists() {
+        return not_forwarding(&format!("its socket {} doesn't exist", socket.display()));
+    }
+
+    let mut files = vec![HostFile {
+        host: socket,
+        container: format!("{}/S.gpg-agent", GNUPG_HOME),
+        read_only: false,
+    }];
+    files.extend(
+        GNUPG_HOME_FILES
+            .iter()
+            .map(|file| (home.join(file), file))
+            .filter(|(host, _)| host.exists())
+            .map(|(host, file)| HostFile {
+                host,
+                container: format!("{}/{}", GNUPG_HOME, file),
+                read_only: true,
+            }),
+    );
+
+    Some(files)
+}
+
+/// Where the host's container socket is mounted in the container
+pub const CONTAINER_SOCKET: &str = "/run/yadt/container.sock";
+
+/// Returns the host's container socket selected by `socket` mounted at
+/// `CONTAINER_SOCKET`, along with the group owning it, which the container
+/// user needs to be in to connect to the docker daemon. Without a socket on
+/// the host, nothing is mounted.
+fn container_socket(socket: ContainerSocket) -> Option<(HostFile, Option<String>)> {
+    let host = match socket {
+        ContainerSocket::None => return None,
+        ContainerSocket::Podman => podman_socket()?,
+        ContainerSocket::Docker => {
+            let host = PathBuf::from(DOCKER_SOCKET);
+            if !host.exists() {
+                log::warn!(
+                    "Not mounting the docker socket, {} doesn't exist. Is the docker daemon running?",
+                    DOCKER_SOCKET
+                );
+                return None;
+            }
+            log::warn!("Mounting the docker socket gives the environment root access to the host");
+            host
+        }
+    };
+
+    let group = match socket {
+        ContainerSocket::Docker => fs::metadata(&host)
+            .ok()
+            .map(|metadata| metadata.gid().to_string()),
+        _ => None,
+    };
+    Some((
+        HostFile {
+            host,
+            container: CONTAINER_SOCKET.to_string(),
+            read_only: false,
+        },
+        group,
+    ))
+}
+
+/// The docker daemon's socket
+const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// Returns the socket of the user's podman service, asking podman where it
+/// is unless it is in the usual place, or None with a warning if the service
+/// isn't listening.
+fn podman_socket() -> Option<PathBuf> {
+    let usual = env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(&dir).join("podman/podman.sock"));
+    if let Some(socket) = usual.filter(|socket| socket.exists()) {
+        return Some(socket);
+    }
+
+    // podman runs on the host, not necessarily as the container runtime
+    let socket = process::Command::new("podman")
+        .args(["info", "--format", "{{.Host.RemoteSocket.Path}}"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| {
+            let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            PathBuf::from(path.strip_prefix("unix://").unwrap_or(&path))
+        })
+        .filter(|socket| socket.exists());
+    if socket.is_none() {
+        log::warn!(
+            "Not mounting the podman socket, the podman service isn't listening. Start it with `systemctl --user enable --now podman.socket`"
+        );
+    }
+    socket
+}
+
+/// Checks that the host lets a rootless podman inside the environment create
+/// its user namespaces and fuse-overlayfs mounts, which would otherwise only
+/// fail once the first nested container is started.
+fn check_nested_containers() -> Result<(), Error> {
+    if !Path::new("/dev/fuse").exists() {
+        return Err(Error::Config(
+            "nested_containers needs /dev/fuse, which the host doesn't have. Load the fuse module with `sudo modprobe fuse`".to_string(),
+        ));
+    }
+
+    let sysctl = |name: &str| {
+        fs::read_to_string(Path::new("/proc/sys").join(name))
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+    };
+    if sysctl("user/max_user_namespaces") == Some(0) {
+        return Err(Error::Config(
+            "nested_containers needs user namespaces, which the host disables with user.max_user_namespaces = 0. Allow them with `sudo sysctl user.max_user_namespaces=15000`".to_string(),
+        ));
+    }
+    // debian's own switch for unprivileged user namespaces
+    if sysctl("kernel/unprivileged_userns_clone") == Some(0) {
+        return Err(Error::Config(
+            "nested_containers needs unprivileged user namespaces, which the host disables with kernel.unprivileged_userns_clone = 0. Allow them with `sudo sysctl kernel.unprivileged_userns_clone=1`".to_string(),
+        ));
+    }
+    // ubuntu's apparmor only allows them to profiled binaries
+    if sysctl("kernel/apparmor_restrict_unprivileged_userns") == Some(1) {
+        return Err(Error::Config(
+            "nested_containers needs unprivileged user namespaces, which apparmor restricts with kernel.apparmor_restrict_unprivileged_userns = 1. Allow them with `sudo sysctl kernel.apparmor_restrict_unprivileged_userns=0`".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Decodes the "%XX" escapes gpgconf writes into values
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Prefix of the names of the named volumes created for `cache_volumes`
+pub const CACHE_VOLUME_PREFIX: &str = "yadt-cache-";
+
+/// Validates the configured cache volumes, returning the name of each volume
+/// along with the container path it is mounted at.
+pub fn cache_volume_specs(config: &Config) -> Result<Vec<(String, &str)>, Error> {
+    config
+        .cache_volumes
+        .iter()
+        .map(|(name, container)| {
+            let valid_name = name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+                && name.starts_with(|c: char| c.is_ascii_alphanumeric());
+            if !valid_name {
+                return Err(Error::Config(format!(
+                    "Invalid cache volume name \"{}\"",
+                    name
+                )));
+            }
+            if !container.starts_with('/') {
+                return Err(Error::Config(format!(
+                    "Invalid cache volume \"{}\": container path must be absolute",
+                    name
+                )));
+            }
+            Ok((
+                format!("{}{}", CACHE_VOLUME_PREFIX, name),
+                container.as_str(),
+            ))
+        })
+        .collect()
+}
+
+/// Returns whether the named volume `name` exists
+pub(crate) fn volume_exists(runtime: &Runtime, name: &str) -> Result<bool, Error> {
+    let mut command = runtime.command();
+    command
+        .arg("volume")
+        .arg("inspect")
+        .arg(name)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    Ok(runtime.status(&mut command)?.success())
+}
+
+/// Creates the named volume `name` unless it already exists, returning
+/// whether it was created. `volume create` isn't idempotent on podman, so the
+/// volume is inspected first.
+fn ensure_volume(runtime: &Runtime, name: &str) -> Result<bool, Error> {
+    if volume_exists(runtime, name)? {
+        return Ok(false);
+    }
+
+    runtime.apply(
+        &format!("Creating volume {}", name),
+        &["volume", "create", name],
+    )?;
+    Ok(true)
+}
+
+/// Prefix of the names of the named volumes persisting the container user's
+/// home with `persist_home`
+pub const HOME_VOLUME_PREFIX: &str = "yadt-home-";
+
+/// Returns the name of the volume persisting the home of `workspace`'s
+/// environments
+pub fn home_volume(workspace: &Path) -> String {
+    format!("{}{}", HOME_VOLUME_PREFIX, workspace_hash(workspace))
+}
+
+/// Prefix of the names of the named volumes dotfiles repositories are
+/// installed into
+pub const DOTFILES_VOLUME_PREFIX: &str = "yadt-dotfiles-";
+
+/// Script run with `sh -c` in the container user's home to clone the dotfiles
+/// repository, passed as `$0`, into `~/.dotfiles` and run the install
+/// command, passed as `$1`, from there
+static DOTFILES_INSTALLER: &str = r#"set -e
+git clone --depth 1 "$0" .dotfiles
+cd .dotfiles
+if [ -n "$1" ]; then
+    sh -c "$1"
+fi"#;
+
+/// Returns the name of the volume `repo` is installed into with `install`.
+/// Either changing makes for a new volume, so that the new dotfiles are
+/// installed afresh.
+fn dotfiles_volume(repo: &str, install: Option<&str>) -> String {
+    let input = format!("{}\0{}", repo, install.unwrap_or_default());
+    format!(
+        "{}{:016x}",
+        DOTFILES_VOLUME_PREFIX,
+        stable_hash(input.as_bytes())
+    )
+}
+
+/// Runs `spec`, which installs dotfiles into the freshly created `volume`.
+/// The volume is removed again if that fails, so that the next start retries
+/// instead of entering a half installed home.
+fn install_dotfiles(runtime: &Runtime, spec: &RunSpec, volume: &str) -> Result<(), Error> {
+    let mut command = runtime.command();
+    command.args(runtime.backend().run_args(spec));
+
+    if runtime.is_dry_run() {
+        println!("{}", render_command(&command));
+        return Ok(());
+    }
+
+    log::info!("Installing dotfiles into volume {}", volume);
+    events::keep_off_stdout(&mut command);
+    let status = runtime.status(&mut command)?;
+    if let Err(error) = check_status(runtime, "Installing dotfiles", status, &[]) {
+        runtime.apply(
+            &format!("Removing volume {}", volume),
+            &["volume", "rm", volume],
+        )?;
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+/// Runs the `on_create` command `hook` in the container described by `spec`,
+/// passing its output through and writing it to `log`, and failing with its
+/// exit code
+fn run_on_create(
+    runtime: &Runtime,
+    spec: &RunSpec,
+    hook: &str,
+    log: Option<&HookLog>,
+) -> Result<(), Error> {
+    let mut command = runtime.command();
+    command.args(runtime.backend().run_args(spec));
+
+    if runtime.is_dry_run() {
+        println!("{}", render_command(&command));
+        return Ok(());
+    }
+
+    log::info!("Running {}", hook);
+    events::keep_off_stdout(&mut command);
+    let status = match log {
+        Some(log) => log.run(runtime, &mut command, hook)?,
+        None => runtime.status(&mut command)?,
+    };
+    if status.success() {
+        return Ok(());
+    }
+
+    Err(Error::Hook {
+        command: hook.to_string(),
+        // a command killed by a signal has no exit code of its own
+        code: status
+            .code()
+            .and_then(|code| u8::try_from(code).ok())
+            .unwrap_or(1),
+    })
+}
+
+/// Network modes every runtime understands, while any other network is the
+/// name of a network created with the runtime
+static NETWORK_MODES: [&str; 3] = ["host", "bridge", "none"];
+
+/// Validates `network`, which is either one of `NETWORK_MODES` or a network
+/// name.
+pub fn validate_network(network: &str) -> Result<(), Error> {
+    let valid_name = network
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+        && network.starts_with(|c: char| c.is_ascii_alphanumeric());
+    if !valid_name {
+        return Err(Error::Config(format!(
+            "Invalid network \"{}\", expected host, bridge, none, or a network name",
+            network
+        )));
+    }
+
+    Ok(())
+}
+
+/// Makes sure the container can join `network`. A named network that doesn't
+/// exist is only created if `create` is set.
+fn ensure_network(runtime: &Runtime, network: &str, create: bool) -> Result<(), Error> {
+    if NETWORK_MODES.contains(&network) {
+        return Ok(());
+    }
+
+    let mut command = runtime.command();
+    command
+        .arg("network")
+        .arg("inspect")
+        .arg(network)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if runtime.status(&mut command)?.success() {
+        return Ok(());
+    }
+
+    if !create {
+        return Err(Error::Conflict(format!(
+            "Network {} doesn't exist, create it with `{} network create {}` or pass --create-network",
+            network, runtime.name, network
+        )));
+    }
+
+    runtime.apply(
+        &format!("Creating network {}", network),
+        &["network", "create", network],
+    )
+}
+
+/// Returns the name of the container for `workspace`, which must be
+/// canonical. The name combines the workspace's directory name, for
+/// readability, with a short hash of its path, so that environments for
+/// different workspaces can run at the same time.
+pub fn container_name(workspace: &Path) -> String {
+    let basename: String = workspace
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let hash = workspace_hash(workspace);
+
+    if basename.is_empty() {
+        format!("yadt-{}", hash)
+    } else {
+        format!("yadt-{}-{}", basename, hash)
+    }
+}
+
+/// Arguments shared by every mode that builds and enters an environment
+#[derive(Args, Default)]
+pub struct EnvironmentArgs {
+    /// Directory to map into the container, defaults to the current directory
+    pub workspace: Option<PathBuf>,
+
+    /// Override where the workspace is mounted, either an absolute path or
+    /// "host" for its path on the host
+    #[arg(long, value_name = "PATH")]
+    pub mount_path: Option<String>,
+
+    /// Another directory to mount alongside the workspace, formatted as
+    /// HOST:CONTAINER, where CONTAINER may be "host" for its path on the host
+    #[arg(long = "extra-workspace", value_name = "HOST:CONTAINER")]
+    pub extra_workspaces: Vec<String>,
+
+    /// Initial working directory, either relative to the workspace or an
+    /// absolute path inside one of the mounted workspaces
+    #[arg(long, value_name = "PATH")]
+    pub workdir: Option<String>,
+
+    /// Keep the container running in the background after the shell exits,
+    /// reusing it for later shells until `yadt stop`
+    #[arg(long)]
+    pub keep_alive: bool,
+
+    /// Mount the workspaces read-only, keeping only `writable_dirs` writable
+    #[arg(long)]
+    pub read_only_workspace: bool,
+
+    /// Override how the workspace volume is relabeled for SELinux
+    #[arg(long, value_enum, value_name = "LABEL")]
+    pub selinux_label: Option<SelinuxLabel>,
+
+    /// Additional volume to mount, formatted as HOST:CONTAINER[:OPTIONS]
+    #[arg(short = 'v', long = "volume", value_name = "VOLUME")]
+    pub volumes: Vec<String>,
+
+    /// Additional tmpfs to mount, formatted as CONTAINER[:OPTIONS], eg.
+    /// /tmp/scratch:size=2g,mode=1777
+    #[arg(long = "tmpfs", value_name = "TMPFS")]
+    pub tmpfs: Vec<String>,
+
+    /// Override the size of /dev/shm, eg. 2g
+    #[arg(long, value_name = "SIZE")]
+    pub shm_size: Option<String>,
+
+    /// Override the container name, which is otherwise derived from the
+    /// workspace path
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Replace the workspace's container if it is already running
+    #[arg(long)]
+    pub replace: bool,
+
+    /// Fail instead of waiting if another yadt is building the workspace's
+    /// environment
+    #[arg(long)]
+    pub no_wait: bool,
+
+    /// Build the environment image even if one exists for the current config
+    #[arg(long)]
+    pub rebuild: bool,
+
+    /// Override the interactive shell, either a path or a nix package name
+    #[arg(long)]
+    pub shell: Option<String>,
+
+    /// Leave out the configured base packages, installing only additional
+    /// packages
+    #[arg(long)]
+    pub no_base_packages: bool,
+
+    /// Also install the default devShell of the workspace's flake.nix
+    #[arg(long)]
+    pub flake: bool,
+
+    /// Also install the nix-shell environment of the workspace's shell.nix
+    /// or default.nix, preferring a flake.nix if there is one
+    #[arg(long)]
+    pub nix_shell: bool,
+
+    /// Build the store paths recorded in yadt.lock, failing if the configured
+    /// packages no longer match it
+    #[arg(long)]
+    pub frozen: bool,
+
+    /// Override the network the environment joins, either host, bridge,
+    /// none, or a network name
+    #[arg(long)]
+    pub network: Option<String>,
+
+    /// Create the named network given by --network or the config if it
+    /// doesn't exist
+    #[arg(long)]
+    pub create_network: bool,
+
+    /// Let GUI applications connect to the host's X server
+    #[arg(long)]
+    pub x11: bool,
+
+    /// Let GUI applications connect to the host's wayland compositor
+    #[arg(long)]
+    pub wayland: bool,
+
+    /// Let applications play audio through the host's PipeWire or
+    /// PulseAudio
+    #[arg(long)]
+    pub audio: bool,
+
+    /// Override the GPUs made available to the environment
+    #[arg(long, value_enum)]
+    pub gpu: Option<Gpu>,
+
+    /// Additional capability to grant the environment, eg. SYS_PTRACE
+    #[arg(long, value_name = "CAPABILITY")]
+    pub cap_add: Vec<String>,
+
+    /// Additional capability to take from the environment, eg. NET_RAW
+    #[arg(long, value_name = "CAPABILITY")]
+    pub cap_drop: Vec<String>,
+
+    /// Additional security option of the environment, eg. seccomp=unconfined
+    #[arg(long, value_name = "OPTION")]
+    pub security_opt: Vec<String>,
+
+    /// Install gdb, strace, and perf, and grant the environment what they
+    /// need to trace and profile processes
+    #[arg(long)]
+    pub debug_tools: bool,
+
+    /// Run the environment privileged, with every capability and access to
+    /// the host's devices. This asks for confirmation unless --yes is passed
+    #[arg(
+        long,
+        conflicts_with_all = ["cap_add", "cap_drop", "security_opt", "debug_tools"]
+    )]
+    pub privileged: bool,
+
+    /// Don't ask for confirmation before running privileged
+    #[arg(long, requires = "privileged")]
+    pub yes: bool,
+
+    /// Wait for the shell to exit instead of replacing yadt with it, so that
+    /// yadt can follow up on the session
+    #[arg(long)]
+    pub no_exec: bool,
+
+    /// Start the podman machine without asking if it isn't running, on
+    /// macOS
+    #[arg(long)]
+    pub start_machine: bool,
+
+    /// Override the most memory the environment may use, eg. "8g"
+    #[arg(long, value_name = "SIZE")]
+    pub memory: Option<String>,
+
+    /// Override the most memory and swap the environment may use together,
+    /// or -1 for unlimited swap
+    #[arg(long, value_name = "SIZE", allow_hyphen_values = true)]
+    pub memory_swap: Option<String>,
+
+    /// Override how many CPUs the environment may use, eg. 3.5
+    #[arg(long)]
+    pub cpus: Option<f64>,
+
+    /// Override the most processes the environment may run at once
+    #[arg(long, value_name = "N")]
+    pub pids_limit: Option<u32>,
+
+    /// Override the platform the environment is built for and runs as, eg.
+    /// "linux/arm64"
+    #[arg(long)]
+    pub platform: Option<String>,
+
+    /// Override when the dev image and nix image are pulled
+    #[arg(long, value_enum)]
+    pub pull: Option<PullPolicy>,
+
+    /// Environment variable to set, either NAME=VALUE or a NAME taking the
+    /// host's value
+    #[arg(short, long = "env", value_name = "NAME[=VALUE]")]
+    pub env: Vec<String>,
+
+    /// Env file whose KEY=VALUE lines are set in the environment, overriding
+    /// the workspace's .env file
+    #[arg(long = "env-file", value_name = "PATH")]
+    pub env_files: Vec<PathBuf>,
+
+    /// Port to publish, formatted as HOST:CONTAINER[/PROTOCOL]. Ignored while
+    /// the environment uses the host's network
+    #[arg(short = 'P', long = "publish", value_name = "PORT")]
+    pub ports: Vec<String>,
+
+    /// Additional package to install for this invocation only, either a
+    /// nixpkgs name or a flake ref
+    #[arg(short, long = "package", value_name = "PACKAGE")]
+    pub packages: Vec<String>,
+
+    /// Preset whose packages to install for this invocation only, as listed
+    /// by `yadt presets`
+    #[arg(long = "preset", value_name = "PRESET")]
+    pub presets: Vec<String>,
+
+    #[command(flatten)]
+    pub groups: GroupArgs,
+
+    /// Command to run in the environment instead of an interactive shell,
+    /// whose exit code becomes yadt's
+    #[arg(last = true, value_name = "COMMAND")]
+    pub command: Vec<String>,
+
+    /// `on_create` commands run after the config's, eg. from a
+    /// devcontainer.json
+    #[arg(skip)]
+    pub on_create: Vec<String>,
+
+    /// Cache volumes mounted besides the config's, eg. from a
+    /// devcontainer.json
+    #[arg(skip)]
+    pub cache_volumes: BTreeMap<String, String>,
+}
+
+/// Containerfiles `yadt` without a subcommand looks for in the workspace, in
+/// order
+pub static CONTAINERFILE_NAMES: [&str; 2] = ["Containerfile", "Dockerfile"];
+
+/// Returns where the dev image of `workspace` comes from when no subcommand
+/// is given, in order of precedence: the `image` or `containerfile` in the
+/// config, a Containerfile or Dockerfile in the workspace, or
+/// `default_base_image`. Configured paths are relative to the workspace.
+pub fn configured_dev_image_source(config: &Config, workspace: &Path) -> DevImageSource {
+    if let Some(image) = &config.image {
+        return DevImageSource::Image(image.clone());
+    }
+    if let Some(containerfile) = &config.containerfile {
+        return DevImageSource::Containerfile {
+            containerfile: workspace.join(expand_home(containerfile)),
+            context: config
+                .context
+                .as_ref()
+                .map(|context| workspace.join(expand_home(context))),
+        };
+    }
+
+    match CONTAINERFILE_NAMES
+        .iter()
+        .map(|name| workspace.join(name))
+        .find(|path| path.is_file())
+    {
+        Some(containerfile) => DevImageSource::Containerfile {
+            containerfile,
+            context: None,
+        },
+        None => DevImageSource::Image(config.default_base_image.clone()),
+    }
+}
+
+/// The package groups of the config toggled for one invocation
+#[derive(Args, Clone, Debug, Default)]
+pub struct GroupArgs {
+    /// Package group of the config to install for this invocation only
+    #[arg(long = "with", value_name = "GROUP")]
+    pub with: Vec<String>,
+
+    /// Package group of enabled_groups to leave out for this invocation only
+    #[arg(long = "without", value_name = "GROUP")]
+    pub without: Vec<String>,
+}
+
+/// Builds and enters the environment of the current directory with `groups`
+/// toggled when no subcommand is given, from the dev image
+/// `configured_dev_image_source` picks. The choice is printed, since nothing
+/// on the command line shows it.
+pub fn run_detected(
+    config_override: Option<PathBuf>,
+    groups: GroupArgs,
+    dry_run: DryRun,
+) -> Result<(), Error> {
+    let workspace = resolve_workspace(None)?;
+    let (config, sources) = Config::load(config_override.clone(), &workspace)?;
+
+    let source = configured_dev_image_source(&config, &workspace);
+    let origin = match (
+        ["image", "containerfile"]
+            .iter()
+            .find_map(|key| sources.of(key)),
+        &source,
+    ) {
+        (Some(source), _) => format!("set in {}", source),
+        (None, DevImageSource::Containerfile { .. }) => "found in the workspace".to_string(),
+        (None, DevImageSource::Image(_)) => format!(
+            "default_base_image, the workspace has no {}",
+            CONTAINERFILE_NAMES.join(" or ")
+        ),
+    };
+    match &source {
+        DevImageSource::Containerfile { containerfile, .. } => log::info!(
+            "Building the dev image from {} ({})",
+            containerfile.display(),
+            origin
+        ),
+        DevImageSource::Image(image) => log::info!("Using dev image {} ({})", image, origin),
+    }
+
+    let environment = EnvironmentArgs {
+        workspace: Some(workspace),
+        groups,
+        ..Default::default()
+    };
+    run_environment(source, environment, config_override, dry_run)
+}
+
+/// Builds and enters the environment described by the devcontainer.json at
+/// `file`, or the one found in the workspace. Its settings come before the
+/// config's and the command line's, which can override them.
+pub fn run_devcontainer(
+    file: Option<PathBuf>,
+    mut environment: EnvironmentArgs,
+    config_override: Option<PathBuf>,
+    dry_run: DryRun,
+) -> Result<(), Error> {
+    let workspace = resolve_workspace(environment.workspace.clone())?;
+    let Some(file) = file.or_else(|| devcontainer::find(&workspace)) else {
+        return Err(Error::Config(format!(
+            "No devcontainer.json found in {}, expected one of {}",
+            workspace.display(),
+            devcontainer::DEVCONTAINER_PATHS.join(", ")
+        )));
+    };
+    let imported = devcontainer::import(&file, &workspace)?;
+
+    environment.workspace = Some(workspace);
+    let source = imported.apply(&mut environment);
+
+    run_environment(source, environment, config_override, dry_run)
+}
+
+/// Rebuilds the environment of `workspace` for the current config on top of
+/// the dev image its newest environment image was built on, and enters it.
+pub fn rebuild_environment(
+    config_override: Option<PathBuf>,
+    workspace: PathBuf,
+    dry_run: DryRun,
+) -> Result<(), Error> {
+    let (config, sources) = Config::load(config_override.clone(), &workspace)?;
+    let runtime = Runtime::detect(&config, &sources, dry_run)?;
+
+    let dev_image = newest_environment_image(&runtime, &workspace)?
+        .and_then(|image| image.dev_image)
+        .ok_or_else(|| {
+            Error::Conflict(format!(
+                "No environment was built for {} yet, start one with `yadt image` or `yadt containerfile` first",
+                workspace.display()
+            ))
+        })?;
+
+    run_environment(
+        DevImageSource::Image(dev_image),
+        EnvironmentArgs {
+            workspace: Some(workspace),
+            ..Default::default()
+        },
+        config_override,
+        dry_run,
+    )
+}
+
+/// Pulls the newest dev image and nix image of `workspace`, rebuilds its
+/// environment on top of them, and enters it. The dev image is the one its
+/// newest environment image was built on if that can be pulled, or else the
+/// configured one.
+pub fn update_environment(
+    config_override: Option<PathBuf>,
+    workspace: PathBuf,
+    dry_run: DryRun,
+) -> Result<(), Error> {
+    let (config, sources) = Config::load(config_override.clone(), &workspace)?;
+    let runtime = Runtime::detect(&config, &sources, dry_run)?;
+
+    let source =
+        match newest_environment_image(&runtime, &workspace)?.and_then(|image| image.dev_image) {
+            Some(dev_image) if !is_image_id(&dev_image) => DevImageSource::Image(dev_image),
+            _ => configured_dev_image_source(&config, &workspace),
+        };
+
+    run_environment(
+        source,
+        EnvironmentArgs {
+            workspace: Some(workspace),
+            rebuild: true,
+            pull: Some(PullPolicy::Always),
+            ..Default::default()
+        },
+        config_override,
+        dry_run,
+    )
+}
+
+/// Returns the name of a running container labeled with `workspace`, if any.
+pub(crate) fn running_workspace_container(
+    runtime: &Runtime,
+    workspace: &Path,
+) -> Result<Option<String>, Error> {
+    let mut filter = OsString::from("label=");
+    filter.push(workspace_label(workspace));
+
+    Ok(running_containers(runtime, &filter)?.into_iter().next())
+}
+
+/// Returns the names of the running containers matching `filter`, as passed
+/// to `ps --filter`
+fn running_containers(runtime: &Runtime, filter: &OsStr) -> Result<Vec<String>, Error> {
+    let mut command = runtime.command();
+
+    command
+        .arg("ps")
+        .arg("--filter")
+        .arg(filter)
+        .arg("--format")
+        .arg("{{.Names}}");
+
+    let output = runtime.output(&mut command)?;
+    check_status(
+        runtime,
+        "Listing containers",
+        output.status,
+        &stderr_lines(&output.stderr),
+    )?;
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Returns whether the container `name` is running, or None if no such
+/// container exists.
+fn container_running(runtime: &Runtime, name: &str) -> Result<Option<bool>, Error> {
+    let mut command = runtime.command();
+    command
+        .arg("container")
+        .arg("inspect")
+        .arg("--format")
+        .arg("{{.State.Running}}")
+        .arg(name)
+        .stderr(Stdio::null());
+    let output = runtime.output(&mut command)?;
+
+    Ok(output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim() == "true"))
+}
+
+/// Returns the config hash the running container `name` was started with, or
+/// None if it carries none.
+pub(crate) fn container_config_hash(
+    runtime: &Runtime,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    let format = format!("{{{{index .Config.Labels \"{}\"}}}}", CONFIG_HASH_LABEL);
+    let output = query(
+        runtime,
+        "Inspecting container",
+        &["container", "inspect", "--format", &format, name],
+    )?;
+
+    // docker prints "<no value>" for a missing label
+    let hash = output.trim();
+    Ok((!hash.is_empty() && hash != "<no value>").then(|| hash.to_string()))
+}
+
+/// Replaces this process with `spec`'s command, run inside its container, or
+/// with `wait` runs it and returns its exit code
+fn exec_in_container(runtime: &Runtime, spec: &ExecSpec, wait: bool) -> Result<u8, Error> {
+    let mut command = runtime.command();
+    command.args(runtime.backend().exec_args(spec));
+
+    if runtime.is_dry_run() {
+        println!("{}", render_command(&command));
+        return Ok(0);
+    }
+
+    report_hand_over(spec.name);
+    runtime.hand_over(&mut command, wait)
+}
+
+/// Reports that the environment in container `name` takes over, as the last
+/// of the events
+fn report_hand_over(name: &str) {
+    events::emit(Event::Container {
+        name: name.to_string(),
+    });
+    events::emit(Event::Exec);
+}
+
+/// Follows up on a shell yadt waited for, which exited with `code` after
+/// starting at `started`, then passes the code on as yadt's. `kept_alive`
+/// names the container if it keeps running without the shell.
+fn end_session(
+    runtime: &Runtime,
+    started: Instant,
+    code: u8,
+    kept_alive: Option<&str>,
+) -> Result<(), Error> {
+    if runtime.is_dry_run() {
+        return Ok(());
+    }
+
+    log::debug!(
+        "The session exited with code {} after {}s",
+        code,
+        started.elapsed().as_secs()
+    );
+    if let Some(name) = kept_alive {
+        log::info!(
+            "Container {} keeps running in the background, stop it with `yadt stop`",
+            name
+        );
+    }
+
+    match code {
+        0 => Ok(()),
+        code => Err(Error::Exited(code)),
+    }
+}
+
+/// Which environments `yadt stop` stops
+#[derive(Debug)]
+pub enum StopTarget {
+    Workspace(PathBuf),
+
+    /// Every running container carrying the yadt label
+    All,
+}
+
+/// Stops the containers running for `target`, which removes them. Anything
+/// kept only in a container, rather than in the workspace or a volume, is
+/// lost. Each container is given `timeout` seconds to exit before it is
+/// killed, and with `force` it is also killed if stopping it fails.
+pub fn stop_environment(
+    runtime: &Runtime,
+    target: &StopTarget,
+    timeout: u32,
+    force: bool,
+) -> Result<(), Error> {
+    let names = match target {
+        StopTarget::Workspace(workspace) => {
+            let name = running_workspace_container(runtime, workspace)?;
+            if name.is_none() {
+                log::info!("The environment for {} is not running", workspace.display());
+            }
+            Vec::from_iter(name)
+        }
+        StopTarget::All => {
+            running_containers(runtime, OsStr::new(&format!("label={}", YADT_LABEL)))?
+        }
+    };
+
+    let timeout = timeout.to_string();
+    for name in &names {
+        log::info!("Stopping container {}", name);
+        let stopped = runtime.apply(
+            &format!("Stopping container {}", name),
+            &["stop", "--time", &timeout, name],
+        );
+        match stopped {
+            Err(error) if force => {
+                log::warn!("{}, killing it instead", error);
+                runtime.apply(&format!("Killing container {}", name), &["kill", name])?;
+            }
+            stopped => stopped?,
+        }
+    }
+
+    Ok(())
+}
+
+/// An advisory lock on building and starting the environment of a
+/// workspace, so that yadt running twice for the same workspace doesn't
+/// build it twice and race on its container's name. The lock is released
+/// when dropped, and isn't inherited by the shell yadt replaces itself with.
+struct BuildLock {
+    _file: fs::File,
+}
+
+impl BuildLock {
+    /// Takes the lock of `workspace`, waiting for another yadt holding it
+    /// unless `no_wait` is set. Returns the lock along with whether it was
+    /// waited for.
+    fn acquire(workspace: &Path, no_wait: bool) -> Result<(Self, bool), Error> {
+        let dir = data_dir().unwrap_or_else(env::temp_dir).join("locks");
+        fs::create_dir_all(&dir).map_err(|source| Error::Path {
+            path: dir.clone(),
+            source,
+        })?;
+        let path = dir.join(format!("{}.lock", workspace_hash(workspace)));
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|source| Error::Path {
+                path: path.clone(),
+                source,
+            })?;
+
+        let lock = |operation| {
+            // SAFETY: the descriptor is owned by `file`, which outlives this
+            match unsafe { libc::flock(file.as_raw_fd(), operation) } {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        };
+        match lock(libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(()) => return Ok((Self { _file: file }, false)),
+            Err(error) if error.kind() != io::ErrorKind::WouldBlock => return Err(error.into()),
+            Err(_) => {}
+        }
+
+        if no_wait {
+            return Err(Error::Conflict(format!(
+                "Another yadt build is in progress for {}",
+                workspace.display()
+            )));
+        }
+        log::info!(
+            "Another yadt build is in progress for {}, waiting for it to finish",
+            workspace.display()
+        );
+        lock(libc::LOCK_EX)?;
+        Ok((Self { _file: file }, true))
+    }
+}
+
+/// Makes the name `name` available for a new container. A stopped container
+/// with that name is leftover and removed, while a running one is only
+/// removed if `replace` is set.
+fn claim_container_name(runtime: &Runtime, name: &str, replace: bool) -> Result<(), Error> {
+    match container_running(runtime, name)? {
+        None => return Ok(()),
+        Some(true) if !replace => {
+            return Err(Error::Conflict(format!(
+                "Container {} is already running, use `yadt enter` to open another shell in it or pass --replace",
+                name
+            )));
+        }
+        Some(true) => log::info!("Replacing running container {}", name),
+        Some(false) => log::info!("Removing stopped container {}", name),
+    }
+
+    runtime.apply(
+        &format!("Removing container {}", name),
+        &["rm", "--force", name],
+    )
+}
+
+/// Returns the home of the container user in `image`, which is remembered
+/// once read, since reading it starts a container. Images without the user
+/// recorded, such as those of a `containerfile_template`, are assumed to have
+/// the home the embedded containerfile creates.
+fn environment_home(
+    runtime: &Runtime,
+    workspace: &Path,
+    user: &HostUser,
+    image: &EnvironmentImage,
+) -> String {
+    // root's home is the same in every image
+    if runtime.is_dry_run() || user.uid == 0 {
+        return user.container_home();
+    }
+    let remembered = State::load(runtime).ok().and_then(|state| {
+        let entry = state.get(workspace)?;
+        (!image.built && entry.config_hash.as_ref() == Some(&image.config_hash))
+            .then(|| entry.home.clone())
+            .flatten()
+    });
+    if let Some(home) = remembered {
+        return home;
+    }
+
+    match image_home(runtime, &image.tag) {
+        Ok(home) => {
+            state::record_home(runtime, workspace, &image.config_hash, &home);
+            home
+        }
+        Err(error) => {
+            log::debug!("Assuming the default home, {}", error);
+            user.container_home()
+        }
+    }
+}
+
+/// Returns the command starting `shell` through `SHELL_LAUNCHER`
+fn shell_command(shell: &str) -> Vec<OsString> {
+    vec![
+        "/bin/sh".into(),
+        "-c".into(),
+        SHELL_LAUNCHER.into(),
+        shell.into(),
+    ]
+}
+
+/// Replaces this process with a new shell inside the container already
+/// running for `workspace`, started like the shells of kept alive containers
+/// in the container's working directory, or with `wait` waits for the shell
+/// to exit. The shell sources the `on_enter` snippets the container was
+/// started with, through the `YADT_ON_ENTER` it inherits.
+pub fn enter_environment(
+    runtime: &Runtime,
+    config: &Config,
+    workspace: &Path,
+    wait: bool,
+) -> Result<(), Error> {
+    let Some(name) = running_workspace_container(runtime, workspace)? else {
+        return Err(Error::Conflict(format!(
+            "No environment is running for {}, start one with `yadt image` or `yadt containerfile` first",
+            workspace.display()
+        )));
+    };
+    let workdir = query(
+        runtime,
+        "Inspecting container",
+        &[
+            "container",
+            "inspect",
+            "--format",
+            "{{.Config.WorkingDir}}",
+            &name,
+        ],
+    )?;
+    let workdir = match workdir.trim() {
+        "" => workspace_mount_path(config.workspace_mount.as_deref(), workspace)?,
+        workdir => workdir.to_string(),
+    };
+    // the launcher sources the snippets mounted when the container started,
+    // which it can't be given afterwards
+    if !config.on_enter.is_empty() {
+        let variables = query(
+            runtime,
+            "Inspecting container",
+            &[
+                "container",
+                "inspect",
+                "--format",
+                "{{range .Config.Env}}{{println .}}{{end}}",
+                &name,
+            ],
+        )?;
+        let variable = format!("YADT_ON_ENTER={}", ON_ENTER_DIR);
+        if !variables.lines().any(|line| line == variable) {
+            log::warn!(
+                "Container {} was started without the on_enter snippets, they run once the environment is restarted",
+                name
+            );
+        }
+    }
+
+    if !runtime.is_dry_run() {
+        state::record_run(runtime, workspace, &name);
+    }
+    let started = Instant::now();
+    let code = exec_in_container(
+        runtime,
+        &ExecSpec {
+            name: &name,
+            tty: io::stdin().is_terminal(),
+            workdir: &workdir,
+            command: shell_command(&config.shell),
+        },
+        wait,
+    )?;
+    end_session(runtime, started, code, Some(&name))
+}
+
+/// Prefix of the `yadt cp` paths that refer to the environment
+pub const ENVIRONMENT_PATH_PREFIX: &str = "env:";
+
+/// Copies `source` to `destination`, one of which is prefixed with
+/// `ENVIRONMENT_PATH_PREFIX` to refer to a path in the environment of
+/// `workspace`, relative to the workspace mount unless it is absolute.
+/// Directories are copied recursively and permissions are kept. Without a
+/// running environment, a temporary container is created from the image
+/// last built, which lacks the workspace and volumes mounted at runtime.
+pub fn copy_files(
+    runtime: &Runtime,
+    config: &Config,
+    workspace: &Path,
+    source: &str,
+    destination: &str,
+) -> Result<(), Error> {
+    let (container_path, to_container) = match (
+        source.strip_prefix(ENVIRONMENT_PATH_PREFIX),
+        destination.strip_prefix(ENVIRONMENT_PATH_PREFIX),
+    ) {
+        (Some(path), None) => (path, false),
+        (None, Some(path)) => (path, true),
+        (Some(_), Some(_)) => {
+            return Err(Error::Config(format!(
+                "Only one side of the copy can be in the environment, drop the {} prefix from the other",
+                ENVIRONMENT_PATH_PREFIX
+            )))
+        }
+        (None, None) => {
+            return Err(Error::Config(format!(
+                "Neither side of the copy is in the environment, prefix one with {}, eg. {}/workspace/target",
+                ENVIRONMENT_PATH_PREFIX, ENVIRONMENT_PATH_PREFIX
+            )))
+        }
+    };
+    let mount = workspace_mount_path(config.workspace_mount.as_deref(), workspace)?;
+    let container_path = if container_path.starts_with('/') {
+        container_path.to_string()
+    } else {
+        format!("{}/{}", mount.trim_end_matches('/'), container_path)
+    };
+
+    let (container, temporary) = match running_workspace_container(runtime, workspace)? {
+        Some(name) => (name, false),
+        None => {
+            // the workspace is only mounted while the environment runs
+            if let Ok(relative) = Path::new(&container_path).strip_prefix(&mount) {
+                return Err(Error::Config(format!(
+                    "No environment is running for {}, so {} is only in the workspace, at {} on the host",
+                    workspace.display(),
+                    container_path,
+                    workspace.join(relative).display()
+                )));
+            }
+            let image = format!("{}:{}", image_repository(workspace), LATEST_TAG);
+            if !image_exists(runtime, &image)? {
+                return Err(Error::Conflict(format!(
+                    "No environment was built for {} yet, build one with `yadt image` or `yadt containerfile` first",
+                    workspace.display()
+                )));
+            }
+            let name = format!("{}-cp-{}", container_name(workspace), process::id());
+            runtime.apply(
+                &format!("Creating container {}", name),
+                &[
+                    "create",
+                    "--label",
+                    &format!("{}=true", YADT_LABEL),
+                    "--name",
+                    &name,
+                    &image,
+                ],
+            )?;
+            (name, true)
+        }
+    };
+
+    let container_arg = OsString::from(format!("{}:{}", container, container_path));
+    let (from, to) = if to_container {
+        (OsString::from(source), container_arg)
+    } else {
+        (container_arg, OsString::from(destination))
+    };
+    let mut command = runtime.command();
+    command.args(runtime.backend().cp_args(&from, &to));
+
+    let result = if runtime.is_dry_run() {
+        println!("{}", render_command(&command));
+        Ok(())
+    } else {
+        let step = format!(
+            "Copying {} to {}",
+            from.to_string_lossy(),
+            to.to_string_lossy()
+        );
+        runtime
+            .output(&mut command)
+            .map_err(Error::from)
+            .and_then(|output| {
+                check_status(runtime, &step, output.status, &stderr_lines(&output.stderr))
+            })
+    };
+
+    // the temporary container is removed even if the copy failed
+    if temporary {
+        runtime.apply(
+            &format!("Removing container {}", container),
+            &["rm", &container],
+        )?;
+    }
+    result
+}
+
+/// Builds the dev image from `source`, installs the configured packages into
+/// it, and replaces this process with a shell inside the environment.
+pub fn run_environment(
+    source: DevImageSource,
+    mut environment: EnvironmentArgs,
+    config_override: Option<PathBuf>,
+    dry_run: DryRun,
+) -> Result<(), Error> {
+    let workspace = resolve_workspace(environment.workspace)?;
+    let (mut config, mut sources) = Config::load(config_override, &workspace)?;
+    let mut packages = environment.packages;
+    if environment.debug_tools {
+        packages.extend(DEBUG_TOOLS_PACKAGES.map(String::from));
+        environment
+            .cap_add
+            .extend(DEBUG_TOOLS_CAPABILITIES.map(String::from));
+        environment
+            .security_opt
+            .push(DEBUG_TOOLS_SECURITY_OPT.to_string());
+    }
+    add_cli_packages(&mut config, &mut sources, packages)?;
+    add_cli_presets(&mut config, &mut sources, environment.presets)?;
+    toggle_cli_groups(
+        &mut config,
+        &mut sources,
+        environment.groups.with,
+        environment.groups.without,
+    )?;
+    for capability in environment.cap_add.iter().chain(&environment.cap_drop) {
+        validate_capability(capability)?;
+    }
+    config.cap_add.extend(environment.cap_add);
+    config.cap_drop.extend(environment.cap_drop);
+    config.security_opt.extend(environment.security_opt);
+    if environment.privileged {
+        confirm_privileged(&config, environment.yes, dry_run)?;
+    } else {
+        warn_weakened_isolation(&config);
+    }
+    config.on_create.extend(environment.on_create);
+    config.cache_volumes.extend(environment.cache_volumes);
+    if let Some(shell) = environment.shell {
+        config.shell = shell;
+        config.validate_packages()?;
+    }
+    if environment.no_base_packages {
+        config.base_packages.clear();
+    }
+    if let Some(network) = environment.network {
+        config.network = network;
+    }
+
+    // malformed volumes should be reported before launching anything
+    let volumes = config
+        .volumes
+        .iter()
+        .chain(&environment.volumes)
+        .map(|entry| VolumeSpec::parse(entry))
+        .collect::<Result<Vec<_>, _>>()?;
+    let cache_volumes = cache_volume_specs(&config)?;
+    validate_network(&config.network)?;
+    let workspace_mount = workspace_mount_path(
+        environment
+            .mount_path
+            .as_deref()
+            .or(config.workspace_mount.as_deref()),
+        &workspace,
+    )?;
+    let mut mounts = vec![Mount {
+        host: workspace.clone(),
+        container: workspace_mount,
+    }];
+    for entry in &environment.extra_workspaces {
+        mounts.push(Mount::parse_extra(entry)?);
+    }
+    check_duplicate_mounts(&mounts, &volumes)?;
+    let workdir = resolve_workdir(environment.workdir.as_deref(), &mounts)?;
+    let read_only = environment.read_only_workspace || config.read_only_workspace;
+
+    // variables given with --env override those read from env files
+    let mut env_files = environment.env_files;
+    let workspace_env_file = workspace.join(WORKSPACE_ENV_FILE);
+    if config.auto_env_file && workspace_env_file.is_file() {
+        env_files.insert(0, workspace_env_file);
+    }
+    let mut env_entries = Vec::new();
+    for path in &env_files {
+        env_entries.extend(
+            env_file::read(path)?
+                .into_iter()
+                .map(|(key, value)| format!("{}={}", key, value)),
+        );
+    }
+    let mut host_files = Vec::new();
+    if let Some(socket) = config.ssh_agent.then(ssh_agent_socket).flatten() {
+        env_entries.push(format!("SSH_AUTH_SOCK={}", SSH_AGENT_SOCKET));
+        host_files.push(socket);
+    }
+    let user = HostUser::current();
+    let dotfiles_dir = match config.dotfiles.as_ref().and_then(|d| d.path.as_deref()) {
+        Some(path) => {
+            let path = expand_home(path);
+            Some(fs::canonicalize(&path).map_err(|source| Error::Path { path, source })?)
+        }
+        None => None,
+    };
+
+    let x11_display = if environment.x11 || config.x11 {
+        let (display, sockets) = x11_display()?;
+        env_entries.push(format!("DISPLAY={}", display));
+        host_files.push(sockets);
+        Some(display)
+    } else {
+        None
+    };
+    // sockets of the host's XDG_RUNTIME_DIR are mounted into a runtime dir
+    // of the container user's own
+    let mut runtime_dir_files = Vec::new();
+    if environment.wayland || config.wayland {
+        if let Some((socket, name)) = wayland_socket(&user) {
+            env_entries.push(format!("WAYLAND_DISPLAY={}", name));
+            runtime_dir_files.push(socket);
+        }
+    }
+    let audio = match (config.audio, environment.audio) {
+        (Audio::Off, true) => Audio::Session,
+        (audio, _) => audio,
+    };
+    let mut devices = Vec::new();
+    if audio != Audio::Off {
+        let (files, variables) = audio_sockets(&user);
+        env_entries.extend(variables);
+        runtime_dir_files.extend(files);
+    }
+    if audio == Audio::Alsa {
+        if Path::new("/dev/snd").exists() {
+            devices.push(OsString::from("/dev/snd"));
+        } else {
+            log::warn!("Not passing in sound devices, the host has no /dev/snd");
+        }
+    }
+    let mut tmpfs = Vec::new();
+    if !runtime_dir_files.is_empty() {
+        env_entries.push(format!("XDG_RUNTIME_DIR={}", container_runtime_dir(&user)));
+        tmpfs.push(runtime_dir_tmpfs(&user));
+        host_files.extend(runtime_dir_files);
+    }
+    let mut groups = Vec::new();
+    if let Some((socket, group)) = container_socket(config.container_socket) {
+        let url = format!("unix://{}", CONTAINER_SOCKET);
+        // docker clients can talk to podman's docker compatible api as well
+        env_entries.push(format!("CONTAINER_HOST={}", url));
+        env_entries.push(format!("DOCKER_HOST={}", url));
+        host_files.push(socket);
+        groups.extend(group);
+    }
+    if let Some(files) = config.gpg_agent.then(gpg_agent_files).flatten() {
+        env_entries.push(format!("GNUPGHOME={}", GNUPG_HOME));
+        host_files.extend(files);
+    }
+    if read_only {
+        env_entries.push("YADT_READ_ONLY_WORKSPACE=1".to_string());
+        let host_workdir = mounts
+            .iter()
+            .find_map(|mount| mount.host_path(&workdir))
+            .expect("The workdir was resolved inside a mount");
+        tmpfs.extend(writable_dir_tmpfs(
+            &config.writable_dirs,
+            &workdir,
+            &host_workdir,
+            &user,
+        )?);
+    }
+    let (scratch, shm_size) = scratch_space(&config, &environment.tmpfs, environment.shm_size)?;
+    tmpfs.extend(scratch);
+
+    let runtime = Runtime::detect(&config, &sources, dry_run)?;
+    // this would otherwise only fail once the container is created
+    runtime.check_local(&format!("the workspace {}", workspace.display()))?;
+    start_machine(&runtime, environment.start_machine)?;
+    for mount in &mounts {
+        runtime.check_shared(
+            &mount.host,
+            &format!("The workspace {}", mount.host.display()),
+        )?;
+    }
+    // a typo would otherwise only fail deep inside the build
+    if config.validate_packages && !runtime.is_dry_run() {
+        check_packages(&runtime, &config)?;
+    }
+
+    // a conflicting container should be reported before spending time on a
+    // build
+    let name = environment
+        .name
+        .unwrap_or_else(|| container_name(&workspace));
+    // a dry run changes nothing another yadt could race on
+    let (build_lock, waited) = if runtime.is_dry_run() {
+        (None, false)
+    } else {
+        let (lock, waited) = BuildLock::acquire(&workspace, environment.no_wait)?;
+        (Some(lock), waited)
+    };
+    // a kept alive container is reused unless it is replaced
+    let keep_alive = environment.keep_alive || config.keep_alive;
+    let reuse =
+        keep_alive && !environment.replace && container_running(&runtime, &name)? == Some(true);
+    if !reuse {
+        claim_container_name(&runtime, &name, environment.replace)?;
+        ensure_network(&runtime, &config.network, environment.create_network)?;
+    }
+    // with the host's network, ports are reachable without publishing them,
+    // while a reused container already holds the ports it published. They are
+    // only probed once a replaced container is removed, which frees them
+    let ports = if reuse {
+        Vec::new()
+    } else if config.network == "host" {
+        if !config.ports.is_empty() || !environment.ports.is_empty() {
+            log::warn!("Ignoring published ports, the environment uses the host's network");
+        }
+        Vec::new()
+    } else {
+        port_specs(config.ports.iter().chain(&environment.ports))?
+    };
+    let gpu = environment.gpu.or(config.gpu);
+    if let Some(gpu) = gpu {
+        check_gpu(&runtime, gpu)?;
+    }
+    let mut security_opt = config.security_opt.clone();
+    if config.nested_containers {
+        check_nested_containers()?;
+        devices.push(OsString::from("/dev/fuse"));
+        // slirp4netns gives nested containers a network through a tap device
+        if Path::new("/dev/net/tun").exists() {
+            devices.push(OsString::from("/dev/net/tun"));
+        }
+        // nested podman relabels its own storage, which SELinux would deny
+        security_opt.push("label=disable".to_string());
+        if runtime.kind != RuntimeKind::Podman {
+            // docker's default profiles block the mounts and unshares of
+            // creating user namespaces, which podman's allow
+            security_opt.push("seccomp=unconfined".to_string());
+            security_opt.push("apparmor=unconfined".to_string());
+        }
+    }
+    let configured_limits = config.resource_limits();
+    let limits = ResourceLimits {
+        memory: environment.memory.or(configured_limits.memory),
+        memory_swap: environment.memory_swap.or(configured_limits.memory_swap),
+        cpus: environment.cpus.or(configured_limits.cpus),
+        pids_limit: environment.pids_limit.or(configured_limits.pids_limit),
+    };
+    limits.validate()?;
+    let platform = environment.platform.or(config.platform.clone());
+    if let Some(platform) = &platform {
+        validate_platform(platform)?;
+        check_platform(platform)?;
+    }
+
+    let auth = AuthFile::new(&config, &runtime)?;
+    let options = BuildOptions {
+        rebuild: environment.rebuild,
+        frozen: environment.frozen,
+        flake: environment.flake || config.use_flake,
+        nix_shell: environment.nix_shell || config.use_nix_shell,
+        pull: environment.pull.or(config.pull_policy),
+        auth: auth.as_ref(),
+        proxy: config.inherit_proxy,
+        platform: platform.as_deref(),
+        build_timeout: config.build_timeout()?,
+        pull_timeout: config.pull_timeout()?,
+        retries: config.retries,
+    };
+
+    // a tty would mangle the output of a one-off command that is piped
+    let tty = environment.command.is_empty() || io::stdin().is_terminal();
+    let command: Vec<OsString> = if environment.command.is_empty() {
+        // a missing shell would otherwise exit the container immediately
+        shell_command(&config.shell)
+    } else {
+        environment
+            .command
+            .into_iter()
+            .map(OsString::from)
+            .collect()
+    };
+
+    // the yadt that was waited for just started the container, so there is
+    // nothing left to build
+    let image = if reuse && waited {
+        None
+    } else if let Some(snapshot) = snapshot_image(&runtime, &workspace)? {
+        Some(snapshot)
+    } else {
+        let building = Instant::now();
+        let dev_image = build_dev_image(&runtime, &workspace, source, &options)?;
+        let image =
+            build_environment_image(&config, &runtime, &workspace, &dev_image, &user, &options)?;
+        progress::finish();
+        if !runtime.is_dry_run() {
+            state::record_build(&runtime, &workspace, &image);
+            log::info!("{}", build_summary(&runtime, &image, building.elapsed()));
+        }
+        Some(image)
+    };
+    if let Some(image) = image.as_ref().filter(|_| !runtime.is_dry_run()) {
+        events::emit(Event::Image {
+            id: image.id.clone(),
+            tag: image.tag.clone(),
+            built: image.built,
+        });
+    }
+
+    if reuse {
+        // the image was still built, so that restarting is quick
+        if let Some(image) = &image {
+            if container_config_hash(&runtime, &name)?.as_ref() != Some(&image.config_hash) {
+                log::warn!(
+                    "The config changed since container {} started, pass --replace to restart it with the rebuilt environment",
+                    name
+                );
+            }
+        }
+        drop(build_lock);
+        if !runtime.is_dry_run() {
+            state::record_run(&runtime, &workspace, &name);
+        }
+        log::info!("Opening a shell in running container {}", name);
+        let started = Instant::now();
+        let code = exec_in_container(
+            &runtime,
+            &ExecSpec {
+                name: &name,
+                tty,
+                workdir: &workdir,
+                command,
+            },
+            environment.no_exec,
+        )?;
+        return end_session(&runtime, started, code, Some(&name));
+    }
+    let image = image.expect("The image is built unless the container is reused");
+    let home = environment_home(&runtime, &workspace, &user, &image);
+
+    // files generated for the container are named after it, replacing those
+    // of the container it replaces, and removed again unless it takes them
+    // over
+    let mut private_dirs = Vec::new();
+    if let Some(display) = &x11_display {
+        if let Some((cookie, dir)) = xauth_cookie(display, &name, runtime.is_dry_run())? {
+            env_entries.push(format!("XAUTHORITY={}", XAUTHORITY));
+            host_files.push(cookie);
+            private_dirs.push(dir);
+        }
+    }
+    if !config.on_enter.is_empty() {
+        env_entries.push(format!("YADT_ON_ENTER={}", ON_ENTER_DIR));
+        let (file, dir) = on_enter_dir(&config.on_enter, &name, runtime.is_dry_run())?;
+        host_files.push(file);
+        private_dirs.push(dir);
+    }
+
+    // mounting files into a dotfiles directory would create them on the
+    // host, and the directory likely has its own git config anyway
+    let git_identity = config.git_identity && dotfiles_dir.is_none();
+    if let (true, Some(dirs)) = (git_identity, BaseDirs::new()) {
+        let (files, config_global) = git_identity_files(dirs.home_dir(), &home);
+        env_entries.extend(config_global);
+        host_files.extend(files);
+    }
+    env_entries.extend(environment.env);
+    let env = environment_variables(&config.env_passthrough, &env_entries)?;
+
+    let selinux_label = SelinuxLabel::resolve(environment.selinux_label.or(config.selinux_label));
+    let mut workspace_vol_options = vec![if read_only { "ro" } else { "rw" }];
+    workspace_vol_options.extend(selinux_label.volume_option());
+
+    for (name, _) in &cache_volumes {
+        ensure_volume(&runtime, name)?;
+    }
+
+    // the home is, in order of precedence, the dotfiles directory, the
+    // persistent home volume, or the volume dotfiles are installed into.
+    // dotfiles are installed into whichever volume when it is created
+    let dotfiles_repo = config
+        .dotfiles
+        .as_ref()
+        .and_then(|dotfiles| Some((dotfiles.repo.as_deref()?, dotfiles.install.as_deref())));
+    let home_volume = if dotfiles_dir.is_some() {
+        if config.persist_home {
+            log::warn!("Not persisting the home directory, dotfiles.path is mounted over it");
+        }
+        None
+    } else if config.persist_home {
+        Some(home_volume(&workspace))
+    } else {
+        dotfiles_repo.map(|(repo, install)| dotfiles_volume(repo, install))
+    };
+
+    let mut home_volume_arg = None;
+    if let Some(volume) = &home_volume {
+        // only the first container mounting a fresh volume takes ownership
+        let fresh = ensure_volume(&runtime, volume)?;
+        let owned_option = fresh
+            .then(|| runtime.backend().owned_volume_option())
+            .flatten();
+        let mut arg = volume_arg(Path::new(volume), &home, &Vec::from_iter(owned_option));
+
+        if let (true, Some((repo, install))) = (fresh, dotfiles_repo) {
+            let spec = RunSpec {
+                image: &image.tag,
+                name: &format!("{}-dotfiles", name),
+                tty: false,
+                detach: false,
+                workdir: &home,
+                user: &user,
+                keep_id: runtime.can_keep_id(),
+                volumes: vec![arg],
+                labels: yadt_labels(&workspace, None),
+                network: &config.network,
+                proxy: config.inherit_proxy,
+                platform: platform.as_deref(),
+                ports: Vec::new(),
+                env: Vec::new(),
+                tmpfs: Vec::new(),
+                shm_size: None,
+                gpu: None,
+                devices: Vec::new(),
+                limits: limits.clone(),
+                cap_add: Vec::new(),
+                cap_drop: Vec::new(),
+                security_opt: Vec::new(),
+                groups: Vec::new(),
+                privileged: false,
+                command: vec![
+                    "/bin/sh".into(),
+                    "-c".into(),
+                    DOTFILES_INSTALLER.into(),
+                    repo.into(),
+                    install.unwrap_or_default().into(),
+                ],
+            };
+            install_dotfiles(&runtime, &spec, volume)?;
+            arg = volume_arg(Path::new(volume), &home, &[]);
+        }
+        home_volume_arg = Some(arg);
+    }
+
+    // the home is mounted first, since the host files below may be mounted
+    // into it
+    let mut run_volumes: Vec<OsString> = mounts
+        .iter()
+        .map(|mount| volume_arg(&mount.host, &mount.container, &workspace_vol_options))
+        .collect();
+    if let Some(dir) = &dotfiles_dir {
+        // the home stays writable in a read-only workspace
+        let mut options = vec!["rw"];
+        options.extend(selinux_label.volume_option());
+        run_volumes.push(volume_arg(dir, &home, &options));
+    }
+    run_volumes.extend(home_volume_arg);
+    run_volumes.extend(volumes.iter().map(|volume| volume.to_arg(selinux_label)));
+    run_volumes.extend(
+        cache_volumes
+            .iter()
+            .map(|(name, container)| OsString::from(format!("{}:{}", name, container))),
+    );
+    run_volumes.extend(host_files.iter().map(|file| file.to_arg(selinux_label)));
+
+    // hooks only run for a fresh image, seeing the workspace as the shell will
+    if image.built && !config.on_create.is_empty() {
+        let log = if runtime.is_dry_run() {
+            None
+        } else {
+            HookLog::create(&workspace, config.hook_logs)
+        };
+        for hook in &config.on_create {
+            let spec = RunSpec {
+                image: &image.tag,
+                name: &format!("{}-on-create", name),
+                tty: false,
+                detach: false,
+                workdir: &workdir,
+                user: &user,
+                keep_id: runtime.can_keep_id(),
+                volumes: run_volumes.clone(),
+                labels: yadt_labels(&workspace, None),
+                network: &config.network,
+                proxy: config.inherit_proxy,
+                platform: platform.as_deref(),
+                ports: Vec::new(),
+                env: env.clone(),
+                tmpfs: tmpfs.clone(),
+                shm_size: shm_size.clone(),
+                gpu,
+                devices: devices.clone(),
+                limits: limits.clone(),
+                cap_add: config.cap_add.clone(),
+                cap_drop: config.cap_drop.clone(),
+                security_opt: security_opt.clone(),
+                groups: groups.clone(),
+                privileged: environment.privileged,
+                command: vec!["/bin/sh".into(), "-c".into(), hook.into()],
+            };
+            run_on_create(&runtime, &spec, hook, log.as_ref())?;
+        }
+    }
+
+    let mut labels = yadt_labels(&workspace, Some(&image.config_hash));
+    if let Some(gpu) = gpu {
+        labels.push(OsString::from(format!("{}={}", GPU_LABEL, gpu.name())));
+    }
+
+    // TODO go back to old $HOME impl bc .bash_history gets added right now
+    let spec = RunSpec {
+        image: &image.tag,
+        name: &name,
+        tty,
+        detach: keep_alive,
+        workdir: &workdir,
+        user: &user,
+        keep_id: runtime.can_keep_id(),
+        volumes: run_volumes,
+        labels,
+        network: &config.network,
+        proxy: config.inherit_proxy,
+        platform: platform.as_deref(),
+        ports: ports.iter().map(PortSpec::to_arg).collect(),
+        env,
+        tmpfs,
+        shm_size,
+        gpu,
+        devices,
+        limits,
+        cap_add: config.cap_add.clone(),
+        cap_drop: config.cap_drop.clone(),
+        security_opt,
+        groups,
+        privileged: environment.privileged,
+        // the container only exits when stopped, while shells are exec'd
+        command: if keep_alive {
+            vec!["sleep".into(), "infinity".into()]
+        } else {
+            command.clone()
+        },
+    };
+
+    let mut run_command = runtime.command();
+    run_command.args(runtime.backend().run_args(&spec));
+
+    if keep_alive {
+        if runtime.is_dry_run() {
+            println!("{}", render_command(&run_command));
+        } else {
+            progress::start(
+                "start_container",
+                format!("Starting container {} in the background", name),
+            );
+            let output = runtime.output(&mut run_command)?;
+            check_status(
+                &runtime,
+                "Starting the container",
+                output.status,
+                &stderr_lines(&output.stderr),
+            )?;
+            progress::finish();
+            state::record_run(&runtime, &workspace, &name);
+        }
+        // the container keeps mounting the files after yadt exits
+        private_dirs.into_iter().for_each(PrivateDir::keep);
+        // started, the container is found by the yadt waiting for it
+        drop(build_lock);
+
+        let started = Instant::now();
+        let code = exec_in_container(
+            &runtime,
+            &ExecSpec {
+                name: &name,
+                tty,
+                workdir: &workdir,
+                command,
+            },
+            environment.no_exec,
+        )?;
+        return end_session(&runtime, started, code, Some(&name));
+    }
+
+    if runtime.is_dry_run() {
+        println!("{}", render_command(&run_command));
+        return Ok(());
+    }
+
+    // replacing this process passes the container's exit code on as yadt's
+    drop(build_lock);
+    state::record_run(&runtime, &workspace, &name);
+    report_hand_over(&name);
+    let started = Instant::now();
+    // replacing this process leaves the private directories to the
+    // container, while one that was waited for has exited and is removed
+    let code = runtime.hand_over(&mut run_command, environment.no_exec)?;
+    end_session(&runtime, started, code, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    /// A directory unique to this process and `name` in the temporary
+    /// directory, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("yadt-test-{}-{}", process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).expect("The temporary directory is writable");
+            Self(fs::canonicalize(&dir).expect("The directory was just created"))
+        }
+
+        /// Creates the file `path` in the directory, along with its parents
+        fn file(&self, path: &str) -> PathBuf {
+            let path = self.0.join(path);
+            fs::create_dir_all(path.parent().expect("The file is in the directory"))
+                .expect("The temporary directory is writable");
+            fs::write(&path, "").expect("The temporary directory is writable");
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Returns the host and container paths of `files`
+    fn mounted(files: &[HostFile]) -> Vec<(&Path, &str)> {
+        files
+            .iter()
+            .inspect(|file| assert!(file.read_only, "{:?} is writable", file))
+            .map(|file| (file.host.as_path(), file.container.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn git_identity_mounts_gitconfig_and_config_dir() {
+        let home = TempDir::new("git-identity-both");
+        let gitconfig = home.file(".gitconfig");
+        home.file(".config/git/ignore");
+
+        let (files, config_global) = git_identity_files(&home.0, "/home/user");
+        assert_eq!(
+            mounted(&files),
+            [
+                (gitconfig.as_path(), "/home/user/.gitconfig"),
+                (
+                    home.0.join(".config/git").as_path(),
+                    "/home/user/.config/git"
+                ),
+            ]
+        );
+        assert_eq!(
+            config_global.as_deref(),
+            Some("GIT_CONFIG_GLOBAL=/home/user/.gitconfig")
+        );
+    }
+
+    #[test]
+    fn git_identity_skips_missing_files() {
+        let home = TempDir::new("git-identity-none");
+        let (files, config_global) = git_identity_files(&home.0, "/home/user");
+        assert!(files.is_empty());
+        assert_eq!(config_global, None);
+
+        home.file(".config/git/config");
+        let (files, config_global) = git_identity_files(&home.0, "/root");
+        assert_eq!(
+            mounted(&files),
+            [(home.0.join(".config/git").as_path(), "/root/.config/git")]
+        );
+        assert_eq!(config_global, None);
+    }
+
+    #[test]
+    fn git_identity_mounts_the_target_of_a_symlinked_gitconfig() {
+        let home = TempDir::new("git-identity-symlink");
+        let target = home.file("dotfiles/gitconfig");
+        symlink(&target, home.0.join(".gitconfig")).unwrap();
+        // a dangling link is as good as a missing file
+        symlink(home.0.join("gone"), home.0.join(".config")).unwrap();
+
+        let (files, config_global) = git_identity_files(&home.0, "/home/user");
+        assert_eq!(
+            mounted(&files),
+            [(target.as_path(), "/home/user/.gitconfig")]
+        );
+        assert!(config_global.is_some());
+    }
+
+    /// A config setting `image` or `containerfile` and `context` as given
+    fn source_config(image: Option<&str>, containerfile: Option<&str>) -> Config {
+        Config {
+            image: image.map(String::from),
+            containerfile: containerfile.map(String::from),
+            context: containerfile.map(|_| "ctx".to_string()),
+            default_base_image: "base:latest".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn dev_image_source_falls_back_to_default_base_image() {
+        let workspace = TempDir::new("source-default");
+        assert_eq!(
+            configured_dev_image_source(&source_config(None, None), &workspace.0),
+            DevImageSource::Image("base:latest".to_string())
+        );
+    }
+
+    #[test]
+    fn dev_image_source_prefers_containerfile_to_dockerfile() {
+        let workspace = TempDir::new("source-detected");
+        let dockerfile = workspace.file("Dockerfile");
+        let config = source_config(None, None);
+        assert_eq!(
+            configured_dev_image_source(&config, &workspace.0),
+            DevImageSource::Containerfile {
+                containerfile: dockerfile,
+                context: None,
+            }
+        );
+
+        let containerfile = workspace.file("Containerfile");
+        assert_eq!(
+            configured_dev_image_source(&config, &workspace.0),
+            DevImageSource::Containerfile {
+                containerfile,
+                context: None,
+            }
+        );
+
+        // only files count
+        let nested = TempDir::new("source-directory");
+        fs::create_dir(nested.0.join("Containerfile")).unwrap();
+        assert_eq!(
+            configured_dev_image_source(&config, &nested.0),
+            DevImageSource::Image("base:latest".to_string())
+        );
+    }
+
+    #[test]
+    fn dev_image_source_config_beats_the_workspace() {
+        let workspace = TempDir::new("source-config");
+        workspace.file("Containerfile");
+
+        assert_eq!(
+            configured_dev_image_source(&source_config(Some("dev:1"), None), &workspace.0),
+            DevImageSource::Image("dev:1".to_string())
+        );
+        assert_eq!(
+            configured_dev_image_source(
+                &source_config(None, Some("docker/dev.Containerfile")),
+                &workspace.0
+            ),
+            DevImageSource::Containerfile {
+                containerfile: workspace.0.join("docker/dev.Containerfile"),
+                context: Some(workspace.0.join("ctx")),
+            }
+        );
+    }
+
+    #[test]
+    fn dev_image_source_image_beats_containerfile() {
+        let workspace = TempDir::new("source-both");
+        assert_eq!(
+            configured_dev_image_source(
+                &source_config(Some("dev:1"), Some("Containerfile")),
+                &workspace.0
+            ),
+            DevImageSource::Image("dev:1".to_string())
+        );
+    }
+
+    #[test]
+    fn dev_image_source_keeps_absolute_paths() {
+        let workspace = TempDir::new("source-absolute");
+        let config = source_config(None, Some("/srv/dev/Containerfile"));
+        assert_eq!(
+            configured_dev_image_source(&config, &workspace.0),
+            DevImageSource::Containerfile {
+                containerfile: PathBuf::from("/srv/dev/Containerfile"),
+                context: Some(workspace.0.join("ctx")),
+            }
+        );
+    }
+
+    #[test]
+    fn scratch_space_mounts_cli_tmpfs_after_the_configs() {
+        let config = Config {
+            tmpfs: vec!["/tmp/scratch:size=2g,mode=1777".to_string()],
+            shm_size: Some("1g".to_string()),
+            ..Default::default()
+        };
+
+        let (tmpfs, shm_size) = scratch_space(&config, &[], None).unwrap();
+        assert_eq!(tmpfs, ["/tmp/scratch:size=2g,mode=1777"]);
+        assert_eq!(shm_size.as_deref(), Some("1g"));
+
+        let (tmpfs, shm_size) = scratch_space(
+            &config,
+            &["/cache:noexec".to_string()],
+            Some("4g".to_string()),
+        )
+        .unwrap();
+        assert_eq!(tmpfs, ["/tmp/scratch:size=2g,mode=1777", "/cache:noexec"]);
+        assert_eq!(shm_size.as_deref(), Some("4g"));
+    }
+
+    #[test]
+    fn scratch_space_errors_name_the_flag() {
+        let config = Config::default();
+
+        let error = scratch_space(&config, &["scratch".to_string()], None).unwrap_err();
+        assert!(matches!(error, Error::Config(_)));
+        assert_eq!(
+            error.to_string(),
+            "Invalid --tmpfs \"scratch\", the path must be absolute"
+        );
+
+        let error = scratch_space(&config, &[], Some("lots".to_string())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid --shm-size \"lots\", expected a size such as \"512m\" or \"8g\""
+        );
+    }
+
+    #[test]
+    fn private_dirs_are_named_after_their_container() {
+        let base = TempDir::new("private-named");
+        let dir = PrivateDir::create_in(&base.0, "on-enter", "yadt-api-1a2b", false).unwrap();
+        assert_eq!(dir.path, base.0.join("yadt-yadt-api-1a2b-on-enter"));
+        let mode = fs::metadata(&dir.path).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // a name given with --name can't reach outside of the base
+        let dir = PrivateDir::create_in(&base.0, "xauth", "../work/api", false).unwrap();
+        assert_eq!(dir.path, base.0.join("yadt-.._work_api-xauth"));
+    }
+
+    #[test]
+    fn private_dirs_replace_the_previous_containers() {
+        let base = TempDir::new("private-replaced");
+        let dir = PrivateDir::create_in(&base.0, "on-enter", "yadt-api", false).unwrap();
+        fs::write(dir.path.join("on-enter.sh"), "echo old\n").unwrap();
+        dir.keep();
+
+        let dir = PrivateDir::create_in(&base.0, "on-enter", "yadt-api", false).unwrap();
+        assert!(fs::read_dir(&dir.path).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn private_dirs_are_removed_unless_kept() {
+        let base = TempDir::new("private-removed");
+        let dir = PrivateDir::create_in(&base.0, "on-enter", "yadt-api", false).unwrap();
+        let path = dir.path.clone();
+        drop(dir);
+        assert!(!path.exists());
+
+        PrivateDir::create_in(&base.0, "on-enter", "yadt-api", false)
+            .unwrap()
+            .keep();
+        assert!(path.is_dir());
+    }
+
+    #[test]
+    fn dry_runs_leave_private_dirs_alone() {
+        let base = TempDir::new("private-dry-run");
+        let dir = PrivateDir::create_in(&base.0, "on-enter", "yadt-api", true).unwrap();
+        assert!(!dir.path.exists());
+        drop(dir);
+
+        // the directory of a running container stays in place
+        let kept = PrivateDir::create_in(&base.0, "on-enter", "yadt-api", false).unwrap();
+        let path = kept.path.clone();
+        kept.keep();
+        drop(PrivateDir::create_in(&base.0, "on-enter", "yadt-api", true).unwrap());
+        assert!(path.is_dir());
+    }
+}
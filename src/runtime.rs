@@ -0,0 +1,756 @@
+//! The container runtime yadt drives, and how its commands are run.
+//!
+//! Every command goes through a `Runner`, so that something other than the
+//! host's process table can be substituted, eg. a runner recording the
+//! commands it was asked to run.
+
+use std::{
+    env,
+    ffi::OsString,
+    io,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Output},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    backend::{ContainerRuntime, Docker, Nerdctl, Podman},
+    config::{Config, ConfigSources},
+    error::Error,
+    query,
+};
+
+/// Runs the commands yadt invokes the container runtime with
+pub trait Runner {
+    /// Runs `command` to completion, capturing its stdout and stderr
+    fn output(&self, command: &mut Command) -> io::Result<Output>;
+
+    /// Runs `command` to completion, with the stdio it was given
+    fn status(&self, command: &mut Command) -> io::Result<ExitStatus>;
+
+    /// Starts `command` without waiting for it, so that its output can be
+    /// forwarded while it runs
+    fn spawn(&self, command: &mut Command) -> io::Result<Child>;
+
+    /// Replaces this process with `command`, only returning if that fails
+    fn exec(&self, command: &mut Command) -> io::Error;
+}
+
+/// Runs commands as child processes of yadt
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProcessRunner;
+
+impl Runner for ProcessRunner {
+    fn output(&self, command: &mut Command) -> io::Result<Output> {
+        command.output()
+    }
+
+    fn status(&self, command: &mut Command) -> io::Result<ExitStatus> {
+        command.status()
+    }
+
+    fn spawn(&self, command: &mut Command) -> io::Result<Child> {
+        command.spawn()
+    }
+
+    fn exec(&self, command: &mut Command) -> io::Error {
+        command.exec()
+    }
+}
+
+/// Records the commands it is asked to run instead of running them, so that
+/// tests can assert on the arguments yadt generates. Every command succeeds,
+/// printing `stdout`, except for processes that would outlive the call.
+#[cfg(test)]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RecordingRunner {
+    pub(crate) stdout: Vec<u8>,
+
+    /// The program and arguments of every command run, shared with the clones
+    /// of the runner that are handed to a `Runtime`
+    pub(crate) commands: std::rc::Rc<std::cell::RefCell<Vec<Vec<String>>>>,
+}
+
+#[cfg(test)]
+impl RecordingRunner {
+    /// Returns a runner whose commands print `stdout`
+    pub(crate) fn printing(stdout: &str) -> Self {
+        Self {
+            stdout: stdout.as_bytes().to_vec(),
+            ..Self::default()
+        }
+    }
+
+    /// Returns the commands run so far
+    pub(crate) fn commands(&self) -> Vec<Vec<String>> {
+        self.commands.borrow().clone()
+    }
+
+    fn record(&self, command: &Command) {
+        let command = std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        self.commands.borrow_mut().push(command);
+    }
+}
+
+#[cfg(test)]
+impl Runner for RecordingRunner {
+    fn output(&self, command: &mut Command) -> io::Result<Output> {
+        use std::os::unix::process::ExitStatusExt;
+
+        self.record(command);
+        Ok(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: self.stdout.clone(),
+            stderr: Vec::new(),
+        })
+    }
+
+    fn status(&self, command: &mut Command) -> io::Result<ExitStatus> {
+        self.output(command).map(|output| output.status)
+    }
+
+    fn spawn(&self, command: &mut Command) -> io::Result<Child> {
+        self.record(command);
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn exec(&self, command: &mut Command) -> io::Error {
+        self.record(command);
+        io::ErrorKind::Unsupported.into()
+    }
+}
+
+/// Container runtimes whose flags differ enough to matter
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuntimeKind {
+    Podman,
+    Docker,
+    Nerdctl,
+}
+
+impl RuntimeKind {
+    /// Returns the backend translating specs into this runtime's flags
+    pub fn backend(self) -> &'static dyn ContainerRuntime {
+        match self {
+            RuntimeKind::Podman => &Podman,
+            RuntimeKind::Docker => &Docker,
+            RuntimeKind::Nerdctl => &Nerdctl,
+        }
+    }
+}
+
+/// Runtimes tried, in order, when `docker_name` isn't configured
+static RUNTIME_CANDIDATES: [&str; 3] = ["podman", "docker", "nerdctl"];
+
+/// The container runtime used for building and running environments, which
+/// is detected once and then consulted wherever flags differ between runtimes
+pub struct Runtime {
+    /// Name or path of the runtime's cli
+    pub(crate) name: String,
+    pub(crate) kind: RuntimeKind,
+
+    /// Version reported by `--version`, eg. "4.9.3"
+    pub(crate) version: Option<String>,
+
+    /// Whether commands that change anything are only printed
+    pub(crate) dry_run: DryRun,
+
+    /// Flags preceding the subcommand of every invocation, connecting to the
+    /// configured service
+    global_args: Vec<OsString>,
+
+    /// The url of the service if it runs on another host, which can't mount
+    /// paths of this one
+    pub(crate) remote: Option<String>,
+
+    /// The VM containers run in, on macOS where podman can't run them itself
+    pub(crate) machine: Option<Machine>,
+
+    runner: Box<dyn Runner>,
+}
+
+/// A VM of `podman machine`, which only sees the host directories shared
+/// into it
+#[derive(Clone, Debug)]
+pub(crate) struct Machine {
+    pub(crate) name: String,
+    pub(crate) running: bool,
+
+    /// Whether podman runs as root in the VM, which can't keep the user's id
+    pub(crate) rootful: bool,
+
+    /// Host directories mounted at the same path in the VM
+    pub(crate) shares: Vec<PathBuf>,
+}
+
+/// Directories `podman machine init` shares into new machines by default
+const DEFAULT_MACHINE_SHARES: [&str; 3] = ["/Users", "/private", "/var/folders"];
+
+/// Whether commands that change anything are run or only printed, as
+/// selected by `--dry-run`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DryRun {
+    #[default]
+    Off,
+
+    /// Print the commands
+    Print,
+
+    /// Print the commands along with the containerfile piped to builds
+    PrintContainerfile,
+}
+
+impl Runtime {
+    /// Probes the configured runtime with `--version`, failing with a hint
+    /// naming where `docker_name` was set if it can't be run. Without a
+    /// configured `docker_name`, podman, docker, and nerdctl are tried in
+    /// that order. The runtime kind is taken from the config if set,
+    /// otherwise from the probe's output, since each runtime names itself.
+    pub fn detect(
+        config: &Config,
+        sources: &ConfigSources,
+        dry_run: DryRun,
+    ) -> Result<Self, Error> {
+        Self::detect_with(Box::new(ProcessRunner), config, sources, dry_run)
+    }
+
+    /// Like `detect`, but runs every command, including the probe, with
+    /// `runner`
+    pub fn detect_with(
+        runner: Box<dyn Runner>,
+        config: &Config,
+        sources: &ConfigSources,
+        dry_run: DryRun,
+    ) -> Result<Self, Error> {
+        let candidates: Vec<&str> = match sources.of("docker_name") {
+            Some(_) => vec![&config.docker_name],
+            None => RUNTIME_CANDIDATES.to_vec(),
+        };
+
+        let mut probe = None;
+        let mut first_error = None;
+        for name in candidates {
+            log::trace!("Running {} --version", name);
+            match runner.output(Command::new(name).arg("--version")) {
+                Ok(output) => {
+                    probe = Some((name, output));
+                    break;
+                }
+                Err(error) => {
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+
+        let Some((name, output)) = probe else {
+            return Err(Error::MissingRuntime {
+                name: config.docker_name.clone(),
+                origin: match sources.of("docker_name") {
+                    Some(source) => format!("docker_name set in {}", source),
+                    None => {
+                        "default docker_name, and neither docker nor nerdctl were found".to_string()
+                    }
+                },
+                source: first_error.expect("No runtime candidates were probed"),
+            });
+        };
+
+        if !output.status.success() {
+            return Err(Error::Runtime(format!(
+                "`{} --version` failed, is it a docker-compatible cli?",
+                name
+            )));
+        }
+
+        // eg. "podman version 4.9.3", "Docker version 24.0.7, build afdd53b",
+        // or "nerdctl version 1.7.6"
+        let version_output = String::from_utf8_lossy(&output.stdout).to_lowercase();
+        let version = version_output
+            .split_whitespace()
+            .skip_while(|word| *word != "version")
+            .nth(1)
+            .map(|version| version.trim_end_matches(',').to_string());
+
+        let kind = config
+            .runtime
+            .unwrap_or(if version_output.contains("podman") {
+                RuntimeKind::Podman
+            } else if version_output.contains("nerdctl") {
+                RuntimeKind::Nerdctl
+            } else {
+                RuntimeKind::Docker
+            });
+
+        let (global_args, url) = match &config.connection {
+            Some(connection) => {
+                let global_args = kind.backend().connection_args(connection).ok_or_else(|| {
+                    Error::Config(format!(
+                        "connection is set, but {} has no named connections",
+                        name
+                    ))
+                })?;
+                (
+                    global_args,
+                    connection_url(&*runner, name, kind, connection),
+                )
+            }
+            None => match env::var(kind.backend().host_variable()) {
+                Ok(url) if !url.is_empty() => (kind.backend().host_args(&url), Some(url)),
+                _ => (Vec::new(), None),
+            },
+        };
+        let remote = url.filter(|url| is_remote_url(url));
+        if let Some(remote) = &remote {
+            log::debug!("Using the {} service at {}", name, remote);
+        }
+
+        // a connection or url configured explicitly may not be the machine's
+        let machine =
+            if cfg!(target_os = "macos") && kind == RuntimeKind::Podman && global_args.is_empty() {
+                default_machine(&*runner, name)
+            } else {
+                None
+            };
+        if let Some(machine) = &machine {
+            log::debug!("Using podman machine {}", machine.name);
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            kind,
+            version,
+            dry_run,
+            global_args,
+            remote,
+            machine,
+            runner,
+        })
+    }
+
+    /// Returns whether commands that change anything are only printed
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run != DryRun::Off
+    }
+
+    /// Runs the runtime with `args`, which change its state, failing with
+    /// `step` if it exits unsuccessfully. In dry runs the command is only
+    /// printed.
+    pub fn apply(&self, step: &str, args: &[&str]) -> Result<(), Error> {
+        if self.is_dry_run() {
+            let mut command = self.command();
+            command.args(args);
+            println!("{}", render_command(&command));
+            return Ok(());
+        }
+
+        query::query(self, step, args).map(drop)
+    }
+
+    /// Returns the backend translating specs into this runtime's flags
+    pub fn backend(&self) -> &'static dyn ContainerRuntime {
+        self.kind.backend()
+    }
+
+    /// Describes the runtime for messages, eg. "podman 4.9.3"
+    pub fn describe(&self) -> String {
+        match &self.version {
+            Some(version) => format!("{} {}", self.name, version),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Returns a new command invoking the runtime's cli, connected to the
+    /// configured service
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.name);
+        command.args(&self.global_args);
+        command
+    }
+
+    /// Fails with why host paths can't be mounted if the runtime's service
+    /// runs on another host, where `what` would be missing
+    pub fn check_local(&self, what: &str) -> Result<(), Error> {
+        match &self.remote {
+            Some(remote) => Err(Error::Config(format!(
+                "{} runs containers on {}, which can't mount {} from this host. Run yadt there, or unset connection, CONTAINER_HOST, and DOCKER_HOST to use the local service",
+                self.name, remote, what
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns whether containers can mount `path`, which only directories
+    /// shared into the podman machine can be
+    pub fn is_shared(&self, path: &Path) -> bool {
+        self.machine
+            .as_ref()
+            .is_none_or(|machine| machine.shares.iter().any(|share| path.starts_with(share)))
+    }
+
+    /// Fails if `path` isn't shared into the podman machine containers run
+    /// in, where `what` would be missing
+    pub fn check_shared(&self, path: &Path, what: &str) -> Result<(), Error> {
+        let Some(machine) = self.machine.as_ref().filter(|_| !self.is_shared(path)) else {
+            return Ok(());
+        };
+        let shares: Vec<_> = machine
+            .shares
+            .iter()
+            .map(|share| share.display().to_string())
+            .collect();
+        Err(Error::Config(format!(
+            "{} isn't shared into podman machine {}, which only mounts {}. Move it under one of those, or recreate the machine with `podman machine init --volume {}:{}`",
+            what,
+            machine.name,
+            shares.join(", "),
+            path.display(),
+            path.display()
+        )))
+    }
+
+    /// Returns whether containers can keep the user's id in their user
+    /// namespace, which podman can't when it runs as root in its machine
+    pub fn can_keep_id(&self) -> bool {
+        !self.machine.as_ref().is_some_and(|machine| machine.rootful)
+    }
+
+    /// Runs `command` with the runtime's runner, capturing its output
+    pub fn output(&self, command: &mut Command) -> io::Result<Output> {
+        log::trace!("Running {}", render_command(command));
+        self.runner.output(command)
+    }
+
+    /// Runs `command` with the runtime's runner
+    pub fn status(&self, command: &mut Command) -> io::Result<ExitStatus> {
+        log::trace!("Running {}", render_command(command));
+        self.runner.status(command)
+    }
+
+    /// Starts `command` with the runtime's runner
+    pub fn spawn(&self, command: &mut Command) -> io::Result<Child> {
+        log::trace!("Running {}", render_command(command));
+        self.runner.spawn(command)
+    }
+
+    /// Replaces this process with `command`, failing with the reason it
+    /// couldn't be
+    pub fn exec(&self, command: &mut Command) -> Error {
+        log::trace!("Executing {}", render_command(command));
+        // exec only returns if the process could not be replaced
+        Error::Io(self.runner.exec(command))
+    }
+
+    /// Hands the terminal over to `command`, the last thing yadt runs. Unless
+    /// `wait` is set, this process is replaced with it, which keeps signals
+    /// and the tty its own. Otherwise yadt waits for it and then returns its
+    /// exit code.
+    pub fn hand_over(&self, command: &mut Command, wait: bool) -> Result<u8, Error> {
+        if !wait {
+            return Err(self.exec(command));
+        }
+
+        let status = self.status(command)?;
+        // a command killed by a signal has no exit code of its own
+        Ok(status
+            .code()
+            .and_then(|code| u8::try_from(code).ok())
+            .unwrap_or(1))
+    }
+}
+
+/// Returns the url of the named `connection` of the runtime `name`, or None
+/// if it can't be looked up
+fn connection_url(
+    runner: &dyn Runner,
+    name: &str,
+    kind: RuntimeKind,
+    connection: &str,
+) -> Option<String> {
+    let mut command = Command::new(name);
+    match kind {
+        RuntimeKind::Podman => command.args([
+            "system",
+            "connection",
+            "list",
+            "--format",
+            "{{.Name}}\t{{.URI}}",
+        ]),
+        _ => command.args([
+            "context",
+            "inspect",
+            connection,
+            "--format",
+            "{{.Endpoints.docker.Host}}",
+        ]),
+    };
+
+    let output = runner.output(&mut command).ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match kind {
+        RuntimeKind::Podman => stdout.lines().find_map(|line| {
+            let (name, url) = line.split_once('\t')?;
+            (name == connection).then(|| url.trim().to_string())
+        }),
+        _ => Some(stdout.trim().to_string()).filter(|url| !url.is_empty()),
+    }
+}
+
+/// Returns the default machine of the podman cli `name`, or None if it has
+/// none or they can't be listed
+fn default_machine(runner: &dyn Runner, name: &str) -> Option<Machine> {
+    let json = |args: &[&str]| -> Option<Value> {
+        let output = runner.output(Command::new(name).args(args)).ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        serde_json::from_slice(&output.stdout).ok()
+    };
+
+    let machines = json(&["machine", "list", "--format", "json"])?;
+    let machines = machines.as_array()?;
+    let machine = machines
+        .iter()
+        .find(|machine| machine["Default"].as_bool() == Some(true))
+        .or_else(|| machines.first())?;
+    let machine_name = machine["Name"].as_str()?.trim_end_matches('*').to_string();
+    let running = machine["Running"].as_bool() == Some(true);
+
+    // a machine that is stopped can still be inspected
+    let inspected = json(&["machine", "inspect", &machine_name]);
+    let inspected = inspected.as_ref().and_then(|inspected| inspected.get(0));
+    let rootful = inspected.is_some_and(|inspected| inspected["Rootful"].as_bool() == Some(true));
+    let shares: Vec<PathBuf> = inspected
+        .and_then(|inspected| inspected["Mounts"].as_array())
+        .map(|mounts| {
+            mounts
+                .iter()
+                .filter_map(|mount| mount["Source"].as_str())
+                .map(PathBuf::from)
+                .collect()
+        })
+        .unwrap_or_default();
+    let shares = if shares.is_empty() {
+        DEFAULT_MACHINE_SHARES.iter().map(PathBuf::from).collect()
+    } else {
+        shares
+    };
+
+    Some(Machine {
+        name: machine_name,
+        running,
+        rootful,
+        shares,
+    })
+}
+
+/// Returns whether the service at `url` runs on another host. Services
+/// reached over ssh or tcp on the loopback address, such as the VM of
+/// `podman machine`, share this host's paths.
+fn is_remote_url(url: &str) -> bool {
+    let Some(rest) = url
+        .strip_prefix("ssh://")
+        .or_else(|| url.strip_prefix("tcp://"))
+    else {
+        return false;
+    };
+    let authority = rest.split('/').next().unwrap_or(rest);
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let host = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or(bracketed),
+        None => host.split(':').next().unwrap_or(host),
+    };
+    !matches!(host, "localhost" | "127.0.0.1" | "::1")
+}
+
+/// Renders `command` as it could be typed into a shell, quoting arguments
+/// where needed
+pub fn render_command(command: &Command) -> String {
+    fn quote(arg: &str) -> String {
+        let is_plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=,@%+".contains(c);
+        if !arg.is_empty() && arg.chars().all(is_plain) {
+            arg.to_string()
+        } else {
+            format!("'{}'", arg.replace('\'', r"'\''"))
+        }
+    }
+
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Splits captured stderr into lines for `check_status`
+pub(crate) fn stderr_lines(stderr: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(stderr)
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Returns an error describing which `step` failed and how if the container
+/// runtime exited unsuccessfully. The last lines the runtime wrote to stderr
+/// are included in the message.
+pub(crate) fn check_status(
+    runtime: &Runtime,
+    step: &str,
+    status: ExitStatus,
+    stderr_tail: &[String],
+) -> Result<(), Error> {
+    if status.success() {
+        return Ok(());
+    }
+
+    let reason = match status.code() {
+        Some(code) => format!("exited with code {}", code),
+        None => "was terminated by a signal".to_string(),
+    };
+
+    let mut message = format!("{} failed: {} {}", step, runtime.describe(), reason);
+    for line in stderr_tail {
+        message.push_str("\n    ");
+        message.push_str(line);
+    }
+
+    Err(Error::Runtime(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::config::ConfigSource;
+
+    /// A runner on a host without any container runtime
+    struct MissingRunner;
+
+    impl Runner for MissingRunner {
+        fn output(&self, _command: &mut Command) -> io::Result<Output> {
+            Err(io::ErrorKind::NotFound.into())
+        }
+
+        fn status(&self, _command: &mut Command) -> io::Result<ExitStatus> {
+            Err(io::ErrorKind::NotFound.into())
+        }
+
+        fn spawn(&self, _command: &mut Command) -> io::Result<Child> {
+            Err(io::ErrorKind::NotFound.into())
+        }
+
+        fn exec(&self, _command: &mut Command) -> io::Error {
+            io::ErrorKind::NotFound.into()
+        }
+    }
+
+    #[test]
+    fn missing_configured_runtime_names_where_it_was_set() {
+        let config = Config {
+            docker_name: "docker".to_string(),
+            ..Config::default()
+        };
+        let mut sources = ConfigSources::default();
+        sources.push(
+            ConfigSource::File(PathBuf::from("/home/user/.config/yadt/config.toml")),
+            &["docker_name".to_string()],
+        );
+
+        let error = Runtime::detect_with(Box::new(MissingRunner), &config, &sources, DryRun::Off)
+            .err()
+            .expect("No runtime can be run");
+        assert!(matches!(error, Error::MissingRuntime { .. }));
+        assert_eq!(error.code(), 4);
+        let message = error.to_string();
+        assert!(
+            message.starts_with(
+                "Could not run container runtime `docker` (docker_name set in /home/user/.config/yadt/config.toml): "
+            ),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("set `docker_name` in the config"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn missing_default_runtime_says_every_candidate_was_tried() {
+        let error = Runtime::detect_with(
+            Box::new(MissingRunner),
+            &Config::default(),
+            &ConfigSources::default(),
+            DryRun::Off,
+        )
+        .err()
+        .expect("No runtime can be run");
+        let message = error.to_string();
+        assert!(
+            message.contains("(default docker_name, and neither docker nor nerdctl were found)"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn detects_the_backend_from_the_version_output() {
+        for (stdout, kind, version) in [
+            ("podman version 4.9.3\n", RuntimeKind::Podman, "4.9.3"),
+            (
+                "Docker version 24.0.7, build afdd53b\n",
+                RuntimeKind::Docker,
+                "24.0.7",
+            ),
+            ("nerdctl version 1.7.6\n", RuntimeKind::Nerdctl, "1.7.6"),
+        ] {
+            let runner = RecordingRunner::printing(stdout);
+            let runtime = Runtime::detect_with(
+                Box::new(runner.clone()),
+                &Config::default(),
+                &ConfigSources::default(),
+                DryRun::Off,
+            )
+            .expect("The probe succeeds");
+
+            assert_eq!(runtime.kind, kind, "{}", stdout);
+            assert_eq!(runtime.version.as_deref(), Some(version));
+            // the first candidate answered, so no other was probed
+            assert_eq!(runner.commands(), [["podman", "--version"]]);
+        }
+    }
+
+    #[test]
+    fn configured_runtime_overrides_the_version_output() {
+        let config = Config {
+            docker_name: "/opt/bin/containers".to_string(),
+            runtime: Some(RuntimeKind::Nerdctl),
+            ..Config::default()
+        };
+        let mut sources = ConfigSources::default();
+        sources.push(
+            ConfigSource::File(PathBuf::from("yadt.toml")),
+            &["docker_name".to_string(), "runtime".to_string()],
+        );
+
+        let runner = RecordingRunner::printing("Docker version 24.0.7, build afdd53b\n");
+        let runtime =
+            Runtime::detect_with(Box::new(runner.clone()), &config, &sources, DryRun::Off)
+                .expect("The probe succeeds");
+        assert_eq!(runtime.kind, RuntimeKind::Nerdctl);
+        assert_eq!(runner.commands(), [["/opt/bin/containers", "--version"]]);
+    }
+}
@@ -0,0 +1,124 @@
+//! Interrupting and killing the builds and pulls yadt waits for.
+//!
+//! Builds and pulls run in a process group of their own, so that everything
+//! they start can be killed along with them once they time out. While one
+//! runs, and from just before it is spawned, SIGINT and SIGTERM are
+//! forwarded to its group instead of killing yadt outright, so that the
+//! build can stop and yadt can remove its temporary files, such as the
+//! iidfile, the merged auth file, and the generated startup files, once the
+//! interruption has unwound to `main`. A second signal kills a build that
+//! doesn't stop. Outside of builds the signals keep their default behavior,
+//! which is also all the shell yadt replaces itself with inherits.
+
+use std::{
+    mem,
+    process::Child,
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use libc::c_int;
+
+/// Signals forwarded to the build
+const FORWARDED_SIGNALS: [c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// Process group of the build signals are forwarded to, or 0 for none
+static CHILD: AtomicI32 = AtomicI32::new(0);
+
+/// The first signal forwarded to the current build, or 0 for none
+static INTERRUPTED: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward(signal: c_int) {
+    let first = INTERRUPTED.swap(signal, Ordering::SeqCst);
+    // a build that isn't spawned yet gets the signal once it is
+    let pid = CHILD.load(Ordering::SeqCst);
+    if pid <= 0 {
+        return;
+    }
+    let signal = match first {
+        0 => signal,
+        _ => libc::SIGKILL,
+    };
+    // SAFETY: kill is async-signal-safe
+    unsafe {
+        libc::kill(-pid, signal);
+    }
+}
+
+/// Sends `signal` to `child` and the processes it started, which must have
+/// been spawned in a process group of its own
+pub(crate) fn kill_group(child: &Child, signal: c_int) {
+    // SAFETY: kill has no preconditions, and fails harmlessly if the group
+    // is already gone
+    unsafe {
+        libc::kill(-(child.id() as i32), signal);
+    }
+}
+
+/// Forwards interrupts to a build until dropped
+pub(crate) struct Forwarding {
+    previous: Vec<(c_int, libc::sigaction)>,
+}
+
+impl Forwarding {
+    /// Starts catching SIGINT and SIGTERM for a build about to be spawned,
+    /// so that one arriving before `attach` can't kill yadt and orphan it
+    pub(crate) fn new() -> Self {
+        CHILD.store(0, Ordering::SeqCst);
+        INTERRUPTED.store(0, Ordering::SeqCst);
+
+        let previous = FORWARDED_SIGNALS
+            .iter()
+            .map(|&signal| {
+                // SAFETY: the handler only touches atomics and calls kill
+                unsafe {
+                    let mut action: libc::sigaction = mem::zeroed();
+                    action.sa_sigaction = forward as extern "C" fn(c_int) as libc::sighandler_t;
+                    // reading the build's output resumes after the handler
+                    action.sa_flags = libc::SA_RESTART;
+                    libc::sigemptyset(&mut action.sa_mask);
+                    let mut previous: libc::sigaction = mem::zeroed();
+                    libc::sigaction(signal, &action, &mut previous);
+                    (signal, previous)
+                }
+            })
+            .collect();
+
+        Self { previous }
+    }
+
+    /// Forwards the signals caught from now on to `child`, which must have
+    /// been spawned in a process group of its own, along with one caught
+    /// while it was being spawned
+    pub(crate) fn attach(&self, child: &Child) {
+        let pid = child.id() as i32;
+        CHILD.store(pid, Ordering::SeqCst);
+        let signal = INTERRUPTED.load(Ordering::SeqCst);
+        if signal != 0 {
+            // SAFETY: the group was just spawned, and is only reaped by
+            // this process
+            unsafe {
+                libc::kill(-pid, signal);
+            }
+        }
+    }
+
+    /// Returns the signal the build was interrupted with, if any
+    pub(crate) fn interrupted(&self) -> Option<c_int> {
+        match INTERRUPTED.load(Ordering::SeqCst) {
+            0 => None,
+            signal => Some(signal),
+        }
+    }
+}
+
+impl Drop for Forwarding {
+    fn drop(&mut self) {
+        for (signal, previous) in &self.previous {
+            // SAFETY: restores the action saved when forwarding started
+            unsafe {
+                libc::sigaction(*signal, previous, std::ptr::null_mut());
+            }
+        }
+        CHILD.store(0, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,488 @@
+//! What yadt remembers about environments between runs.
+//!
+//! The state file in the data directory records, for each workspace, the
+//! environment image last built for it, the container it last ran in, and
+//! when. Apart from the snapshot an environment was set to start from, it
+//! only caches what the labels of yadt's images and containers also record,
+//! so a state file that is missing or can't be read is rebuilt from them
+//! instead of failing, and failing to write it only warns. A lost snapshot
+//! setting only means that the environment is built from its config again.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    build::{environment_tag, is_image_id, workspace_hash, EnvironmentImage},
+    config::data_dir,
+    error::Error,
+    query,
+    runtime::Runtime,
+};
+
+/// Name of the state file in the data directory
+const STATE_FILE_NAME: &str = "state.json";
+
+/// What yadt remembers about the environment of a workspace
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WorkspaceState {
+    /// Canonical path of the workspace
+    pub workspace: PathBuf,
+
+    /// Id of the environment image last built for the workspace
+    #[serde(default)]
+    pub image_id: Option<String>,
+
+    /// Tag of the environment image last built for the workspace
+    #[serde(default)]
+    pub image_tag: Option<String>,
+
+    /// The config hash the image was built for
+    #[serde(default)]
+    pub config_hash: Option<String>,
+
+    /// Home of the container user in the image built for `config_hash`,
+    /// which dev images with a user of the host's id decide
+    #[serde(default)]
+    pub home: Option<String>,
+
+    /// Name of the container the environment last ran in
+    #[serde(default)]
+    pub container: Option<String>,
+
+    /// When the environment last ran, in seconds since the unix epoch
+    #[serde(default)]
+    pub last_run: Option<i64>,
+
+    /// Tag of the snapshot the environment starts from instead of the image
+    /// built for its config, set with `yadt snapshot --use`
+    #[serde(default)]
+    pub snapshot: Option<String>,
+}
+
+/// The environments yadt remembers, keyed by workspace hash
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default)]
+    pub workspaces: BTreeMap<String, WorkspaceState>,
+}
+
+impl State {
+    /// Returns the path of the state file, if a data directory can be
+    /// determined for this platform
+    pub fn path() -> Option<PathBuf> {
+        data_dir().map(|dir| dir.join(STATE_FILE_NAME))
+    }
+
+    /// Reads the state file, rebuilding it from the labels of the runtime's
+    /// images and containers if it is missing or can't be read
+    pub fn load(runtime: &Runtime) -> Result<Self, Error> {
+        let Some(path) = Self::path() else {
+            return Self::from_labels(runtime);
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(text) => match serde_json::from_str(&text) {
+                Ok(state) => return Ok(state),
+                Err(error) => log::warn!(
+                    "Rebuilding {}, which can't be read: {}",
+                    path.display(),
+                    error
+                ),
+            },
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                log::debug!("Creating {}", path.display());
+            }
+            Err(error) => log::warn!(
+                "Rebuilding {}, which can't be read: {}",
+                path.display(),
+                error
+            ),
+        }
+
+        let state = Self::from_labels(runtime)?;
+        if !runtime.is_dry_run() {
+            state.save();
+        }
+        Ok(state)
+    }
+
+    /// Recovers what the labels of the runtime's images and containers
+    /// record, which lacks when environments last ran unless their
+    /// container still exists
+    pub fn from_labels(runtime: &Runtime) -> Result<Self, Error> {
+        let mut state = Self::default();
+
+        let mut images = query::list_images(runtime)?;
+        // newer images replace older ones of the same workspace
+        images.sort_by_key(|image| image.created);
+        for image in images {
+            let (Some(workspace), Some(config_hash)) = (image.workspace, image.config_hash) else {
+                continue;
+            };
+            let entry = state.entry(Path::new(&workspace));
+            entry.image_tag = Some(environment_tag(&entry.workspace, &config_hash));
+            entry.image_id = Some(image.id);
+            entry.config_hash = Some(config_hash);
+        }
+
+        let mut containers = query::list_containers(runtime)?;
+        containers.sort_by_key(|container| container.created);
+        for container in containers {
+            let Some(workspace) = container.workspace else {
+                continue;
+            };
+            let entry = state.entry(Path::new(&workspace));
+            entry.container = Some(container.name);
+            entry.last_run = container.created;
+        }
+
+        Ok(state)
+    }
+
+    /// Returns what is remembered about `workspace`, if anything
+    pub fn get(&self, workspace: &Path) -> Option<&WorkspaceState> {
+        self.workspaces.get(&workspace_hash(workspace))
+    }
+
+    /// Returns the entry of `workspace`, creating it if it doesn't exist
+    fn entry(&mut self, workspace: &Path) -> &mut WorkspaceState {
+        self.workspaces
+            .entry(workspace_hash(workspace))
+            .or_insert_with(|| WorkspaceState {
+                workspace: workspace.to_path_buf(),
+                ..Default::default()
+            })
+    }
+
+    /// Writes the state file, replacing it at once so that a yadt reading it
+    /// meanwhile never sees half of it. Failing to write it only warns,
+    /// since it can be rebuilt.
+    pub fn save(&self) {
+        let Some(path) = Self::path() else {
+            return;
+        };
+        let temp_path = path.with_extension(format!("json.{}", process::id()));
+
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| {
+                let text = serde_json::to_string_pretty(self).map_err(io::Error::from)?;
+                fs::write(&temp_path, text)
+            })
+            .and_then(|()| fs::rename(&temp_path, &path));
+        if let Err(error) = result {
+            let _ = fs::remove_file(&temp_path);
+            log::warn!("Could not update {}: {}", path.display(), error);
+        }
+    }
+}
+
+/// Returns the current time in seconds since the unix epoch
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64)
+}
+
+/// Name of the file in the data directory locked while the state file is
+/// updated
+const LOCK_FILE_NAME: &str = "state.lock";
+
+/// An exclusive lock on updating the state file, so that yadt running
+/// twice at once doesn't lose either's changes. The lock is released when
+/// dropped.
+struct StateLock {
+    _file: fs::File,
+}
+
+impl StateLock {
+    /// Waits for the lock, which is None if it can't be taken, since the
+    /// state is only a record
+    fn acquire() -> Option<Self> {
+        let dir = data_dir()?;
+        let path = dir.join(LOCK_FILE_NAME);
+        let result = fs::create_dir_all(&dir)
+            .and_then(|()| {
+                fs::OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(&path)
+            })
+            .and_then(|file| {
+                // SAFETY: the descriptor is owned by `file`, which outlives this
+                match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } {
+                    0 => Ok(file),
+                    _ => Err(io::Error::last_os_error()),
+                }
+            });
+        match result {
+            Ok(file) => Some(Self { _file: file }),
+            Err(error) => {
+                log::warn!("Could not lock {}: {}", path.display(), error);
+                None
+            }
+        }
+    }
+}
+
+/// Applies `change` to the state file under `StateLock`. The state is only
+/// a record, so failing to update it only warns
+fn update(runtime: &Runtime, change: impl FnOnce(&mut State)) {
+    let _lock = StateLock::acquire();
+    match State::load(runtime) {
+        Ok(mut state) => {
+            change(&mut state);
+            state.save();
+        }
+        Err(error) => log::warn!("Could not update {}: {}", STATE_FILE_NAME, error),
+    }
+}
+
+/// Remembers `image` as the environment image last built for `workspace`
+pub fn record_build(runtime: &Runtime, workspace: &Path, image: &EnvironmentImage) {
+    update(runtime, |state| {
+        let entry = state.entry(workspace);
+        // a reused image is only known by its tag
+        if is_image_id(&image.id) {
+            entry.image_id = Some(image.id.clone());
+        }
+        entry.image_tag = Some(environment_tag(workspace, &image.config_hash));
+        // a rebuilt image may be built from a dev image with another user
+        if image.built || entry.config_hash.as_ref() != Some(&image.config_hash) {
+            entry.home = None;
+        }
+        entry.config_hash = Some(image.config_hash.clone());
+    });
+}
+
+/// Remembers `home` as the home of the container user in the image built
+/// for `config_hash`, unless another image was built for `workspace` since
+pub fn record_home(runtime: &Runtime, workspace: &Path, config_hash: &str, home: &str) {
+    update(runtime, |state| {
+        let entry = state.entry(workspace);
+        if entry.config_hash.as_deref() == Some(config_hash) {
+            entry.home = Some(home.to_string());
+        }
+    });
+}
+
+/// Remembers that the environment of `workspace` was just started or
+/// entered in the container `name`
+pub fn record_run(runtime: &Runtime, workspace: &Path, name: &str) {
+    update(runtime, |state| {
+        let entry = state.entry(workspace);
+        entry.container = Some(name.to_string());
+        entry.last_run = Some(now());
+    });
+}
+
+/// Makes the environment of `workspace` start from the snapshot `tag`, or
+/// from the image built for its config again if `tag` is None
+pub fn use_snapshot(runtime: &Runtime, workspace: &Path, tag: Option<String>) -> Result<(), Error> {
+    let _lock = StateLock::acquire();
+    let mut state = State::load(runtime)?;
+    state.entry(workspace).snapshot = tag;
+    state.save();
+    Ok(())
+}
+
+/// Forgets the environment of `workspace`, once it was removed
+pub fn forget(runtime: &Runtime, workspace: &Path) {
+    update(runtime, |state| {
+        state.workspaces.remove(&workspace_hash(workspace));
+    });
+}
+
+/// Returns the remembered workspaces `query` picks out, preferring, in
+/// order, those whose directory name is `query`, starts with it, or
+/// contains it, then those whose path contains it, and finally those whose
+/// directory name contains its characters in order. Case is ignored.
+pub fn match_workspaces<'a>(
+    query: &str,
+    workspaces: impl IntoIterator<Item = &'a Path>,
+) -> Vec<&'a Path> {
+    let query = query.to_lowercase();
+    let is_subsequence = |name: &str| {
+        let mut name = name.chars();
+        query.chars().all(|c| name.any(|n| n == c))
+    };
+    let rank = |workspace: &Path| {
+        let path = workspace.to_string_lossy().to_lowercase();
+        let name = workspace
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if name == query {
+            Some(0)
+        } else if name.starts_with(&query) {
+            Some(1)
+        } else if name.contains(&query) {
+            Some(2)
+        } else if path.contains(&query) {
+            Some(3)
+        } else if is_subsequence(&name) {
+            Some(4)
+        } else {
+            None
+        }
+    };
+
+    let ranked: Vec<(u8, &Path)> = workspaces
+        .into_iter()
+        .filter_map(|workspace| Some((rank(workspace)?, workspace)))
+        .collect();
+    let best = ranked.iter().map(|(rank, _)| *rank).min();
+    ranked
+        .into_iter()
+        .filter(|(rank, _)| Some(*rank) == best)
+        .map(|(_, workspace)| workspace)
+        .collect()
+}
+
+/// Returns the remembered workspace `query` picks out with
+/// `match_workspaces`, or the one that ran most recently if `query` is None.
+/// A query matching several workspaces fails listing them.
+pub fn recall_workspace(runtime: &Runtime, query: Option<&str>) -> Result<PathBuf, Error> {
+    let state = State::load(runtime)?;
+    let Some(query) = query else {
+        return state
+            .workspaces
+            .into_values()
+            .filter(|entry| entry.last_run.is_some())
+            .max_by_key(|entry| entry.last_run)
+            .map(|entry| entry.workspace)
+            .ok_or_else(|| Error::Config("No environment ran yet".to_string()));
+    };
+
+    let matches = match_workspaces(
+        query,
+        state
+            .workspaces
+            .values()
+            .map(|entry| entry.workspace.as_path()),
+    );
+    match matches.as_slice() {
+        [] => Err(Error::Config(format!(
+            "No directory or remembered workspace matches \"{}\", see `yadt recent`",
+            query
+        ))),
+        [workspace] => Ok(workspace.to_path_buf()),
+        candidates => {
+            let candidates: Vec<String> = candidates
+                .iter()
+                .map(|workspace| format!("\n    {}", workspace.display()))
+                .collect();
+            Err(Error::Conflict(format!(
+                "\"{}\" matches several workspaces, pass more of the path:{}",
+                query,
+                candidates.concat()
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORKSPACES: [&str; 6] = [
+        "/home/dev/src/api-server",
+        "/home/dev/src/api",
+        "/home/dev/src/web-api",
+        "/home/dev/work/Billing",
+        "/home/dev/work/billing-api-client",
+        "/srv/checkouts/Frontend",
+    ];
+
+    fn matches(query: &str) -> Vec<&'static str> {
+        match_workspaces(query, WORKSPACES.iter().map(Path::new))
+            .into_iter()
+            .map(|workspace| workspace.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn exact_names_win_over_prefixes() {
+        assert_eq!(matches("api"), ["/home/dev/src/api"]);
+        assert_eq!(matches("api-server"), ["/home/dev/src/api-server"]);
+    }
+
+    #[test]
+    fn prefixes_win_over_names_containing_the_query() {
+        assert_eq!(matches("api-"), ["/home/dev/src/api-server"]);
+        assert_eq!(matches("web"), ["/home/dev/src/web-api"]);
+        assert_eq!(
+            matches("-api"),
+            ["/home/dev/src/web-api", "/home/dev/work/billing-api-client"]
+        );
+    }
+
+    #[test]
+    fn names_win_over_paths_containing_the_query() {
+        assert_eq!(matches("end"), ["/srv/checkouts/Frontend"]);
+        assert_eq!(matches("checkouts"), ["/srv/checkouts/Frontend"]);
+        assert_eq!(
+            matches("src/"),
+            [
+                "/home/dev/src/api-server",
+                "/home/dev/src/api",
+                "/home/dev/src/web-api",
+            ]
+        );
+    }
+
+    #[test]
+    fn ties_return_every_candidate() {
+        assert_eq!(
+            matches("billing"),
+            ["/home/dev/work/Billing"],
+            "the exact name is a tier of its own"
+        );
+        assert_eq!(
+            matches("bill"),
+            [
+                "/home/dev/work/Billing",
+                "/home/dev/work/billing-api-client"
+            ]
+        );
+        assert_eq!(
+            matches("work"),
+            [
+                "/home/dev/work/Billing",
+                "/home/dev/work/billing-api-client"
+            ]
+        );
+    }
+
+    #[test]
+    fn case_is_ignored() {
+        assert_eq!(matches("FRONTEND"), ["/srv/checkouts/Frontend"]);
+        assert_eq!(matches("Api-Server"), ["/home/dev/src/api-server"]);
+        assert_eq!(matches("SRV/"), ["/srv/checkouts/Frontend"]);
+    }
+
+    #[test]
+    fn falls_back_to_names_containing_the_characters_in_order() {
+        assert_eq!(matches("apsrv"), ["/home/dev/src/api-server"]);
+        assert_eq!(matches("frnd"), ["/srv/checkouts/Frontend"]);
+        assert_eq!(matches("bac"), ["/home/dev/work/billing-api-client"]);
+        // the characters must come in order, and only names are searched
+        assert!(matches("dnorf").is_empty());
+        assert!(matches("hmdv").is_empty());
+    }
+
+    #[test]
+    fn nothing_matches_without_workspaces() {
+        assert!(match_workspaces("api", []).is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//! Building an environment against a fake container runtime whose builds
+//! write the iidfile yadt reads the image id from.
+
+use std::{
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{self, Command, Output},
+};
+
+/// A runtime answering like docker whose builds write `$YADT_TEST_IID` to
+/// the iidfile they were given, and whose containers exit immediately
+const FAKE_RUNTIME: &str = r#"#!/bin/sh
+case "$1" in
+  --version) echo "Docker version 24.0.7, build afdd53b"; exit 0;;
+  build)
+    while [ $# -gt 0 ]; do
+      if [ "$1" = --iidfile ]; then printf '%s' "$YADT_TEST_IID" > "$2"; fi
+      shift
+    done
+    cat > /dev/null
+    exit 0;;
+  container|inspect|image) exit 1;;
+esac
+exit 0
+"#;
+
+/// The id the fake runtime's builds report
+const IMAGE_ID: &str = "sha256:4f53cda18c2baa0c0354bb5f9a3ecbe5ed12ab4d8e11ba873c2f11161202b945";
+
+/// A directory of its own for a test, removed along with it
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("yadt-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("workspace")).unwrap();
+        fs::create_dir_all(dir.join("home")).unwrap();
+        fs::create_dir_all(dir.join("run")).unwrap();
+        let runtime = dir.join("runtime");
+        fs::write(&runtime, FAKE_RUNTIME).unwrap();
+        fs::set_permissions(&runtime, fs::Permissions::from_mode(0o755)).unwrap();
+        Self(dir)
+    }
+
+    fn home(&self) -> PathBuf {
+        self.0.join("home")
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Runs `yadt --output json image debian:stable --no-exec` in the workspace
+/// of `dir`, with builds writing `iid` to their iidfile
+fn run_yadt(dir: &TestDir, iid: &str) -> Output {
+    let home = dir.home();
+    Command::new(env!("CARGO_BIN_EXE_yadt"))
+        .args(["--output", "json", "image", "debian:stable", "--no-exec"])
+        .current_dir(dir.0.join("workspace"))
+        .env("HOME", &home)
+        .env("XDG_CONFIG_HOME", home.join(".config"))
+        .env("XDG_DATA_HOME", home.join(".local/share"))
+        .env("XDG_STATE_HOME", home.join(".local/state"))
+        .env("XDG_CACHE_HOME", home.join(".cache"))
+        .env("XDG_RUNTIME_DIR", dir.0.join("run"))
+        .env("YADT_DOCKER_NAME", dir.0.join("runtime"))
+        .env("YADT_TEST_IID", iid)
+        .output()
+        .expect("yadt was built")
+}
+
+/// Returns the events yadt wrote to stdout of the kind `event`
+fn events(output: &Output, event: &str) -> Vec<serde_json::Value> {
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).expect("Events are json"))
+        .filter(|value| value["event"] == event)
+        .collect()
+}
+
+/// Returns the image ids state.json records under `home`
+fn recorded_image_ids(home: &Path) -> Vec<String> {
+    let state = fs::read_to_string(home.join(".local/share/yadt/state.json")).unwrap();
+    let state: serde_json::Value = serde_json::from_str(&state).unwrap();
+    state["workspaces"]
+        .as_object()
+        .expect("state.json maps workspaces to their state")
+        .values()
+        .filter_map(|entry| entry["image_id"].as_str().map(str::to_string))
+        .collect()
+}
+
+#[test]
+fn the_image_id_is_read_from_the_iidfile() {
+    let dir = TestDir::new("iidfile");
+    let output = run_yadt(&dir, &format!("{}\n", IMAGE_ID));
+
+    assert!(
+        output.status.success(),
+        "yadt failed: {}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    let images = events(&output, "image");
+    assert_eq!(images.len(), 1, "{:?}", images);
+    assert_eq!(images[0]["id"], IMAGE_ID);
+    assert_eq!(images[0]["built"], true);
+    assert_eq!(recorded_image_ids(&dir.home()), [IMAGE_ID]);
+}
+
+#[test]
+fn an_empty_iidfile_fails_the_build() {
+    let dir = TestDir::new("empty-iidfile");
+    let output = run_yadt(&dir, "");
+
+    assert_eq!(output.status.code(), Some(3));
+    let errors = events(&output, "error");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(errors[0]["category"], "empty_build_output");
+    assert_eq!(errors[0]["exit_code"], 3);
+    let message = errors[0]["message"].as_str().unwrap();
+    assert!(
+        message.contains("without writing an image id"),
+        "{}",
+        message
+    );
+    assert!(events(&output, "image").is_empty());
+}
@@ -0,0 +1,137 @@
+//! Interrupting yadt while it waits for a build, against a fake container
+//! runtime whose builds sleep until they are killed.
+
+use std::{
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::{self, Child, Command, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// A runtime answering like docker whose builds record their pid and the
+/// iidfile they were given, write the iidfile, and then sleep in place of
+/// the script
+const FAKE_RUNTIME: &str = r#"#!/bin/sh
+case "$1" in
+  --version) echo "Docker version 24.0.7, build afdd53b"; exit 0;;
+  build)
+    while [ $# -gt 0 ]; do
+      if [ "$1" = --iidfile ]; then echo "sha256:abc123" > "$2"; echo "$2" > "$YADT_TEST_DIR/iidfile"; fi
+      shift
+    done
+    cat > /dev/null
+    echo $$ > "$YADT_TEST_DIR/build.pid.tmp"
+    mv "$YADT_TEST_DIR/build.pid.tmp" "$YADT_TEST_DIR/build.pid"
+    exec sleep 60;;
+  container|inspect|image) exit 1;;
+esac
+exit 0
+"#;
+
+/// Polls `done` until it returns something or `timeout` passes
+fn wait_for<T>(timeout: Duration, mut done: impl FnMut() -> Option<T>) -> Option<T> {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if let Some(value) = done() {
+            return Some(value);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    None
+}
+
+/// Returns whether process `pid` is gone, counting a zombie as gone since
+/// nothing is left of it but its exit status
+fn is_gone(pid: i32) -> bool {
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        // the state follows the parenthesized command name
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .is_some_and(|(_, rest)| rest.trim_start().starts_with('Z')),
+        // SAFETY: signal 0 only checks whether the process exists
+        Err(_) => unsafe { libc::kill(pid, 0) != 0 },
+    }
+}
+
+/// Starts `yadt image` in a workspace of `dir`, with a home and runtime
+/// directory of its own, a config generating startup files, and the fake
+/// runtime at `runtime`
+fn start_yadt(dir: &Path, runtime: &Path) -> Child {
+    let workspace = dir.join("workspace");
+    let home = dir.join("home");
+    fs::create_dir_all(&workspace).unwrap();
+    fs::create_dir_all(home.join(".config/yadt")).unwrap();
+    fs::create_dir_all(dir.join("run")).unwrap();
+    fs::write(
+        home.join(".config/yadt/config.toml"),
+        "on_enter = [\"echo entered\"]\n",
+    )
+    .unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_yadt"))
+        .args(["image", "debian:stable"])
+        .current_dir(&workspace)
+        .env("HOME", &home)
+        .env("XDG_CONFIG_HOME", home.join(".config"))
+        .env("XDG_STATE_HOME", home.join(".local/state"))
+        .env("XDG_CACHE_HOME", home.join(".cache"))
+        .env("XDG_RUNTIME_DIR", dir.join("run"))
+        .env("YADT_DOCKER_NAME", runtime)
+        .env("YADT_TEST_DIR", dir)
+        .spawn()
+        .expect("yadt was built")
+}
+
+#[test]
+fn sigint_during_a_build_kills_it_and_exits_with_130() {
+    let dir = env::temp_dir().join(format!("yadt-test-{}-interrupt", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let runtime = dir.join("runtime");
+    fs::write(&runtime, FAKE_RUNTIME).unwrap();
+    fs::set_permissions(&runtime, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut yadt = start_yadt(&dir, &runtime);
+    let build = wait_for(Duration::from_secs(20), || {
+        let pid = fs::read_to_string(dir.join("build.pid")).ok()?;
+        pid.trim().parse::<i32>().ok()
+    });
+    let Some(build) = build else {
+        let _ = yadt.kill();
+        panic!("The build never started");
+    };
+
+    // SAFETY: yadt is a child of this test, so its pid wasn't reused
+    unsafe {
+        libc::kill(yadt.id() as i32, libc::SIGINT);
+    }
+    let status: Option<ExitStatus> = wait_for(Duration::from_secs(10), || {
+        yadt.try_wait().expect("yadt can be waited for")
+    });
+    let Some(status) = status else {
+        let _ = yadt.kill();
+        // SAFETY: the build is still ours to clean up
+        unsafe {
+            libc::kill(build, libc::SIGKILL);
+        }
+        panic!("yadt didn't exit after SIGINT");
+    };
+
+    assert_eq!(status.code(), Some(130));
+    assert!(is_gone(build), "The build {} outlived yadt", build);
+    let iidfile = fs::read_to_string(dir.join("iidfile")).unwrap();
+    assert!(
+        !Path::new(iidfile.trim()).exists(),
+        "The iidfile {} was left behind",
+        iidfile.trim()
+    );
+    let left: Vec<_> = fs::read_dir(dir.join("run"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert!(left.is_empty(), "{:?} were left behind", left);
+
+    let _ = fs::remove_dir_all(&dir);
+}